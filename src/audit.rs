@@ -0,0 +1,202 @@
+//! `ncp verify --manifest FILE DIR`: check a received tree against the
+//! manifest written for it, later and without a connection.
+//!
+//! Every `ok` record names a file, relative to `DIR`, that must be there
+//! with its size and checksum; the algorithm is told by the checksum's
+//! length, 8 hex digits for CRC-32 and 64 for SHA-256, and a record without
+//! one (`--checksum none` or `tree`) is held to its size only. A file below
+//! `DIR` that no record names, with any status, is extra. Where a path is
+//! recorded more than once, as when a file was retried, the last record
+//! counts. Each file is printed as `match`, `mismatch`, `missing` or
+//! `extra`, then a count of each, and anything but a match fails.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+use crate::checksum::{calculate_file_checksum, to_hex, ChecksumAlg};
+use crate::directory::{walk_directory, Filter};
+use crate::json::{self, Json};
+use crate::types::{NcpError, Result};
+
+/// How a file compares with its record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Match,
+    Mismatch,
+    Missing,
+    Extra,
+}
+
+impl Verdict {
+    fn label(self) -> &'static str {
+        match self {
+            Verdict::Match => "match",
+            Verdict::Mismatch => "mismatch",
+            Verdict::Missing => "missing",
+            Verdict::Extra => "extra",
+        }
+    }
+}
+
+/// An `ok` record: the size and checksum the file was saved with.
+struct Expected {
+    size: Option<u64>,
+    checksum: String,
+}
+
+/// Audit `dir` against `manifest`, printing each file, and fail unless
+/// every one matched.
+pub fn verify(manifest: &Path, dir: &Path) -> Result<()> {
+    let verdicts = audit(manifest, dir)?;
+    let count = |verdict| verdicts.iter().filter(|(v, _)| *v == verdict).count();
+    for (verdict, path) in &verdicts {
+        status!("{:<8} {}", verdict.label(), path);
+    }
+    status!(
+        "Audited {} file{}: {} matched, {} mismatched, {} missing, {} extra",
+        verdicts.len(),
+        if verdicts.len() == 1 { "" } else { "s" },
+        count(Verdict::Match),
+        count(Verdict::Mismatch),
+        count(Verdict::Missing),
+        count(Verdict::Extra)
+    );
+    let bad = verdicts.len() - count(Verdict::Match);
+    if bad > 0 {
+        let message = format!("{} of {} files do not match {}", bad, verdicts.len(), manifest.display());
+        return Err(NcpError::ChecksumMismatch(message));
+    }
+    Ok(())
+}
+
+/// The verdict on every file `manifest` expects in `dir`, in its order,
+/// then on every extra file, in the order of a walk.
+pub fn audit(manifest: &Path, dir: &Path) -> Result<Vec<(Verdict, String)>> {
+    let (order, records) = load(manifest)?;
+    let mut verdicts = Vec::new();
+    for path in &order {
+        if let Some(expected) = &records[path] {
+            verdicts.push((check(&dir.join(path), expected)?, path.clone()));
+        }
+    }
+
+    // The manifest may have been written into the tree it describes.
+    let manifest = fs::canonicalize(manifest).ok();
+    for entry in walk_directory(dir, &Filter::default())? {
+        let entry = entry?;
+        if entry.is_dir || records.contains_key(&entry.relative_path) {
+            continue;
+        }
+        if manifest.is_some() && fs::canonicalize(&entry.path).ok() == manifest {
+            continue;
+        }
+        verdicts.push((Verdict::Extra, entry.relative_path));
+    }
+    Ok(verdicts)
+}
+
+/// The paths of `manifest` in the order first recorded, and the last
+/// record of each: what is expected of it if `ok`, `None` otherwise.
+type Records = (Vec<String>, HashMap<String, Option<Expected>>);
+
+fn load(manifest: &Path) -> Result<Records> {
+    let text = fs::read_to_string(manifest)
+        .map_err(|e| format!("Cannot read manifest {}: {}", manifest.display(), e))?;
+    let mut order = Vec::new();
+    let mut records = HashMap::new();
+    for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let at = |why: &str| -> NcpError {
+            format!("{}: line {}: {}", manifest.display(), number + 1, why).into()
+        };
+        let record = json::parse(line).map_err(|e| at(&e.to_string()))?;
+        let field = |key| record.get(key).and_then(Json::as_str);
+        let path = field("path").ok_or_else(|| at("a record without a path"))?.to_string();
+        if !is_relative(&path) {
+            return Err(at(&format!("{} is not a path inside the directory", path)));
+        }
+        let expected = (field("status") == Some("ok")).then(|| Expected {
+            size: record.get("size").and_then(Json::as_u64),
+            checksum: field("checksum").unwrap_or_default().to_ascii_lowercase(),
+        });
+        if records.insert(path.clone(), expected).is_none() {
+            order.push(path);
+        }
+    }
+    Ok((order, records))
+}
+
+/// Whether `path` stays inside the directory it is joined to.
+fn is_relative(path: &str) -> bool {
+    Path::new(path).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// How the file at `path` compares with `expected`.
+fn check(path: &Path, expected: &Expected) -> Result<Verdict> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(Verdict::Mismatch),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Verdict::Missing),
+        Err(e) => return Err(format!("Cannot read {}: {}", path.display(), e).into()),
+    };
+    if expected.size.is_some_and(|size| size != metadata.len()) {
+        return Ok(Verdict::Mismatch);
+    }
+    let alg = match expected.checksum.len() {
+        0 => return Ok(Verdict::Match),
+        8 => ChecksumAlg::Crc32,
+        64 => ChecksumAlg::Sha256,
+        _ => return Err(format!("Unknown checksum {} for {}", expected.checksum, path.display()).into()),
+    };
+    let digest = calculate_file_checksum(path, alg)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    Ok(if to_hex(&digest) == expected.checksum { Verdict::Match } else { Verdict::Mismatch })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+
+    #[test]
+    fn test_tampered_and_deleted_files_are_flagged() {
+        let root = std::env::temp_dir().join(format!("ncp-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("tree/sub")).unwrap();
+        let manifest_path = root.join("manifest.jsonl");
+        let manifest = Manifest::create(&manifest_path).unwrap();
+        for (path, contents, alg) in [
+            ("same.txt", "hello", ChecksumAlg::Sha256),
+            ("sub/crc.txt", "world", ChecksumAlg::Crc32),
+            ("tampered.txt", "original", ChecksumAlg::Sha256),
+            ("deleted.txt", "gone", ChecksumAlg::Sha256),
+        ] {
+            let file = root.join("tree").join(path);
+            fs::write(&file, contents).unwrap();
+            let checksum = to_hex(&calculate_file_checksum(&file, alg).unwrap());
+            manifest.done(path, contents.len() as u64, &checksum, None).unwrap();
+        }
+        manifest.skipped("kept.txt", 4, "Destination file already exists").unwrap();
+        fs::write(root.join("tree/kept.txt"), "mine").unwrap();
+        // Same size, other bytes: only the checksum tells.
+        fs::write(root.join("tree/tampered.txt"), "0riginal").unwrap();
+        fs::remove_file(root.join("tree/deleted.txt")).unwrap();
+        fs::write(root.join("tree/sub/new.txt"), "extra").unwrap();
+
+        assert_eq!(
+            audit(&manifest_path, &root.join("tree")).unwrap(),
+            vec![
+                (Verdict::Match, "same.txt".to_string()),
+                (Verdict::Match, "sub/crc.txt".to_string()),
+                (Verdict::Mismatch, "tampered.txt".to_string()),
+                (Verdict::Missing, "deleted.txt".to_string()),
+                (Verdict::Extra, "sub/new.txt".to_string()),
+            ]
+        );
+        let err = verify(&manifest_path, &root.join("tree")).unwrap_err().to_string();
+        assert!(err.contains("3 of 5 files do not match"), "{}", err);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,43 @@
+//! `send --batch`: the small files of a directory sent back to back, for
+//! trees of thousands of them where waiting on each file's preflight and
+//! result would take longer than the data.
+//!
+//! The sender marks the `Meta` of a file of at most `MAX_FILE_SIZE` bytes
+//! `batched` and follows it at once with its `TransferStart`, body and
+//! `Checksum`, then goes on to the next. The receiver takes each one as it
+//! would any other, but holds back its `PreflightOk` or `PreflightFail`
+//! and `TransferResult`, and reads past the body of a file it declines.
+//! After at most `MAX_FILES` files or `MAX_BYTES` bytes, and before any
+//! entry that needs an answer first, the sender writes `BatchEnd`; the
+//! receiver then writes everything it held back at once, and the sender
+//! reads it file by file as if it had waited for each.
+//!
+//! A larger file still waits for its preflight, so that the receiver can
+//! check the space for it before any of it is sent. A receiver that takes
+//! batches says so with `CAPABILITY` in the handshake; without it, or with
+//! `--checksum tree` or a receiver that is only verifying, every file is
+//! sent on its own.
+
+/// Listed in `handshake::CAPABILITIES` by a receiver that takes batches.
+pub const CAPABILITY: &str = "batch";
+
+/// Largest file sent in a batch.
+pub const MAX_FILE_SIZE: u64 = 64 * 1024;
+
+/// Most files in one batch. The answers to them wait in the socket
+/// buffers until the sender reads them, which they must not fill.
+pub const MAX_FILES: usize = 128;
+
+/// Most file data in one batch, so that little is sent in vain if the
+/// receiver declines it.
+pub const MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Whether a file of `size` bytes goes into a batch.
+pub fn fits(size: u64) -> bool {
+    size <= MAX_FILE_SIZE
+}
+
+/// Whether a batch of `files` files and `bytes` bytes is to be ended.
+pub fn full(files: usize, bytes: u64) -> bool {
+    files >= MAX_FILES || bytes >= MAX_BYTES
+}
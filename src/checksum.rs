@@ -0,0 +1,313 @@
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::types::Result;
+
+/// Digest used to verify each file, chosen with `send --checksum`. The name
+/// travels in `FileMeta.checksum_alg` so the receiver computes the same one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumAlg {
+    /// No verification beyond the byte count.
+    None,
+    /// CRC-32 (IEEE): catches corruption, not tampering.
+    Crc32,
+    #[default]
+    Sha256,
+}
+
+impl ChecksumAlg {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(ChecksumAlg::None),
+            "crc32" => Ok(ChecksumAlg::Crc32),
+            "sha256" => Ok(ChecksumAlg::Sha256),
+            _ => Err(format!("Invalid checksum: {} (expected none, crc32 or sha256)", value).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlg::None => "none",
+            ChecksumAlg::Crc32 => "crc32",
+            ChecksumAlg::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Listed in `handshake::CAPABILITIES`; a directory is only sent with a
+/// tree checksum to a receiver that lists it too.
+pub const TREE_CAPABILITY: &str = "checksum:tree";
+
+/// Listed in `handshake::CAPABILITIES`; a file that arrives with the wrong
+/// checksum is only offered again on the same connection, rather than
+/// failing it, when both sides list it.
+pub const RETRY_CAPABILITY: &str = "checksum:retry";
+
+/// What one digest covers (`--checksum per-file` or `--checksum tree`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumScope {
+    /// Each file is verified on its own as it arrives.
+    #[default]
+    PerFile,
+    /// The files of a directory transfer are verified together, by one
+    /// digest over their contents in the order they were sent, after the
+    /// last one. A mismatch fails the transfer without saying which file
+    /// is wrong.
+    Tree,
+}
+
+impl ChecksumScope {
+    /// `None` for anything that is not a scope, such as an algorithm name.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "per-file" => Some(ChecksumScope::PerFile),
+            "tree" => Some(ChecksumScope::Tree),
+            _ => None,
+        }
+    }
+}
+
+/// Incremental checksum over a byte stream. Feeding the same bytes in any
+/// chunking yields the same digest.
+pub enum StreamingChecksum {
+    None,
+    Crc32(Crc32),
+    Sha256(Box<Sha256>),
+}
+
+impl StreamingChecksum {
+    pub fn new(alg: ChecksumAlg) -> Self {
+        match alg {
+            ChecksumAlg::None => StreamingChecksum::None,
+            ChecksumAlg::Crc32 => StreamingChecksum::Crc32(Crc32::new()),
+            ChecksumAlg::Sha256 => StreamingChecksum::Sha256(Box::new(Sha256::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamingChecksum::None => {}
+            StreamingChecksum::Crc32(crc) => crc.update(data),
+            StreamingChecksum::Sha256(sha) => sha.update(data),
+        }
+    }
+
+    /// Digest as raw big-endian bytes, matching `FileMeta.checksum`. Empty
+    /// for `ChecksumAlg::None`.
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            StreamingChecksum::None => Vec::new(),
+            StreamingChecksum::Crc32(crc) => crc.finalize().to_be_bytes().to_vec(),
+            StreamingChecksum::Sha256(sha) => sha.finalize().to_vec(),
+        }
+    }
+}
+
+impl Default for StreamingChecksum {
+    fn default() -> Self {
+        Self::new(ChecksumAlg::default())
+    }
+}
+
+/// Byte-at-a-time lookup table for the reflected IEEE polynomial.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32 { crc: !0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = CRC32_TABLE[((self.crc ^ byte as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finalize(self) -> u32 {
+        !self.crc
+    }
+}
+
+/// CRC-32 of a single buffer, as used for `TRANSFER_CHUNKED` chunks.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+/// HMAC-SHA256 (RFC 2104) of `data` under `key`, for `--psk`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    // HMAC takes a key of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Compare two digests in time that does not depend on where they differ.
+pub fn digests_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn calculate_file_checksum(path: &Path, alg: ChecksumAlg) -> io::Result<Vec<u8>> {
+    calculate_checksum(&mut File::open(path)?, alg)
+}
+
+/// Digest of everything `reader` gives until it ends.
+pub fn calculate_checksum<R: Read>(reader: &mut R, alg: ChecksumAlg) -> io::Result<Vec<u8>> {
+    let mut checksum = StreamingChecksum::new(alg);
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        checksum.update(&buffer[..n]);
+    }
+
+    Ok(checksum.finalize())
+}
+
+/// Feed the first `len` bytes of `reader` into `checksum`, returning how
+/// many were actually available.
+pub fn hash_prefix<R: Read>(
+    reader: &mut R,
+    len: u64,
+    checksum: &mut StreamingChecksum,
+) -> io::Result<u64> {
+    let mut limited = reader.take(len);
+    let mut buffer = [0u8; 8192];
+    let mut total = 0u64;
+
+    loop {
+        let n = limited.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        checksum.update(&buffer[..n]);
+        total += n as u64;
+    }
+
+    Ok(total)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(alg: ChecksumAlg, data: &[u8]) -> String {
+        let mut checksum = StreamingChecksum::new(alg);
+        checksum.update(data);
+        to_hex(&checksum.finalize())
+    }
+
+    #[test]
+    fn test_chunking_independent() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+
+        for alg in [ChecksumAlg::Crc32, ChecksumAlg::Sha256] {
+            let mut whole = StreamingChecksum::new(alg);
+            whole.update(&data);
+
+            for size in [1, 5, 63, 64, 65] {
+                let mut pieces = StreamingChecksum::new(alg);
+                for chunk in data.chunks(size) {
+                    pieces.update(chunk);
+                }
+                assert_eq!(to_hex(&pieces.finalize()), digest(alg, &data), "{:?} by {}", alg, size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sha256_known_vectors() {
+        let sha256 = |data: &[u8]| digest(ChecksumAlg::Sha256, data);
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&[b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256_known_vectors() {
+        // RFC 4231 test cases 2 and 6.
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let long_key = [0xaau8; 131];
+        let data = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        assert_eq!(
+            to_hex(&hmac_sha256(&long_key, data)),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert!(digests_equal(b"same", b"same"));
+        assert!(!digests_equal(b"same", b"sane"));
+        assert!(!digests_equal(b"same", b"sam"));
+    }
+
+    #[test]
+    fn test_crc32_and_none() {
+        assert_eq!(digest(ChecksumAlg::Crc32, b"123456789"), "cbf43926");
+        assert_eq!(digest(ChecksumAlg::None, b"123456789"), "");
+        assert_eq!(ChecksumAlg::parse("crc32").unwrap(), ChecksumAlg::Crc32);
+        assert!(ChecksumAlg::parse("md5").is_err());
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(from_hex("abc"), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! `--checksum-cache`: digests of source files from earlier sends, so that
+//! hashing a large tree up front (`--skip-existing`, a receiver's
+//! `--verify-only`) only reads the files that changed since.
+//!
+//! The cache is a JSON Lines file with one object per file: `path`
+//! (absolute), `alg`, `size`, `mtime` (nanoseconds since the epoch) and
+//! `checksum` in hex. An entry is only used while the file keeps the size
+//! and mtime it had when hashed; a file rewritten with both unchanged is
+//! taken to be unchanged too. Unreadable lines are dropped, and the file is
+//! rewritten when the send ends if anything was added.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::checksum::{calculate_file_checksum, from_hex, to_hex, ChecksumAlg};
+use crate::json::{self, Json};
+use crate::types::Result;
+
+/// What a file looked like when its digest was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    mtime: u64,
+    checksum: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Keyed by absolute path and algorithm name.
+    entries: HashMap<(String, String), Entry>,
+    changed: bool,
+}
+
+/// Where digests are looked up and kept; the default keeps none and hashes
+/// every time.
+#[derive(Debug, Default)]
+pub struct ChecksumCache {
+    file: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl ChecksumCache {
+    /// Load the cache at `path`; a missing file is an empty cache.
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Cannot read checksum cache {}: {}", path.display(), e).into()),
+        };
+        let mut entries = HashMap::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            match parse_line(line) {
+                Some((key, entry)) => {
+                    entries.insert(key, entry);
+                }
+                None => vlog!("Ignoring a malformed line in {}", path.display()),
+            }
+        }
+        vvlog!("Loaded {} cached checksums from {}", entries.len(), path.display());
+        Ok(ChecksumCache {
+            file: Some(path.to_path_buf()),
+            state: Mutex::new(State {
+                entries,
+                changed: false,
+            }),
+        })
+    }
+
+    /// The `alg` digest of the file at `path`, from the cache if the file
+    /// is unchanged since it was taken, and otherwise computed and kept.
+    pub fn checksum(&self, path: &Path, alg: ChecksumAlg) -> io::Result<Vec<u8>> {
+        let Some(key) = self.file.as_ref().and_then(|_| key(path, alg)) else {
+            return calculate_file_checksum(path, alg);
+        };
+        let metadata = fs::metadata(path)?;
+        // Without an mtime there is nothing to tell a changed file by.
+        let Some(mtime) = mtime_nanos(&metadata) else {
+            return calculate_file_checksum(path, alg);
+        };
+        let size = metadata.len();
+
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = state.entries.get(&key)
+            && entry.size == size
+            && entry.mtime == mtime
+        {
+            vvlog!("Cached checksum for {}", path.display());
+            return Ok(entry.checksum.clone());
+        }
+        // Not held while hashing, which may take a while.
+        drop(state);
+
+        let checksum = calculate_file_checksum(path, alg)?;
+        let entry = Entry {
+            size,
+            mtime,
+            checksum: checksum.clone(),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.insert(key, entry);
+        state.changed = true;
+        Ok(checksum)
+    }
+
+    /// Write the cache back if anything was added, replacing the old file
+    /// in one rename so that a failed write leaves it as it was.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !state.changed {
+            return Ok(());
+        }
+        let mut lines: Vec<String> = state
+            .entries
+            .iter()
+            .map(|((file, alg), entry)| {
+                let fields = vec![
+                    ("path".to_string(), Json::str(file)),
+                    ("alg".to_string(), Json::str(alg)),
+                    ("size".to_string(), Json::u64(entry.size)),
+                    ("mtime".to_string(), Json::u64(entry.mtime)),
+                    ("checksum".to_string(), Json::str(&to_hex(&entry.checksum))),
+                ];
+                Json::Object(fields).to_string()
+            })
+            .collect();
+        lines.sort();
+
+        let fail = |e: io::Error| format!("Cannot write checksum cache {}: {}", path.display(), e);
+        let mut temp = path.clone().into_os_string();
+        temp.push(".ncp_temp");
+        let temp = PathBuf::from(temp);
+        let mut out = File::create(&temp).map_err(fail)?;
+        for line in &lines {
+            writeln!(out, "{}", line).map_err(fail)?;
+        }
+        out.sync_all().map_err(fail)?;
+        fs::rename(&temp, path).map_err(fail)?;
+        vvlog!("Saved {} checksums to {}", lines.len(), path.display());
+        Ok(())
+    }
+}
+
+/// The cache key for `path`, or `None` for a name that cannot be stored.
+fn key(path: &Path, alg: ChecksumAlg) -> Option<(String, String)> {
+    let path = std::path::absolute(path).ok()?;
+    Some((path.to_str()?.to_string(), alg.name().to_string()))
+}
+
+fn mtime_nanos(metadata: &fs::Metadata) -> Option<u64> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+fn parse_line(line: &str) -> Option<((String, String), Entry)> {
+    let record = json::parse(line).ok()?;
+    let text = |name| record.get(name).and_then(Json::as_str);
+    let number = |name| record.get(name).and_then(Json::as_u64);
+    let key = (text("path")?.to_string(), text("alg")?.to_string());
+    let entry = Entry {
+        size: number("size")?,
+        mtime: number("mtime")?,
+        checksum: from_hex(text("checksum")?)?,
+    };
+    Some((key, entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unchanged_files_are_not_hashed_again() {
+        let root = std::env::temp_dir().join(format!("ncp-checksum-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let (file, cache) = (root.join("data.txt"), root.join("cache.jsonl"));
+        fs::write(&file, "version 1").unwrap();
+        let first = calculate_file_checksum(&file, ChecksumAlg::Sha256).unwrap();
+
+        let cached = ChecksumCache::open(&cache).unwrap();
+        assert_eq!(cached.checksum(&file, ChecksumAlg::Sha256).unwrap(), first);
+        cached.save().unwrap();
+        assert_eq!(fs::read_to_string(&cache).unwrap().lines().count(), 1);
+
+        // Same size and mtime: only the cache can still give the old digest.
+        let mtime = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::write(&file, "version 2").unwrap();
+        File::options().write(true).open(&file).unwrap().set_modified(mtime).unwrap();
+        let cached = ChecksumCache::open(&cache).unwrap();
+        assert_eq!(cached.checksum(&file, ChecksumAlg::Sha256).unwrap(), first);
+
+        // A new mtime, or another digest, means hashing again.
+        let second = calculate_file_checksum(&file, ChecksumAlg::Sha256).unwrap();
+        let later = mtime + std::time::Duration::from_secs(5);
+        File::options().write(true).open(&file).unwrap().set_modified(later).unwrap();
+        assert_eq!(cached.checksum(&file, ChecksumAlg::Sha256).unwrap(), second);
+        let crc = calculate_file_checksum(&file, ChecksumAlg::Crc32).unwrap();
+        assert_eq!(cached.checksum(&file, ChecksumAlg::Crc32).unwrap(), crc);
+        cached.save().unwrap();
+        assert_eq!(fs::read_to_string(&cache).unwrap().lines().count(), 2);
+
+        // A damaged cache loses entries, not the send.
+        fs::write(&cache, "not json\n").unwrap();
+        let cached = ChecksumCache::open(&cache).unwrap();
+        assert_eq!(cached.checksum(&file, ChecksumAlg::Sha256).unwrap(), second);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,1298 @@
+//! The `ncp` command line: argument parsing and dispatch to `send` and
+//! `recv`, and the local `hash` command. The binary does nothing but call
+//! `run`.
+
+use std::env;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::checksum::{calculate_checksum, calculate_file_checksum, to_hex, ChecksumAlg, ChecksumScope};
+use crate::checksum_cache::ChecksumCache;
+use crate::config::{self, Section, Value};
+use crate::directory::{read_file_list, Filter};
+use crate::manifest::Manifest;
+use crate::net::{self, IpFamily, SocketOptions};
+use crate::protocol::{self, WireFormat};
+use crate::proxy::Proxy;
+use crate::tls;
+use crate::types::{
+    DedupMode, LimitScope, MirrorMode, OverwriteMode, RecvArgs, Result, SendArgs, STDIO_PATH,
+};
+use crate::types::{DEFAULT_RETRIES, DEFAULT_RETRY_DELAY};
+use crate::utils::{self, parse_bytes, DEFAULT_BUFFER_SIZE, DEFAULT_PROGRESS_INTERVAL};
+use crate::{audit, compress, events, interrupt, logging, pause, recv, send};
+
+enum Command {
+    Send(Box<SendArgs>),
+    Recv(Box<RecvArgs>),
+    Hash(ChecksumAlg, Vec<PathBuf>),
+    Verify { manifest: PathBuf, dir: PathBuf },
+}
+
+fn print_usage() {
+    eprintln!(
+        "Usage:
+  ncp send [options] --host <HOST> --port <PORT> <SRC>
+  ncp send [options] --host <HOST:PORT | [IPV6]:PORT> <SRC>
+  ncp send [options] --listen --port <PORT> <SRC>
+  ncp send [options] --host <HOST> --port <PORT> --files-from <LIST>
+  ncp recv [options] --port <PORT> <DST>
+  ncp recv [options] --host <HOST> --port <PORT> <DST>
+  ncp recv [options] --pull <PATH> --host <HOST> --port <PORT> <DST>
+  ncp hash [--checksum <ALG>] <FILE>...
+  ncp verify --manifest <FILE> <DIR>
+
+A SRC of - sends standard input; a DST of - writes the file to standard output.
+A listening side given --port 0 picks a free port and prints it.
+
+Options:
+  --retries <N>                 Connection attempts before giving up (send, default 3)
+  --retry-delay <MS>            Wait before the first retry (send, default 1000)
+  --retry-backoff <FACTOR>      Multiply the wait by FACTOR after each retry (send, default 1.0)
+  --overwrite <ask|yes|no|newer|older>
+                                Policy for existing destination files (default ask)
+  --no-clobber                  Same as --overwrite no
+  -r, --recursive               Send directories, not only files (send)
+  --listen                      Wait for the receiver to connect (send)
+  --accept-timeout <SECONDS>    Give up if no receiver connects in time (send --listen, default never)
+  --once                        Serve a single receiver, then exit; what --listen always does (send)
+  --mirror                      Delete destination entries missing from the source (send, directories)
+  --mirror-dry-run              Report what --mirror would delete without deleting
+  --exclude <GLOB>              Leave out matching entries and everything below them (send, repeatable)
+  --include <GLOB>              Only send matching files and directory trees (send, repeatable)
+  --relative-to <BASE>          Send SRC under its path below BASE, e.g. var/log/app from / (send)
+  --files-from <LIST>           Send the paths listed in LIST, one per line, instead of SRC (send)
+  --files-from0 <LIST>          Same, with the paths separated by NUL bytes (send)
+  --ignore-missing              Warn about listed paths that do not exist, instead of failing (send)
+  --json                        Emit newline-delimited JSON events on stdout
+  --progress-socket <PATH>      Also send JSON events to the Unix socket or named pipe at PATH
+  --format <binary|json>        Control message encoding to negotiate (send, default binary)
+  --compress                    Compress file data with zstd (send)
+  --compress-level <N>          zstd level, 1 to 22; implies --compress (send, default 3)
+  --compress-shared             Compress a directory as one zstd stream; implies --compress (send)
+  --verify-chunks               Check each chunk of file data with CRC32 as it arrives (send)
+  --framed                      Send file data in length-prefixed blocks, so a cut is caught (send)
+  --parallel <N>                Split a single file over N connections, up to 16 (send)
+  --batch                       Send small files back to back, answered together, not one by one (send)
+  --checksum <ALG>              Verify files with none, crc32 or sha256 (send, default sha256)
+  --checksum tree               Verify a directory with one checksum at the end, not per file (send)
+  --limit <RATE>                Cap the transfer rate, e.g. 500K or 5M per second (send)
+  --limit-scope <file|wire>     Count file data or the compressed bytes sent for --limit (default file)
+  --preserve                    Keep file permissions and modification times (send)
+  --xattrs                      Keep extended attributes, where both file systems have them (send)
+  --skip-existing               Skip files the receiver already has with the same checksum (send)
+  --checksum-on-preflight       Also only resume a partial file whose checksum matches so far (send)
+  --checksum-cache <PATH>       Keep checksums in PATH and reuse those of unchanged files (send)
+  --dry-run                     List what would be sent and the space it needs, then exit (send)
+  --buffer-size <BYTES>         Copy file data in chunks of this size, e.g. 64K or 1M (default 256K)
+  --max-message-size <BYTES>    Refuse control messages larger than this, at least 64K (default 1M)
+  --progress-interval <MS>      Update progress at most this often (default 200, 0 = every chunk)
+  --timeout <SECONDS>           Give up on a silent peer after this long (default 300, 0 = never)
+  --resume                      Continue partial files left by an interrupted transfer (both sides)
+  --keep-alive                  Keep accepting transfers until interrupted (recv, listening)
+  --as, --output-name <NAME>    Save a single received file as NAME inside DST (recv)
+  --mkdir                       Create missing parent directories of DST (recv)
+  --into                        Merge a received directory into an existing DST, not inside it (recv)
+  --verify-only                 Compare DST with the sender's checksums instead of receiving (recv)
+  --in-place                    Overwrite existing files directly, without a temp copy (recv)
+  --fsync                       Flush each file to disk before reporting it received (recv)
+  --verify-read                 Check each saved file's checksum again as it reads back (recv)
+  --dedup                       Hard-link files with the same contents as one received before (recv)
+  --dedup-reflink               Same, sharing blocks by reflink instead, on Linux (recv)
+  --write-special               Write into a FIFO or device at the destination, not refuse it (recv)
+  --allow-root <DIR>            Refuse entries that would land outside DIR (recv, repeatable)
+  --strip-components <N>        Drop the first N components of each name inside a directory (recv)
+  --delete                      Remove what a received directory did not bring from DST (recv)
+  --delete-dry-run              Report what --delete would remove without removing it (recv)
+  --max-file-size <SIZE>        Refuse files larger than SIZE, e.g. 2G (recv)
+  --pull <PATH>                 Ask a listening sender for PATH inside its SRC, or . for all of it (recv)
+  --manifest <PATH>             Record each file handled as a JSON line in PATH
+  --psk <KEY>                   Require both sides to prove they share KEY before transferring
+  --tls                         Encrypt each connection with TLS (recv needs --cert and --key)
+  --cert, --key <PEM>           Certificate chain and private key to receive with (recv)
+  --ca <PEM>                    Trust only the CAs in PEM, not the public ones (send)
+  --insecure                    Take any certificate the receiver shows, e.g. a self-signed one (send)
+  -4, -6                        Only use IPv4 or IPv6 addresses (listeners bind :: with -6)
+  --bind <ADDR>                 Connect from this local address, e.g. 10.8.0.2 or [::1]:4000 (send)
+  --proxy <URL>                 Connect through socks5://[user:pass@]host:port or http://... (send)
+  --no-nodelay                  Let TCP batch small writes (Nagle), instead of sending at once
+  --sndbuf, --rcvbuf <BYTES>    Kernel send or receive buffer size of each connection, e.g. 4M
+  -v, -vv                       Increase logging verbosity (or set NCP_LOG=info or debug)
+  --log-file <PATH>             Append log lines to PATH as well as printing them
+  --config <PATH>               Read default flags from PATH (default ~/.config/ncp/config.toml)
+  --no-<FLAG>                   Turn off a switch the config file turns on, e.g. --no-compress
+  -q, --quiet                   Print nothing but errors and warnings (--json events still go out)
+  --si                          Print sizes in powers of 1000 (KB, MB) rather than 1024 (KiB, MiB)"
+    );
+}
+
+fn take_value<'a>(args: &'a [String], i: &mut usize, flag: &str) -> Result<&'a str> {
+    *i += 1;
+    args.get(*i)
+        .map(String::as_str)
+        .ok_or_else(|| format!("Missing value for {}", flag).into())
+}
+
+fn parse_port(value: &str) -> Result<u16> {
+    net::parse_port(value).map_err(|why| format!("Invalid port {}: {}", value, why).into())
+}
+
+fn parse_buffer_size(value: &str) -> Result<usize> {
+    match usize::try_from(parse_bytes(value)?) {
+        Ok(0) => Err("--buffer-size must be greater than 0".into()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("Buffer size too large: {}", value).into()),
+    }
+}
+
+fn parse_socket_buffer(flag: &str, value: &str) -> Result<usize> {
+    match usize::try_from(parse_bytes(value)?) {
+        Ok(0) => Err(format!("{} must be greater than 0", flag).into()),
+        Ok(size) if size <= net::MAX_SOCKET_BUFFER => Ok(size),
+        _ => {
+            let max = net::MAX_SOCKET_BUFFER;
+            Err(format!("{} too large: {} (at most {} bytes)", flag, value, max).into())
+        }
+    }
+}
+
+fn parse_output_name(value: &str) -> Result<String> {
+    if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\\']) {
+        return Err(format!("--as takes a plain file name, not {:?}", value).into());
+    }
+    Ok(value.to_string())
+}
+
+/// More than any real tree is deep; a larger count is surely a typo.
+const MAX_STRIP_COMPONENTS: usize = 64;
+
+fn parse_strip_components(value: &str) -> Result<usize> {
+    match value.parse::<usize>() {
+        Ok(count) if count <= MAX_STRIP_COMPONENTS => Ok(count),
+        Ok(_) => {
+            Err(format!("--strip-components must be at most {}: {}", MAX_STRIP_COMPONENTS, value).into())
+        }
+        Err(_) => Err(format!("Invalid --strip-components: {}", value).into()),
+    }
+}
+
+fn parse_checksum(value: &str) -> Result<ChecksumAlg> {
+    ChecksumAlg::parse(value).map_err(|_| {
+        format!("Invalid checksum: {} (expected none, crc32, sha256, per-file or tree)", value).into()
+    })
+}
+
+fn parse_pattern(value: &str) -> Result<String> {
+    if value.is_empty() {
+        return Err("--include and --exclude need a non-empty pattern".into());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_psk(value: &str) -> Result<String> {
+    if value.is_empty() {
+        return Err("--psk must not be empty".into());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_timeout(value: &str) -> Result<Option<Duration>> {
+    let seconds: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid timeout: {}", value))?;
+    Ok((seconds > 0).then(|| Duration::from_secs(seconds)))
+}
+
+fn parse_retry_delay(value: &str) -> Result<Duration> {
+    let millis: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid retry delay: {}", value))?;
+    Ok(Duration::from_millis(millis))
+}
+
+fn parse_progress_interval(value: &str) -> Result<Duration> {
+    let millis: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid progress interval: {}", value))?;
+    Ok(Duration::from_millis(millis))
+}
+
+fn parse_retry_backoff(value: &str) -> Result<f64> {
+    match value.parse::<f64>() {
+        Ok(factor) if factor.is_finite() && factor >= 1.0 => Ok(factor),
+        Ok(_) => Err("--retry-backoff must be at least 1.0".into()),
+        Err(_) => Err(format!("Invalid retry backoff: {}", value).into()),
+    }
+}
+
+/// Open the `--manifest` file, once every other argument has parsed.
+fn open_manifest(path: Option<PathBuf>) -> Result<Manifest> {
+    match path {
+        Some(path) => Manifest::create(&path),
+        None => Ok(Manifest::default()),
+    }
+}
+
+fn parse_compress_level(value: &str) -> Result<i32> {
+    let levels = compress::levels();
+    match value.parse() {
+        Ok(level) if levels.contains(&level) => Ok(level),
+        _ => Err(format!(
+            "Invalid compression level: {} (expected {} to {})",
+            value,
+            levels.start(),
+            levels.end()
+        )
+        .into()),
+    }
+}
+
+/// Whether a switch was turned on.
+fn on(switch: Option<bool>) -> bool {
+    switch == Some(true)
+}
+
+/// The flags of a command, from its command line or from the config file,
+/// each `None` until one of them gives it. The command line is laid over
+/// the file's with `over`, and only the result is checked and turned into
+/// the command's arguments.
+trait Flags: Default {
+    /// Turn the switch `flag` on or off; false if `flag` is not one.
+    fn switch(&mut self, flag: &str, on: bool) -> bool;
+
+    /// Set the option `flag` to what `value` gives; false, without asking
+    /// for it, if `flag` is not one.
+    fn option(&mut self, flag: &str, value: impl FnOnce() -> Result<String>) -> Result<bool>;
+
+    /// Take an argument that is not a flag.
+    fn positional(&mut self, arg: &str) -> Result<()>;
+
+    /// These flags over `file`'s, field by field. Those of `file` that
+    /// cannot be combined with one given here are dropped, so that the
+    /// command line wins there too.
+    fn over(self, file: Self) -> Self;
+
+    fn common(&self) -> &CommonFlags;
+}
+
+/// The flags of `args`, a command line after its command. A switch is
+/// turned off with `--no-` before its name.
+fn parse_flags<F: Flags>(args: &[String]) -> Result<F> {
+    let mut flags = F::default();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let negated = arg.strip_prefix("--no-").map(|name| format!("--{}", name));
+        let known = arg == "-v"
+            || arg == "-vv"
+            || flags.switch(arg, true)
+            || negated.is_some_and(|flag| flags.switch(&flag, false))
+            || flags.option(arg, || take_value(args, &mut i, arg).map(str::to_string))?;
+        if !known {
+            if arg.starts_with('-') && arg != STDIO_PATH {
+                return Err(format!("Unknown option: {}", arg).into());
+            }
+            flags.positional(arg)?;
+        }
+        i += 1;
+    }
+    Ok(flags)
+}
+
+/// Whether `F` has an option `flag`, one that takes a value.
+fn takes_value<F: Flags>(flag: &str) -> bool {
+    !matches!(F::default().option(flag, || Ok(String::new())), Ok(false))
+}
+
+/// Give `flags` what `setting` says; false if they have no flag of its
+/// name.
+fn set<F: Flags>(flags: &mut F, setting: &config::Setting) -> Result<bool> {
+    let flag = setting.flag();
+    match &setting.value {
+        Value::Switch(on) if flags.switch(&flag, *on) => Ok(true),
+        Value::Switch(_) if takes_value::<F>(&flag) => {
+            Err(format!("{} takes a value, not true or false", setting.key).into())
+        }
+        Value::Switch(_) => Ok(false),
+        Value::Values(_) if F::default().switch(&flag, true) => {
+            Err(format!("{} is a switch: set it to true or false", setting.key).into())
+        }
+        Value::Values(_) if !takes_value::<F>(&flag) => Ok(false),
+        Value::Values(values) => {
+            for value in values {
+                flags.option(&flag, || Ok(value.clone()))?;
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Whether `F` has the flag `setting` names.
+fn knows<F: Flags>(setting: &config::Setting) -> bool {
+    !matches!(set(&mut F::default(), setting), Ok(false))
+}
+
+/// The flags `file` gives the command of `section`: its keys at the top
+/// that are flags of the command, and those of its own table. `Other` is
+/// the other command's, whose keys at the top are left to it; a key that
+/// neither has, or one in a table whose command lacks it, is an error.
+fn file_defaults<F: Flags, Other: Flags>(file: &config::File, section: Section) -> Result<F> {
+    let mut flags = F::default();
+    for setting in &file.settings {
+        let ours = setting.section == Section::Both || setting.section == section;
+        if ours && set(&mut flags, setting).map_err(|e| file.error(setting.line, e))? {
+            continue;
+        }
+        let owner = match setting.section {
+            Section::Both if knows::<Other>(setting) => continue,
+            table if table != section && knows::<Other>(setting) => continue,
+            table if table == section && knows::<Other>(setting) => section.other(),
+            table if table != section && knows::<F>(setting) => section,
+            _ => return Err(file.error(setting.line, format!("unknown key {}", setting.key))),
+        };
+        let why = format!(
+            "{} is a flag of {} only; move it out of {}",
+            setting.key,
+            owner.command(),
+            setting.section.name()
+        );
+        return Err(file.error(setting.line, why));
+    }
+    Ok(flags)
+}
+
+/// `flags`, from the command line, over the defaults the config file gives
+/// `command`: the file `--config` names, or without it the one at
+/// `config::default_path` if there is one. `Other` is the other command's.
+fn with_config<F: Flags, Other: Flags>(flags: F, command: &str) -> Result<F> {
+    let path = match &flags.common().config {
+        Some(path) => path.clone(),
+        None => match config::default_path().filter(|path| path.is_file()) {
+            Some(path) => path,
+            None => return Ok(flags),
+        },
+    };
+    let file = config::File::load(&path)?;
+    Ok(flags.over(file_defaults::<F, Other>(&file, Section::of(command))?))
+}
+
+/// Flags both `send` and `recv` take.
+#[derive(Default)]
+struct CommonFlags {
+    /// The name, and the port if `--host` gave one.
+    host: Option<(String, Option<u16>)>,
+    port: Option<u16>,
+    overwrite: Option<OverwriteMode>,
+    resume: Option<bool>,
+    buffer_size: Option<usize>,
+    progress_interval: Option<Duration>,
+    timeout: Option<Option<Duration>>,
+    family: Option<IpFamily>,
+    nodelay: Option<bool>,
+    sndbuf: Option<usize>,
+    rcvbuf: Option<usize>,
+    manifest: Option<PathBuf>,
+    psk: Option<String>,
+    tls: Option<bool>,
+    quiet: Option<bool>,
+    json: Option<bool>,
+    si: Option<bool>,
+    log_file: Option<PathBuf>,
+    progress_socket: Option<PathBuf>,
+    max_message_size: Option<usize>,
+    config: Option<PathBuf>,
+}
+
+impl Flags for CommonFlags {
+    fn switch(&mut self, flag: &str, on: bool) -> bool {
+        let switch = match flag {
+            "--resume" => &mut self.resume,
+            "--tls" => &mut self.tls,
+            "-q" | "--quiet" => &mut self.quiet,
+            "--json" => &mut self.json,
+            "--si" => &mut self.si,
+            "--nodelay" => &mut self.nodelay,
+            "--no-nodelay" => {
+                self.nodelay = Some(!on);
+                return true;
+            }
+            "--no-clobber" => {
+                if on {
+                    self.overwrite = Some(OverwriteMode::No);
+                }
+                return true;
+            }
+            "-4" | "-6" => {
+                self.family = Some(if flag == "-4" { IpFamily::V4 } else { IpFamily::V6 });
+                return true;
+            }
+            _ => return false,
+        };
+        *switch = Some(on);
+        true
+    }
+
+    fn option(&mut self, flag: &str, value: impl FnOnce() -> Result<String>) -> Result<bool> {
+        match flag {
+            "--host" => self.host = Some(net::split_host_port(&value()?)?),
+            "--port" => self.port = Some(parse_port(&value()?)?),
+            "--overwrite" => self.overwrite = Some(OverwriteMode::parse(&value()?)?),
+            "--buffer-size" => self.buffer_size = Some(parse_buffer_size(&value()?)?),
+            "--progress-interval" => self.progress_interval = Some(parse_progress_interval(&value()?)?),
+            "--timeout" => self.timeout = Some(parse_timeout(&value()?)?),
+            "--sndbuf" => self.sndbuf = Some(parse_socket_buffer(flag, &value()?)?),
+            "--rcvbuf" => self.rcvbuf = Some(parse_socket_buffer(flag, &value()?)?),
+            "--manifest" => self.manifest = Some(PathBuf::from(value()?)),
+            "--psk" => self.psk = Some(parse_psk(&value()?)?),
+            "--log-file" => self.log_file = Some(PathBuf::from(value()?)),
+            "--progress-socket" => self.progress_socket = Some(PathBuf::from(value()?)),
+            "--max-message-size" => self.max_message_size = Some(parse_max_message_size(&value()?)?),
+            "--config" => self.config = Some(PathBuf::from(value()?)),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn positional(&mut self, arg: &str) -> Result<()> {
+        Err(format!("Unexpected argument: {}", arg).into())
+    }
+
+    fn over(self, mut file: Self) -> Self {
+        // A port in the --host given here wins over the file's --port, as
+        // it would over its host.
+        if self.host.as_ref().is_some_and(|(_, port)| port.is_some()) {
+            file.port = None;
+        }
+        CommonFlags {
+            host: self.host.or(file.host),
+            port: self.port.or(file.port),
+            overwrite: self.overwrite.or(file.overwrite),
+            resume: self.resume.or(file.resume),
+            buffer_size: self.buffer_size.or(file.buffer_size),
+            progress_interval: self.progress_interval.or(file.progress_interval),
+            timeout: self.timeout.or(file.timeout),
+            family: self.family.or(file.family),
+            nodelay: self.nodelay.or(file.nodelay),
+            sndbuf: self.sndbuf.or(file.sndbuf),
+            rcvbuf: self.rcvbuf.or(file.rcvbuf),
+            manifest: self.manifest.or(file.manifest),
+            psk: self.psk.or(file.psk),
+            tls: self.tls.or(file.tls),
+            quiet: self.quiet.or(file.quiet),
+            json: self.json.or(file.json),
+            si: self.si.or(file.si),
+            log_file: self.log_file.or(file.log_file),
+            progress_socket: self.progress_socket.or(file.progress_socket),
+            max_message_size: self.max_message_size.or(file.max_message_size),
+            config: self.config,
+        }
+    }
+
+    fn common(&self) -> &CommonFlags {
+        self
+    }
+}
+
+impl CommonFlags {
+    /// Set up the output these flags ask for, for the whole run.
+    fn apply(&self) -> Result<()> {
+        logging::set_quiet(on(self.quiet));
+        events::set_json(on(self.json));
+        utils::set_si_units(on(self.si));
+        if let Some(path) = &self.log_file {
+            logging::set_log_file(path)?;
+        }
+        if let Some(path) = &self.progress_socket {
+            events::set_progress_socket(path)?;
+        }
+        if let Some(size) = self.max_message_size {
+            protocol::set_max_message_size(size);
+        }
+        Ok(())
+    }
+
+    /// `--host` split into its name and the port it gave, if any.
+    fn host(&mut self) -> (Option<String>, Option<u16>) {
+        self.host.take().map_or((None, None), |(name, port)| (Some(name), port))
+    }
+
+    fn socket(&self) -> SocketOptions {
+        let default = SocketOptions::default();
+        SocketOptions {
+            nodelay: self.nodelay.unwrap_or(default.nodelay),
+            sndbuf: self.sndbuf.or(default.sndbuf),
+            rcvbuf: self.rcvbuf.or(default.rcvbuf),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SendFlags {
+    common: CommonFlags,
+    src: Option<PathBuf>,
+    retries: Option<u32>,
+    retry_delay: Option<Duration>,
+    retry_backoff: Option<f64>,
+    recursive: Option<bool>,
+    listen: Option<bool>,
+    accept_timeout: Option<Option<Duration>>,
+    once: Option<bool>,
+    mirror: Option<bool>,
+    mirror_dry_run: Option<bool>,
+    format: Option<WireFormat>,
+    compress: Option<bool>,
+    compress_level: Option<i32>,
+    compress_shared: Option<bool>,
+    verify_chunks: Option<bool>,
+    framed: Option<bool>,
+    parallel: Option<u32>,
+    batch: Option<bool>,
+    checksum: Option<ChecksumAlg>,
+    checksum_scope: Option<ChecksumScope>,
+    checksum_cache: Option<PathBuf>,
+    preserve: Option<bool>,
+    xattrs: Option<bool>,
+    skip_existing: Option<bool>,
+    checksum_on_preflight: Option<bool>,
+    dry_run: Option<bool>,
+    limit: Option<u64>,
+    limit_scope: Option<LimitScope>,
+    ca: Option<PathBuf>,
+    insecure: Option<bool>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    /// The list, and whether its names end in NUL (`--files-from0`).
+    files_from: Option<(PathBuf, bool)>,
+    ignore_missing: Option<bool>,
+    relative_to: Option<PathBuf>,
+    bind: Option<SocketAddr>,
+    proxy: Option<Proxy>,
+}
+
+impl Flags for SendFlags {
+    fn switch(&mut self, flag: &str, on: bool) -> bool {
+        let switch = match flag {
+            "-r" | "--recursive" => &mut self.recursive,
+            "--listen" => &mut self.listen,
+            "--once" => &mut self.once,
+            "--mirror" => &mut self.mirror,
+            "--mirror-dry-run" => &mut self.mirror_dry_run,
+            "--compress" => &mut self.compress,
+            "--compress-shared" => &mut self.compress_shared,
+            "--verify-chunks" => &mut self.verify_chunks,
+            "--framed" => &mut self.framed,
+            "--batch" => &mut self.batch,
+            "--preserve" => &mut self.preserve,
+            "--xattrs" => &mut self.xattrs,
+            "--skip-existing" => &mut self.skip_existing,
+            "--checksum-on-preflight" => &mut self.checksum_on_preflight,
+            "--dry-run" => &mut self.dry_run,
+            "--insecure" => &mut self.insecure,
+            "--ignore-missing" => &mut self.ignore_missing,
+            _ => return self.common.switch(flag, on),
+        };
+        *switch = Some(on);
+        true
+    }
+
+    fn option(&mut self, flag: &str, value: impl FnOnce() -> Result<String>) -> Result<bool> {
+        match flag {
+            "--retries" => {
+                let value = value()?;
+                let retries = value.parse().map_err(|_| format!("Invalid retries value: {}", value))?;
+                self.retries = Some(retries);
+            }
+            "--retry-delay" => self.retry_delay = Some(parse_retry_delay(&value()?)?),
+            "--retry-backoff" => self.retry_backoff = Some(parse_retry_backoff(&value()?)?),
+            "--accept-timeout" => self.accept_timeout = Some(parse_timeout(&value()?)?),
+            "--format" => self.format = Some(WireFormat::parse(&value()?)?),
+            "--compress-level" => self.compress_level = Some(parse_compress_level(&value()?)?),
+            "--parallel" => {
+                let value = value()?;
+                let parallel = value.parse().map_err(|_| format!("Invalid --parallel: {}", value))?;
+                self.parallel = Some(parallel);
+            }
+            "--checksum" => {
+                let value = value()?;
+                match ChecksumScope::parse(&value) {
+                    Some(scope) => self.checksum_scope = Some(scope),
+                    None => self.checksum = Some(parse_checksum(&value)?),
+                }
+            }
+            "--checksum-cache" => self.checksum_cache = Some(PathBuf::from(value()?)),
+            "--limit" => {
+                let rate = parse_bytes(&value()?)?;
+                if rate == 0 {
+                    return Err("--limit must be greater than 0".into());
+                }
+                self.limit = Some(rate);
+            }
+            "--limit-scope" => self.limit_scope = Some(LimitScope::parse(&value()?)?),
+            "--ca" => self.ca = Some(PathBuf::from(value()?)),
+            "--include" => self.include.get_or_insert_default().push(parse_pattern(&value()?)?),
+            "--exclude" => self.exclude.get_or_insert_default().push(parse_pattern(&value()?)?),
+            "--files-from" | "--files-from0" => {
+                self.files_from = Some((PathBuf::from(value()?), flag == "--files-from0"))
+            }
+            "--relative-to" => self.relative_to = Some(PathBuf::from(value()?)),
+            "--bind" => self.bind = Some(net::parse_bind(&value()?)?),
+            "--proxy" => self.proxy = Some(Proxy::parse(&value()?)?),
+            _ => return self.common.option(flag, value),
+        }
+        Ok(true)
+    }
+
+    fn positional(&mut self, arg: &str) -> Result<()> {
+        if self.src.is_some() {
+            return Err(format!("Unexpected argument: {}", arg).into());
+        }
+        self.src = Some(PathBuf::from(arg));
+        Ok(())
+    }
+
+    fn over(self, mut file: Self) -> Self {
+        self.drop_conflicts(&mut file);
+        SendFlags {
+            common: self.common.over(file.common),
+            src: self.src,
+            retries: self.retries.or(file.retries),
+            retry_delay: self.retry_delay.or(file.retry_delay),
+            retry_backoff: self.retry_backoff.or(file.retry_backoff),
+            recursive: self.recursive.or(file.recursive),
+            listen: self.listen.or(file.listen),
+            accept_timeout: self.accept_timeout.or(file.accept_timeout),
+            once: self.once.or(file.once),
+            mirror: self.mirror.or(file.mirror),
+            mirror_dry_run: self.mirror_dry_run.or(file.mirror_dry_run),
+            format: self.format.or(file.format),
+            compress: self.compress.or(file.compress),
+            compress_level: self.compress_level.or(file.compress_level),
+            compress_shared: self.compress_shared.or(file.compress_shared),
+            verify_chunks: self.verify_chunks.or(file.verify_chunks),
+            framed: self.framed.or(file.framed),
+            parallel: self.parallel.or(file.parallel),
+            batch: self.batch.or(file.batch),
+            checksum: self.checksum.or(file.checksum),
+            checksum_scope: self.checksum_scope.or(file.checksum_scope),
+            checksum_cache: self.checksum_cache.or(file.checksum_cache),
+            preserve: self.preserve.or(file.preserve),
+            xattrs: self.xattrs.or(file.xattrs),
+            skip_existing: self.skip_existing.or(file.skip_existing),
+            checksum_on_preflight: self.checksum_on_preflight.or(file.checksum_on_preflight),
+            dry_run: self.dry_run.or(file.dry_run),
+            limit: self.limit.or(file.limit),
+            limit_scope: self.limit_scope.or(file.limit_scope),
+            ca: self.ca.or(file.ca),
+            insecure: self.insecure.or(file.insecure),
+            include: self.include.or(file.include),
+            exclude: self.exclude.or(file.exclude),
+            files_from: self.files_from.or(file.files_from),
+            ignore_missing: self.ignore_missing.or(file.ignore_missing),
+            relative_to: self.relative_to.or(file.relative_to),
+            bind: self.bind.or(file.bind),
+            proxy: self.proxy.or(file.proxy),
+        }
+    }
+
+    fn common(&self) -> &CommonFlags {
+        &self.common
+    }
+}
+
+impl SendFlags {
+    fn compresses(&self) -> bool {
+        on(self.compress) || self.compress_level.is_some() || on(self.compress_shared)
+    }
+
+    fn splits(&self) -> bool {
+        self.parallel.is_some_and(|streams| streams > 1)
+    }
+
+    fn drop_compression(&mut self) {
+        self.compress = None;
+        self.compress_level = None;
+        self.compress_shared = None;
+    }
+
+    /// Drop from `file` what `SendArgs::check` or `into_args` would refuse
+    /// alongside what these flags give.
+    fn drop_conflicts(&self, file: &mut SendFlags) {
+        // Each of the first three frames file data its own way, and a
+        // split file is written where it belongs as it arrives.
+        if self.compresses() || self.compress == Some(false) {
+            file.verify_chunks = None;
+            file.framed = None;
+            file.parallel = None;
+        }
+        if self.compress == Some(false) {
+            file.drop_compression();
+        }
+        if on(self.verify_chunks) || on(self.framed) || self.splits() {
+            file.drop_compression();
+            file.verify_chunks = None;
+            file.framed = None;
+            file.parallel = None;
+        }
+        if self.listen.is_some() || self.splits() || self.bind.is_some() || self.proxy.is_some() {
+            file.accept_timeout = None;
+            file.once = None;
+        }
+        if on(self.listen) {
+            file.bind = None;
+            file.proxy = None;
+            file.parallel = None;
+        }
+        if self.splits() || self.bind.is_some() || self.proxy.is_some() {
+            file.listen = None;
+        }
+        // The receiver would delete whatever was filtered out.
+        if on(self.mirror) || on(self.mirror_dry_run) {
+            file.mirror_dry_run = None;
+            file.include = None;
+            file.exclude = None;
+            file.relative_to = None;
+        }
+        if self.include.is_some() || self.exclude.is_some() || self.relative_to.is_some() {
+            file.mirror = None;
+            file.mirror_dry_run = None;
+        }
+        if self.checksum == Some(ChecksumAlg::None) {
+            file.skip_existing = None;
+            file.checksum_on_preflight = None;
+            file.checksum_scope = None;
+        }
+        let digests = on(self.skip_existing) || on(self.checksum_on_preflight);
+        if (digests || self.checksum_scope == Some(ChecksumScope::Tree))
+            && file.checksum == Some(ChecksumAlg::None)
+        {
+            file.checksum = None;
+        }
+        if self.src.is_some() {
+            file.files_from = None;
+        }
+        if on(self.insecure) || self.common.tls == Some(false) {
+            file.ca = None;
+        }
+        if self.ca.is_some() || self.common.tls == Some(false) {
+            file.insecure = None;
+        }
+    }
+
+    fn into_args(mut self) -> Result<SendArgs> {
+        let (host, host_port) = self.common.host();
+        let listen = on(self.listen);
+        let dry_run = on(self.dry_run);
+        if host.is_none() && !listen && !dry_run {
+            return Err("--host is required (or use --listen)".into());
+        }
+        if (self.accept_timeout.is_some() || on(self.once)) && !listen {
+            return Err("--accept-timeout and --once only apply with --listen".into());
+        }
+        if self.bind.is_some() && listen {
+            return Err("--bind only applies when connecting, not with --listen".into());
+        }
+        if self.proxy.is_some() && listen {
+            return Err("--proxy only applies when connecting, not with --listen".into());
+        }
+        // An explicit --port wins over one given as part of --host. A dry
+        // run never connects, so it needs neither.
+        let port = match self.common.port.or(host_port) {
+            Some(port) => port,
+            None if dry_run => 0,
+            None => return Err("--port is required".into()),
+        };
+        if port == 0 && !listen && !dry_run {
+            return Err("--port 0 only works with --listen".into());
+        }
+        let files_from = match &self.files_from {
+            Some((list, nul)) => Some(read_file_list(list, *nul)?),
+            None => None,
+        };
+        let compress_shared = on(self.compress_shared);
+        let common = &self.common;
+
+        let mut args = SendArgs {
+            host,
+            port,
+            src: match (self.src, &files_from) {
+                (None, Some(_)) => PathBuf::new(),
+                (Some(_), Some(_)) => return Err("--files-from names the sources; give no SRC".into()),
+                (src, None) => src.ok_or("Source path is required")?,
+            },
+            recursive: on(self.recursive),
+            retries: self.retries.unwrap_or(DEFAULT_RETRIES),
+            retry_delay: self.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY),
+            retry_backoff: self.retry_backoff.unwrap_or(1.0),
+            overwrite: common.overwrite.unwrap_or(OverwriteMode::Ask),
+            listen,
+            accept_timeout: self.accept_timeout.flatten(),
+            mirror: match (on(self.mirror_dry_run), on(self.mirror)) {
+                (true, _) => MirrorMode::DryRun,
+                (false, true) => MirrorMode::Delete,
+                (false, false) => MirrorMode::Off,
+            },
+            format: self.format.unwrap_or(WireFormat::Binary),
+            resume: on(common.resume),
+            compress: on(self.compress) || self.compress_level.is_some() || compress_shared,
+            compress_level: self.compress_level.unwrap_or(compress::ZSTD_LEVEL),
+            compress_shared,
+            verify_chunks: on(self.verify_chunks),
+            framed: on(self.framed),
+            parallel: self.parallel.unwrap_or(1),
+            batch: on(self.batch),
+            checksum: self.checksum.unwrap_or_default(),
+            checksum_scope: self.checksum_scope.unwrap_or_default(),
+            checksum_cache: ChecksumCache::default(),
+            timeout: common.timeout.unwrap_or(Some(net::DEFAULT_TIMEOUT)),
+            limit: self.limit,
+            limit_scope: self.limit_scope.unwrap_or(LimitScope::File),
+            preserve: on(self.preserve),
+            xattrs: on(self.xattrs),
+            skip_existing: on(self.skip_existing),
+            checksum_on_preflight: on(self.checksum_on_preflight),
+            dry_run,
+            buffer_size: common.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            progress_interval: common.progress_interval.unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+            family: common.family.unwrap_or(IpFamily::Any),
+            bind: self.bind,
+            socket: common.socket(),
+            proxy: self.proxy,
+            manifest: Manifest::default(),
+            filter: Filter {
+                include: self.include.unwrap_or_default(),
+                exclude: self.exclude.unwrap_or_default(),
+            },
+            relative_to: self.relative_to,
+            files_from,
+            ignore_missing: on(self.ignore_missing),
+            psk: common.psk.clone(),
+            tls: tls::client(on(common.tls), self.ca.as_deref(), on(self.insecure))?,
+        };
+        args.check()?;
+        args.manifest = open_manifest(self.common.manifest)?;
+        if let Some(path) = self.checksum_cache {
+            args.checksum_cache = ChecksumCache::open(&path)?;
+        }
+        Ok(args)
+    }
+}
+
+#[derive(Default)]
+struct RecvFlags {
+    common: CommonFlags,
+    dst: Option<PathBuf>,
+    keep_alive: Option<bool>,
+    output_name: Option<String>,
+    mkdir: Option<bool>,
+    into: Option<bool>,
+    in_place: Option<bool>,
+    fsync: Option<bool>,
+    verify_read: Option<bool>,
+    dedup: Option<bool>,
+    dedup_reflink: Option<bool>,
+    write_special: Option<bool>,
+    allow_roots: Option<Vec<PathBuf>>,
+    strip_components: Option<usize>,
+    delete: Option<bool>,
+    delete_dry_run: Option<bool>,
+    max_file_size: Option<u64>,
+    verify_only: Option<bool>,
+    pull: Option<String>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+impl Flags for RecvFlags {
+    fn switch(&mut self, flag: &str, on: bool) -> bool {
+        let switch = match flag {
+            "--keep-alive" => &mut self.keep_alive,
+            "--mkdir" => &mut self.mkdir,
+            "--into" => &mut self.into,
+            "--in-place" => &mut self.in_place,
+            "--fsync" => &mut self.fsync,
+            "--verify-read" => &mut self.verify_read,
+            "--dedup" => &mut self.dedup,
+            "--dedup-reflink" => &mut self.dedup_reflink,
+            "--write-special" => &mut self.write_special,
+            "--delete" => &mut self.delete,
+            "--delete-dry-run" => &mut self.delete_dry_run,
+            "--verify-only" => &mut self.verify_only,
+            _ => return self.common.switch(flag, on),
+        };
+        *switch = Some(on);
+        true
+    }
+
+    fn option(&mut self, flag: &str, value: impl FnOnce() -> Result<String>) -> Result<bool> {
+        match flag {
+            "--as" | "--output-name" => self.output_name = Some(parse_output_name(&value()?)?),
+            "--allow-root" => self.allow_roots.get_or_insert_default().push(PathBuf::from(value()?)),
+            "--strip-components" => self.strip_components = Some(parse_strip_components(&value()?)?),
+            "--max-file-size" => self.max_file_size = Some(parse_bytes(&value()?)?),
+            "--pull" => self.pull = Some(value()?),
+            "--cert" => self.cert = Some(PathBuf::from(value()?)),
+            "--key" => self.key = Some(PathBuf::from(value()?)),
+            _ => return self.common.option(flag, value),
+        }
+        Ok(true)
+    }
+
+    fn positional(&mut self, arg: &str) -> Result<()> {
+        if self.dst.is_some() {
+            return Err(format!("Unexpected argument: {}", arg).into());
+        }
+        self.dst = Some(PathBuf::from(arg));
+        Ok(())
+    }
+
+    fn over(self, mut file: Self) -> Self {
+        self.drop_conflicts(&mut file);
+        RecvFlags {
+            common: self.common.over(file.common),
+            dst: self.dst,
+            keep_alive: self.keep_alive.or(file.keep_alive),
+            output_name: self.output_name.or(file.output_name),
+            mkdir: self.mkdir.or(file.mkdir),
+            into: self.into.or(file.into),
+            in_place: self.in_place.or(file.in_place),
+            fsync: self.fsync.or(file.fsync),
+            verify_read: self.verify_read.or(file.verify_read),
+            dedup: self.dedup.or(file.dedup),
+            dedup_reflink: self.dedup_reflink.or(file.dedup_reflink),
+            write_special: self.write_special.or(file.write_special),
+            allow_roots: self.allow_roots.or(file.allow_roots),
+            strip_components: self.strip_components.or(file.strip_components),
+            delete: self.delete.or(file.delete),
+            delete_dry_run: self.delete_dry_run.or(file.delete_dry_run),
+            max_file_size: self.max_file_size.or(file.max_file_size),
+            verify_only: self.verify_only.or(file.verify_only),
+            pull: self.pull.or(file.pull),
+            cert: self.cert.or(file.cert),
+            key: self.key.or(file.key),
+        }
+    }
+
+    fn common(&self) -> &CommonFlags {
+        &self.common
+    }
+}
+
+impl RecvFlags {
+    /// Drop from `file` what `into_args` would refuse alongside what these
+    /// flags give.
+    fn drop_conflicts(&self, file: &mut RecvFlags) {
+        if self.common.host.is_some() {
+            file.keep_alive = None;
+        }
+        if on(self.keep_alive) {
+            file.common.host = None;
+            file.pull = None;
+        }
+        if on(self.dedup) {
+            file.dedup_reflink = None;
+        }
+        if on(self.delete) {
+            file.delete_dry_run = None;
+        }
+        if self.dst.as_deref() == Some(Path::new(STDIO_PATH)) {
+            file.keep_alive = None;
+            file.output_name = None;
+            file.verify_only = None;
+            file.in_place = None;
+            file.delete = None;
+            file.delete_dry_run = None;
+        }
+        if self.common.tls == Some(false) {
+            file.cert = None;
+            file.key = None;
+        }
+    }
+
+    fn into_args(mut self) -> Result<RecvArgs> {
+        let (host, host_port) = self.common.host();
+        let keep_alive = on(self.keep_alive);
+        let verify_only = on(self.verify_only);
+        let in_place = on(self.in_place);
+        let delete = match (on(self.delete_dry_run), on(self.delete)) {
+            (true, _) => MirrorMode::DryRun,
+            (false, true) => MirrorMode::Delete,
+            (false, false) => MirrorMode::Off,
+        };
+        let stdout = self.dst.as_deref() == Some(Path::new(STDIO_PATH));
+        if keep_alive && host.is_some() {
+            return Err("--keep-alive cannot be combined with --host".into());
+        }
+        if stdout && (keep_alive || self.output_name.is_some()) {
+            return Err("--keep-alive and --as cannot be combined with writing to stdout".into());
+        }
+        if stdout && verify_only {
+            return Err("--verify-only needs a destination to compare against, not stdout".into());
+        }
+        if stdout && in_place {
+            return Err("--in-place needs a destination directory, not stdout".into());
+        }
+        if stdout && delete != MirrorMode::Off {
+            return Err("--delete needs a destination directory, not stdout".into());
+        }
+
+        // An explicit --port wins over one given as part of --host.
+        let port = self.common.port.or(host_port).ok_or("--port is required")?;
+        if port == 0 && host.is_some() {
+            return Err("--port 0 only works when listening".into());
+        }
+        if self.pull.is_some() && host.is_none() {
+            return Err("--pull requires --host: the sender must be listening".into());
+        }
+
+        let common = self.common;
+        Ok(RecvArgs {
+            host,
+            port,
+            dst: self.dst.ok_or("Destination path is required")?,
+            overwrite: common.overwrite.unwrap_or(OverwriteMode::Ask),
+            resume: on(common.resume),
+            timeout: common.timeout.unwrap_or(Some(net::DEFAULT_TIMEOUT)),
+            keep_alive,
+            buffer_size: common.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            progress_interval: common.progress_interval.unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+            family: common.family.unwrap_or(IpFamily::Any),
+            socket: common.socket(),
+            output_name: self.output_name,
+            mkdir: on(self.mkdir),
+            into: on(self.into),
+            verify_only,
+            in_place,
+            fsync: on(self.fsync),
+            verify_read: on(self.verify_read),
+            dedup: match (on(self.dedup_reflink), on(self.dedup)) {
+                (true, _) => DedupMode::Reflink,
+                (false, true) => DedupMode::HardLink,
+                (false, false) => DedupMode::Off,
+            },
+            write_special: on(self.write_special),
+            allow_roots: self.allow_roots.unwrap_or_default(),
+            strip_components: self.strip_components.unwrap_or(0),
+            max_file_size: self.max_file_size,
+            delete,
+            pull: self.pull,
+            manifest: open_manifest(common.manifest)?,
+            tls: tls::server(on(common.tls), self.cert.as_deref(), self.key.as_deref())?,
+            psk: common.psk,
+        })
+    }
+}
+
+/// `ncp hash`: the algorithm and the files to print digests of.
+fn parse_hash_args(args: &[String]) -> Result<(ChecksumAlg, Vec<PathBuf>)> {
+    let mut alg = ChecksumAlg::default();
+    let mut files = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--checksum" => alg = ChecksumAlg::parse(take_value(args, &mut i, "--checksum")?)?,
+            "-v" | "-vv" | "-q" | "--quiet" => {}
+            "--log-file" => {
+                take_value(args, &mut i, "--log-file")?;
+            }
+            arg if arg.starts_with('-') && arg != STDIO_PATH => {
+                return Err(format!("Unknown option: {}", arg).into())
+            }
+            arg => files.push(PathBuf::from(arg)),
+        }
+        i += 1;
+    }
+    if alg == ChecksumAlg::None {
+        return Err("--checksum none has no digest to print".into());
+    }
+    if files.is_empty() {
+        return Err("hash needs at least one FILE (or - for standard input)".into());
+    }
+    Ok((alg, files))
+}
+
+/// Print `<digest>  <path>` for each of `files`, as `sha256sum` does. A
+/// file that cannot be read is reported, and the rest are still hashed.
+fn hash_files(alg: ChecksumAlg, files: &[PathBuf]) -> Result<()> {
+    let mut out = io::stdout().lock();
+    let mut failed = 0;
+    for path in files {
+        let digest = if path.as_os_str() == STDIO_PATH {
+            calculate_checksum(&mut io::stdin().lock(), alg)
+        } else {
+            calculate_file_checksum(path, alg)
+        };
+        match digest {
+            Ok(digest) => writeln!(out, "{}  {}", to_hex(&digest), path.display())?,
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files could not be hashed", failed, files.len()).into());
+    }
+    Ok(())
+}
+
+/// `ncp verify`: the manifest and the directory to audit against it.
+fn parse_verify_args(args: &[String]) -> Result<(PathBuf, PathBuf)> {
+    let mut manifest = None;
+    let mut dirs = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--manifest" => manifest = Some(PathBuf::from(take_value(args, &mut i, "--manifest")?)),
+            "-v" | "-vv" | "-q" | "--quiet" => {}
+            "--log-file" => {
+                take_value(args, &mut i, "--log-file")?;
+            }
+            arg if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg).into()),
+            arg => dirs.push(PathBuf::from(arg)),
+        }
+        i += 1;
+    }
+    let manifest = manifest.ok_or("verify needs --manifest FILE")?;
+    match <[PathBuf; 1]>::try_from(dirs) {
+        Ok([dir]) => Ok((manifest, dir)),
+        Err(_) => Err("verify needs exactly one DIR".into()),
+    }
+}
+
+/// `-v` and `-vv` if given, otherwise `NCP_LOG`.
+fn parse_verbosity(args: &[String]) -> u8 {
+    let flags = args
+        .iter()
+        .map(|a| match a.as_str() {
+            "-v" => 1,
+            "-vv" => 2,
+            _ => 0,
+        })
+        .sum::<u8>()
+        .min(2);
+    if flags > 0 {
+        return flags;
+    }
+    match env::var("NCP_LOG") {
+        Ok(value) => logging::parse_env_verbosity(&value).unwrap_or_else(|| {
+            eprintln!("Warning: ignoring NCP_LOG={} (expected off, info or debug)", value);
+            0
+        }),
+        Err(_) => 0,
+    }
+}
+
+/// The value of `flag`, an option both commands take, such as
+/// `--log-file`; the last one if given more than once.
+fn common_arg<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>> {
+    match args.iter().rposition(|a| a == flag) {
+        Some(mut i) => Ok(Some(take_value(args, &mut i, flag)?)),
+        None => Ok(None),
+    }
+}
+
+fn path_arg(args: &[String], flag: &str) -> Result<Option<PathBuf>> {
+    Ok(common_arg(args, flag)?.map(PathBuf::from))
+}
+
+fn parse_max_message_size(value: &str) -> Result<usize> {
+    let size = parse_bytes(value)?;
+    if size < protocol::MIN_MESSAGE_SIZE as u64 {
+        let min = protocol::MIN_MESSAGE_SIZE;
+        return Err(format!("--max-message-size must be at least {} bytes", min).into());
+    }
+    if size > u32::MAX as u64 {
+        return Err(format!("--max-message-size too large: {}", value).into());
+    }
+    Ok(size as usize)
+}
+
+fn parse_args(args: &[String]) -> Result<Command> {
+    let (command, rest) = args.split_first().ok_or("Missing command (send or recv)")?;
+    // Verbosity comes from the command line or NCP_LOG, never the file.
+    logging::set_verbosity(parse_verbosity(rest));
+
+    let command = match command.as_str() {
+        "send" => {
+            let flags = with_config::<SendFlags, RecvFlags>(parse_flags(rest)?, command)?;
+            flags.common.apply()?;
+            Command::Send(Box::new(flags.into_args()?))
+        }
+        "recv" => {
+            let flags = with_config::<RecvFlags, SendFlags>(parse_flags(rest)?, command)?;
+            flags.common.apply()?;
+            let args = flags.into_args()?;
+            if args.writes_stdout() {
+                if events::json_enabled() {
+                    return Err("--json cannot be combined with writing to stdout".into());
+                }
+                logging::set_stdout_data(true);
+            }
+            Command::Recv(Box::new(args))
+        }
+        "hash" | "verify" => {
+            logging::set_quiet(rest.iter().any(|a| a == "-q" || a == "--quiet"));
+            if let Some(path) = path_arg(rest, "--log-file")? {
+                logging::set_log_file(&path)?;
+            }
+            if command == "hash" {
+                let (alg, files) = parse_hash_args(rest)?;
+                Command::Hash(alg, files)
+            } else {
+                let (manifest, dir) = parse_verify_args(rest)?;
+                Command::Verify { manifest, dir }
+            }
+        }
+        other => return Err(format!("Unknown command: {}", other).into()),
+    };
+    Ok(command)
+}
+
+/// Exit code for arguments that do not parse.
+const EXIT_USAGE: u8 = 10;
+
+/// Run `ncp` with `args`, which leave out the program name, and return the
+/// status to exit with.
+pub fn run(args: &[String]) -> ExitCode {
+    let command = match parse_args(args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            print_usage();
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    interrupt::install();
+
+    let result = match command {
+        Command::Send(args) => {
+            // Keys on a stdin that is being sent would be file data.
+            if !args.reads_stdin() {
+                pause::watch();
+            }
+            let result = send::execute(*args);
+            pause::restore();
+            result.map(drop)
+        }
+        Command::Recv(args) => recv::execute(*args).map(drop),
+        Command::Hash(alg, files) => hash_files(alg, &files),
+        Command::Verify { manifest, dir } => audit::verify(&manifest, &dir),
+    };
+
+    if let Err(e) = result {
+        // The receiver's code, for scripts that tell its failures apart.
+        let message = match e.peer_code() {
+            Some(code) => format!("{} ({})", e, code.as_str_name()),
+            None => e.to_string(),
+        };
+        eprintln!("Error: {}", message);
+        logging::log_to_file("ERROR", &message);
+        events::error(&message);
+        return ExitCode::from(e.exit_status());
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,640 @@
+//! File body encodings selected by `TransferMode`.
+//!
+//! A raw body is exactly `file_size` bytes. A zstd body is the compressed
+//! stream cut into blocks `[len: u32 BE][bytes]` and closed by an empty
+//! block, so the receiver finds the end without knowing the compressed size
+//! up front and never reads into the message that follows. A raw body whose
+//! `file_size` is `UNKNOWN_SIZE` (read from a pipe) uses the same blocks,
+//! and so does a framed body (`--framed`) of any size: a body that is cut
+//! short then fails for want of its closing block instead of running into
+//! whatever follows, and a stray byte in it is a bad length, not file data.
+//! A chunked body carries the raw bytes as `[len: u32 BE][crc32: u32 BE]
+//! [bytes]` chunks, also closed by an empty length, and the receiver checks
+//! each chunk before it writes any of it.
+//!
+//! With `--compress`, each file is checked first: one that is compressed
+//! already, by its extension or because a sample barely shrinks, goes raw.
+//!
+//! A shared zstd body (`--compress-shared`) is laid out like a zstd one,
+//! but its bytes are a piece of a single stream that runs through every
+//! shared body of the connection, so small similar files are compressed
+//! against the ones before them. The sender flushes the stream at the end
+//! of each file, which lets the receiver decode all of the file from its
+//! own blocks, and both ends keep the stream's window from file to file.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Take, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+
+use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+use crate::checksum::crc32;
+use crate::proto::TransferMode;
+use crate::protocol::UNKNOWN_SIZE;
+use crate::types::Result;
+
+/// Default for `--compress-level`.
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// Levels `--compress-level` accepts.
+pub fn levels() -> RangeInclusive<i32> {
+    1..=*zstd::compression_level_range().end()
+}
+
+/// Formats that are compressed already; zstd only costs time on them.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz4",
+    "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "pptx", "rar", "tbz2",
+    "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Bytes from the start of a file that are test-compressed.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Smallest share of the sample compression has to save to be used.
+const MIN_SAVING: f64 = 0.1;
+
+/// Largest block or chunk either side will write or accept.
+const MAX_BLOCK: usize = 256 * 1024;
+
+/// Listed in `handshake::CAPABILITIES` by a receiver that takes shared zstd
+/// bodies.
+pub const SHARED_CAPABILITY: &str = "compress:zstd-shared";
+
+/// Whether this build can encode and decode bodies in `mode`.
+pub fn supported(mode: TransferMode) -> bool {
+    matches!(
+        mode,
+        TransferMode::TransferRaw
+            | TransferMode::TransferChunked
+            | TransferMode::TransferZstd
+            | TransferMode::TransferFramed
+            | TransferMode::TransferZstdShared
+    )
+}
+
+pub struct BlockWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(MAX_BLOCK);
+        self.inner.write_all(&(len as u32).to_be_bytes())?;
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BlockWriter<W> {
+    fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        Ok(self.inner)
+    }
+}
+
+pub struct BlockReader<R: Read> {
+    inner: R,
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: Read> BlockReader<R> {
+    fn new(inner: R) -> Self {
+        BlockReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let mut len = [0u8; 4];
+            self.inner.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+            if len > MAX_BLOCK {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Block too large: {} bytes", len),
+                ));
+            }
+            self.remaining = len;
+        }
+        let want = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(MAX_BLOCK);
+        let mut header = [0u8; 8];
+        header[..4].copy_from_slice(&(len as u32).to_be_bytes());
+        header[4..].copy_from_slice(&crc32(&buf[..len]).to_be_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> ChunkWriter<W> {
+    fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a chunked body one whole chunk at a time, so a chunk that fails
+/// its CRC is reported before any of its bytes are handed out.
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    chunk: Vec<u8>,
+    pos: usize,
+    /// File offset of the next chunk, for error messages.
+    offset: u64,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    fn new(inner: R, offset: u64) -> Self {
+        ChunkReader {
+            inner,
+            chunk: Vec::new(),
+            pos: 0,
+            offset,
+            done: false,
+        }
+    }
+
+    /// Read and check the next chunk. Returns `false` at the end of the body.
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        let mut len = [0u8; 4];
+        self.inner.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            return Ok(false);
+        }
+        if len > MAX_BLOCK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk too large: {} bytes", len),
+            ));
+        }
+        let mut expected = [0u8; 4];
+        self.inner.read_exact(&mut expected)?;
+        let expected = u32::from_be_bytes(expected);
+
+        self.chunk.resize(len, 0);
+        self.inner.read_exact(&mut self.chunk)?;
+        let actual = crc32(&self.chunk);
+        if actual != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Corrupt chunk at byte offset {} ({} bytes): CRC32 {:08x}, sender sent {:08x}",
+                    self.offset, len, actual, expected
+                ),
+            ));
+        }
+        self.offset += len as u64;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.chunk.len() && !self.next_chunk()? {
+            self.done = true;
+            return Ok(0);
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The sender's end of the zstd stream that runs through the shared bodies
+/// of a connection. Clones are the same stream.
+#[derive(Clone)]
+pub struct SharedEncoder(Rc<RefCell<zstd::stream::write::Encoder<'static, Vec<u8>>>>);
+
+impl SharedEncoder {
+    pub fn new(level: i32) -> io::Result<Self> {
+        let encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+        Ok(SharedEncoder(Rc::new(RefCell::new(encoder))))
+    }
+}
+
+/// Feeds one file's bytes into the shared stream, and sends what comes
+/// out in blocks.
+pub struct SharedWriter<W: Write> {
+    encoder: SharedEncoder,
+    blocks: BlockWriter<W>,
+}
+
+impl<W: Write> SharedWriter<W> {
+    /// Send what the encoder has let out so far.
+    fn drain(&mut self) -> io::Result<()> {
+        let mut encoder = self.encoder.0.borrow_mut();
+        self.blocks.write_all(encoder.get_ref())?;
+        encoder.get_mut().clear();
+        Ok(())
+    }
+
+    /// Flush the stream, so that everything written can be decoded from
+    /// the blocks sent, and close the body. The stream goes on.
+    fn finish(mut self) -> io::Result<W> {
+        self.encoder.0.borrow_mut().flush()?;
+        self.drain()?;
+        self.blocks.finish()
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.encoder.0.borrow_mut().write(buf)?;
+        self.drain()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.blocks.flush()
+    }
+}
+
+/// The receiver's end of the stream `SharedEncoder` writes. Clones are the
+/// same stream.
+#[derive(Clone)]
+pub struct SharedDecoder(Rc<RefCell<zstd::stream::raw::Decoder<'static>>>);
+
+impl SharedDecoder {
+    pub fn new() -> io::Result<Self> {
+        Ok(SharedDecoder(Rc::new(RefCell::new(zstd::stream::raw::Decoder::new()?))))
+    }
+}
+
+/// Decodes one file's blocks through the shared stream.
+pub struct SharedReader<R: Read> {
+    decoder: SharedDecoder,
+    blocks: BlockReader<R>,
+    input: Vec<u8>,
+    pos: usize,
+    ended: bool,
+}
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut decoder = self.decoder.0.borrow_mut();
+        loop {
+            if self.pos == self.input.len() && !self.ended {
+                self.input.resize(MAX_BLOCK, 0);
+                let n = self.blocks.read(&mut self.input)?;
+                self.input.truncate(n);
+                self.pos = 0;
+                self.ended = n == 0;
+            }
+            let mut input = InBuffer::around(&self.input[self.pos..]);
+            let mut output = OutBuffer::around(&mut *buf);
+            decoder.run(&mut input, &mut output)?;
+            self.pos += input.pos();
+            match output.pos() {
+                // The blocks are over and the decoder has nothing left.
+                0 if self.ended && self.pos == self.input.len() => return Ok(0),
+                0 => continue,
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+/// Whether the file at `path` is worth sending zstd-compressed at `level`.
+pub fn worth_compressing(path: &Path, level: i32) -> io::Result<bool> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase());
+    if extension.is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.as_str())) {
+        return Ok(false);
+    }
+
+    let mut sample = Vec::new();
+    File::open(path)?.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(true);
+    }
+    let compressed = zstd::bulk::compress(&sample, level)?;
+    Ok((compressed.len() as f64) <= sample.len() as f64 * (1.0 - MIN_SAVING))
+}
+
+/// Sender side: file bytes go in, the encoded body goes out to `W`.
+pub enum BodyWriter<W: Write> {
+    Raw(W),
+    Blocks(BlockWriter<W>),
+    Chunked(ChunkWriter<W>),
+    Zstd(zstd::stream::write::Encoder<'static, BlockWriter<W>>),
+    Shared(SharedWriter<W>),
+}
+
+impl<W: Write> BodyWriter<W> {
+    /// `len` is the `file_size` announced for the body; `level` only
+    /// matters for zstd. A shared zstd body is made by `shared`.
+    pub fn new(inner: W, mode: TransferMode, len: u64, level: i32) -> Result<Self> {
+        match mode {
+            TransferMode::TransferRaw if len == UNKNOWN_SIZE => {
+                Ok(BodyWriter::Blocks(BlockWriter { inner }))
+            }
+            TransferMode::TransferFramed => Ok(BodyWriter::Blocks(BlockWriter { inner })),
+            TransferMode::TransferRaw => Ok(BodyWriter::Raw(inner)),
+            TransferMode::TransferChunked => Ok(BodyWriter::Chunked(ChunkWriter { inner })),
+            TransferMode::TransferZstd => Ok(BodyWriter::Zstd(zstd::stream::write::Encoder::new(
+                BlockWriter { inner },
+                level,
+            )?)),
+            TransferMode::TransferZstdShared => Err("A shared zstd body needs its stream".into()),
+        }
+    }
+
+    /// A shared zstd body, the next piece of `encoder`'s stream.
+    pub fn shared(inner: W, encoder: &SharedEncoder) -> Self {
+        BodyWriter::Shared(SharedWriter {
+            encoder: encoder.clone(),
+            blocks: BlockWriter { inner },
+        })
+    }
+
+    /// Flush any buffered output and close the body.
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = match self {
+            BodyWriter::Raw(inner) => inner,
+            BodyWriter::Blocks(blocks) => blocks.finish()?,
+            BodyWriter::Chunked(chunks) => chunks.finish()?,
+            BodyWriter::Zstd(encoder) => encoder.finish()?.finish()?,
+            BodyWriter::Shared(shared) => shared.finish()?,
+        };
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for BodyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            BodyWriter::Raw(inner) => inner.write(buf),
+            BodyWriter::Blocks(blocks) => blocks.write(buf),
+            BodyWriter::Chunked(chunks) => chunks.write(buf),
+            BodyWriter::Zstd(encoder) => encoder.write(buf),
+            BodyWriter::Shared(shared) => shared.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            BodyWriter::Raw(inner) => inner.flush(),
+            BodyWriter::Blocks(blocks) => blocks.flush(),
+            BodyWriter::Chunked(chunks) => chunks.flush(),
+            BodyWriter::Zstd(encoder) => encoder.flush(),
+            BodyWriter::Shared(shared) => shared.flush(),
+        }
+    }
+}
+
+/// Receiver side: yields the decoded file bytes and then EOF once the body
+/// is complete. A raw body is limited to `len` bytes.
+pub enum BodyReader<R: Read> {
+    Raw(Take<R>),
+    Blocks(BlockReader<R>),
+    Chunked(ChunkReader<R>),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<BlockReader<R>>>),
+    Shared(SharedReader<R>),
+}
+
+impl<R: Read> BodyReader<R> {
+    /// `offset` is the file position the body starts at. A shared zstd
+    /// body is read by `shared`.
+    pub fn new(inner: R, mode: TransferMode, len: u64, offset: u64) -> Result<Self> {
+        match mode {
+            TransferMode::TransferRaw if len == UNKNOWN_SIZE => {
+                Ok(BodyReader::Blocks(BlockReader::new(inner)))
+            }
+            TransferMode::TransferFramed => Ok(BodyReader::Blocks(BlockReader::new(inner))),
+            TransferMode::TransferRaw => Ok(BodyReader::Raw(inner.take(len))),
+            TransferMode::TransferChunked => Ok(BodyReader::Chunked(ChunkReader::new(inner, offset))),
+            TransferMode::TransferZstd => {
+                let blocks = BlockReader::new(inner);
+                Ok(BodyReader::Zstd(zstd::stream::read::Decoder::new(blocks)?.single_frame()))
+            }
+            TransferMode::TransferZstdShared => Err("A shared zstd body needs its stream".into()),
+        }
+    }
+
+    /// A shared zstd body, the next piece of `decoder`'s stream.
+    pub fn shared(inner: R, decoder: &SharedDecoder) -> Self {
+        BodyReader::Shared(SharedReader {
+            decoder: decoder.clone(),
+            blocks: BlockReader::new(inner),
+            input: Vec::new(),
+            pos: 0,
+            ended: false,
+        })
+    }
+
+    /// Consume whatever is left of the body framing, failing if the sender
+    /// put anything after the end of the compressed stream.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            // These end where the body does.
+            BodyReader::Raw(_) | BodyReader::Blocks(_) | BodyReader::Chunked(_) => Ok(()),
+            // Read to its closing block, which is all the decoder had to say.
+            BodyReader::Shared(_) => Ok(()),
+            BodyReader::Zstd(decoder) => {
+                let trailing = io::copy(&mut decoder.finish(), &mut io::sink())?;
+                if trailing != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} unexpected bytes after compressed body", trailing),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BodyReader::Raw(inner) => inner.read(buf),
+            BodyReader::Blocks(blocks) => blocks.read(buf),
+            BodyReader::Chunked(chunks) => chunks.read(buf),
+            BodyReader::Zstd(decoder) => decoder.read(buf),
+            BodyReader::Shared(shared) => shared.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn roundtrip(mode: TransferMode, data: &[u8], len: u64) {
+        let mut body = BodyWriter::new(Vec::new(), mode, len, ZSTD_LEVEL).unwrap();
+        body.write_all(data).unwrap();
+        let mut wire = body.finish().unwrap();
+        wire.extend_from_slice(b"NEXT");
+
+        let mut cursor = Cursor::new(wire);
+        let mut reader = BodyReader::new(&mut cursor, mode, len, 0).unwrap();
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(decoded, data);
+
+        // The following message must be left untouched.
+        let mut rest = Vec::new();
+        cursor.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"NEXT");
+    }
+
+    #[test]
+    fn test_body_roundtrip() {
+        let data: Vec<u8> = (0..600_000u32).map(|i| (i % 251) as u8).collect();
+        let len = data.len() as u64;
+        roundtrip(TransferMode::TransferRaw, &data, len);
+        roundtrip(TransferMode::TransferZstd, &data, len);
+        roundtrip(TransferMode::TransferZstd, b"", 0);
+        roundtrip(TransferMode::TransferRaw, &data, UNKNOWN_SIZE);
+        roundtrip(TransferMode::TransferRaw, b"", UNKNOWN_SIZE);
+        roundtrip(TransferMode::TransferZstd, &data, UNKNOWN_SIZE);
+        roundtrip(TransferMode::TransferChunked, &data, len);
+        roundtrip(TransferMode::TransferChunked, b"", 0);
+        roundtrip(TransferMode::TransferChunked, &data, UNKNOWN_SIZE);
+        roundtrip(TransferMode::TransferFramed, &data, len);
+        roundtrip(TransferMode::TransferFramed, b"", 0);
+    }
+
+    #[test]
+    fn test_shared_stream_beats_one_per_file() {
+        let files: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("[server.{i}]\nhost = \"db{i}.example.com\"\nport = 5432\nretries = 3\n"))
+            .map(|text| text.repeat(4).into_bytes())
+            .collect();
+        let mut per_file = 0;
+        for data in &files {
+            let len = data.len() as u64;
+            let mode = TransferMode::TransferZstd;
+            let mut body = BodyWriter::new(Vec::new(), mode, len, ZSTD_LEVEL).unwrap();
+            body.write_all(data).unwrap();
+            per_file += body.finish().unwrap().len();
+        }
+        let encoder = SharedEncoder::new(ZSTD_LEVEL).unwrap();
+        let mut wire = Vec::new();
+        for data in &files {
+            let mut body = BodyWriter::shared(wire, &encoder);
+            body.write_all(data).unwrap();
+            wire = body.finish().unwrap();
+        }
+        assert!(wire.len() * 2 < per_file, "shared {} bytes, per file {}", wire.len(), per_file);
+        wire.extend_from_slice(b"NEXT");
+
+        let decoder = SharedDecoder::new().unwrap();
+        let mut cursor = Cursor::new(wire);
+        for data in &files {
+            let mut reader = BodyReader::shared(&mut cursor, &decoder);
+            let mut decoded = Vec::new();
+            reader.read_to_end(&mut decoded).unwrap();
+            reader.finish().unwrap();
+            assert_eq!(&decoded, data);
+        }
+        let mut rest = Vec::new();
+        cursor.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"NEXT");
+    }
+
+    #[test]
+    fn test_truncated_framed_body_fails() {
+        let data: Vec<u8> = (0..2 * MAX_BLOCK as u32).map(|i| (i % 251) as u8).collect();
+        let len = data.len() as u64;
+        let mut body = BodyWriter::new(Vec::new(), TransferMode::TransferFramed, len, 0).unwrap();
+        body.write_all(&data).unwrap();
+        let wire = body.finish().unwrap();
+
+        // Cut inside a block, and then just before the closing one.
+        for cut in [wire.len() - 100, wire.len() - 4] {
+            let framed = Cursor::new(&wire[..cut]);
+            let mut reader = BodyReader::new(framed, TransferMode::TransferFramed, len, 0).unwrap();
+            let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_chunk_fails_before_its_data() {
+        let data: Vec<u8> = (0..3 * MAX_BLOCK as u32).map(|i| (i % 251) as u8).collect();
+        let mut body = BodyWriter::new(Vec::new(), TransferMode::TransferChunked, 0, 0).unwrap();
+        body.write_all(&data).unwrap();
+        let mut wire = body.finish().unwrap();
+        // Flip a byte inside the second chunk, past its 8-byte header.
+        wire[2 * 8 + MAX_BLOCK + 100] ^= 0xff;
+
+        let mut reader =
+            BodyReader::new(Cursor::new(wire), TransferMode::TransferChunked, 0, 1000).unwrap();
+        let mut decoded = Vec::new();
+        let err = reader.read_to_end(&mut decoded).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let offset = 1000 + MAX_BLOCK;
+        assert!(err.to_string().contains(&format!("byte offset {}", offset)), "{}", err);
+        // Only the good first chunk got through.
+        assert_eq!(decoded, data[..MAX_BLOCK]);
+    }
+
+    #[test]
+    fn test_oversized_block_rejected() {
+        let mut wire = ((MAX_BLOCK + 1) as u32).to_be_bytes().to_vec();
+        wire.extend_from_slice(&[0u8; 16]);
+        let mut reader =
+            BodyReader::new(Cursor::new(wire), TransferMode::TransferZstd, 0, 0).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+}
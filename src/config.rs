@@ -0,0 +1,281 @@
+//! Default flags from a config file (`--config`, or
+//! `~/.config/ncp/config.toml`).
+//!
+//! The file is TOML, read with the `toml` crate. Each key is the long name
+//! of a flag, with `_` or `-` between words, and a value is a string or an
+//! integer for a flag that takes one, `true` or `false` for a switch, or an
+//! array for a flag given several times:
+//!
+//! ```toml
+//! timeout = 60
+//!
+//! [send]
+//! host = "backup.lan"
+//! port = 9000
+//! compress = true
+//! exclude = ["*.tmp", "target"]
+//!
+//! [recv]
+//! overwrite = "yes"
+//! ```
+//!
+//! Keys at the top are for both commands, and each takes those of its own
+//! flags it finds there; those in `[send]` or `[recv]` are for that command
+//! only. `cli` turns the settings into the defaults of the command being
+//! run and lays the command line over them flag by flag, so that a flag
+//! given there replaces the file's, a switch can be turned off with
+//! `--no-<flag>`, and the environment (`NCP_LOG`) still comes between.
+
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::de::{DeTable, DeValue};
+use toml::Spanned;
+
+use crate::types::{NcpError, Result};
+
+/// Where the config file is looked for without `--config`:
+/// `$XDG_CONFIG_HOME/ncp/config.toml`, else `~/.config/ncp/config.toml`
+/// (`%APPDATA%\ncp\config.toml` on Windows).
+pub fn default_path() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+        }
+    };
+    base.map(|dir| dir.join("ncp").join("config.toml"))
+}
+
+/// The commands a setting is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// A key at the top, for whichever command has the flag.
+    Both,
+    Send,
+    Recv,
+}
+
+impl Section {
+    /// The table of `command`, `send` or `recv`.
+    pub fn of(command: &str) -> Section {
+        if command == "send" { Section::Send } else { Section::Recv }
+    }
+
+    /// The table of the other command.
+    pub fn other(self) -> Section {
+        if self == Section::Send { Section::Recv } else { Section::Send }
+    }
+
+    /// The command whose table this is.
+    pub fn command(self) -> &'static str {
+        if self == Section::Send { "send" } else { "recv" }
+    }
+
+    /// The name of its table, as written in the file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Both => "the top",
+            Section::Send => "[send]",
+            Section::Recv => "[recv]",
+        }
+    }
+}
+
+/// A value as a flag takes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// A switch turned on or off.
+    Switch(bool),
+    /// The values of an option, one for each time it would be given.
+    Values(Vec<String>),
+}
+
+/// One `key = value` of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub section: Section,
+    /// The key as written.
+    pub key: String,
+    pub value: Value,
+    /// Line of the key, from 1.
+    pub line: usize,
+}
+
+impl Setting {
+    /// The long flag the key names, such as `--buffer-size` for
+    /// `buffer_size`.
+    pub fn flag(&self) -> String {
+        format!("--{}", self.key.replace('_', "-"))
+    }
+}
+
+/// A config file, read.
+#[derive(Debug)]
+pub struct File {
+    path: PathBuf,
+    /// In the order written.
+    pub settings: Vec<Setting>,
+}
+
+impl File {
+    /// Read the file at `path`.
+    pub fn load(path: &Path) -> Result<File> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?;
+        File::parse(path, &text)
+    }
+
+    /// The file at `path` that holds `text`.
+    pub fn parse(path: &Path, text: &str) -> Result<File> {
+        let mut file = File {
+            path: path.to_path_buf(),
+            settings: Vec::new(),
+        };
+        let table = DeTable::parse(text).map_err(|e| {
+            let line = e.span().map_or(1, |span| line_at(text, span.start));
+            file.error(line, e.message())
+        })?;
+        for (key, value) in table.get_ref() {
+            match (key.get_ref().as_ref(), value.get_ref()) {
+                (name @ ("send" | "recv"), DeValue::Table(table)) => {
+                    for (key, value) in table {
+                        file.add(text, Section::of(name), key, value)?;
+                    }
+                }
+                (name, DeValue::Table(_)) => {
+                    let why = format!("unknown table [{}], expected [send] or [recv]", name);
+                    return Err(file.error(line_at(text, key.span().start), why));
+                }
+                _ => file.add(text, Section::Both, key, value)?,
+            }
+        }
+        file.settings.sort_by_key(|setting| setting.line);
+        Ok(file)
+    }
+
+    /// `why`, for the setting on `line`.
+    pub fn error(&self, line: usize, why: impl Display) -> NcpError {
+        format!("{}:{}: {}", self.path.display(), line, why).into()
+    }
+
+    fn add(
+        &mut self,
+        text: &str,
+        section: Section,
+        key: &Spanned<std::borrow::Cow<'_, str>>,
+        value: &Spanned<DeValue<'_>>,
+    ) -> Result<()> {
+        let line = line_at(text, key.span().start);
+        if key.get_ref() == "config" {
+            return Err(self.error(line, "a config file cannot name another one"));
+        }
+        let value = match value.get_ref() {
+            DeValue::Boolean(on) => Value::Switch(*on),
+            DeValue::Array(values) => Value::Values(
+                values
+                    .into_iter()
+                    .map(|value| scalar(value.get_ref()))
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(|why| self.error(line, why))?,
+            ),
+            value => Value::Values(vec![scalar(value).map_err(|why| self.error(line, why))?]),
+        };
+        self.settings.push(Setting {
+            section,
+            key: key.get_ref().to_string(),
+            value,
+            line,
+        });
+        Ok(())
+    }
+}
+
+/// A string or an integer, as the command line would give it.
+fn scalar(value: &DeValue<'_>) -> std::result::Result<String, &'static str> {
+    match value {
+        DeValue::String(value) => Ok(value.to_string()),
+        DeValue::Integer(value) => i64::from_str_radix(value.as_str(), value.radix())
+            .map(|value| value.to_string())
+            .map_err(|_| "integer too large"),
+        _ => Err("expected a string, an integer, true, false or an array of strings and integers"),
+    }
+}
+
+/// The line, from 1, of byte `offset` in `text`.
+fn line_at(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<Setting>> {
+        File::parse(Path::new("ncp.toml"), text).map(|file| file.settings)
+    }
+
+    fn setting(section: Section, key: &str, value: Value, line: usize) -> Setting {
+        Setting {
+            section,
+            key: key.to_string(),
+            value,
+            line,
+        }
+    }
+
+    fn values(values: &[&str]) -> Value {
+        Value::Values(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn test_tables_and_values() {
+        let text = r#"
+# Shared by both commands.
+timeout = 60
+buffer_size = "1M"  # a comment after a value
+
+[send]
+host = "backup.lan"
+port = 9_000
+compress = true
+resume = false
+exclude = ["*.tmp", 'target']
+
+[recv]
+overwrite = "yes"
+"#;
+        assert_eq!(
+            parse(text).unwrap(),
+            vec![
+                setting(Section::Both, "timeout", values(&["60"]), 3),
+                setting(Section::Both, "buffer_size", values(&["1M"]), 4),
+                setting(Section::Send, "host", values(&["backup.lan"]), 7),
+                setting(Section::Send, "port", values(&["9000"]), 8),
+                setting(Section::Send, "compress", Value::Switch(true), 9),
+                setting(Section::Send, "resume", Value::Switch(false), 10),
+                setting(Section::Send, "exclude", values(&["*.tmp", "target"]), 11),
+                setting(Section::Recv, "overwrite", values(&["yes"]), 14),
+            ]
+        );
+        assert_eq!(parse(text).unwrap()[1].flag(), "--buffer-size");
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        for (text, why) in [
+            ("port 9000", "ncp.toml:1: "),
+            ("\n[both]", "ncp.toml:2: unknown table [both]"),
+            ("host = \"open", "ncp.toml:1: "),
+            ("\n\ntimeout = 1.5", "ncp.toml:3: expected a string, an integer"),
+            ("config = \"other.toml\"", "ncp.toml:1: a config file cannot name another one"),
+        ] {
+            let err = parse(text).unwrap_err().to_string();
+            assert!(err.starts_with(why), "{:?} gave {:?}", text, err);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! `recv --dedup`: a file received with the same contents as an earlier one
+//! of the connection is replaced by a hard link to it, or with
+//! `--dedup-reflink` made to share its blocks (`FICLONE`, on Linux file
+//! systems such as Btrfs and XFS), rather than kept as a second copy.
+//!
+//! Files are matched by size and checksum, then compared byte for byte
+//! before either is linked, so that neither a CRC-32 collision nor a change
+//! to the earlier file since it was saved gives one the wrong contents.
+//! Hard-linked files are one file: they share permissions, times and
+//! attributes, the last ones applied, and a change to one shows in all. A
+//! reflinked file keeps its own. A link that cannot be made, across file
+//! systems say, leaves the copy in place with a warning.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::types::DedupMode;
+
+/// The first file saved with each size and digest.
+#[derive(Debug, Default)]
+pub struct Originals {
+    files: HashMap<(u64, Vec<u8>), PathBuf>,
+}
+
+impl Originals {
+    /// The earlier file with the same contents as the `size` bytes just
+    /// saved at `path`, whose digest is `digest`. If there is none, `path`
+    /// is the one later files are matched against. An empty file, or one
+    /// without a digest, matches nothing.
+    pub fn find(&mut self, path: &Path, size: u64, digest: &[u8]) -> io::Result<Option<PathBuf>> {
+        if size == 0 || digest.is_empty() {
+            return Ok(None);
+        }
+        let key = (size, digest.to_vec());
+        match self.files.get(&key) {
+            Some(original) if same_contents(original, path)? => Ok(Some(original.clone())),
+            Some(_) => Ok(None),
+            None => {
+                self.files.insert(key, path.to_path_buf());
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Make the file at `path` share the contents of `original`, as `mode`
+/// says.
+pub fn link(mode: DedupMode, original: &Path, path: &Path) -> io::Result<()> {
+    match mode {
+        DedupMode::Off => Ok(()),
+        DedupMode::HardLink => hard_link(original, path),
+        DedupMode::Reflink => reflink(original, path),
+    }
+}
+
+/// Whether the files at `a` and `b` hold the same bytes.
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (BufReader::new(File::open(a)?), BufReader::new(File::open(b)?));
+    let (mut buf_a, mut buf_b) = (vec![0u8; 64 * 1024], vec![0u8; 64 * 1024]);
+    loop {
+        let n = read_full(&mut a, &mut buf_a)?;
+        if n != read_full(&mut b, &mut buf_b)? || buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fill `buf` from `reader` as far as it goes; short only at the end.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// A new link to `original`, renamed over `path` so that there is a file
+/// there throughout.
+fn hard_link(original: &Path, path: &Path) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".ncp_dedup");
+    let temp = path.with_file_name(name);
+    let _ = fs::remove_file(&temp);
+    fs::hard_link(original, &temp)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(original: &Path, path: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = File::open(original)?;
+    let target = fs::OpenOptions::new().write(true).open(path)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_original: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_matches_only_the_same_bytes() {
+        let dir = std::env::temp_dir().join(format!("ncp-dedup-find-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in [("a", "same"), ("b", "same"), ("c", "diff"), ("d", "")] {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let mut originals = Originals::default();
+        let digest = b"digest".as_slice();
+        assert_eq!(originals.find(&dir.join("a"), 4, digest).unwrap(), None);
+        assert_eq!(originals.find(&dir.join("b"), 4, digest).unwrap(), Some(dir.join("a")));
+        // Same size and digest, as with a collision, but other bytes.
+        assert_eq!(originals.find(&dir.join("c"), 4, digest).unwrap(), None);
+        assert_eq!(originals.find(&dir.join("d"), 0, b"").unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,559 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use crate::glob;
+use crate::types::Result;
+
+/// One entry of a directory walk.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Absolute (or caller-relative) path on the local filesystem.
+    pub path: PathBuf,
+    /// Path relative to the walk root, always `/`-separated.
+    pub relative_path: String,
+    /// `relative_path` as the file system spells it, where the `String` has
+    /// replaced any bytes that are not UTF-8.
+    pub exact_relative_path: PathBuf,
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// Files and bytes below a walk, from `Walk::totals`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// `--include` and `--exclude` patterns for a walk, in `glob::matches`
+/// syntax. A pattern with a `/` is matched against the whole relative path,
+/// any other against the entry's name alone.
+///
+/// An excluded directory is not even listed. With include patterns, only
+/// files matching one (or inside a directory matching one) are walked, and
+/// a directory only once something inside it is.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    fn excludes(&self, entry: &FileEntry) -> bool {
+        self.exclude.iter().any(|p| pattern_matches(p, &entry.relative_path))
+    }
+
+    /// Whether `entry` is wanted in its own right; `inside` is whether its
+    /// directory already was.
+    fn includes(&self, entry: &FileEntry, inside: bool) -> bool {
+        self.include.is_empty()
+            || inside
+            || self.include.iter().any(|p| pattern_matches(p, &entry.relative_path))
+    }
+}
+
+fn pattern_matches(pattern: &str, relative_path: &str) -> bool {
+    if pattern.contains('/') {
+        glob::matches(pattern.trim_start_matches('/'), relative_path)
+    } else {
+        let name = relative_path.rsplit('/').next().unwrap_or(relative_path);
+        glob::matches(pattern, name)
+    }
+}
+
+/// A depth-first walk that lists each directory only when it is reached, so
+/// memory grows with the depth of the tree rather than its size. Within each
+/// directory, subdirectories come first, then files, each group sorted by
+/// name; a directory is always yielded before its contents.
+pub struct Walk {
+    /// The unvisited entries of every directory on the current path.
+    levels: Vec<Level>,
+    filter: Filter,
+    /// Entries due out before the walk goes any further.
+    ready: VecDeque<FileEntry>,
+}
+
+struct Level {
+    entries: std::vec::IntoIter<FileEntry>,
+    /// The directory being listed, until something in it is included and
+    /// it is yielded first.
+    held: Option<FileEntry>,
+    /// Everything below is included regardless of `--include`.
+    included: bool,
+}
+
+impl Level {
+    fn root(entries: Vec<FileEntry>) -> Self {
+        Level {
+            entries: entries.into_iter(),
+            held: None,
+            included: false,
+        }
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<FileEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.ready.pop_front() {
+                return Some(Ok(entry));
+            }
+            let level = self.levels.last_mut()?;
+            let inside = level.included;
+            let Some(entry) = level.entries.next() else {
+                self.levels.pop();
+                continue;
+            };
+            if self.filter.excludes(&entry) {
+                vvlog!("Excluding {}", entry.relative_path);
+                continue;
+            }
+            let included = self.filter.includes(&entry, inside);
+            if included {
+                self.release_held();
+            }
+            if entry.is_dir {
+                let (prefix, exact_prefix) = (&entry.relative_path, &entry.exact_relative_path);
+                let children = match list_level(&entry.path, prefix, exact_prefix) {
+                    Ok(children) => children,
+                    Err(e) => return Some(Err(e)),
+                };
+                let held = if included {
+                    self.ready.push_back(entry);
+                    None
+                } else {
+                    Some(entry)
+                };
+                self.levels.push(Level {
+                    entries: children.into_iter(),
+                    held,
+                    included,
+                });
+            } else if included {
+                self.ready.push_back(entry);
+            }
+        }
+    }
+}
+
+impl Walk {
+    fn new(entries: Vec<FileEntry>, filter: &Filter) -> Self {
+        Walk {
+            levels: vec![Level::root(entries)],
+            filter: filter.clone(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Queue the held-back directories on the current path, outermost
+    /// first, ahead of an entry about to be included inside them.
+    fn release_held(&mut self) {
+        for level in &mut self.levels {
+            if let Some(dir) = level.held.take() {
+                self.ready.push_back(dir);
+            }
+        }
+    }
+
+    /// Count the files and bytes of the whole walk without keeping its
+    /// entries. Sizes are as of this pass; a tree that changes before it is
+    /// walked again will be sent as it is then.
+    pub fn totals(self) -> Result<Totals> {
+        totals(self)
+    }
+}
+
+/// Count the files and bytes of `entries`, as `Walk::totals` does.
+pub fn totals(entries: impl Iterator<Item = Result<FileEntry>>) -> Result<Totals> {
+    let mut totals = Totals::default();
+    for entry in entries {
+        let entry = entry?;
+        if !entry.is_dir {
+            totals.files += 1;
+            totals.bytes += entry.size;
+        }
+    }
+    Ok(totals)
+}
+
+/// Walk `root` recursively, leaving out what `filter` does. Symlinks are
+/// skipped. The root itself is not included.
+pub fn walk_directory(root: &Path, filter: &Filter) -> Result<Walk> {
+    Ok(Walk::new(list_level(root, "", Path::new(""))?, filter))
+}
+
+/// Walk `root` as seen from `base`, a directory it is inside
+/// (`--relative-to`): every relative path starts with where `root` is below
+/// `base`. The directories on the way down come first, then `root` itself,
+/// whether a file or a directory, and then its walk. `filter` sees the
+/// longer paths, and never leaves out the directories on the way.
+pub fn walk_relative(root: &Path, base: &Path, filter: &Filter) -> Result<Walk> {
+    let (base, below) = relative_prefix(root, base)?;
+    let mut ready = VecDeque::new();
+    let (mut relative_path, mut exact_relative_path) = (String::new(), PathBuf::new());
+    let mut components = below.iter().peekable();
+    while let Some(name) = components.next() {
+        relative_path = join_relative(&relative_path, &name.to_string_lossy());
+        exact_relative_path.push(name);
+        if components.peek().is_some() {
+            ready.push_back(FileEntry {
+                path: base.join(&exact_relative_path),
+                relative_path: relative_path.clone(),
+                exact_relative_path: exact_relative_path.clone(),
+                size: 0,
+                is_dir: true,
+            });
+        }
+    }
+
+    let path = base.join(&exact_relative_path);
+    let metadata = fs::metadata(&path)?;
+    let own = FileEntry {
+        path,
+        relative_path,
+        exact_relative_path,
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        is_dir: metadata.is_dir(),
+    };
+    let mut walk = if own.is_dir {
+        let children = list_level(&own.path, &own.relative_path, &own.exact_relative_path)?;
+        ready.push_back(own);
+        Walk::new(children, filter)
+    } else {
+        Walk::new(vec![own], filter)
+    };
+    walk.ready = ready;
+    Ok(walk)
+}
+
+/// Walk each of `paths` as `walk_relative` does from `base`, one after the
+/// other (`--files-from`). A directory on the way to several of them, or
+/// an entry listed again or inside a directory listed before, comes once.
+pub fn walk_listed<'a>(
+    paths: &'a [PathBuf],
+    base: &'a Path,
+    filter: &'a Filter,
+) -> impl Iterator<Item = Result<FileEntry>> + 'a {
+    let mut seen = HashSet::new();
+    paths
+        .iter()
+        .flat_map(move |path| -> Box<dyn Iterator<Item = Result<FileEntry>>> {
+            match walk_relative(path, base, filter) {
+                Ok(walk) => Box::new(walk),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        })
+        .filter(move |entry| match entry {
+            Ok(entry) => seen.insert(entry.relative_path.clone()),
+            Err(_) => true,
+        })
+}
+
+/// The deepest directory that all of `paths` are inside, each one's own
+/// name kept, for `--files-from` without `--relative-to`.
+pub fn common_base(paths: &[PathBuf]) -> Result<PathBuf> {
+    let mut base: Option<PathBuf> = None;
+    for path in paths {
+        let path = lexical_absolute(path)?;
+        let parent = path.parent().ok_or_else(|| format!("Cannot send {}", path.display()))?;
+        base = Some(match base {
+            None => parent.to_path_buf(),
+            Some(base) => {
+                let shared = base.components().zip(parent.components()).take_while(|(a, b)| a == b);
+                shared.map(|(a, _)| a).collect()
+            }
+        });
+    }
+    base.ok_or_else(|| "The list of files to send is empty".into())
+}
+
+/// The paths in the list at `path`, or on standard input for `-`: one per
+/// line, or with `nul` separated by NUL bytes (`--files-from0`). Empty
+/// entries are left out, as is the `\r` of a Windows line ending.
+pub fn read_file_list(path: &Path, nul: bool) -> Result<Vec<PathBuf>> {
+    let mut list = Vec::new();
+    let read = if path.as_os_str() == "-" {
+        io::stdin().lock().read_to_end(&mut list)
+    } else {
+        fs::File::open(path).and_then(|mut file| file.read_to_end(&mut list))
+    };
+    read.map_err(|e| format!("Cannot read the list of files {}: {}", path.display(), e))?;
+    let separator = if nul { 0 } else { b'\n' };
+    let paths = list.split(|&b| b == separator).map(|entry| match entry {
+        [rest @ .., b'\r'] if !nul => rest,
+        entry => entry,
+    });
+    Ok(paths.filter(|entry| !entry.is_empty()).map(path_from_bytes).collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// `base` made absolute, and the path of `root` below it. Both are taken
+/// as written, with `.` and `..` resolved but not symlinks, so a `root`
+/// reached through a link still counts as inside `base`.
+fn relative_prefix(root: &Path, base: &Path) -> Result<(PathBuf, PathBuf)> {
+    let (absolute_root, absolute_base) = (lexical_absolute(root)?, lexical_absolute(base)?);
+    match absolute_root.strip_prefix(&absolute_base) {
+        Ok(below) if !below.as_os_str().is_empty() => Ok((absolute_base.clone(), below.to_path_buf())),
+        _ => Err(format!("{} is not inside --relative-to {}", root.display(), base.display()).into()),
+    }
+}
+
+fn lexical_absolute(path: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            other => resolved.push(other),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Walk several sources as if they were the children of one directory, in
+/// the order given: a file becomes an entry named after it, a directory an
+/// entry followed by its walk. Two sources with the same file name are an
+/// error. `filter` applies to the sources themselves as well.
+pub fn list_sources(paths: &[PathBuf], filter: &Filter) -> Result<Walk> {
+    let mut entries = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+
+    for path in paths {
+        let exact_name = path
+            .file_name()
+            .ok_or_else(|| format!("Source has no file name: {}", path.display()))?;
+        let name = exact_name.to_string_lossy().to_string();
+        if !seen.insert(name.clone()) {
+            return Err(format!("More than one source is named {}", name).into());
+        }
+
+        let metadata = fs::metadata(path)?;
+        entries.push(FileEntry {
+            path: path.clone(),
+            relative_path: name,
+            exact_relative_path: PathBuf::from(exact_name),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            is_dir: metadata.is_dir(),
+        });
+    }
+
+    Ok(Walk::new(entries, filter))
+}
+
+/// The entries directly inside `dir`, in walk order.
+fn list_level(dir: &Path, prefix: &str, exact_prefix: &Path) -> Result<Vec<FileEntry>> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = entry.file_name();
+
+        if file_type.is_dir() {
+            dirs.push((name, entry.path()));
+        } else if file_type.is_file() {
+            let size = entry.metadata()?.len();
+            files.push((name, entry.path(), size));
+        } else {
+            vlog!("Skipping non-regular file: {}", entry.path().display());
+        }
+    }
+
+    dirs.sort_by(|a, b| a.0.cmp(&b.0));
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let dirs = dirs.into_iter().map(|(name, path)| FileEntry {
+        path,
+        relative_path: join_relative(prefix, &name.to_string_lossy()),
+        exact_relative_path: exact_prefix.join(name),
+        size: 0,
+        is_dir: true,
+    });
+    let files = files.into_iter().map(|(name, path, size)| FileEntry {
+        path,
+        relative_path: join_relative(prefix, &name.to_string_lossy()),
+        exact_relative_path: exact_prefix.join(name),
+        size,
+        is_dir: false,
+    });
+    Ok(dirs.chain(files).collect())
+}
+
+fn join_relative(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Total size in bytes of all files in `entries`.
+pub fn calculate_total_size(entries: &[FileEntry]) -> u64 {
+    entries.iter().filter(|e| !e.is_dir).map(|e| e.size).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_order_and_totals() {
+        let root = std::env::temp_dir().join(format!("ncp-walk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("b/inner")).unwrap();
+        fs::create_dir_all(root.join("a")).unwrap();
+        for (name, size) in [("z.txt", 1), ("m.txt", 2), ("b/inner/x", 3), ("b/y", 4), ("a/w", 5)] {
+            fs::write(root.join(name), vec![0u8; size]).unwrap();
+        }
+
+        let names: Vec<String> = walk_directory(&root, &Filter::default())
+            .unwrap()
+            .map(|e| e.unwrap().relative_path)
+            .collect();
+        assert_eq!(names, ["a", "a/w", "b", "b/inner", "b/inner/x", "b/y", "m.txt", "z.txt"]);
+
+        let totals = walk_directory(&root, &Filter::default()).unwrap().totals().unwrap();
+        assert_eq!(totals, Totals { files: 5, bytes: 15 });
+
+        let paths = [root.join("z.txt"), root.join("b")];
+        let sources = list_sources(&paths, &Filter::default()).unwrap();
+        let names: Vec<String> = sources.map(|e| e.unwrap().relative_path).collect();
+        assert_eq!(names, ["z.txt", "b", "b/inner", "b/inner/x", "b/y"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_walk_relative_keeps_the_path_from_base() {
+        let root = std::env::temp_dir().join(format!("ncp-relative-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("var/log/app/old")).unwrap();
+        for name in ["var/log/app/a.log", "var/log/app/old/b.log", "var/log/other.log"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+
+        let walk = walk_relative(&root.join("var/log/app"), &root, &Filter::default()).unwrap();
+        let names: Vec<String> = walk.map(|e| e.unwrap().relative_path).collect();
+        let app = ["var/log/app", "var/log/app/old", "var/log/app/old/b.log", "var/log/app/a.log"];
+        assert_eq!(names[..2], ["var", "var/log"]);
+        assert_eq!(names[2..], app);
+
+        let file = root.join("var/log/app/a.log");
+        let walk = walk_relative(&file, &root.join("var/./log/app/.."), &Filter::default()).unwrap();
+        let entries: Vec<FileEntry> = walk.map(|e| e.unwrap()).collect();
+        let names: Vec<&str> = entries.iter().map(|e| e.relative_path.as_str()).collect();
+        assert_eq!(names, ["app", "app/a.log"]);
+        assert_eq!(entries[1].path, file);
+        assert_eq!(entries[1].size, "var/log/app/a.log".len() as u64);
+
+        for base in [root.join("var/log/app"), root.join("var/log/app/old"), root.join("elsewhere")] {
+            let err = walk_relative(&root.join("var/log/app"), &base, &Filter::default()).err().unwrap();
+            assert!(err.to_string().contains("is not inside --relative-to"), "{}", err);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_listed_paths_share_their_directories() {
+        let root = std::env::temp_dir().join(format!("ncp-listed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        for name in ["a/one.txt", "a/b/two.txt", "a/b/three.txt"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+        fs::write(root.join("list"), "a/b/two.txt\r\n\na/one.txt\na/b\n").unwrap();
+        fs::write(root.join("list0"), "a/b/two.txt\0a/one.txt\0a/b\0").unwrap();
+
+        let listed = ["a/b/two.txt", "a/one.txt", "a/b"];
+        for (list, nul) in [("list", false), ("list0", true)] {
+            let list = read_file_list(&root.join(list), nul).unwrap();
+            assert_eq!(list, listed.map(PathBuf::from));
+        }
+        let paths: Vec<PathBuf> = listed.iter().map(|p| root.join(p)).collect();
+        let base = common_base(&paths).unwrap();
+        assert_eq!(base, lexical_absolute(&root.join("a")).unwrap());
+
+        let names: Vec<String> =
+            walk_listed(&paths, &base, &Filter::default()).map(|e| e.unwrap().relative_path).collect();
+        assert_eq!(names, ["b", "b/two.txt", "one.txt", "b/three.txt"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn filtered_names(root: &Path, include: &[&str], exclude: &[&str]) -> Vec<String> {
+        let filter = Filter {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+        };
+        walk_directory(root, &filter).unwrap().map(|e| e.unwrap().relative_path).collect()
+    }
+
+    #[test]
+    fn test_exclude_prunes_subtree() {
+        let root = std::env::temp_dir().join(format!("ncp-exclude-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("node_modules/dep")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        for name in ["node_modules/dep/index.js", "src/main.rs", "src/main.tmp", "README"] {
+            fs::write(root.join(name), name).unwrap();
+        }
+
+        let names = filtered_names(&root, &[], &["node_modules", "*.tmp"]);
+        assert_eq!(names, ["src", "src/main.rs", "README"]);
+        let names = filtered_names(&root, &[], &["src/main.*"]);
+        let all_modules = ["node_modules", "node_modules/dep", "node_modules/dep/index.js"];
+        assert_eq!(names[..3], all_modules);
+        assert_eq!(names[3..], ["src", "README"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_include_whitelists_matching_files() {
+        let root = std::env::temp_dir().join(format!("ncp-include-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/bin")).unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(root.join("assets")).unwrap();
+        let files = ["src/lib.rs", "src/bin/main.rs", "src/notes.md", "docs/guide.md"];
+        for name in files.into_iter().chain(["build.rs", "Cargo.toml"]) {
+            fs::write(root.join(name), name).unwrap();
+        }
+
+        // Directories with nothing included are left out altogether.
+        let names = filtered_names(&root, &["*.rs"], &[]);
+        assert_eq!(names, ["src", "src/bin", "src/bin/main.rs", "src/lib.rs", "build.rs"]);
+
+        // A matching directory brings everything in it.
+        let names = filtered_names(&root, &["docs", "*.toml"], &[]);
+        assert_eq!(names, ["docs", "docs/guide.md", "Cargo.toml"]);
+
+        // Exclusions still win.
+        let names = filtered_names(&root, &["*.rs"], &["bin"]);
+        assert_eq!(names, ["src", "src/lib.rs", "build.rs"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::types::{NcpError, Result};
+
+/// Bytes available to an unprivileged user on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn get_available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn get_available_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetDiskFreeSpaceExW(
+            lpDirectoryName: *const u16,
+            lpFreeBytesAvailableToCaller: *mut u64,
+            lpTotalNumberOfBytes: *mut u64,
+            lpTotalNumberOfFreeBytes: *mut u64,
+        ) -> i32;
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    let mut total = 0u64;
+    let mut free = 0u64;
+
+    let ret = unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, &mut total, &mut free) };
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(available)
+}
+
+/// Space promised to transfers that passed preflight but have not finished
+/// writing. Clones share the same counter, so every connection handler can
+/// hold one and preflight sees what the others have already claimed.
+#[derive(Debug, Clone, Default)]
+pub struct SpaceLedger {
+    reserved: Arc<Mutex<u64>>,
+}
+
+impl SpaceLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve `size` bytes on the filesystem holding `path`, failing if the
+    /// free space minus outstanding reservations cannot hold it.
+    pub fn reserve(&self, path: &Path, size: u64) -> Result<Reservation> {
+        let mut reserved = self.reserved.lock().unwrap();
+        let available = get_available_space(path)?;
+        self.reserve_locked(&mut reserved, available, size)
+    }
+
+    fn reserve_locked(&self, reserved: &mut u64, available: u64, size: u64) -> Result<Reservation> {
+        let free = available.saturating_sub(*reserved);
+        if free < size {
+            return Err(NcpError::InsufficientSpace {
+                needed: size,
+                free,
+                reserved: *reserved,
+            });
+        }
+
+        *reserved += size;
+        Ok(Reservation {
+            ledger: self.clone(),
+            outstanding: size,
+            available: free,
+        })
+    }
+
+    pub fn reserved(&self) -> u64 {
+        *self.reserved.lock().unwrap()
+    }
+}
+
+/// A claim on the ledger, released when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    ledger: SpaceLedger,
+    outstanding: u64,
+    available: u64,
+}
+
+impl Reservation {
+    /// Free space (net of other reservations) at the time of the claim.
+    pub fn available(&self) -> u64 {
+        self.available
+    }
+
+    /// Hand back `bytes` of the claim once they have actually been written,
+    /// since the filesystem's own free-space figure now accounts for them.
+    pub fn consume(&mut self, bytes: u64) {
+        let bytes = bytes.min(self.outstanding);
+        self.outstanding -= bytes;
+        *self.ledger.reserved.lock().unwrap() -= bytes;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.ledger.reserved.lock().unwrap() -= self.outstanding;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    fn reserve(ledger: &SpaceLedger, available: u64, size: u64) -> Result<Reservation> {
+        let mut reserved = ledger.reserved.lock().unwrap();
+        ledger.reserve_locked(&mut reserved, available, size)
+    }
+
+    #[test]
+    fn test_concurrent_reservations_cannot_overcommit() {
+        let ledger = SpaceLedger::new();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let ledger = ledger.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    // Each transfer fits on its own, but not both together.
+                    let reservation = reserve(&ledger, 100, 60);
+                    barrier.wait();
+                    reservation.is_ok()
+                })
+            })
+            .collect();
+
+        let accepted = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|ok| *ok)
+            .count();
+        assert_eq!(accepted, 1);
+        assert_eq!(ledger.reserved(), 0);
+    }
+
+    #[test]
+    fn test_reservation_released_and_consumed() {
+        let ledger = SpaceLedger::new();
+
+        let mut first = reserve(&ledger, 100, 60).unwrap();
+        assert_eq!(first.available(), 100);
+        assert!(reserve(&ledger, 100, 60).is_err());
+
+        first.consume(20);
+        assert_eq!(ledger.reserved(), 40);
+
+        drop(first);
+        assert_eq!(ledger.reserved(), 0);
+        assert!(reserve(&ledger, 100, 60).is_ok());
+    }
+
+    #[test]
+    fn test_shortfall_is_insufficient_space() {
+        let ledger = SpaceLedger::new();
+        let _held = reserve(&ledger, 100, 30).unwrap();
+
+        let err = reserve(&ledger, 100, 80).unwrap_err();
+        assert!(matches!(
+            err,
+            NcpError::InsufficientSpace { needed: 80, free: 70, reserved: 30 }
+        ));
+        assert_eq!(err.code(), crate::proto::ErrorCode::ErrNoSpace);
+        assert_eq!(
+            err.to_string(),
+            "Insufficient disk space: need 80 B, available 70 B (30 B reserved by other transfers)"
+        );
+    }
+}
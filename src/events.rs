@@ -0,0 +1,363 @@
+//! Newline-delimited JSON event stream for `--json` and `--progress-socket`.
+//!
+//! Every event is one JSON object on its own line: of stdout with `--json`,
+//! and of the Unix socket or Windows named pipe given to `--progress-socket`,
+//! for a frontend that wants progress while the terminal keeps its own. A
+//! transfer opens with `transfer_start`. For each file the order is
+//! `file_start`, zero or more `file_progress`, then `file_done`; files are
+//! reported one after another in transfer order, and a single `done` (or
+//! `error`) event closes the transfer. `done` doubles as the summary of the
+//! whole transfer, so a script only interested in the outcome can read the
+//! last line.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::json::{escape_into, Json};
+use crate::protocol::UNKNOWN_SIZE;
+use crate::types::Result;
+use crate::utils::RateMeter;
+
+pub static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Where `--progress-socket` sends every event, until the reader goes away.
+static SOCKET: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Whether `SOCKET` is set, checked before any event is rendered.
+static SOCKET_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// The rate of the file being transferred, for `file_progress`.
+static RATE: Mutex<Option<RateMeter>> = Mutex::new(None);
+
+pub fn set_json(enabled: bool) {
+    JSON_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn json_enabled() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Send events to the Unix socket or Windows named pipe at `path` from now
+/// on, as well as to stdout with `--json`. Something must be listening there.
+pub fn set_progress_socket(path: &Path) -> Result<()> {
+    let socket = connect_socket(path)
+        .map_err(|e| format!("Cannot connect to progress socket {}: {}", path.display(), e))?;
+    *SOCKET.lock().unwrap_or_else(|e| e.into_inner()) = Some(socket);
+    SOCKET_OUTPUT.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn connect_socket(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+}
+
+/// A named pipe such as `\\.\pipe\ncp` opens like a file.
+#[cfg(windows)]
+fn connect_socket(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(std::fs::OpenOptions::new().write(true).open(path)?))
+}
+
+fn enabled() -> bool {
+    json_enabled() || SOCKET_OUTPUT.load(Ordering::Relaxed)
+}
+
+pub enum Value<'a> {
+    Str(&'a str),
+    U64(u64),
+    Bool(bool),
+    Json(Json),
+}
+
+fn render(event: &str, fields: &[(&str, Value)]) -> String {
+    let mut line = String::from("{\"event\":\"");
+    escape_into(&mut line, event);
+    line.push('"');
+
+    for (key, value) in fields {
+        line.push_str(",\"");
+        escape_into(&mut line, key);
+        line.push_str("\":");
+        match value {
+            Value::Str(s) => {
+                line.push('"');
+                escape_into(&mut line, s);
+                line.push('"');
+            }
+            Value::U64(n) => line.push_str(&n.to_string()),
+            Value::Bool(b) => line.push_str(if *b { "true" } else { "false" }),
+            Value::Json(json) => line.push_str(&json.to_string()),
+        }
+    }
+
+    line.push('}');
+    line
+}
+
+/// Write one event line wherever events go: stdout with `--json`, the
+/// progress socket if there is one.
+pub fn emit(event: &str, fields: &[(&str, Value)]) {
+    if !enabled() {
+        return;
+    }
+    write_line(&render(event, fields));
+}
+
+fn write_line(line: &str) {
+    if json_enabled() {
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+    if SOCKET_OUTPUT.load(Ordering::Relaxed) {
+        write_socket(line);
+    }
+}
+
+/// A reader that stops listening does not stop the transfer; it just gets
+/// no more events.
+fn write_socket(line: &str) {
+    let mut socket = SOCKET.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(out) = socket.as_mut() else {
+        return;
+    };
+    if let Err(e) = writeln!(out, "{}", line).and_then(|()| out.flush()) {
+        eprintln!("Warning: no more progress events go to the progress socket: {}", e);
+        *socket = None;
+        SOCKET_OUTPUT.store(false, Ordering::Relaxed);
+    }
+}
+
+/// A file size, or `null` for data read from a pipe.
+fn size_value(size: u64) -> Value<'static> {
+    if size == UNKNOWN_SIZE {
+        Value::Json(Json::Null)
+    } else {
+        Value::U64(size)
+    }
+}
+
+/// The port a listening side is bound to, which `--port 0` leaves to the OS.
+pub fn listening(port: u16) {
+    emit("listening", &[("port", Value::U64(port as u64))]);
+}
+
+/// A session with the peer at `peer` begins; a retry starts a new one.
+pub fn transfer_start(session: &str, peer: &str) {
+    emit("transfer_start", &[("session", Value::Str(session)), ("peer", Value::Str(peer))]);
+}
+
+pub fn file_start(path: &str, size: u64) {
+    *RATE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    emit("file_start", &[("path", Value::Str(path)), ("size", size_value(size))]);
+}
+
+/// `rate`, in bytes per second, is left out until it can be told, such as
+/// on the first update of a resumed file.
+pub fn file_progress(path: &str, bytes: u64, size: u64) {
+    if !enabled() {
+        return;
+    }
+    let rate = {
+        let mut meter = RATE.lock().unwrap_or_else(|e| e.into_inner());
+        match meter.as_mut() {
+            Some(meter) => {
+                meter.update(bytes);
+                meter.rate().map(|rate| rate as u64)
+            }
+            None => {
+                *meter = Some(RateMeter::new(bytes));
+                None
+            }
+        }
+    };
+    let mut fields = vec![
+        ("path", Value::Str(path)),
+        ("bytes", Value::U64(bytes)),
+        ("size", size_value(size)),
+    ];
+    if let Some(rate) = rate {
+        fields.push(("rate", Value::U64(rate)));
+    }
+    emit("file_progress", &fields);
+}
+
+pub fn file_done(path: &str, bytes: u64, checksum: &str) {
+    emit(
+        "file_done",
+        &[
+            ("path", Value::Str(path)),
+            ("status", Value::Str("ok")),
+            ("bytes", Value::U64(bytes)),
+            ("checksum", Value::Str(checksum)),
+        ],
+    );
+}
+
+pub fn file_skipped(path: &str, reason: &str) {
+    emit(
+        "file_done",
+        &[
+            ("path", Value::Str(path)),
+            ("status", Value::Str("skipped")),
+            ("reason", Value::Str(reason)),
+        ],
+    );
+}
+
+/// Per-file outcomes of one transfer, reported by `done`.
+pub struct Summary {
+    started: Instant,
+    files: Vec<Outcome>,
+}
+
+/// What happened to one file of a `Summary`.
+pub struct Outcome {
+    pub path: String,
+    pub size: u64,
+    /// `ok` or `skipped`.
+    pub status: &'static str,
+    /// Hex digest the file was verified against; empty if it was skipped or
+    /// sent without a checksum.
+    pub checksum: String,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Summary {
+            started: Instant::now(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn transferred(&mut self, path: &str, size: u64, checksum: &str) {
+        self.push(path, size, "ok", checksum);
+    }
+
+    pub fn skipped(&mut self, path: &str, size: u64) {
+        self.push(path, size, "skipped", "");
+    }
+
+    fn push(&mut self, path: &str, size: u64, status: &'static str, checksum: &str) {
+        self.files.push(Outcome {
+            path: path.to_string(),
+            size,
+            status,
+            checksum: checksum.to_string(),
+        });
+    }
+
+    pub fn files(&self) -> &[Outcome] {
+        &self.files
+    }
+
+    pub fn files_transferred(&self) -> u64 {
+        self.transferred_files().count() as u64
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.transferred_files().map(|file| file.size).sum()
+    }
+
+    /// Time since the transfer began.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn transferred_files(&self) -> impl Iterator<Item = &Outcome> {
+        self.files.iter().filter(|file| file.status == "ok")
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn render_done(summary: &Summary) -> String {
+    let files = summary
+        .files
+        .iter()
+        .map(|file| {
+            Json::Object(vec![
+                ("path".to_string(), Json::str(&file.path)),
+                ("size".to_string(), Json::u64(file.size)),
+                ("status".to_string(), Json::str(file.status)),
+            ])
+        })
+        .collect();
+    let elapsed = format!("{:.3}", summary.started.elapsed().as_secs_f64());
+
+    render(
+        "done",
+        &[
+            ("ok", Value::Bool(true)),
+            ("files_transferred", Value::U64(summary.files_transferred())),
+            ("total_bytes", Value::U64(summary.total_bytes())),
+            ("elapsed_seconds", Value::Json(Json::Number(elapsed))),
+            // A file whose checksum does not match ends the transfer with an
+            // `error` event, so reaching `done` means every file verified.
+            ("checksum_ok", Value::Bool(true)),
+            ("files", Value::Json(Json::Array(files))),
+        ],
+    )
+}
+
+pub fn done(summary: &Summary) {
+    if enabled() {
+        write_line(&render_done(summary));
+    }
+}
+
+pub fn error(message: &str) {
+    emit("error", &[("ok", Value::Bool(false)), ("message", Value::Str(message))]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_escapes_strings() {
+        let line = render(
+            "file_start",
+            &[("path", Value::Str("dir/\"odd\"\n.txt")), ("size", Value::U64(7))],
+        );
+        assert_eq!(
+            line,
+            r#"{"event":"file_start","path":"dir/\"odd\"\n.txt","size":7}"#
+        );
+    }
+
+    #[test]
+    fn test_render_bool() {
+        let line = render("done", &[("ok", Value::Bool(true))]);
+        assert_eq!(line, r#"{"event":"done","ok":true}"#);
+    }
+
+    #[test]
+    fn test_summary_totals() {
+        let mut summary = Summary::new();
+        summary.transferred("a.txt", 3, "aa");
+        summary.skipped("b.bin", 100);
+        summary.transferred("sub/c.bin", 4096, "cc");
+
+        let done = crate::json::parse(&render_done(&summary)).unwrap();
+        assert_eq!(done.get("event").and_then(Json::as_str), Some("done"));
+        assert_eq!(done.get("files_transferred").and_then(Json::as_u64), Some(2));
+        assert_eq!(done.get("total_bytes").and_then(Json::as_u64), Some(4099));
+        assert_eq!(done.get("checksum_ok").and_then(Json::as_bool), Some(true));
+        assert!(matches!(done.get("elapsed_seconds"), Some(Json::Number(_))));
+
+        let files = done.get("files").and_then(Json::as_array).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[1].get("path").and_then(Json::as_str), Some("b.bin"));
+        assert_eq!(files[1].get("status").and_then(Json::as_str), Some("skipped"));
+        let sizes: u64 = files.iter().filter_map(|f| f.get("size").and_then(Json::as_u64)).sum();
+        assert_eq!(sizes, 4199);
+    }
+}
@@ -0,0 +1,76 @@
+//! Length-prefixed protobuf framing: `[len: u32 BE][protobuf bytes]`.
+
+
+use std::io::{self, Read, Write};
+
+use prost::Message;
+
+/// Upper bound on a single control frame. Control messages are tiny, so
+/// anything larger than this is treated as a framing error.
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+pub fn write_message<W: Write, M: Message>(writer: &mut W, msg: &M) -> io::Result<()> {
+    let buf = msg.encode_to_vec();
+    if buf.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Message too large: {} bytes", buf.len()),
+        ));
+    }
+    writer.write_all(&(buf.len() as u32).to_be_bytes())?;
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+pub fn read_message<R: Read, M: Message + Default>(reader: &mut R) -> io::Result<M> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame too large: {} bytes (max {})", len, MAX_FRAME_SIZE),
+        ));
+    }
+
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    M::decode(&buf[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_roundtrip() {
+        let msg = proto::Error {
+            session_id: "abc".to_string(),
+            code: 4,
+            message: "disk full".to_string(),
+        };
+        let mut buf = Vec::new();
+        write_message(&mut buf, &msg).unwrap();
+
+        let decoded: proto::Error = read_message(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let buf = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes().to_vec();
+        let result: io::Result<proto::Error> = read_message(&mut Cursor::new(buf));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut buf = 10u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0u8; 3]);
+        let result: io::Result<proto::Error> = read_message(&mut Cursor::new(buf));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
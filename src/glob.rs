@@ -0,0 +1,159 @@
+//! Wildcard expansion for `send` sources, for when the shell left a pattern
+//! unexpanded (quoted, or a shell without globbing).
+//!
+//! Supports `*`, `?` and `[...]` classes (with `!` or `^` to negate and
+//! `a-z` ranges) in any path component. As in a shell, wildcards do not
+//! match a leading `.`.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::types::Result;
+
+/// Whether `path` contains any wildcard characters.
+pub fn has_magic(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// All existing paths matching `pattern`, sorted within each directory.
+/// Fails if nothing matches.
+pub fn expand(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let mut candidates = vec![PathBuf::new()];
+
+    for component in pattern.components() {
+        let glob = match component {
+            Component::Normal(part) if has_magic(Path::new(part)) => part.to_string_lossy(),
+            other => {
+                for candidate in &mut candidates {
+                    candidate.push(other.as_os_str());
+                }
+                continue;
+            }
+        };
+
+        let mut next = Vec::new();
+        for base in &candidates {
+            let dir = if base.as_os_str().is_empty() { Path::new(".") } else { base };
+            let Ok(read) = fs::read_dir(dir) else {
+                continue;
+            };
+            let mut names: Vec<_> = read.filter_map(|e| e.ok()).map(|e| e.file_name()).collect();
+            names.sort();
+            for name in names {
+                let Some(name_str) = name.to_str() else {
+                    continue;
+                };
+                if name_str.starts_with('.') && !glob.starts_with('.') {
+                    continue;
+                }
+                if matches(&glob, name_str) {
+                    next.push(base.join(&name));
+                }
+            }
+        }
+        candidates = next;
+    }
+
+    // Literal components after a wildcard are not checked along the way.
+    candidates.retain(|p| p.exists());
+    if candidates.is_empty() {
+        return Err(format!("No files match {}", pattern.display()).into());
+    }
+    Ok(candidates)
+}
+
+/// Match a single path component against a wildcard pattern.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_from(&pattern, &name)
+}
+
+fn match_from(p: &[char], n: &[char]) -> bool {
+    match p.first() {
+        None => n.is_empty(),
+        Some('*') => (0..=n.len()).any(|i| match_from(&p[1..], &n[i..])),
+        Some('?') => !n.is_empty() && match_from(&p[1..], &n[1..]),
+        Some('[') => {
+            let Some(&c) = n.first() else {
+                return false;
+            };
+            match match_class(&p[1..], c) {
+                Some((true, rest)) => match_from(rest, &n[1..]),
+                Some((false, _)) => false,
+                // An unclosed `[` is an ordinary character.
+                None => c == '[' && match_from(&p[1..], &n[1..]),
+            }
+        }
+        Some(&c) => n.first() == Some(&c) && match_from(&p[1..], &n[1..]),
+    }
+}
+
+/// Match `c` against the class body following a `[`. Returns whether it
+/// matched and the pattern after the closing `]`, or `None` if unclosed.
+fn match_class(p: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negated, start) = match p.first() {
+        Some('!' | '^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut found = false;
+    let mut i = start;
+    loop {
+        let first = *p.get(i)?;
+        // A `]` straight after the opening bracket is part of the class.
+        if first == ']' && i > start {
+            return Some((found != negated, &p[i + 1..]));
+        }
+        match (p.get(i + 1), p.get(i + 2)) {
+            (Some('-'), Some(&last)) if last != ']' => {
+                found |= (first..=last).contains(&c);
+                i += 3;
+            }
+            _ => {
+                found |= first == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*.txt", "a.txt"));
+        assert!(matches("*.txt", ".txt"));
+        assert!(!matches("*.txt", "a.txt.bak"));
+        assert!(matches("file?.log", "file1.log"));
+        assert!(!matches("file?.log", "file.log"));
+        assert!(matches("[abc]*", "beta"));
+        assert!(!matches("[!abc]*", "beta"));
+        assert!(matches("v[0-9]", "v7"));
+        assert!(!matches("v[0-9]", "vx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("a[b", "a[b"));
+    }
+
+    #[test]
+    fn test_expand_txt() {
+        let dir = std::env::temp_dir().join(format!("ncp-glob-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.txt", "a.txt", "c.log", ".hidden.txt", "sub/d.txt"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        let found = expand(&dir.join("*.txt")).unwrap();
+        assert_eq!(found, vec![dir.join("a.txt"), dir.join("b.txt")]);
+
+        let found = expand(&dir.join("*").join("d.txt")).unwrap();
+        assert_eq!(found, vec![dir.join("sub/d.txt")]);
+
+        let err = expand(&dir.join("*.csv")).unwrap_err();
+        assert!(err.to_string().contains("No files match"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
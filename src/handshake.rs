@@ -0,0 +1,425 @@
+//! Connection handshake.
+//!
+//! The sender opens every connection with a `Probe` and the receiver answers
+//! with `Established`, both as length-prefixed protobuf frames (see
+//! `framing`), before any `protocol` message is exchanged. The session ID
+//! chosen by the sender is repeated in every `Meta` and `TransferStart` and
+//! checked by the receiver. Peers must speak the same `PROTOCOL_VERSION`.
+//!
+//! With `--psk`, each side proves it holds the key before anything else is
+//! sent: `Probe` and `Established` each carry a random nonce, `Established`
+//! answers the sender's with an HMAC, and the sender answers the receiver's
+//! in an `Authenticate` message, confirmed by an `AuthResult`. A side with a
+//! key refuses a peer without one.
+//!
+//! A receiver that pulls (`recv --pull`) says so in `Established` and, once
+//! the handshake is done, names what it wants in a `PullRequest`, which the
+//! sender answers with a `PullResult` before sending it. One that deletes
+//! (`recv --delete`) asks in `Established` for the mirror list a sender sends
+//! with `--mirror`. Only a receiver that can take the extra connections of
+//! a split file lists the `parallel` capability (see `parallel`).
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::batch;
+use crate::checksum::{self, digests_equal, hmac_sha256};
+use crate::compress;
+use crate::framing;
+use crate::keepalive;
+use crate::parallel;
+use crate::proto::{
+    AuthResult, Authenticate, ErrorCode, Established, Probe, PullRequest, PullResult,
+    PROTOCOL_VERSION,
+};
+use crate::types::{MirrorMode, NcpError, Result};
+
+/// Features this build supports, as listed in `Probe::capabilities`.
+pub const CAPABILITIES: &[&str] = &[
+    batch::CAPABILITY,
+    "checksum:crc32",
+    "checksum:sha256",
+    checksum::TREE_CAPABILITY,
+    checksum::RETRY_CAPABILITY,
+    "compress:zstd",
+    compress::SHARED_CAPABILITY,
+    "format:json",
+    keepalive::CAPABILITY,
+    "mirror",
+    parallel::CAPABILITY,
+    "resume",
+];
+
+/// A fresh, practically unique session ID: 16 hex digits.
+pub fn new_session_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    hasher.write_u32(std::process::id());
+    format!("{:016x}", hasher.finish())
+}
+
+/// Bytes in each side's `--psk` challenge.
+const NONCE_LEN: usize = 32;
+
+/// Which side an HMAC proves, so one side's answer is never valid for the other.
+const SENDER_ROLE: &[u8] = b"ncp sender";
+const RECEIVER_ROLE: &[u8] = b"ncp receiver";
+
+/// A challenge nobody can predict, from the OS. Without one the handshake
+/// fails rather than offer a challenge that could be guessed.
+fn new_nonce() -> Result<Vec<u8>> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    getrandom::fill(&mut nonce)
+        .map_err(|e| format!("Cannot make a --psk challenge: no random numbers from the OS: {}", e))?;
+    Ok(nonce)
+}
+
+/// The answer to `nonce` for `role` in `session_id`.
+fn proof(psk: &[u8], role: &[u8], session_id: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(role.len() + session_id.len() + nonce.len() + 2);
+    data.extend_from_slice(role);
+    data.push(0);
+    data.extend_from_slice(session_id.as_bytes());
+    data.push(0);
+    data.extend_from_slice(nonce);
+    hmac_sha256(psk, &data).to_vec()
+}
+
+/// Sender side: send a `Probe` for `session_id` and wait for the receiver to
+/// accept it. The receiver is asked to ping often enough for `timeout` (see
+/// `keepalive`); with a `psk`, it has to prove it holds the same one.
+pub fn open<S: Read + Write>(
+    stream: &mut S,
+    session_id: &str,
+    timeout: Option<Duration>,
+    psk: Option<&[u8]>,
+) -> Result<Established> {
+    let mut probe = Probe::new(session_id.to_string());
+    probe.capabilities = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    probe.keepalive_seconds = keepalive::interval_seconds(timeout);
+    if psk.is_some() {
+        probe.auth_nonce = new_nonce()?;
+    }
+    framing::write_message(stream, &probe)?;
+
+    let established: Established =
+        framing::read_message(stream).map_err(|e| incompatible("receiver", e))?;
+    if established.version != PROTOCOL_VERSION {
+        return Err(NcpError::Protocol(format!(
+            "Receiver speaks protocol version {}, this ncp speaks {}",
+            established.version, PROTOCOL_VERSION
+        )));
+    }
+    check_session(session_id, &established.session_id, "Established")?;
+
+    match psk {
+        None if !established.auth_nonce.is_empty() => {
+            return Err(NcpError::Auth("Receiver requires a pre-shared key (use --psk)".to_string()));
+        }
+        None => {}
+        Some(_) if established.auth_nonce.is_empty() => {
+            let message = "Receiver has no pre-shared key; not sending without one";
+            return Err(NcpError::Auth(message.to_string()));
+        }
+        Some(psk) => {
+            let expected = proof(psk, RECEIVER_ROLE, session_id, &probe.auth_nonce);
+            if !digests_equal(&expected, &established.auth_proof) {
+                let message = "Receiver failed authentication: the pre-shared keys differ";
+                return Err(NcpError::Auth(message.to_string()));
+            }
+            let answer = Authenticate {
+                proof: proof(psk, SENDER_ROLE, session_id, &established.auth_nonce),
+            };
+            framing::write_message(stream, &answer)?;
+            let result: AuthResult = framing::read_message(stream)?;
+            if !result.ok {
+                let message = format!("Receiver rejected authentication: {}", result.reason);
+                return Err(NcpError::PeerError(ErrorCode::ErrAuth, message));
+            }
+            vlog!("Authenticated with the pre-shared key");
+        }
+    }
+
+    vlog!(
+        "Session {} established (capabilities: {})",
+        session_id,
+        established.capabilities.join(", ")
+    );
+    Ok(established)
+}
+
+/// Receiver side: read the sender's `Probe` and answer it. `Established` is
+/// sent even for a version mismatch so the sender can report it too. With a
+/// `psk`, nothing is accepted from a sender that cannot prove it holds it;
+/// `pull` announces a `request_pull` to follow, `verify_only` asks for a
+/// checksum with every file instead of its data, `delete` for a mirror
+/// list, and `parallel` offers to take a file over extra connections. Like
+/// `open`, the sender is asked to ping often enough for `timeout`.
+pub fn accept<S: Read + Write>(
+    stream: &mut S,
+    psk: Option<&[u8]>,
+    pull: bool,
+    verify_only: bool,
+    delete: MirrorMode,
+    parallel: bool,
+    timeout: Option<Duration>,
+) -> Result<Probe> {
+    let probe: Probe = framing::read_message(stream).map_err(|e| incompatible("sender", e))?;
+
+    let nonce = match psk {
+        Some(_) => new_nonce()?,
+        None => Vec::new(),
+    };
+    let auth_proof = match psk {
+        Some(psk) if !probe.auth_nonce.is_empty() => {
+            proof(psk, RECEIVER_ROLE, &probe.session_id, &probe.auth_nonce)
+        }
+        _ => Vec::new(),
+    };
+    let established = Established {
+        session_id: probe.session_id.clone(),
+        version: PROTOCOL_VERSION.to_string(),
+        capabilities: CAPABILITIES
+            .iter()
+            .filter(|c| probe.capabilities.iter().any(|p| p == *c))
+            .filter(|c| parallel || **c != parallel::CAPABILITY)
+            .map(|c| c.to_string())
+            .collect(),
+        server_time: Some(SystemTime::now().into()),
+        auth_nonce: nonce.clone(),
+        auth_proof,
+        pull,
+        keepalive_seconds: keepalive::interval_seconds(timeout),
+        verify_only,
+        delete: delete != MirrorMode::Off,
+        delete_dry_run: delete == MirrorMode::DryRun,
+    };
+    framing::write_message(stream, &established)?;
+
+    if probe.version != PROTOCOL_VERSION {
+        return Err(NcpError::Protocol(format!(
+            "Sender speaks protocol version {}, this ncp speaks {}",
+            probe.version, PROTOCOL_VERSION
+        )));
+    }
+    if probe.session_id.is_empty() {
+        return Err(NcpError::Protocol("Sender did not provide a session ID".to_string()));
+    }
+    if let Some(psk) = psk {
+        authenticate_sender(stream, psk, &probe, &nonce)?;
+    }
+
+    vlog!("Session {} opened by {}", probe.session_id, probe.client_name);
+    vvlog!("Sender keepalive: {}s", probe.keepalive_seconds);
+    Ok(probe)
+}
+
+/// Check the sender's answer to `nonce` and tell it the outcome.
+fn authenticate_sender<S: Read + Write>(
+    stream: &mut S,
+    psk: &[u8],
+    probe: &Probe,
+    nonce: &[u8],
+) -> Result<()> {
+    let sender = &probe.client_name;
+    if probe.auth_nonce.is_empty() {
+        let message = format!("Sender {} has no pre-shared key (--psk); refusing it", sender);
+        return Err(NcpError::Auth(message));
+    }
+    // A sender that finds our proof wrong hangs up instead of answering.
+    let answer: Authenticate = framing::read_message(stream).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => NcpError::Auth(format!(
+            "Sender {} failed authentication: it hung up, so the pre-shared keys differ",
+            sender
+        )),
+        _ => format!("Sender {} did not authenticate: {}", sender, e).into(),
+    })?;
+    let expected = proof(psk, SENDER_ROLE, &probe.session_id, nonce);
+    if !digests_equal(&expected, &answer.proof) {
+        let result = AuthResult {
+            ok: false,
+            reason: "wrong pre-shared key".to_string(),
+        };
+        framing::write_message(stream, &result)?;
+        let message = format!("Sender {} failed authentication: wrong pre-shared key", sender);
+        return Err(NcpError::Auth(message));
+    }
+    framing::write_message(stream, &AuthResult { ok: true, reason: String::new() })?;
+    vlog!("Sender {} authenticated with the pre-shared key", sender);
+    Ok(())
+}
+
+/// Receiver side of a pull: ask the sender for `path`, which it serves
+/// next unless it refuses.
+pub fn request_pull<S: Read + Write>(stream: &mut S, session_id: &str, path: &str) -> Result<()> {
+    let request = PullRequest {
+        session_id: session_id.to_string(),
+        path: path.to_string(),
+    };
+    framing::write_message(stream, &request)?;
+    let result: PullResult = framing::read_message(stream)?;
+    if !result.ok {
+        let message = format!("Sender refused to send {}: {}", path, result.reason);
+        return Err(NcpError::PeerError(ErrorCode::ErrInvalidArg, message));
+    }
+    vlog!("Pulling {}", path);
+    Ok(())
+}
+
+/// Sender side of a pull: read the receiver's `PullRequest` and answer it
+/// with whether `resolve` accepts its path.
+pub fn serve_pull<S: Read + Write, T>(
+    stream: &mut S,
+    session_id: &str,
+    resolve: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    let request: PullRequest = framing::read_message(stream)?;
+    check_session(session_id, &request.session_id, "PullRequest")?;
+    let resolved = resolve(&request.path);
+    let result = match &resolved {
+        Ok(_) => PullResult { ok: true, reason: String::new() },
+        Err(e) => PullResult { ok: false, reason: e.to_string() },
+    };
+    framing::write_message(stream, &result)?;
+    resolved.map_err(|e| format!("Refused to send {:?}: {}", request.path, e).into())
+}
+
+/// Fail unless a message of kind `what` belongs to session `expected`.
+pub fn check_session(expected: &str, got: &str, what: &str) -> Result<()> {
+    if got != expected {
+        let message = format!("{} for session {:?}, expected {:?}", what, got, expected);
+        return Err(NcpError::Protocol(message));
+    }
+    Ok(())
+}
+
+/// A peer that predates the handshake sends (or expects) something else
+/// entirely, which shows up as a garbled or missing frame.
+fn incompatible(peer: &str, err: io::Error) -> NcpError {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => NcpError::Protocol(format!(
+            "Handshake with {} failed ({}); is it running a compatible ncp?",
+            peer, err
+        )),
+        _ => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_handshake_agrees_on_session() {
+        let (mut client, mut server) = pair();
+        let receiver = thread::spawn(move || {
+            let accepted = accept(&mut server, None, false, false, MirrorMode::Off, true, None);
+            accepted.map(|p| p.session_id).ok()
+        });
+
+        let session_id = new_session_id();
+        let timeout = Some(Duration::from_secs(30));
+        let established = open(&mut client, &session_id, timeout, None).unwrap();
+        assert_eq!(established.session_id, session_id);
+        assert_eq!(established.capabilities.len(), CAPABILITIES.len());
+        assert_eq!(receiver.join().unwrap(), Some(session_id));
+    }
+
+    #[test]
+    fn test_version_mismatch_fails_both_sides() {
+        let (mut client, mut server) = pair();
+        let receiver = thread::spawn(move || {
+            accept(&mut server, None, false, false, MirrorMode::Off, false, None).is_err()
+        });
+
+        let mut probe = Probe::new("s".to_string());
+        probe.version = "0".to_string();
+        framing::write_message(&mut client, &probe).unwrap();
+        let established: Established = framing::read_message(&mut client).unwrap();
+        assert_eq!(established.version, PROTOCOL_VERSION);
+        assert!(receiver.join().unwrap());
+    }
+
+    #[test]
+    fn test_receiver_of_another_version_refused() {
+        let (mut client, mut server) = pair();
+        let receiver = thread::spawn(move || {
+            let probe: Probe = framing::read_message(&mut server).unwrap();
+            let established = Established {
+                session_id: probe.session_id,
+                version: "1".to_string(),
+                ..Default::default()
+            };
+            framing::write_message(&mut server, &established).unwrap();
+        });
+
+        let err = open(&mut client, &new_session_id(), None, None).unwrap_err();
+        assert!(matches!(err, NcpError::Protocol(_)), "{:?}", err);
+        assert!(err.to_string().contains("Receiver speaks protocol version 1"), "{}", err);
+        receiver.join().unwrap();
+    }
+
+    #[test]
+    fn test_pre_handshake_peer_rejected() {
+        // An older sender starts straight away with a binary Meta.
+        let (mut client, mut server) = pair();
+        client.write_all(&[1, 0, 0, 0, 20]).unwrap();
+        let err = accept(&mut server, None, false, false, MirrorMode::Off, false, None).unwrap_err();
+        assert!(err.to_string().contains("compatible ncp"), "{}", err);
+    }
+
+    /// Run both sides, with the sender holding `sender_psk` and the receiver
+    /// `receiver_psk`; returns each side's outcome.
+    fn authenticate(
+        sender_psk: Option<&str>,
+        receiver_psk: Option<&'static str>,
+    ) -> (Result<()>, Result<()>) {
+        let (mut client, mut server) = pair();
+        let receiver = thread::spawn(move || {
+            let psk = receiver_psk.map(str::as_bytes);
+            let accepted = accept(&mut server, psk, false, false, MirrorMode::Off, false, None);
+            accepted.map(|_| ()).map_err(|e| e.to_string())
+        });
+        let sent = open(&mut client, &new_session_id(), None, sender_psk.map(str::as_bytes));
+        // The receiver may still be waiting for an answer that never comes.
+        drop(client);
+        (sent.map(|_| ()), receiver.join().unwrap().map_err(Into::into))
+    }
+
+    #[test]
+    fn test_matching_psk_accepted() {
+        let (sent, received) = authenticate(Some("secret"), Some("secret"));
+        assert!(sent.is_ok(), "{:?}", sent);
+        assert!(received.is_ok(), "{:?}", received);
+    }
+
+    #[test]
+    fn test_wrong_psk_rejected_by_both_sides() {
+        let (sent, received) = authenticate(Some("secret"), Some("guess"));
+        assert!(sent.unwrap_err().to_string().contains("failed authentication"));
+        assert!(received.unwrap_err().to_string().contains("keys differ"));
+    }
+
+    #[test]
+    fn test_psk_on_one_side_only_rejected() {
+        let (sent, received) = authenticate(None, Some("secret"));
+        assert!(sent.unwrap_err().to_string().contains("requires a pre-shared key"));
+        assert!(received.unwrap_err().to_string().contains("has no pre-shared key"));
+
+        let (sent, received) = authenticate(Some("secret"), None);
+        assert!(sent.unwrap_err().to_string().contains("Receiver has no pre-shared key"));
+        assert!(received.is_ok());
+    }
+}
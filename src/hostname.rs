@@ -0,0 +1,55 @@
+use std::io;
+
+/// Used when the system will not tell us its name.
+const FALLBACK_HOSTNAME: &str = "ncp-client";
+
+/// Name of the local machine, used as the `client_name` in `Probe`.
+pub fn get_hostname() -> String {
+    match query_hostname() {
+        Ok(name) if !name.is_empty() => name,
+        _ => FALLBACK_HOSTNAME.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn query_hostname() -> io::Result<String> {
+    let mut buf = [0u8; 256];
+
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // POSIX leaves termination unspecified on truncation.
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(windows)]
+fn query_hostname() -> io::Result<String> {
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetComputerNameW(lpBuffer: *mut u16, nSize: *mut u32) -> i32;
+    }
+
+    // MAX_COMPUTERNAME_LENGTH is 15; leave room for the terminator.
+    let mut buf = [0u16; 64];
+    let mut len = buf.len() as u32;
+
+    let ret = unsafe { GetComputerNameW(buf.as_mut_ptr(), &mut len) };
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(String::from_utf16_lossy(&buf[..len as usize]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hostname_not_empty() {
+        assert!(!get_hostname().is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! Ctrl-C and SIGTERM handling.
+//!
+//! An interrupt ends the process with exit code 130 after deleting the temp
+//! files of transfers still in progress, so none are left behind. Temp files
+//! kept for `--resume` are not tracked and survive.
+//!
+//! On Unix the signals are blocked in every thread and picked up by one
+//! that waits in `sigwait(2)`, which leaves it free to lock and print. On
+//! Windows the console control handler already runs on a thread of its own.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+use crate::{events, pause};
+
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Temp files to delete if the transfer is interrupted.
+static PARTIAL: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Start handling interrupts. Must be called before any other thread is
+/// spawned, so that they all inherit the blocked signals.
+pub fn install() {
+    platform::install();
+}
+
+/// Delete `path` if the process is interrupted.
+pub fn track(path: &Path) {
+    let mut partial = PARTIAL.lock().unwrap_or_else(|e| e.into_inner());
+    partial.get_or_insert_with(HashSet::new).insert(path.to_path_buf());
+}
+
+/// `path` was finished, kept or removed; an interrupt leaves it alone.
+pub fn untrack(path: &Path) {
+    let mut partial = PARTIAL.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(partial) = partial.as_mut() {
+        partial.remove(path);
+    }
+}
+
+fn abort() -> ! {
+    // Held until exit so that no transfer can start a new temp file.
+    let partial = PARTIAL.lock().unwrap_or_else(|e| e.into_inner());
+    for path in partial.iter().flatten() {
+        match fs::remove_file(path) {
+            Ok(()) => vlog!("Removed {}", path.display()),
+            Err(e) => vlog!("Could not remove {}: {}", path.display(), e),
+        }
+    }
+    pause::restore();
+    // The terminal has echoed ^C, possibly after a progress line.
+    eprintln!("\nTransfer aborted");
+    events::error("Transfer aborted");
+    process::exit(EXIT_INTERRUPTED);
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::{ptr, thread};
+
+    pub fn install() {
+        let set = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            if libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) != 0 {
+                vlog!("Cannot block interrupt signals; Ctrl-C will not clean up");
+                return;
+            }
+            set
+        };
+        thread::spawn(move || {
+            loop {
+                let mut signal = 0;
+                if unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+                    vvlog!("Received signal {}", signal);
+                    super::abort();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    type Handler = unsafe extern "system" fn(u32) -> i32;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<Handler>, add: i32) -> i32;
+    }
+
+    unsafe extern "system" fn on_ctrl(_kind: u32) -> i32 {
+        super::abort()
+    }
+
+    pub fn install() {
+        if unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) } == 0 {
+            vlog!("Cannot install a Ctrl-C handler; interrupts will not clean up");
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    pub fn install() {}
+}
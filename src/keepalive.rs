@@ -0,0 +1,159 @@
+//! Pings that keep a connection alive while one side is busy with
+//! something other than the peer: walking a large tree, hashing a file for
+//! `--skip-existing`, waiting at an overwrite prompt, or removing what a
+//! mirror left over.
+//!
+//! Each side says in the handshake how often it wants to hear from the
+//! other (`keepalive_seconds` in `Probe` and `Established`), a fraction of
+//! its own `--timeout`. While busy, a side runs a `Keepalive` that sends a
+//! `Ping` at the peer's interval from a background thread; the peer, which
+//! is waiting for a control message, restarts its read timeout on every
+//! one and answers with a `Pong` (see `protocol::read_control`). A stall
+//! on one side then neither trips the other's `--timeout` nor lets NAT or
+//! firewall state expire, while a dead peer is still noticed after one
+//! `--timeout` of silence.
+
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::net::Stream;
+use crate::protocol::{write_message, Message, WireFormat};
+
+/// Listed in `handshake::CAPABILITIES`; nobody pings a peer without it.
+pub const CAPABILITY: &str = "keepalive";
+
+/// Longest interval asked for, however long `--timeout` is.
+const MAX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The interval to ask the peer for, in whole seconds: a third of
+/// `timeout`, so a late ping or two is not fatal, and at most
+/// `MAX_INTERVAL` even without a timeout.
+pub fn interval_seconds(timeout: Option<Duration>) -> u32 {
+    let interval = timeout.map_or(MAX_INTERVAL, |t| (t / 3).min(MAX_INTERVAL));
+    interval.as_secs().max(1) as u32
+}
+
+/// How often to ping a peer that listed `capabilities` and asked for
+/// `seconds`, or `None` if it does not take pings.
+pub fn agreed(capabilities: &[String], seconds: u32) -> Option<Duration> {
+    let shared = capabilities.iter().any(|c| c == CAPABILITY);
+    (shared && seconds > 0).then(|| Duration::from_secs(seconds.into()))
+}
+
+/// Pings the peer until dropped. Nothing else may write to the stream
+/// meanwhile, so it is only held around work that does not touch it.
+pub struct Keepalive {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Keepalive {
+    /// Send a `Ping` over `stream` every `interval`. With `None`, or a
+    /// stream that cannot be shared with another thread, nothing is sent.
+    pub fn start(stream: &Stream, format: WireFormat, interval: Option<Duration>) -> Self {
+        let idle = Keepalive {
+            stop: None,
+            thread: None,
+        };
+        let Some(interval) = interval else {
+            return idle;
+        };
+        let mut stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => {
+                vvlog!("Cannot ping the peer: {}", e);
+                return idle;
+            }
+        };
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // A broken connection is for whoever owns the stream to report.
+                if write_message(&mut stream, format, &Message::Ping).is_err() {
+                    break;
+                }
+                vvlog!("Sent keepalive ping");
+            }
+        });
+        Keepalive {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        // Hanging up wakes the thread at once; a ping it is writing is
+        // finished first, so the owner's next message is not cut into.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::read_control;
+    use std::net::{TcpListener, TcpStream};
+
+    /// A connected pair; reads on the second end give up after `timeout`.
+    fn pair(timeout: Duration) -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (waiting, _) = listener.accept().unwrap();
+        waiting.set_read_timeout(Some(timeout)).unwrap();
+        (busy, waiting)
+    }
+
+    /// The busy end stalls for several read timeouts of the waiting end,
+    /// pinging if `interval` is set, and then sends `Done`.
+    fn stall(mut busy: TcpStream, interval: Option<Duration>) -> thread::JoinHandle<Message> {
+        thread::spawn(move || {
+            let stream = Stream::from(busy.try_clone().unwrap());
+            let keepalive = Keepalive::start(&stream, WireFormat::Json, interval);
+            thread::sleep(Duration::from_millis(600));
+            drop(keepalive);
+            let _ = write_message(&mut busy, WireFormat::Json, &Message::Done);
+            // The pongs come back ahead of the answer, and are skipped too.
+            busy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            read_control(&mut busy, WireFormat::Json).unwrap_or(Message::Pong)
+        })
+    }
+
+    #[test]
+    fn test_pings_carry_a_connection_through_a_long_stall() {
+        let (busy, mut waiting) = pair(Duration::from_millis(200));
+        let busy = stall(busy, Some(Duration::from_millis(50)));
+
+        assert_eq!(read_control(&mut waiting, WireFormat::Json).unwrap(), Message::Done);
+        write_message(&mut waiting, WireFormat::Json, &Message::Done).unwrap();
+        assert_eq!(busy.join().unwrap(), Message::Done);
+    }
+
+    #[test]
+    fn test_a_silent_stall_times_out() {
+        let (busy, mut waiting) = pair(Duration::from_millis(200));
+        let busy = stall(busy, None);
+
+        assert!(read_control(&mut waiting, WireFormat::Json).is_err());
+        drop(waiting);
+        busy.join().unwrap();
+    }
+
+    #[test]
+    fn test_interval_is_a_third_of_the_timeout() {
+        assert_eq!(interval_seconds(Some(Duration::from_secs(300))), 30);
+        assert_eq!(interval_seconds(Some(Duration::from_secs(12))), 4);
+        assert_eq!(interval_seconds(Some(Duration::from_secs(1))), 1);
+        assert_eq!(interval_seconds(None), 30);
+
+        let caps = vec![CAPABILITY.to_string()];
+        assert_eq!(agreed(&caps, 4), Some(Duration::from_secs(4)));
+        assert_eq!(agreed(&caps, 0), None);
+        assert_eq!(agreed(&[], 4), None);
+    }
+}
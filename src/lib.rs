@@ -0,0 +1,428 @@
+//! ncp sends files and directory trees over TCP, verified end to end.
+//!
+//! The `ncp` binary is a thin wrapper around `cli::run`. Other programs can
+//! drive a transfer directly: `receive` waits for one sender, `send_file`
+//! connects to a receiver, and both return a `TransferReport`.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! let opts = ncp::Options { recursive: true, ..Default::default() };
+//! let report = ncp::send_file("backup.example.com:9000", Path::new("photos"), &opts)?;
+//! println!("sent {} files, {} bytes", report.files.len(), report.bytes);
+//! # Ok::<(), ncp::NcpError>(())
+//! ```
+//!
+//! Status lines and progress are printed as the command prints them;
+//! `set_quiet(true)` silences everything but errors and warnings.
+
+#[macro_use]
+mod logging;
+
+pub mod cli;
+
+mod audit;
+mod batch;
+mod checksum;
+mod checksum_cache;
+mod compress;
+mod config;
+mod dedup;
+mod directory;
+mod diskspace;
+mod events;
+mod framing;
+mod glob;
+mod handshake;
+mod hostname;
+mod interrupt;
+mod json;
+mod keepalive;
+mod manifest;
+mod net;
+mod parallel;
+mod pause;
+mod progress;
+mod proto;
+mod protocol;
+mod proxy;
+mod recv;
+mod retry;
+mod send;
+mod tls;
+mod types;
+mod utils;
+mod xattrs;
+mod zerocopy;
+
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use checksum_cache::ChecksumCache;
+use directory::Filter;
+use manifest::Manifest;
+use protocol::WireFormat;
+use proxy::Proxy;
+use types::{RecvArgs, SendArgs, DEFAULT_RETRIES, DEFAULT_RETRY_DELAY};
+
+pub use checksum::{ChecksumAlg, ChecksumScope};
+pub use logging::{set_quiet, set_verbosity};
+pub use net::{IpFamily, SocketOptions};
+pub use proto::ErrorCode;
+pub use types::{DedupMode, LimitScope, MirrorMode, NcpError, OverwriteMode, Result};
+
+/// Settings for `send_file` and `receive`, named after the command-line
+/// flags they stand for. Each side only reads the ones that apply to it;
+/// the defaults are the command's.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Send a directory, or a wildcard that matches one (`-r`, send).
+    pub recursive: bool,
+    /// What the receiver does with a file that already exists
+    /// (`--overwrite`). Sent as a preference; an explicit receiver setting
+    /// wins. `Ask` prompts on the terminal, so unlike the command's the
+    /// default is `No`: a library call never waits on stdin unless asked to.
+    pub overwrite: OverwriteMode,
+    /// Continue partial files left by an interrupted transfer (`--resume`).
+    pub resume: bool,
+    /// Connection attempts before giving up (`--retries`, send).
+    pub retries: u32,
+    /// Wait before the first retry (`--retry-delay`, send).
+    pub retry_delay: Duration,
+    /// Multiplier for the wait after each retry (`--retry-backoff`, send).
+    pub retry_backoff: f64,
+    /// Delete destination entries missing from a directory source
+    /// (`--mirror`, send).
+    pub mirror: MirrorMode,
+    /// Compress file data with zstd at this level (`--compress-level`,
+    /// send); `None` sends it raw.
+    pub compress: Option<i32>,
+    /// Compress the files of a directory as one zstd stream
+    /// (`--compress-shared`, send); implies `compress`.
+    pub compress_shared: bool,
+    /// Check each chunk of file data with CRC32 (`--verify-chunks`, send).
+    pub verify_chunks: bool,
+    /// Send file data in length-prefixed blocks (`--framed`, send).
+    pub framed: bool,
+    /// Split a single file over this many connections (`--parallel`,
+    /// send).
+    pub parallel: u32,
+    /// Send the small files of a directory back to back (`--batch`, send).
+    pub batch: bool,
+    /// Digest every file is verified with (`--checksum`, send).
+    pub checksum: ChecksumAlg,
+    /// Verify a directory file by file or with one digest at the end
+    /// (`--checksum tree`, send).
+    pub checksum_scope: ChecksumScope,
+    /// Reuse the digests of unchanged files kept here
+    /// (`--checksum-cache`, send).
+    pub checksum_cache: Option<PathBuf>,
+    /// Cap on bytes of file data per second (`--limit`, send).
+    pub limit: Option<u64>,
+    /// Whether `limit` counts file data or bytes on the wire
+    /// (`--limit-scope`, send).
+    pub limit_scope: LimitScope,
+    /// Keep file permissions and modification times (`--preserve`, send).
+    pub preserve: bool,
+    /// Send extended attributes for the receiver to set (`--xattrs`, send).
+    pub xattrs: bool,
+    /// Skip files the receiver already has (`--skip-existing`, send).
+    pub skip_existing: bool,
+    /// Skip files the receiver already has, and only resume a partial file
+    /// that matches the source so far (`--checksum-on-preflight`, send).
+    pub checksum_on_preflight: bool,
+    /// `--include` patterns for directory sources.
+    pub include: Vec<String>,
+    /// `--exclude` patterns for directory sources.
+    pub exclude: Vec<String>,
+    /// Send the source under its path below this directory
+    /// (`--relative-to`, send).
+    pub relative_to: Option<PathBuf>,
+    /// Bytes copied at a time (`--buffer-size`).
+    pub buffer_size: usize,
+    /// Least time between progress updates (`--progress-interval`).
+    pub progress_interval: Duration,
+    /// Give up on a silent peer after this long (`--timeout`); `None` waits
+    /// forever.
+    pub timeout: Option<Duration>,
+    /// Only use addresses of this family (`-4`, `-6`).
+    pub family: IpFamily,
+    /// `TCP_NODELAY` and kernel buffer sizes of each connection
+    /// (`--no-nodelay`, `--sndbuf`, `--rcvbuf`).
+    pub socket: SocketOptions,
+    /// Connect through this proxy, `socks5://[user:pass@]host:port` or
+    /// `http://[user:pass@]host:port` (`--proxy`, send).
+    pub proxy: Option<String>,
+    /// Record each file handled as a JSON line here (`--manifest`).
+    pub manifest: Option<PathBuf>,
+    /// Key both sides must prove they share (`--psk`).
+    pub psk: Option<String>,
+    /// Encrypt each connection with TLS (`--tls`).
+    pub tls: bool,
+    /// Trust only the CAs in this PEM file for the receiver's certificate
+    /// (`--ca`, send).
+    pub ca: Option<PathBuf>,
+    /// Take any certificate the receiver shows (`--insecure`, send).
+    pub insecure: bool,
+    /// Certificate chain to receive with, as PEM (`--cert`, recv).
+    pub cert: Option<PathBuf>,
+    /// Private key of `cert`, as PEM (`--key`, recv).
+    pub key: Option<PathBuf>,
+    /// Save a single received file under this name (`--as`, recv).
+    pub output_name: Option<String>,
+    /// Create missing parent directories of the destination (`--mkdir`,
+    /// recv).
+    pub mkdir: bool,
+    /// Merge a received directory into an existing destination (`--into`,
+    /// recv).
+    pub into: bool,
+    /// Only compare the destination with the sender's checksums
+    /// (`--verify-only`, recv).
+    pub verify_only: bool,
+    /// Overwrite existing files directly instead of through a temp file
+    /// (`--in-place`, recv).
+    pub in_place: bool,
+    /// Flush each received file to disk before reporting it saved
+    /// (`--fsync`, recv).
+    pub fsync: bool,
+    /// Read each saved file back and check its checksum again
+    /// (`--verify-read`, recv).
+    pub verify_read: bool,
+    /// Link files with the same contents as one received before to it
+    /// (`--dedup`, `--dedup-reflink`, recv).
+    pub dedup: DedupMode,
+    /// Write into a FIFO or device found at the destination instead of
+    /// refusing the file (`--write-special`, recv).
+    pub write_special: bool,
+    /// Refuse entries that would land outside all of these directories
+    /// (`--allow-root`, recv).
+    pub allow_roots: Vec<PathBuf>,
+    /// Drop this many leading components from each name inside a received
+    /// directory (`--strip-components`, recv).
+    pub strip_components: usize,
+    /// Remove destination entries a received directory did not bring
+    /// (`--delete`, recv); `DryRun` only reports them.
+    pub delete: MirrorMode,
+    /// Refuse files larger than this many bytes (`--max-file-size`, recv).
+    pub max_file_size: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            recursive: false,
+            overwrite: OverwriteMode::No,
+            resume: false,
+            retries: DEFAULT_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            retry_backoff: 1.0,
+            mirror: MirrorMode::Off,
+            compress: None,
+            compress_shared: false,
+            verify_chunks: false,
+            framed: false,
+            parallel: 1,
+            batch: false,
+            checksum: ChecksumAlg::default(),
+            checksum_scope: ChecksumScope::default(),
+            checksum_cache: None,
+            limit: None,
+            limit_scope: LimitScope::File,
+            preserve: false,
+            xattrs: false,
+            skip_existing: false,
+            checksum_on_preflight: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            relative_to: None,
+            buffer_size: utils::DEFAULT_BUFFER_SIZE,
+            progress_interval: utils::DEFAULT_PROGRESS_INTERVAL,
+            timeout: Some(net::DEFAULT_TIMEOUT),
+            family: IpFamily::Any,
+            socket: SocketOptions::default(),
+            proxy: None,
+            manifest: None,
+            psk: None,
+            tls: false,
+            ca: None,
+            insecure: false,
+            cert: None,
+            key: None,
+            output_name: None,
+            mkdir: false,
+            into: false,
+            verify_only: false,
+            in_place: false,
+            fsync: false,
+            verify_read: false,
+            dedup: DedupMode::Off,
+            write_special: false,
+            allow_roots: Vec::new(),
+            strip_components: 0,
+            delete: MirrorMode::Off,
+            max_file_size: None,
+        }
+    }
+}
+
+/// What a finished transfer did, file by file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferReport {
+    /// In the order they were sent.
+    pub files: Vec<FileReport>,
+    /// Bytes of file data transferred; skipped files do not count.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReport {
+    /// The name the sender gave it, relative to the destination in a
+    /// directory transfer.
+    pub path: String,
+    pub size: u64,
+    /// Hex digest both sides verified the file against; empty if it was
+    /// skipped, sent with `ChecksumAlg::None` or verified as part of a tree
+    /// (`ChecksumScope::Tree`).
+    pub checksum: String,
+    /// The receiver declined it or already had it.
+    pub skipped: bool,
+}
+
+impl TransferReport {
+    fn from_summary(summary: &events::Summary) -> Self {
+        let files = summary
+            .files()
+            .iter()
+            .map(|file| FileReport {
+                path: file.path.clone(),
+                size: file.size,
+                checksum: file.checksum.clone(),
+                skipped: file.status != "ok",
+            })
+            .collect();
+        TransferReport {
+            files,
+            bytes: summary.total_bytes(),
+        }
+    }
+}
+
+/// Send the file or directory at `path` to the receiver at `addr`, given as
+/// `host:port` or `[ipv6]:port`.
+pub fn send_file(addr: &str, path: &Path, opts: &Options) -> Result<TransferReport> {
+    let (host, port) = net::split_host_port(addr)?;
+    let port = port.ok_or_else(|| format!("No port in address: {}", addr))?;
+    if port == 0 {
+        return Err(format!("Cannot connect to port 0: {}", addr).into());
+    }
+    let args = SendArgs {
+        host: Some(host),
+        port,
+        src: path.to_path_buf(),
+        recursive: opts.recursive,
+        retries: opts.retries,
+        retry_delay: opts.retry_delay,
+        retry_backoff: opts.retry_backoff,
+        overwrite: opts.overwrite,
+        listen: false,
+        accept_timeout: None,
+        mirror: opts.mirror,
+        format: WireFormat::Binary,
+        resume: opts.resume,
+        compress: opts.compress.is_some() || opts.compress_shared,
+        compress_level: opts.compress.unwrap_or(compress::ZSTD_LEVEL),
+        compress_shared: opts.compress_shared,
+        verify_chunks: opts.verify_chunks,
+        framed: opts.framed,
+        parallel: opts.parallel,
+        batch: opts.batch,
+        checksum: opts.checksum,
+        checksum_scope: opts.checksum_scope,
+        checksum_cache: ChecksumCache::default(),
+        timeout: opts.timeout,
+        limit: opts.limit,
+        limit_scope: opts.limit_scope,
+        preserve: opts.preserve,
+        xattrs: opts.xattrs,
+        skip_existing: opts.skip_existing,
+        checksum_on_preflight: opts.checksum_on_preflight,
+        dry_run: false,
+        buffer_size: opts.buffer_size,
+        progress_interval: opts.progress_interval,
+        family: opts.family,
+        bind: None,
+        socket: opts.socket,
+        proxy: opts.proxy.as_deref().map(Proxy::parse).transpose()?,
+        manifest: Manifest::default(),
+        filter: Filter {
+            include: opts.include.clone(),
+            exclude: opts.exclude.clone(),
+        },
+        relative_to: opts.relative_to.clone(),
+        files_from: None,
+        ignore_missing: false,
+        psk: opts.psk.clone(),
+        tls: tls::client(opts.tls, opts.ca.as_deref(), opts.insecure)?,
+    };
+    args.check()?;
+    let checksum_cache = match &opts.checksum_cache {
+        Some(path) => ChecksumCache::open(path)?,
+        None => ChecksumCache::default(),
+    };
+    let args = SendArgs {
+        manifest: open_manifest(opts)?,
+        checksum_cache,
+        ..args
+    };
+    Ok(TransferReport::from_summary(&send::execute(args)?))
+}
+
+/// Listen on `addr`, an IP address and port such as `0.0.0.0:9000` or
+/// `[::]:9000`, and receive one transfer into `dst`.
+pub fn receive(addr: &str, dst: &Path, opts: &Options) -> Result<TransferReport> {
+    let addr = net::parse_bind(addr)?;
+    if opts.buffer_size == 0 {
+        return Err("--buffer-size must be greater than 0".into());
+    }
+    let args = RecvArgs {
+        host: None,
+        port: addr.port(),
+        dst: dst.to_path_buf(),
+        overwrite: opts.overwrite,
+        resume: opts.resume,
+        timeout: opts.timeout,
+        keep_alive: false,
+        buffer_size: opts.buffer_size,
+        progress_interval: opts.progress_interval,
+        family: opts.family,
+        socket: opts.socket,
+        output_name: opts.output_name.clone(),
+        mkdir: opts.mkdir,
+        into: opts.into,
+        verify_only: opts.verify_only,
+        in_place: opts.in_place,
+        fsync: opts.fsync,
+        verify_read: opts.verify_read,
+        dedup: opts.dedup,
+        write_special: opts.write_special,
+        allow_roots: opts.allow_roots.clone(),
+        strip_components: opts.strip_components,
+        max_file_size: opts.max_file_size,
+        delete: opts.delete,
+        pull: None,
+        manifest: open_manifest(opts)?,
+        psk: opts.psk.clone(),
+        tls: tls::server(opts.tls, opts.cert.as_deref(), opts.key.as_deref())?,
+    };
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| format!("Cannot listen on {}: {}", addr, e))?;
+    Ok(TransferReport::from_summary(&recv::receive_on(&listener, &args)?))
+}
+
+fn open_manifest(opts: &Options) -> Result<Manifest> {
+    match &opts.manifest {
+        Some(path) => Manifest::create(path),
+        None => Ok(Manifest::default()),
+    }
+}
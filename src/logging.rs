@@ -0,0 +1,207 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::types::Result;
+
+/// Global verbosity level: 0 = normal, 1 = `-v`, 2 = `-vv`.
+pub static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+/// Set by `-q`: status lines, progress and `vlog!` output are dropped, and
+/// only errors and warnings reach stderr.
+pub static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Set by `recv -`: stdout carries the received file, so status lines and
+/// progress go to stderr instead.
+pub static STDOUT_DATA: AtomicBool = AtomicBool::new(false);
+
+pub fn set_stdout_data(data: bool) {
+    STDOUT_DATA.store(data, Ordering::Relaxed);
+}
+
+pub fn stdout_is_data() -> bool {
+    STDOUT_DATA.load(Ordering::Relaxed)
+}
+
+/// The `-v` level, or 0 when quiet wins.
+pub fn verbosity() -> u8 {
+    if quiet() {
+        return 0;
+    }
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// The verbosity `NCP_LOG` asks for: `info` or `1` like `-v`, `debug` or
+/// `2` like `-vv`, `0` or `off` for neither. `None` if unset or unknown.
+pub fn parse_env_verbosity(value: &str) -> Option<u8> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "0" | "off" | "" => Some(0),
+        "1" | "info" => Some(1),
+        "2" | "debug" => Some(2),
+        _ => None,
+    }
+}
+
+/// Where `--log-file` copies every log line.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Append log lines to `path` as well as stderr from now on.
+pub fn set_log_file(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Cannot open log file {}: {}", path.display(), e))?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// Write one log line to stderr and the log file: a UTC timestamp, the
+/// level and, with `-vv`, the module it came from.
+pub fn log(level: &str, module: &str, args: fmt::Arguments) {
+    let line = format_line(SystemTime::now(), level, module, args);
+    eprintln!("{}", line);
+    to_file(&line);
+}
+
+/// Record `message` in the log file only, for what already went to stderr
+/// in another form, like the final error.
+pub fn log_to_file(level: &str, message: &str) {
+    to_file(&format_line(SystemTime::now(), level, "", format_args!("{}", message)));
+}
+
+fn to_file(line: &str) {
+    let mut file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(file) = file.as_mut() {
+        // Logging must never fail the transfer; a full disk shows elsewhere.
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+fn format_line(now: SystemTime, level: &str, module: &str, args: fmt::Arguments) -> String {
+    if module.is_empty() || VERBOSITY.load(Ordering::Relaxed) < 2 {
+        format!("{} [{}] {}", timestamp(now), level, args)
+    } else {
+        format!("{} [{}] {}: {}", timestamp(now), level, module, args)
+    }
+}
+
+/// `now` as RFC 3339 in UTC with milliseconds, e.g. `2024-05-01T09:30:00.250Z`.
+fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The proleptic Gregorian date `days` after 1970-01-01 (Howard Hinnant's
+/// algorithm).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Log to stderr when running with `-v` or higher.
+macro_rules! vlog {
+    ($($arg:tt)*) => {
+        if $crate::logging::verbosity() >= 1 {
+            $crate::logging::log("INFO", module_path!(), format_args!($($arg)*));
+        }
+    };
+}
+
+/// Log to stderr when running with `-vv`.
+macro_rules! vvlog {
+    ($($arg:tt)*) => {
+        if $crate::logging::verbosity() >= 2 {
+            $crate::logging::log("DEBUG", module_path!(), format_args!($($arg)*));
+        }
+    };
+}
+
+/// User-facing status line. Goes to stdout, or to stderr while stdout is
+/// carrying the `--json` event stream or file data; nowhere with `-q`.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::logging::quiet() {
+        } else if $crate::events::json_enabled() || $crate::logging::stdout_is_data() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_log_lines_carry_a_parseable_timestamp() {
+        let when = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        let line = format_line(when, "INFO", "", format_args!("Sent {} files", 3));
+        assert_eq!(line, "2024-02-29T12:34:56.789Z [INFO] Sent 3 files");
+
+        let line = format_line(SystemTime::now(), "DEBUG", "", format_args!("x"));
+        let (stamp, rest) = line.split_once(' ').unwrap();
+        assert_eq!(rest, "[DEBUG] x");
+        let fields: Vec<u64> = stamp
+            .trim_end_matches('Z')
+            .split(['-', 'T', ':', '.'])
+            .map(|f| f.parse().unwrap())
+            .collect();
+        assert_eq!(fields.len(), 7, "{}", stamp);
+        assert!(fields[0] >= 2024 && (1..=12).contains(&fields[1]) && (1..=31).contains(&fields[2]));
+        assert!(fields[3] < 24 && fields[4] < 60 && fields[5] < 60 && fields[6] < 1000);
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn test_env_verbosity() {
+        assert_eq!(parse_env_verbosity("debug"), Some(2));
+        assert_eq!(parse_env_verbosity("INFO"), Some(1));
+        assert_eq!(parse_env_verbosity("off"), Some(0));
+        assert_eq!(parse_env_verbosity("loud"), None);
+    }
+}
@@ -1,2 +1,7 @@
-fn main() -> io::Result<()> {
+use std::env;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    ncp::cli::run(&args)
 }
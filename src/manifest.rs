@@ -0,0 +1,105 @@
+//! `--manifest`: a JSON Lines record of every file a transfer handles.
+//!
+//! One object per file, written as soon as the file is finished with:
+//! `path` (the name the sender gave it, relative to the destination in a
+//! directory transfer), `size` (`null` for standard input), `status` (`ok`,
+//! `skipped` or `failed`) and then `checksum` for `ok` or `reason`
+//! otherwise. The receiver adds `saved_as`, where the file ended up on disk.
+//! Since both sides use the same `path`, their manifests can be compared
+//! line for line.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::json::Json;
+use crate::protocol::UNKNOWN_SIZE;
+use crate::types::Result;
+
+/// Where records go; the default records nothing.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    out: Option<Mutex<File>>,
+}
+
+impl Manifest {
+    /// Start a new manifest at `path`, replacing any file already there.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .map_err(|e| format!("Cannot create manifest {}: {}", path.display(), e))?;
+        Ok(Manifest {
+            out: Some(Mutex::new(file)),
+        })
+    }
+
+    pub fn done(
+        &self,
+        path: &str,
+        size: u64,
+        checksum: &str,
+        saved_as: Option<&Path>,
+    ) -> io::Result<()> {
+        let mut fields = vec![("checksum", Json::str(checksum))];
+        if let Some(saved_as) = saved_as {
+            fields.push(("saved_as", Json::str(&saved_as.display().to_string())));
+        }
+        self.record(path, size, "ok", fields)
+    }
+
+    pub fn skipped(&self, path: &str, size: u64, reason: &str) -> io::Result<()> {
+        self.record(path, size, "skipped", vec![("reason", Json::str(reason))])
+    }
+
+    pub fn failed(&self, path: &str, size: u64, reason: &str) -> io::Result<()> {
+        self.record(path, size, "failed", vec![("reason", Json::str(reason))])
+    }
+
+    fn record(&self, path: &str, size: u64, status: &str, extra: Vec<(&str, Json)>) -> io::Result<()> {
+        let Some(out) = &self.out else {
+            return Ok(());
+        };
+        let size = if size == UNKNOWN_SIZE { Json::Null } else { Json::u64(size) };
+        let mut fields = vec![
+            ("path".to_string(), Json::str(path)),
+            ("size".to_string(), size),
+            ("status".to_string(), Json::str(status)),
+        ];
+        fields.extend(extra.into_iter().map(|(key, value)| (key.to_string(), value)));
+        let mut line = Json::Object(fields).to_string();
+        line.push('\n');
+
+        // One unbuffered write per record, so every finished entry is with
+        // the OS even if ncp dies straight after.
+        let mut out = out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(line.as_bytes())?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_are_json_lines() {
+        let path = std::env::temp_dir().join(format!("ncp-manifest-{}", std::process::id()));
+        let manifest = Manifest::create(&path).unwrap();
+        manifest.done("a.txt", 5, "abcd", Some(Path::new("/tmp/a.txt"))).unwrap();
+        manifest.skipped("b.txt", 7, "Destination file already exists").unwrap();
+        manifest.failed("stdin", UNKNOWN_SIZE, "Connection reset").unwrap();
+        Manifest::default().done("ignored", 1, "", None).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Json> = text.lines().map(|l| crate::json::parse(l).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].get("path").and_then(Json::as_str), Some("a.txt"));
+        assert_eq!(lines[0].get("size").and_then(Json::as_u64), Some(5));
+        assert_eq!(lines[0].get("saved_as").and_then(Json::as_str), Some("/tmp/a.txt"));
+        assert_eq!(lines[1].get("status").and_then(Json::as_str), Some("skipped"));
+        assert!(matches!(lines[2].get("size"), Some(Json::Null)));
+        assert_eq!(lines[2].get("status").and_then(Json::as_str), Some("failed"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,628 @@
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream, ToSocketAddrs,
+};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, SockRef, Type};
+
+use crate::tls::TlsStream;
+use crate::types::{NcpError, Result};
+
+/// Applied when `--timeout` is not given. Long enough that a slow but
+/// healthy peer is never cut off, short enough that a dead one is noticed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Address family restriction selected with `-4` / `-6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            IpFamily::Any => "IP",
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+        }
+    }
+
+    /// Wildcard address to listen on.
+    pub fn unspecified(self) -> IpAddr {
+        match self {
+            IpFamily::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+}
+
+/// Parse a port number, or say why `value` is not one: it is either not a
+/// number at all or outside 0-65535.
+pub fn parse_port(value: &str) -> std::result::Result<u16, &'static str> {
+    use std::num::IntErrorKind;
+
+    value.parse().map_err(|e: std::num::ParseIntError| match e.kind() {
+        IntErrorKind::Empty => "no port given",
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => "out of range 0-65535",
+        // Still a number, just a negative one.
+        _ if value.parse::<i64>().is_ok() => "out of range 0-65535",
+        _ => "not a number",
+    })
+}
+
+/// Split a `--host` value into a host and an optional port. Accepts names
+/// and IPv4 addresses with or without `:port`, bare IPv6 addresses (which
+/// cannot carry a port) and bracketed IPv6 such as `[::1]:9000`. Brackets
+/// are removed; a zone such as `%eth0` is kept for `resolve`.
+pub fn split_host_port(value: &str) -> Result<(String, Option<u16>)> {
+    let parse_port = |p: &str| -> Result<u16> {
+        parse_port(p).map_err(|why| format!("Invalid port in host {}: {}", value, why).into())
+    };
+
+    if let Some(rest) = value.strip_prefix('[') {
+        let (host, after) = rest
+            .split_once(']')
+            .ok_or_else(|| format!("Unclosed '[' in host: {}", value))?;
+        return match after {
+            "" => Ok((host.to_string(), None)),
+            _ => match after.strip_prefix(':') {
+                Some(port) => Ok((host.to_string(), Some(parse_port(port)?))),
+                None => Err(format!("Unexpected text after ']' in host: {}", value).into()),
+            },
+        };
+    }
+
+    match value.split_once(':') {
+        Some((host, port)) if !port.contains(':') => {
+            Ok((host.to_string(), Some(parse_port(port)?)))
+        }
+        _ => Ok((value.to_string(), None)),
+    }
+}
+
+/// An IPv6 literal with a zone, e.g. `fe80::1%eth0` or `fe80::1%2`. The
+/// standard resolver only understands numeric zones, so names are looked up
+/// here.
+fn parse_scoped(host: &str, port: u16) -> Option<Result<SocketAddr>> {
+    let (addr, zone) = host.split_once('%')?;
+    let addr: Ipv6Addr = addr.parse().ok()?;
+    let scope = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => match interface_index(zone) {
+            Some(index) => index,
+            None => return Some(Err(format!("Unknown network interface: {}", zone).into())),
+        },
+    };
+    Some(Ok(SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope))))
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// All addresses for `host:port` in resolver order, limited to `family`.
+pub fn resolve(host: &str, port: u16, family: IpFamily) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = match parse_scoped(host, port) {
+        Some(addr) => vec![addr?],
+        None => (host, port).to_socket_addrs()?.collect(),
+    };
+    let allowed: Vec<SocketAddr> = addrs.into_iter().filter(|a| family.allows(a)).collect();
+    if allowed.is_empty() {
+        return Err(format!("{} has no {} address", host, family.name()).into());
+    }
+    Ok(allowed)
+}
+
+/// Parse a `--bind` value: an IP address, optionally with a port as in
+/// `10.0.0.5:4000` or `[fe80::1%eth0]:4000`. Port 0 lets the OS pick one.
+pub fn parse_bind(value: &str) -> Result<SocketAddr> {
+    let invalid = || format!("Invalid bind address: {}", value);
+    let (host, port) = split_host_port(value).map_err(|_| invalid())?;
+    let port = port.unwrap_or(0);
+    match parse_scoped(&host, port) {
+        Some(addr) => addr.map_err(|e| e.to_string().into()),
+        None => {
+            let ip: IpAddr = host.parse().map_err(|_| invalid())?;
+            Ok(SocketAddr::new(ip, port))
+        }
+    }
+}
+
+/// How long a connection attempt has to itself before the next address is
+/// tried alongside it; the "Connection Attempt Delay" of RFC 8305.
+const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+
+/// Connect to `host:port`, racing the resolved addresses as `race` does and
+/// giving each one at most `timeout` (the OS default if `None`). With
+/// `bind`, the connection leaves from that local address, so only addresses
+/// of its family are tried.
+pub fn connect(
+    host: &str,
+    port: u16,
+    family: IpFamily,
+    timeout: Option<Duration>,
+    bind: Option<SocketAddr>,
+) -> Result<TcpStream> {
+    let mut addrs = resolve(host, port, family)?;
+    if let Some(local) = bind {
+        addrs.retain(|addr| addr.is_ipv4() == local.is_ipv4());
+        if addrs.is_empty() {
+            return Err(format!("{} has no address reachable from --bind {}", host, local).into());
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err(format!("Could not resolve {}", host).into());
+    }
+
+    let attempt = move |addr| match (bind, timeout) {
+        (Some(local), timeout) => connect_from(local, addr, timeout),
+        (None, Some(timeout)) => TcpStream::connect_timeout(&addr, timeout),
+        (None, None) => TcpStream::connect(addr),
+    };
+    let raced = race(interleave(addrs), CONNECT_STAGGER, attempt);
+    let (addr, stream) = raced.map_err(|e| describe(e.into()))?;
+    vvlog!("Connected to {}", addr);
+    configure(&stream, timeout)?;
+    Ok(stream)
+}
+
+/// Order `addrs` to alternate between IPv6 and IPv4, starting with the
+/// family the resolver put first, so that a family that is unreachable
+/// delays the other by one stagger at most.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_v6 = first.is_ipv6();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Happy Eyeballs (RFC 8305): connect to the first of `addrs` to answer.
+/// Each attempt runs on its own thread; the next one starts as soon as one
+/// fails, or once `stagger` passes without an answer, while the earlier
+/// ones carry on. Attempts still running when one wins cannot be stopped,
+/// so they are left to finish, and whatever they connect is closed.
+fn race<F>(addrs: Vec<SocketAddr>, stagger: Duration, attempt: F) -> io::Result<(SocketAddr, TcpStream)>
+where
+    F: Fn(SocketAddr) -> io::Result<TcpStream> + Send + Sync + 'static,
+{
+    let attempt = Arc::new(attempt);
+    let (done, results) = mpsc::channel();
+    let mut pending = addrs.into_iter();
+    let mut running = 0;
+    let mut last_err = None;
+    let mut start_next = true;
+
+    loop {
+        if start_next && let Some(addr) = pending.next() {
+            vvlog!("Trying {}", addr);
+            let (attempt, done) = (Arc::clone(&attempt), done.clone());
+            thread::spawn(move || {
+                let _ = done.send((addr, attempt(addr)));
+            });
+            running += 1;
+        }
+        if running == 0 {
+            break;
+        }
+        match results.recv_timeout(stagger) {
+            Ok((addr, Ok(stream))) => return Ok((addr, stream)),
+            Ok((addr, Err(e))) => {
+                vvlog!("Cannot connect to {}: {}", addr, e);
+                running -= 1;
+                last_err = Some(e);
+                start_next = true;
+            }
+            // Nobody hangs up while `done` is held here.
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => start_next = true,
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")))
+}
+
+/// Bind a socket to `local`, then connect it to `addr`. `TcpStream` can only
+/// connect from an address the OS picks.
+fn connect_from(
+    local: SocketAddr,
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> io::Result<TcpStream> {
+    let socket = socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&local.into()).map_err(|err| {
+        io::Error::new(err.kind(), format!("Cannot bind to {}: {}", local, err))
+    })?;
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+        None => socket.connect(&addr.into())?,
+    }
+    Ok(socket.into())
+}
+
+/// Ports below this need root, or `CAP_NET_BIND_SERVICE` on Linux.
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// Listen on `port` on every address of `family`. Port 0 lets the OS pick
+/// a free one; the port actually bound is returned with the listener.
+pub fn listen(family: IpFamily, port: u16) -> Result<(TcpListener, u16)> {
+    let listener = TcpListener::bind((family.unspecified(), port)).map_err(|e| {
+        if e.kind() == io::ErrorKind::PermissionDenied && (1..FIRST_UNPRIVILEGED_PORT).contains(&port) {
+            let hint = "ports below 1024 need root; pick a higher --port";
+            return NcpError::from(format!("Cannot listen on port {}: {} ({})", port, e, hint));
+        }
+        NcpError::from(e)
+    })?;
+    let port = listener.local_addr()?.port();
+    Ok((listener, port))
+}
+
+/// How often a listener waiting with a timeout checks for a connection.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// Wait for one connection on `listener`, giving up after `timeout` (never
+/// if `None`).
+pub fn accept(listener: &TcpListener, timeout: Option<Duration>) -> Result<(TcpStream, SocketAddr)> {
+    let Some(timeout) = timeout else {
+        return Ok(listener.accept()?);
+    };
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                // Some platforms hand the listener's mode down to the stream.
+                stream.set_nonblocking(false)?;
+                listener.set_nonblocking(false)?;
+                return Ok((stream, peer));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    let secs = timeout.as_secs_f64();
+                    return Err(format!("Nobody connected within {}s (--accept-timeout)", secs).into());
+                }
+                thread::sleep(ACCEPT_POLL);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// A connection as the transfer sees it: the bytes of the socket, or of
+/// the TLS session over it (`--tls`).
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    /// Another handle on the same connection, for a thread that writes to
+    /// it while this one does not.
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Plain(stream) => stream.try_clone().map(Stream::Plain),
+            Stream::Tls(stream) => stream.try_clone().map(Stream::Tls),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Plain(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// What has a socket to set up and name: a bare `TcpStream`, or a
+/// `Stream` whose socket may be beneath TLS.
+pub trait Socket {
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Socket for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl Socket for Stream {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.socket(),
+        }
+    }
+}
+
+/// The address at the other end of `stream`, for messages.
+pub fn peer_name(stream: &impl Socket) -> String {
+    stream
+        .socket()
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown peer".to_string())
+}
+
+/// Bound every read and write on `stream` by `timeout`.
+pub fn configure(stream: &impl Socket, timeout: Option<Duration>) -> io::Result<()> {
+    stream.socket().set_read_timeout(timeout)?;
+    stream.socket().set_write_timeout(timeout)
+}
+
+/// How each connection's socket is set up (`--no-nodelay`, `--sndbuf`,
+/// `--rcvbuf`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small writes at once (`TCP_NODELAY`). Control messages are
+    /// small and each waits for its answer, so Nagle's algorithm would
+    /// only hold them back.
+    pub nodelay: bool,
+    /// Kernel send buffer size in bytes (`SO_SNDBUF`); `None` keeps the
+    /// system's.
+    pub sndbuf: Option<usize>,
+    /// Kernel receive buffer size in bytes (`SO_RCVBUF`); `None` keeps the
+    /// system's.
+    pub rcvbuf: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions { nodelay: true, sndbuf: None, rcvbuf: None }
+    }
+}
+
+/// Apply `options` to a connected `stream`. The kernel may round a buffer
+/// size, Linux doubles it, and one set after connecting no longer changes
+/// the window scale agreed on, so a large `rcvbuf` reaches its full effect
+/// only with the system's autotuning limits raised as well.
+pub fn tune(stream: &impl Socket, options: &SocketOptions) -> io::Result<()> {
+    let stream = stream.socket();
+    stream.set_nodelay(options.nodelay)?;
+    let socket = SockRef::from(stream);
+    if let Some(size) = options.sndbuf {
+        socket.set_send_buffer_size(buffer_size(size)?)?;
+    }
+    if let Some(size) = options.rcvbuf {
+        socket.set_recv_buffer_size(buffer_size(size)?)?;
+    }
+    Ok(())
+}
+
+/// The largest buffer size a socket takes: the C API's is an `int`.
+pub const MAX_SOCKET_BUFFER: usize = i32::MAX as usize;
+
+fn buffer_size(size: usize) -> io::Result<usize> {
+    if size > MAX_SOCKET_BUFFER {
+        let why = format!("socket buffer size {} is over {}", size, MAX_SOCKET_BUFFER);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, why));
+    }
+    Ok(size)
+}
+
+
+/// Replace the platform's wording for a socket timeout ("Resource
+/// temporarily unavailable" on Unix) with one that says what happened.
+pub fn describe(err: NcpError) -> NcpError {
+    match err {
+        NcpError::Io(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            let message = "Timed out waiting for the peer (see --timeout)";
+            NcpError::Io(io::Error::new(io::ErrorKind::TimedOut, message))
+        }
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_read_timeout_is_described() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let timeout = Some(Duration::from_millis(50));
+        let mut stream = connect("127.0.0.1", port, IpFamily::Any, timeout, None).unwrap();
+        let _peer = listener.accept().unwrap();
+
+        let err = stream.read(&mut [0u8; 1]).unwrap_err();
+        assert!(describe(err.into()).to_string().starts_with("Timed out"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bound_connect_reaches_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let bind = Some(parse_bind("127.0.0.1").unwrap());
+        for timeout in [Some(Duration::from_secs(5)), None] {
+            let stream = connect("127.0.0.1", port, IpFamily::Any, timeout, bind).unwrap();
+            let (_peer, from) = listener.accept().unwrap();
+            assert_eq!(from, stream.local_addr().unwrap());
+            assert_eq!(from.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+
+        // An IPv6 source cannot reach an IPv4 destination.
+        let v6 = Some(parse_bind("::1").unwrap());
+        assert!(connect("127.0.0.1", port, IpFamily::Any, None, v6).is_err());
+    }
+
+    #[test]
+    fn test_parse_bind() {
+        assert_eq!(parse_bind("10.0.0.5").unwrap(), "10.0.0.5:0".parse().unwrap());
+        assert_eq!(parse_bind("10.0.0.5:4000").unwrap(), "10.0.0.5:4000".parse().unwrap());
+        assert_eq!(parse_bind("[::1]:4000").unwrap(), "[::1]:4000".parse().unwrap());
+        assert!(parse_bind("example.com").is_err());
+        assert!(parse_bind("10.0.0.5:port").is_err());
+    }
+
+    #[test]
+    fn test_tune_sets_nodelay_and_buffers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        tune(&stream, &SocketOptions::default()).unwrap();
+        assert!(stream.nodelay().unwrap());
+
+        let options = SocketOptions { nodelay: false, sndbuf: Some(64 * 1024), rcvbuf: Some(64 * 1024) };
+        tune(&stream, &options).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+
+        let options = SocketOptions { sndbuf: Some(MAX_SOCKET_BUFFER + 1), ..options };
+        assert!(tune(&stream, &options).is_err());
+    }
+
+    #[test]
+    fn test_listen_on_ephemeral_port() {
+        let (listener, port) = listen(IpFamily::V4, 0).unwrap();
+        assert_ne!(port, 0);
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        let split = |v: &str| split_host_port(v).unwrap();
+        assert_eq!(split("example.com"), ("example.com".to_string(), None));
+        assert_eq!(split("10.0.0.1:9000"), ("10.0.0.1".to_string(), Some(9000)));
+        assert_eq!(split("::1"), ("::1".to_string(), None));
+        assert_eq!(split("[::1]"), ("::1".to_string(), None));
+        assert_eq!(split("[::1]:9000"), ("::1".to_string(), Some(9000)));
+        assert_eq!(split("[fe80::1%eth0]:22"), ("fe80::1%eth0".to_string(), Some(22)));
+
+        assert!(split_host_port("[::1").is_err());
+        assert!(split_host_port("[::1]9000").is_err());
+        assert!(split_host_port("[::1]:port").is_err());
+    }
+
+    #[test]
+    fn test_port_errors_say_what_is_wrong() {
+        assert_eq!(parse_port("9000"), Ok(9000));
+        assert_eq!(parse_port("0"), Ok(0));
+        assert_eq!(parse_port("65536"), Err("out of range 0-65535"));
+        assert_eq!(parse_port("99999999999999999999"), Err("out of range 0-65535"));
+        assert_eq!(parse_port("-1"), Err("out of range 0-65535"));
+        assert_eq!(parse_port("http"), Err("not a number"));
+        assert_eq!(parse_port("90 00"), Err("not a number"));
+        assert_eq!(parse_port(""), Err("no port given"));
+
+        let err = split_host_port("example.com:70000").unwrap_err().to_string();
+        assert_eq!(err, "Invalid port in host example.com:70000: out of range 0-65535");
+    }
+
+    #[test]
+    fn test_addresses_alternate_between_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave(addrs).iter().map(|a| a.to_string()).collect();
+        assert_eq!(ordered, ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+    }
+
+    #[test]
+    fn test_race_connects_past_a_stalled_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // Stands in for a route that drops IPv6 silently.
+        let stalled: SocketAddr = "[2001:db8::1]:9".parse().unwrap();
+        let attempt = move |addr: SocketAddr| {
+            if addr == stalled {
+                thread::sleep(Duration::from_secs(3));
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            TcpStream::connect(addr)
+        };
+
+        let started = Instant::now();
+        let stagger = Duration::from_millis(100);
+        let (addr, stream) = race(vec![stalled, reachable], stagger, attempt).unwrap();
+        assert_eq!(addr, reachable);
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+
+        // An address that refuses at once hands over without waiting out the stagger.
+        let refused = {
+            let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap()
+        };
+        let started = Instant::now();
+        let patient = Duration::from_secs(5);
+        let (addr, _) = race(vec![refused, reachable], patient, TcpStream::connect).unwrap();
+        assert_eq!(addr, reachable);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+
+        let err = race(vec![refused], stagger, TcpStream::connect).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_resolve_family_and_zone() {
+        let v6 = resolve("::1", 9000, IpFamily::V6).unwrap();
+        assert_eq!(v6, vec!["[::1]:9000".parse().unwrap()]);
+        assert!(resolve("::1", 9000, IpFamily::V4).is_err());
+        assert!(resolve("127.0.0.1", 9000, IpFamily::V6).is_err());
+
+        match resolve("fe80::1%3", 22, IpFamily::Any).unwrap()[0] {
+            SocketAddr::V6(addr) => assert_eq!(addr.scope_id(), 3),
+            other => panic!("unexpected {}", other),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! `--parallel`: one large file split over several connections, for links
+//! where a single TCP stream cannot fill the pipe.
+//!
+//! After the usual preflight on the main connection, the sender announces
+//! the number of streams in `TransferStart` and the file is cut into that
+//! many contiguous ranges (`split`). The first goes over the main
+//! connection; for each other one the sender opens a new connection, runs
+//! the handshake with the same session ID, and sends a `Range` naming it
+//! before its raw bytes. The receiver writes every range at its offset in
+//! the temp file as it arrives, answers each extra connection with a
+//! `TransferResult`, and once all are in, hashes the reassembled file to
+//! check it against the sender's trailer on the main connection.
+//!
+//! Only a receiver that is listening for a single transfer can accept the
+//! extra connections; it says so with `CAPABILITY` in the handshake, and a
+//! sender that does not see it sends the file over one connection.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::events;
+use crate::progress::ProgressReporter;
+use crate::retry;
+use crate::types::Result;
+use crate::utils::{ProgressTicker, Throttle};
+
+/// Listed in `handshake::CAPABILITIES` by a receiver that takes extra
+/// connections.
+pub const CAPABILITY: &str = "parallel";
+
+/// Most connections one file is split over.
+pub const MAX_STREAMS: u32 = 16;
+
+/// Smallest range worth a connection of its own.
+const MIN_RANGE: u64 = 1024 * 1024;
+
+/// How often the progress of a split file is redrawn at most.
+const POLL: Duration = Duration::from_millis(50);
+
+/// How many of `requested` streams a file of `size` bytes is split over:
+/// none gets less than `MIN_RANGE`.
+pub fn streams_for(requested: u32, size: u64) -> u32 {
+    let most = (size / MIN_RANGE).clamp(1, MAX_STREAMS as u64) as u32;
+    requested.clamp(1, most)
+}
+
+/// The ranges `size` bytes are cut into for `streams` connections, in
+/// order; the first ones take any remainder.
+pub fn split(size: u64, streams: u32) -> Vec<Range<u64>> {
+    let streams = streams.max(1) as u64;
+    let (share, extra) = (size / streams, size % streams);
+    let mut start = 0;
+    (0..streams)
+        .map(|i| {
+            let end = start + share + u64::from(i < extra);
+            let range = start..end;
+            start = end;
+            range
+        })
+        .collect()
+}
+
+/// Send the bytes of `range` from the file at `path` to `out`, adding them
+/// to `sent` as they go, at most `limit` bytes per second.
+pub fn send_range<W: Write>(
+    out: &mut W,
+    path: &Path,
+    range: Range<u64>,
+    buffer_size: usize,
+    limit: Option<u64>,
+    sent: &AtomicU64,
+) -> Result<()> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut reader = file.take(range.end - range.start);
+    let mut buffer = vec![0u8; buffer_size];
+    let mut throttle = limit.map(Throttle::new);
+    let mut copied = 0u64;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        out.write_all(&buffer[..n])?;
+        copied += n as u64;
+        sent.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.sent(n as u64);
+        }
+    }
+    out.flush()?;
+    if copied != range.end - range.start {
+        return Err(format!("File size changed during transfer: {} ended early", path.display()).into());
+    }
+    Ok(())
+}
+
+/// Read the bytes of `range` from `input` into `file` at their offset,
+/// adding them to `received` as they arrive.
+pub fn receive_range<R: Read>(
+    input: &mut R,
+    file: &File,
+    range: Range<u64>,
+    buffer_size: usize,
+    received: &AtomicU64,
+) -> Result<()> {
+    let mut buffer = vec![0u8; buffer_size];
+    let mut offset = range.start;
+    while offset < range.end {
+        let want = buffer.len().min((range.end - offset) as usize);
+        let n = input.read(&mut buffer[..want])?;
+        if n == 0 {
+            let reason = format!(
+                "Connection closed unexpectedly: received {} of the {} bytes from {}",
+                offset - range.start,
+                range.end - range.start,
+                range.start
+            );
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, reason).into());
+        }
+        // At an offset, a retry writes the same bytes to the same place.
+        retry::transient("Write", || write_all_at(file, &buffer[..n], offset))?;
+        offset += n as u64;
+        received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Write `buf` at `offset` without moving the cursor other threads share.
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// Report how much of `name`, `size` bytes over every stream, is `done`
+/// until `finished` says all the streams are.
+pub fn show_progress(
+    reporter: &mut dyn ProgressReporter,
+    name: &str,
+    size: u64,
+    done: &AtomicU64,
+    interval: Duration,
+    finished: impl Fn() -> bool,
+) -> io::Result<()> {
+    let mut progress = ProgressTicker::new(interval);
+    reporter.on_start(0, size);
+    while !finished() {
+        if progress.tick() {
+            let done = done.load(Ordering::Relaxed);
+            events::file_progress(name, done, size);
+            reporter.on_progress(done, size)?;
+        }
+        thread::sleep(POLL);
+    }
+    reporter.on_file_done(name, done.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_covers_the_file_in_order() {
+        assert_eq!(split(10, 3), vec![0..4, 4..7, 7..10]);
+        assert_eq!(split(8 << 20, 4)[3], (6 << 20)..(8 << 20));
+        assert_eq!(split(0, 1), vec![0..0]);
+
+        assert_eq!(streams_for(4, 10 * MIN_RANGE), 4);
+        assert_eq!(streams_for(4, 2 * MIN_RANGE + 1), 2);
+        assert_eq!(streams_for(4, 100), 1);
+        assert_eq!(streams_for(64, u64::MAX), MAX_STREAMS);
+    }
+}
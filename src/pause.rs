@@ -0,0 +1,132 @@
+//! Pausing a send from the terminal: `p` stops the flow of file data and
+//! `r` lets it go on, without tearing down the connection.
+//!
+//! A thread reads keys from stdin, which is switched out of line mode
+//! (and echo) so that a key takes effect without Enter, and sets a flag
+//! the transfer checks between chunks. Paused between files, the sender
+//! pings the receiver (see `keepalive`), so a pause can last as long as it
+//! likes. Paused in the middle of a file there is no way to ping: the
+//! receiver is reading file data, and gives up after its own `--timeout`
+//! of silence, so a long pause needs a receiver with a long or no
+//! `--timeout`.
+//!
+//! Only used by `ncp send` in the foreground of a terminal, and not when
+//! stdin is the data being sent.
+
+use std::io::{self, IsTerminal, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Set while the user has the transfer paused.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// How often a paused transfer looks whether it may go on.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Watch stdin for `p` and `r` for the rest of the process, if it is the
+/// terminal the process runs in the foreground of. Undo the terminal
+/// changes with `restore`.
+pub fn watch() {
+    if !io::stdin().is_terminal() || !platform::foreground() {
+        return;
+    }
+    platform::key_mode();
+    thread::spawn(|| {
+        let mut key = [0u8; 1];
+        while let Ok(1) = io::stdin().lock().read(&mut key) {
+            match key[0].to_ascii_lowercase() {
+                b'p' => PAUSED.store(true, Ordering::Relaxed),
+                b'r' => PAUSED.store(false, Ordering::Relaxed),
+                _ => {}
+            }
+        }
+    });
+    vlog!("Press p to pause the transfer and r to resume it");
+}
+
+/// Put the terminal back the way `watch` found it.
+pub fn restore() {
+    platform::restore();
+}
+
+/// Whether the user asked for a pause; cheap enough for every chunk.
+pub fn requested() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Block until the user resumes. Whoever calls this keeps the connection
+/// alive meanwhile, if it can.
+pub fn hold() {
+    status!("Paused; press r to resume");
+    while requested() {
+        thread::sleep(POLL);
+    }
+    status!("Resumed");
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::sync::Mutex;
+
+    /// The terminal settings before `key_mode`.
+    static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+    /// A background job that reads the terminal is stopped by the shell.
+    pub fn foreground() -> bool {
+        unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+    }
+
+    /// Deliver keys as they are pressed, without echoing them. Ctrl-C
+    /// still raises SIGINT.
+    pub fn key_mode() {
+        unsafe {
+            let mut settings: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut settings) != 0 {
+                return;
+            }
+            let saved = settings;
+            settings.c_lflag &= !(libc::ICANON | libc::ECHO);
+            settings.c_cc[libc::VMIN] = 1;
+            settings.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &settings) == 0 {
+                *SAVED.lock().unwrap_or_else(|e| e.into_inner()) = Some(saved);
+            }
+        }
+    }
+
+    pub fn restore() {
+        if let Some(saved) = SAVED.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+        }
+    }
+}
+
+/// Elsewhere the terminal stays in line mode: `p` or `r` then Enter.
+#[cfg(not(unix))]
+mod platform {
+    pub fn foreground() -> bool {
+        true
+    }
+
+    pub fn key_mode() {}
+
+    pub fn restore() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_lasts_until_resumed() {
+        PAUSED.store(true, Ordering::Relaxed);
+        let held = thread::spawn(hold);
+        thread::sleep(POLL * 3);
+        assert!(!held.is_finished());
+
+        PAUSED.store(false, Ordering::Relaxed);
+        held.join().unwrap();
+        assert!(!requested());
+    }
+}
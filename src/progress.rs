@@ -0,0 +1,105 @@
+//! Where the progress of file data goes while it is copied.
+//!
+//! The copy loops of both sides report to a `ProgressReporter`: every file's
+//! data starts, moves on at most every `--progress-interval`, and ends, and
+//! then the whole transfer does. `Terminal` draws the line of each file,
+//! and `Silent` draws nothing under `--quiet` and `--json`. A directory
+//! transfer on the sending side reports to its overall line instead (see
+//! `send`). `--json` and `--progress-socket` events are not a reporter's:
+//! they go out under `--quiet` as well, so `events` is called alongside.
+
+use std::io;
+
+use crate::events;
+use crate::logging;
+use crate::protocol::UNKNOWN_SIZE;
+use crate::utils::FileProgress;
+
+pub trait ProgressReporter {
+    /// The data of a file begins: `total` bytes, or `UNKNOWN_SIZE` for a
+    /// pipe, of which `done` were there already, e.g. after a resume.
+    fn on_start(&mut self, done: u64, total: u64);
+
+    /// `done` of the `total` bytes are through.
+    fn on_progress(&mut self, done: u64, total: u64) -> io::Result<()>;
+
+    /// All of `name` is through, `done` bytes in the end.
+    fn on_file_done(&mut self, name: &str, done: u64) -> io::Result<()>;
+
+    /// Something else is about to be printed, such as the pause notice: end
+    /// the line that is showing, and start a fresh one on the next update.
+    fn on_interrupt(&mut self) {}
+
+    /// The file is sent again from the start, another `total` bytes.
+    fn on_retry(&mut self, _total: u64) {}
+
+    /// Nothing more is coming.
+    fn on_complete(&mut self) {}
+}
+
+/// One progress line per file, e.g. `Sent: 1.00 MiB/2.86 MiB  1.20 MiB/s,
+/// ETA 0:02`, redrawn in place and left showing the average rate.
+pub struct Terminal {
+    label: &'static str,
+    file: Option<FileProgress>,
+}
+
+impl Terminal {
+    pub fn new(label: &'static str) -> Self {
+        Terminal { label, file: None }
+    }
+}
+
+impl ProgressReporter for Terminal {
+    fn on_start(&mut self, done: u64, total: u64) {
+        self.file = Some(match total {
+            UNKNOWN_SIZE => FileProgress::open_ended(self.label),
+            total => FileProgress::new(self.label, total, done),
+        });
+    }
+
+    fn on_progress(&mut self, done: u64, _total: u64) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.draw(done),
+            None => Ok(()),
+        }
+    }
+
+    fn on_file_done(&mut self, _name: &str, done: u64) -> io::Result<()> {
+        match self.file.take() {
+            Some(mut file) => file.finish(done),
+            None => Ok(()),
+        }
+    }
+
+    fn on_interrupt(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            file.end();
+        }
+    }
+}
+
+/// Reports nowhere.
+pub struct Silent;
+
+impl ProgressReporter for Silent {
+    fn on_start(&mut self, _done: u64, _total: u64) {}
+
+    fn on_progress(&mut self, _done: u64, _total: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn on_file_done(&mut self, _name: &str, _done: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reporter for the terminal: `Terminal` with `label`, or `Silent` if
+/// no progress is drawn (`--quiet`, `--json`).
+pub fn terminal(label: &'static str) -> Box<dyn ProgressReporter> {
+    if logging::quiet() || events::json_enabled() {
+        Box::new(Silent)
+    } else {
+        Box::new(Terminal::new(label))
+    }
+}
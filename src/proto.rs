@@ -0,0 +1,344 @@
+//! Message types for `proto/ncp.proto` (package `ncp.v1`).
+//!
+//! These are written in the shape prost-build generates so the crate builds
+//! without requiring `protoc`. Keep them in sync with the `.proto` file.
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::hostname::get_hostname;
+
+/// Sent in `Probe` and `Established`; peers that differ refuse each other
+/// before any `protocol` message. Raise it with every change to those
+/// encodings that a peer could misread, unless the change is only used
+/// once a capability says the peer takes it. 2: `Meta` carries `batched`.
+/// 3: `MirrorList` comes in parts, marked `more`.
+pub const PROTOCOL_VERSION: &str = "3";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorCode {
+    ErrorUnknown = 0,
+    ErrProtocol = 1,
+    ErrNoSpace = 2,
+    ErrPermission = 3,
+    ErrChecksum = 4,
+    ErrTimeout = 5,
+    ErrAuth = 6,
+    ErrInvalidArg = 7,
+    ErrResumeNotSupported = 8,
+    ErrUnexpectedEof = 9,
+    /// the destination already holds this exact file
+    ErrAlreadyPresent = 10,
+    /// the destination holds a different file that may not be replaced
+    ErrExists = 11,
+    /// the file is over the receiver's --max-file-size
+    ErrTooLarge = 12,
+}
+
+impl ErrorCode {
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ErrorCode::ErrorUnknown => "ERROR_UNKNOWN",
+            ErrorCode::ErrProtocol => "ERR_PROTOCOL",
+            ErrorCode::ErrNoSpace => "ERR_NO_SPACE",
+            ErrorCode::ErrPermission => "ERR_PERMISSION",
+            ErrorCode::ErrChecksum => "ERR_CHECKSUM",
+            ErrorCode::ErrTimeout => "ERR_TIMEOUT",
+            ErrorCode::ErrAuth => "ERR_AUTH",
+            ErrorCode::ErrInvalidArg => "ERR_INVALID_ARG",
+            ErrorCode::ErrResumeNotSupported => "ERR_RESUME_NOT_SUPPORTED",
+            ErrorCode::ErrUnexpectedEof => "ERR_UNEXPECTED_EOF",
+            ErrorCode::ErrAlreadyPresent => "ERR_ALREADY_PRESENT",
+            ErrorCode::ErrExists => "ERR_EXISTS",
+            ErrorCode::ErrTooLarge => "ERR_TOO_LARGE",
+        }
+    }
+}
+
+impl From<&io::Error> for ErrorCode {
+    fn from(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => ErrorCode::ErrPermission,
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ErrorCode::ErrTimeout,
+            io::ErrorKind::UnexpectedEof => ErrorCode::ErrUnexpectedEof,
+            io::ErrorKind::InvalidInput => ErrorCode::ErrInvalidArg,
+            io::ErrorKind::InvalidData => ErrorCode::ErrProtocol,
+            io::ErrorKind::StorageFull => ErrorCode::ErrNoSpace,
+            _ => ErrorCode::ErrorUnknown,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Probe {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub version: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "3")]
+    pub capabilities: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// how often the receiver should ping while busy; 0 for never
+    #[prost(uint32, tag = "4")]
+    pub keepalive_seconds: u32,
+    #[prost(string, tag = "5")]
+    pub client_name: ::prost::alloc::string::String,
+    /// sender's --psk challenge; empty without a key
+    #[prost(bytes = "vec", tag = "6")]
+    pub auth_nonce: ::prost::alloc::vec::Vec<u8>,
+}
+
+impl Probe {
+    pub fn new(session_id: String) -> Self {
+        Probe {
+            session_id,
+            version: PROTOCOL_VERSION.to_string(),
+            capabilities: Vec::new(),
+            keepalive_seconds: 30,
+            client_name: get_hostname(),
+            auth_nonce: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Established {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub version: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "3")]
+    pub capabilities: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(message, optional, tag = "4")]
+    pub server_time: ::core::option::Option<::prost_types::Timestamp>,
+    /// receiver's --psk challenge; empty without a key
+    #[prost(bytes = "vec", tag = "5")]
+    pub auth_nonce: ::prost::alloc::vec::Vec<u8>,
+    /// HMAC answering Probe.auth_nonce
+    #[prost(bytes = "vec", tag = "6")]
+    pub auth_proof: ::prost::alloc::vec::Vec<u8>,
+    /// a PullRequest follows the handshake
+    #[prost(bool, tag = "7")]
+    pub pull: bool,
+    /// how often the sender should ping while busy; 0 for never
+    #[prost(uint32, tag = "8")]
+    pub keepalive_seconds: u32,
+    /// the receiver only compares checksums (recv --verify-only)
+    #[prost(bool, tag = "9")]
+    pub verify_only: bool,
+    /// the receiver asks for a MirrorList after a directory (recv --delete)
+    #[prost(bool, tag = "10")]
+    pub delete: bool,
+    /// and only reports what it would remove (recv --delete-dry-run)
+    #[prost(bool, tag = "11")]
+    pub delete_dry_run: bool,
+}
+
+/// Sent by the sender after Established when the receiver has a key.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Authenticate {
+    /// HMAC answering Established.auth_nonce
+    #[prost(bytes = "vec", tag = "1")]
+    pub proof: ::prost::alloc::vec::Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AuthResult {
+    #[prost(bool, tag = "1")]
+    pub ok: bool,
+    #[prost(string, tag = "2")]
+    pub reason: ::prost::alloc::string::String,
+}
+
+/// Sent by a pulling receiver once the handshake is done.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PullRequest {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    /// relative to what the sender serves; empty for all of it
+    #[prost(string, tag = "2")]
+    pub path: ::prost::alloc::string::String,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PullResult {
+    #[prost(bool, tag = "1")]
+    pub ok: bool,
+    #[prost(string, tag = "2")]
+    pub reason: ::prost::alloc::string::String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+#[allow(clippy::enum_variant_names)]
+pub enum OverwritePolicy {
+    OverwriteUnspecified = 0,
+    OverwriteYes = 1,
+    OverwriteNo = 2,
+    /// only with an older file, by mtime
+    OverwriteNewer = 3,
+    /// only with a newer file, by mtime
+    OverwriteOlder = 4,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FileMeta {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub size: u64,
+    #[prost(bool, tag = "3")]
+    pub is_dir: bool,
+    /// as POSIX octal, e.g. 0o644 => 420
+    #[prost(uint32, tag = "4")]
+    pub mode: u32,
+    #[prost(message, optional, tag = "5")]
+    pub mtime: ::core::option::Option<::prost_types::Timestamp>,
+    /// "sha256", "xxhash64", etc.
+    #[prost(string, tag = "6")]
+    pub checksum_alg: ::prost::alloc::string::String,
+    /// raw bytes (not hex)
+    #[prost(bytes = "vec", tag = "7")]
+    pub checksum: ::prost::alloc::vec::Vec<u8>,
+    /// extended attributes for --xattrs, values in hex
+    #[prost(map = "string, string", tag = "8")]
+    pub attrs: HashMap<::prost::alloc::string::String, ::prost::alloc::string::String>,
+    /// body encoding the sender intends to use, so preflight can refuse it
+    #[prost(enumeration = "TransferMode", tag = "9")]
+    pub transfer_mode: i32,
+    /// sender's preference, used when the receiver would otherwise ask
+    #[prost(enumeration = "OverwritePolicy", tag = "10")]
+    pub overwrite: i32,
+    /// exact bytes of a name that is not valid UTF-8, which `name` can only
+    /// carry lossily; empty otherwise
+    #[prost(bytes = "vec", tag = "11")]
+    pub raw_name: ::prost::alloc::vec::Vec<u8>,
+    /// a directory root that only groups its entries, such as the matches of
+    /// a wildcard, which go straight into the destination
+    #[prost(bool, tag = "12")]
+    pub contents_only: bool,
+    /// on a directory root: its files are verified by one `checksum_alg`
+    /// digest over all of them, sent after the last, instead of each alone
+    #[prost(bool, tag = "13")]
+    pub tree_checksum: bool,
+    /// a small file of a batch (`send --batch`): its `TransferStart`, body
+    /// and `Checksum` follow at once, and it is answered after `BatchEnd`
+    #[prost(bool, tag = "14")]
+    pub batched: bool,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Meta {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub file: ::core::option::Option<FileMeta>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PreflightOk {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(bool, tag = "2")]
+    pub destination_exists: bool,
+    #[prost(uint64, tag = "3")]
+    pub available_space: u64,
+    #[prost(string, tag = "4")]
+    pub temp_path: ::prost::alloc::string::String,
+    /// bytes of a partial file the receiver already holds (resume)
+    #[prost(uint64, tag = "5")]
+    pub resume_offset: u64,
+    /// digest of those bytes, if the Meta carried a checksum
+    #[prost(bytes = "vec", tag = "6")]
+    pub resume_checksum: ::prost::alloc::vec::Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PreflightFail {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(enumeration = "ErrorCode", tag = "2")]
+    pub code: i32,
+    #[prost(string, tag = "3")]
+    pub reason: ::prost::alloc::string::String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+#[allow(clippy::enum_variant_names)]
+pub enum TransferMode {
+    TransferRaw = 0,
+    TransferChunked = 1,
+    TransferZstd = 2,
+    TransferFramed = 3,
+    TransferZstdShared = 4,
+}
+
+impl TransferMode {
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            TransferMode::TransferRaw => "TRANSFER_RAW",
+            TransferMode::TransferChunked => "TRANSFER_CHUNKED",
+            TransferMode::TransferZstd => "TRANSFER_ZSTD",
+            TransferMode::TransferFramed => "TRANSFER_FRAMED",
+            TransferMode::TransferZstdShared => "TRANSFER_ZSTD_SHARED",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransferStart {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(enumeration = "TransferMode", tag = "2")]
+    pub mode: i32,
+    #[prost(uint64, tag = "3")]
+    pub file_size: u64,
+    /// for chunked mode
+    #[prost(uint32, tag = "4")]
+    pub chunk_size: u32,
+    /// first byte being sent; 0 unless resuming
+    #[prost(uint64, tag = "5")]
+    pub offset: u64,
+    /// connections carrying the body (--parallel); 0 or 1 for just this one
+    #[prost(uint32, tag = "6")]
+    pub streams: u32,
+}
+
+/// First message on each extra connection of a parallel transfer, followed
+/// by the raw bytes of its range.
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RangeStart {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+    #[prost(uint64, tag = "3")]
+    pub length: u64,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransferResult {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(bool, tag = "2")]
+    pub ok: bool,
+    #[prost(enumeration = "ErrorCode", tag = "3")]
+    pub code: i32,
+    #[prost(string, tag = "4")]
+    pub reason: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "5")]
+    pub checksum: ::prost::alloc::vec::Vec<u8>,
+    #[prost(uint64, tag = "6")]
+    pub received_bytes: u64,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Error {
+    #[prost(string, tag = "1")]
+    pub session_id: ::prost::alloc::string::String,
+    #[prost(enumeration = "ErrorCode", tag = "2")]
+    pub code: i32,
+    #[prost(string, tag = "3")]
+    pub message: ::prost::alloc::string::String,
+}
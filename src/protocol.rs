@@ -0,0 +1,1462 @@
+//! Control messages used by the live transfer.
+//!
+//! They follow the `Probe`/`Established` handshake (see `handshake`), and
+//! `Meta` and `TransferStart` carry the session ID agreed on there.
+//!
+//! The default binary encoding is `[type: u8][len: u32 BE][payload]`.
+//! Multi-byte integers are big-endian; strings are `[len: u32 BE][utf-8 bytes]`.
+//! A file name that is not valid UTF-8 travels lossily in `name` and exactly
+//! in `raw_name` (see `raw_name` and `entry_name`).
+//! After a `TransferStart` the sender writes the file body in the encoding
+//! named by its `mode` (see `compress`), then a `Checksum` over the decoded
+//! bytes which the receiver verifies before renaming the temp file into place.
+//! The sender ends the session with an empty `Done`; a connection that closes
+//! without one was cut off. A side that gives up for a reason the messages
+//! above have no place for says so with an `Error` (`[message][code: u8]`,
+//! an `ErrorCode`) before closing; whoever reads it fails with that code.
+//! Either side may send an empty `Ping` while the other waits for it, which
+//! is answered with an empty `Pong` (see `keepalive`).
+//!
+//! A `Meta` marked `batched` (`send --batch`) is followed at once by its
+//! `TransferStart`, body and `Checksum`, whatever the receiver makes of it;
+//! the receiver holds back its answers to such files, reading past the
+//! body of one it declines, until an empty `BatchEnd`, and then writes them
+//! all, in order, at once.
+//!
+//! The list of paths a `MirrorList` carries can be far larger than one
+//! message may be, so it is sent in parts of at most `MIRROR_PART_SIZE`
+//! bytes (see `mirror_list_parts`), each but the last marked `more`; the
+//! receiver collects them and acts on the whole list.
+//!
+//! A `TransferStart` with `streams` above 1 splits the body over that many
+//! connections (see `parallel`): this one carries the first range, and each
+//! other one opens with the handshake and a binary `Range` (`[session_id]
+//! [offset: u64][length: u64]`), followed by its raw bytes and answered
+//! with a `TransferResult` of its own.
+//!
+//! A sender may instead negotiate the JSON encoding (`--format json`) by
+//! sending a binary `MSG_FORMAT` request naming `json`; once the receiver
+//! echoes it back, every further control message is `[len: u32 BE][JSON
+//! object]` with a `"type"` field (`meta`, `preflight_ok`, `preflight_fail`,
+//! `transfer_start`, `checksum`, `transfer_result`, `mirror_list`, `done`,
+//! `ping`, `pong`, `error`, `range`, `batch_end`) and the same field names as the binary
+//! payloads; digests are hex strings, and a `Meta`'s `attrs` an object of
+//! strings. File bodies are not affected by the control format; see
+//! `compress`.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use prost_types::Timestamp;
+
+use crate::checksum::{from_hex, to_hex};
+use crate::framing::MAX_FRAME_SIZE;
+use crate::json::{self, Json};
+use crate::proto::{
+    Error, FileMeta, Meta, PreflightFail, PreflightOk, RangeStart, TransferMode, TransferResult,
+    TransferStart,
+};
+use crate::types::{NcpError, Result};
+
+pub const MSG_META: u8 = 1;
+pub const MSG_PREFLIGHT_OK: u8 = 2;
+pub const MSG_PREFLIGHT_FAIL: u8 = 3;
+pub const MSG_TRANSFER_START: u8 = 4;
+pub const MSG_TRANSFER_RESULT: u8 = 5;
+pub const MSG_MIRROR_LIST: u8 = 6;
+pub const MSG_FORMAT: u8 = 7;
+pub const MSG_CHECKSUM: u8 = 8;
+pub const MSG_DONE: u8 = 9;
+pub const MSG_PING: u8 = 10;
+pub const MSG_PONG: u8 = 11;
+pub const MSG_ERROR: u8 = 12;
+pub const MSG_RANGE: u8 = 13;
+pub const MSG_BATCH_END: u8 = 14;
+
+/// `size` and `file_size` of a file read from a pipe, whose length is only
+/// known once it has all been sent. Its body ends with an empty block (see
+/// `compress`).
+pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// Encoding of control messages on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Binary,
+    Json,
+}
+
+impl WireFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "binary" => Ok(WireFormat::Binary),
+            "json" => Ok(WireFormat::Json),
+            _ => Err(format!("Invalid format: {} (expected binary or json)", value).into()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WireFormat::Binary => "binary",
+            WireFormat::Json => "json",
+        }
+    }
+}
+
+/// Any control message, independent of its wire encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Meta(Meta),
+    PreflightOk(PreflightOk),
+    PreflightFail(PreflightFail),
+    TransferStart(TransferStart),
+    Checksum(FileChecksum),
+    TransferResult(TransferResult),
+    MirrorList(MirrorList),
+    /// Request (sender) or acknowledgement (receiver) of a control format.
+    Format(String),
+    /// Sent after the last entry: everything the sender meant to send has
+    /// been sent.
+    Done,
+    /// Keeps the connection alive while the sender of it is busy.
+    Ping,
+    /// The answer to a `Ping`.
+    Pong,
+    /// The sender of it failed and is about to close the connection.
+    Error(Error),
+    /// Opens an extra connection of a parallel transfer.
+    Range(RangeStart),
+    /// Sent after the last file of a batch: answer them now.
+    BatchEnd,
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Meta(_) => "Meta",
+            Message::PreflightOk(_) => "PreflightOk",
+            Message::PreflightFail(_) => "PreflightFail",
+            Message::TransferStart(_) => "TransferStart",
+            Message::Checksum(_) => "Checksum",
+            Message::TransferResult(_) => "TransferResult",
+            Message::MirrorList(_) => "MirrorList",
+            Message::Format(_) => "Format",
+            Message::Done => "Done",
+            Message::Ping => "Ping",
+            Message::Pong => "Pong",
+            Message::Error(_) => "Error",
+            Message::Range(_) => "Range",
+            Message::BatchEnd => "BatchEnd",
+        }
+    }
+}
+
+/// Digest of the raw bytes that followed a `TransferStart`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChecksum {
+    pub alg: String,
+    pub digest: Vec<u8>,
+}
+
+/// Authoritative list of relative paths making up a directory transfer,
+/// sent after the last entry when mirroring. `more` says another part of
+/// the list follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorList {
+    pub paths: Vec<String>,
+    pub dry_run: bool,
+    pub more: bool,
+}
+
+/// Default largest payload a peer may declare (`--max-message-size`),
+/// matching the protobuf framing cap. Checked before anything is allocated
+/// for the message.
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAME_SIZE;
+
+/// Smallest `--max-message-size`: a `MirrorList` part, the only message
+/// that grows with the tree, always fits in it.
+pub const MIN_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Most bytes of paths in one `MirrorList` part, in either encoding, with
+/// room to spare for the rest of the message.
+pub const MIRROR_PART_SIZE: usize = MIN_MESSAGE_SIZE / 2;
+
+static MAX_LENGTH: AtomicUsize = AtomicUsize::new(MAX_MESSAGE_SIZE);
+
+/// Set the largest payload a peer may declare, and this side may write
+/// (`--max-message-size`); at least `MIN_MESSAGE_SIZE`.
+pub fn set_max_message_size(len: usize) {
+    MAX_LENGTH.store(len.max(MIN_MESSAGE_SIZE), Ordering::Relaxed);
+}
+
+pub fn max_message_size() -> usize {
+    MAX_LENGTH.load(Ordering::Relaxed)
+}
+
+fn check_length(len: usize) -> Result<()> {
+    let max = max_message_size();
+    if len > max {
+        return Err(NcpError::Protocol(format!(
+            "Message length {} exceeds maximum of {} bytes",
+            len, max
+        )));
+    }
+    Ok(())
+}
+
+fn write_header<W: Write>(writer: &mut W, msg_type: u8, len: usize) -> Result<()> {
+    check_length(len)?;
+    let len = len as u32;
+    writer.write_all(&[msg_type])?;
+    writer.write_all(&len.to_be_bytes())?;
+    Ok(())
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// `[present: u8][seconds: i64 BE][nanos: i32 BE]`; absent is all zeros.
+fn put_timestamp(buf: &mut Vec<u8>, ts: Option<&Timestamp>) {
+    let (present, seconds, nanos) = ts.map_or((0u8, 0, 0), |ts| (1, ts.seconds, ts.nanos));
+    buf.push(present);
+    buf.extend_from_slice(&seconds.to_be_bytes());
+    buf.extend_from_slice(&nanos.to_be_bytes());
+}
+
+pub fn read_exact_bytes<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io::Error::new(e.kind(), "Connection closed unexpectedly").into()
+        } else {
+            e.into()
+        }
+    })
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut buf = [0u8; 1];
+    read_exact_bytes(reader, &mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    read_exact_bytes(reader, &mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    read_exact_bytes(reader, &mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_timestamp<R: Read>(reader: &mut R) -> Result<Option<Timestamp>> {
+    let present = read_u8(reader)? != 0;
+    let seconds = read_u64(reader)? as i64;
+    let nanos = read_u32(reader)? as i32;
+    Ok(present.then_some(Timestamp { seconds, nanos }))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String> {
+    let len = read_u32(reader)? as usize;
+    check_length(len)?;
+    let mut buf = vec![0u8; len];
+    read_exact_bytes(reader, &mut buf)?;
+    String::from_utf8(buf).map_err(|_| NcpError::Protocol("Invalid UTF-8 in message".to_string()))
+}
+
+/// A length field of `what`, checked against the `reader.limit()` bytes
+/// left of the message it is in before anything is allocated for it.
+fn read_len_within<R: Read>(reader: &mut io::Take<R>, what: &str) -> Result<usize> {
+    let len = read_u32(reader)? as usize;
+    if len as u64 > reader.limit() {
+        return Err(NcpError::Protocol(format!(
+            "{} of {} bytes overruns the {} left in its message",
+            what,
+            len,
+            reader.limit()
+        )));
+    }
+    Ok(len)
+}
+
+fn read_bytes_within<R: Read>(reader: &mut io::Take<R>, what: &str) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; read_len_within(reader, what)?];
+    read_exact_bytes(reader, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_string_within<R: Read>(reader: &mut io::Take<R>, what: &str) -> Result<String> {
+    String::from_utf8(read_bytes_within(reader, what)?)
+        .map_err(|_| NcpError::Protocol("Invalid UTF-8 in message".to_string()))
+}
+
+/// Decode a message of `len` declared bytes with `decode`, which must take
+/// exactly those: a field running past them, or bytes left after the last
+/// one, is inconsistent framing.
+fn read_exactly<R: Read, T>(
+    reader: R,
+    len: u32,
+    what: &str,
+    decode: impl FnOnce(&mut io::Take<R>) -> Result<T>,
+) -> Result<T> {
+    let reader = &mut reader.take(len as u64);
+    let decoded = match decode(reader) {
+        Err(NcpError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof && reader.limit() == 0 => {
+            let reason = format!("{} of {} bytes is too short for its fields", what, len);
+            return Err(NcpError::Protocol(reason));
+        }
+        decoded => decoded?,
+    };
+    if reader.limit() > 0 {
+        return Err(NcpError::Protocol(format!(
+            "{} of {} bytes has {} left after its fields",
+            what,
+            len,
+            reader.limit()
+        )));
+    }
+    Ok(decoded)
+}
+
+pub fn read_message_type<R: Read>(reader: &mut R) -> Result<u8> {
+    read_u8(reader)
+}
+
+pub fn read_message_length<R: Read>(reader: &mut R) -> Result<u32> {
+    let len = read_u32(reader)?;
+    check_length(len as usize)?;
+    Ok(len)
+}
+
+/// The bytes of `name` for `FileMeta::raw_name` if they are not valid UTF-8,
+/// otherwise nothing. Windows names are UTF-16 and only ever sent lossily.
+pub fn raw_name(name: &Path) -> Vec<u8> {
+    if name.to_str().is_some() {
+        return Vec::new();
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        name.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    Vec::new()
+}
+
+/// The name `meta` gives its entry, byte for byte as on the sender where
+/// this platform can represent it. `raw_name` must render as `name`, which
+/// is what the receiver validates.
+pub fn entry_name(meta: &FileMeta) -> Result<PathBuf> {
+    if meta.raw_name.is_empty() {
+        return Ok(PathBuf::from(&meta.name));
+    }
+    if String::from_utf8_lossy(&meta.raw_name) != meta.name {
+        return Err(NcpError::Protocol(format!("Raw name of {} does not match it", meta.name)));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(meta.raw_name.clone())))
+    }
+    #[cfg(not(unix))]
+    Ok(PathBuf::from(&meta.name))
+}
+
+pub fn write_meta<W: Write>(writer: &mut W, meta: &Meta) -> Result<()> {
+    let session_id = &meta.session_id;
+    let meta = meta.file.as_ref().ok_or("Meta has no file")?;
+    let mut payload = Vec::new();
+    put_string(&mut payload, session_id);
+    put_string(&mut payload, &meta.name);
+    payload.extend_from_slice(&meta.size.to_be_bytes());
+    payload.push(meta.is_dir as u8);
+    payload.push(meta.transfer_mode as u8);
+    payload.push(meta.overwrite as u8);
+    payload.extend_from_slice(&meta.mode.to_be_bytes());
+    put_timestamp(&mut payload, meta.mtime.as_ref());
+    put_string(&mut payload, &meta.checksum_alg);
+    payload.extend_from_slice(&(meta.checksum.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&meta.checksum);
+    payload.extend_from_slice(&(meta.raw_name.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&meta.raw_name);
+    payload.push(meta.contents_only as u8);
+    payload.push(meta.tree_checksum as u8);
+    payload.push(meta.batched as u8);
+    let mut attrs: Vec<_> = meta.attrs.iter().collect();
+    attrs.sort();
+    payload.extend_from_slice(&(attrs.len() as u32).to_be_bytes());
+    for (name, value) in attrs {
+        put_string(&mut payload, name);
+        put_string(&mut payload, value);
+    }
+
+    write_header(writer, MSG_META, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// A `Meta` of `len` bytes, as its header declares.
+pub fn read_meta<R: Read>(reader: &mut R, len: u32) -> Result<Meta> {
+    read_exactly(reader, len, "Meta", decode_meta)
+}
+
+fn decode_meta<R: Read>(reader: &mut io::Take<R>) -> Result<Meta> {
+    let session_id = read_string_within(reader, "Session ID")?;
+    let name = read_string_within(reader, "Name")?;
+    let size = read_u64(reader)?;
+    let is_dir = read_u8(reader)? != 0;
+    // Left unvalidated so the receiver can decline an unknown mode in
+    // preflight instead of dropping the connection.
+    let transfer_mode = read_u8(reader)? as i32;
+    let overwrite = read_u8(reader)? as i32;
+    let mode = read_u32(reader)?;
+    let mtime = read_timestamp(reader)?;
+    // Also checked by the receiver, which declines names it does not know.
+    let checksum_alg = read_string_within(reader, "Checksum algorithm")?;
+    // Only sent for `--skip-existing` and `--checksum-on-preflight`; empty otherwise.
+    let checksum = read_bytes_within(reader, "Checksum")?;
+    // Only sent for names that are not valid UTF-8; empty otherwise.
+    let raw_name = read_bytes_within(reader, "Raw name")?;
+    let contents_only = read_u8(reader)? != 0;
+    let tree_checksum = read_u8(reader)? != 0;
+    let batched = read_u8(reader)? != 0;
+    // Only sent for `--xattrs`; none otherwise.
+    // Each takes at least the lengths of its name and value.
+    let attr_count = read_u32(reader)? as u64;
+    if attr_count * 8 > reader.limit() {
+        let left = reader.limit();
+        let reason = format!("{} attributes overrun the {} bytes left in Meta", attr_count, left);
+        return Err(NcpError::Protocol(reason));
+    }
+    let mut attrs = HashMap::new();
+    for _ in 0..attr_count {
+        let name = read_string_within(reader, "Attribute name")?;
+        attrs.insert(name, read_string_within(reader, "Attribute value")?);
+    }
+
+    let file = FileMeta {
+        name,
+        size,
+        is_dir,
+        mode,
+        mtime,
+        checksum_alg,
+        checksum,
+        transfer_mode,
+        overwrite,
+        raw_name,
+        contents_only,
+        tree_checksum,
+        batched,
+        attrs,
+    };
+    Ok(Meta {
+        session_id,
+        file: Some(file),
+    })
+}
+
+pub fn write_preflight_ok<W: Write>(writer: &mut W, ok: &PreflightOk) -> Result<()> {
+    let mut payload = Vec::new();
+    payload.push(ok.destination_exists as u8);
+    payload.extend_from_slice(&ok.available_space.to_be_bytes());
+    payload.extend_from_slice(&ok.resume_offset.to_be_bytes());
+    payload.extend_from_slice(&(ok.resume_checksum.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&ok.resume_checksum);
+
+    write_header(writer, MSG_PREFLIGHT_OK, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_preflight_ok<R: Read>(reader: &mut R) -> Result<PreflightOk> {
+    let destination_exists = read_u8(reader)? != 0;
+    let available_space = read_u64(reader)?;
+    let resume_offset = read_u64(reader)?;
+    // Only sent for a partial file offered to a sender that put its
+    // checksum in the Meta; empty otherwise.
+    let resume_checksum_len = read_u32(reader)? as usize;
+    check_length(resume_checksum_len)?;
+    let mut resume_checksum = vec![0u8; resume_checksum_len];
+    read_exact_bytes(reader, &mut resume_checksum)?;
+
+    Ok(PreflightOk {
+        destination_exists,
+        available_space,
+        resume_offset,
+        resume_checksum,
+        ..Default::default()
+    })
+}
+
+pub fn write_preflight_fail<W: Write>(writer: &mut W, fail: &PreflightFail) -> Result<()> {
+    let mut payload = Vec::new();
+    put_string(&mut payload, &fail.reason);
+    payload.push(fail.code as u8);
+
+    write_header(writer, MSG_PREFLIGHT_FAIL, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_error<W: Write>(writer: &mut W, error: &Error) -> Result<()> {
+    let mut payload = Vec::new();
+    put_string(&mut payload, &error.message);
+    payload.push(error.code as u8);
+
+    write_header(writer, MSG_ERROR, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_error<R: Read>(reader: &mut R) -> Result<Error> {
+    let message = read_string(reader)?;
+    let code = read_u8(reader)? as i32;
+
+    Ok(Error {
+        message,
+        code,
+        ..Default::default()
+    })
+}
+
+/// A `PreflightFail` of `len` bytes, as its header declares.
+pub fn read_preflight_fail<R: Read>(reader: &mut R, len: u32) -> Result<PreflightFail> {
+    read_exactly(reader, len, "PreflightFail", |reader| {
+        let reason = read_string_within(reader, "Reason")?;
+        let code = read_u8(reader)? as i32;
+        Ok(PreflightFail {
+            reason,
+            code,
+            ..Default::default()
+        })
+    })
+}
+
+pub fn write_transfer_start<W: Write>(writer: &mut W, start: &TransferStart) -> Result<()> {
+    let mut payload = Vec::new();
+    put_string(&mut payload, &start.session_id);
+    payload.push(start.mode as u8);
+    payload.extend_from_slice(&start.file_size.to_be_bytes());
+    payload.extend_from_slice(&start.offset.to_be_bytes());
+    payload.extend_from_slice(&start.streams.to_be_bytes());
+
+    write_header(writer, MSG_TRANSFER_START, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_transfer_start<R: Read>(reader: &mut R) -> Result<TransferStart> {
+    let session_id = read_string(reader)?;
+    let mode = read_u8(reader)? as i32;
+    let file_size = read_u64(reader)?;
+    let offset = read_u64(reader)?;
+    let streams = read_u32(reader)?;
+
+    if TransferMode::try_from(mode).is_err() {
+        return Err(NcpError::Protocol(format!("Unknown transfer mode: {}", mode)));
+    }
+
+    if offset > file_size {
+        let message = format!("Transfer offset {} is past the end of the file", offset);
+        return Err(NcpError::Protocol(message));
+    }
+
+    Ok(TransferStart {
+        session_id,
+        mode,
+        file_size,
+        offset,
+        streams,
+        ..Default::default()
+    })
+}
+
+pub fn write_range<W: Write>(writer: &mut W, range: &RangeStart) -> Result<()> {
+    let mut payload = Vec::new();
+    put_string(&mut payload, &range.session_id);
+    payload.extend_from_slice(&range.offset.to_be_bytes());
+    payload.extend_from_slice(&range.length.to_be_bytes());
+
+    write_header(writer, MSG_RANGE, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_range<R: Read>(reader: &mut R) -> Result<RangeStart> {
+    let session_id = read_string(reader)?;
+    let offset = read_u64(reader)?;
+    let length = read_u64(reader)?;
+
+    Ok(RangeStart {
+        session_id,
+        offset,
+        length,
+    })
+}
+
+pub fn write_checksum<W: Write>(writer: &mut W, checksum: &FileChecksum) -> Result<()> {
+    let mut payload = Vec::new();
+    put_string(&mut payload, &checksum.alg);
+    payload.extend_from_slice(&(checksum.digest.len() as u32).to_be_bytes());
+    payload.extend_from_slice(&checksum.digest);
+
+    write_header(writer, MSG_CHECKSUM, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_checksum<R: Read>(reader: &mut R) -> Result<FileChecksum> {
+    let alg = read_string(reader)?;
+    let len = read_u32(reader)? as usize;
+    check_length(len)?;
+    let mut digest = vec![0u8; len];
+    read_exact_bytes(reader, &mut digest)?;
+
+    Ok(FileChecksum { alg, digest })
+}
+
+pub fn write_transfer_result<W: Write>(writer: &mut W, result: &TransferResult) -> Result<()> {
+    let mut payload = Vec::new();
+    payload.push(result.ok as u8);
+    payload.extend_from_slice(&result.received_bytes.to_be_bytes());
+    put_string(&mut payload, &result.reason);
+    payload.push(result.code as u8);
+
+    write_header(writer, MSG_TRANSFER_RESULT, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_transfer_result<R: Read>(reader: &mut R) -> Result<TransferResult> {
+    let ok = read_u8(reader)? != 0;
+    let received_bytes = read_u64(reader)?;
+    let reason = read_string(reader)?;
+    let code = read_u8(reader)? as i32;
+
+    Ok(TransferResult {
+        ok,
+        received_bytes,
+        reason,
+        code,
+        ..Default::default()
+    })
+}
+
+pub fn write_mirror_list<W: Write>(writer: &mut W, list: &MirrorList) -> Result<()> {
+    let mut payload = Vec::new();
+    payload.push(list.dry_run as u8);
+    payload.extend_from_slice(&(list.paths.len() as u32).to_be_bytes());
+    for path in &list.paths {
+        put_string(&mut payload, path);
+    }
+    payload.push(list.more as u8);
+
+    write_header(writer, MSG_MIRROR_LIST, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_mirror_list<R: Read>(reader: &mut R) -> Result<MirrorList> {
+    let dry_run = read_u8(reader)? != 0;
+    let count = read_u32(reader)?;
+    let mut paths = Vec::new();
+    for _ in 0..count {
+        paths.push(read_string(reader)?);
+    }
+    let more = read_u8(reader)? != 0;
+
+    Ok(MirrorList { paths, dry_run, more })
+}
+
+/// `paths` as the `MirrorList` parts to send one after the other, each
+/// within `MIRROR_PART_SIZE` bytes whichever the encoding. There is always
+/// at least one, so that an empty list is still sent.
+pub fn mirror_list_parts(paths: Vec<String>, dry_run: bool) -> Vec<MirrorList> {
+    let mut parts = vec![MirrorList { paths: Vec::new(), dry_run, more: false }];
+    let mut size = 0;
+    for path in paths {
+        // A JSON string may escape many bytes, so it is measured itself.
+        let cost = (4 + path.len()).max(Json::str(&path).to_string().len() + 1);
+        if size + cost > MIRROR_PART_SIZE && size > 0 {
+            parts.last_mut().unwrap().more = true;
+            parts.push(MirrorList { paths: Vec::new(), dry_run, more: false });
+            size = 0;
+        }
+        size += cost;
+        parts.last_mut().unwrap().paths.push(path);
+    }
+    parts
+}
+
+pub fn write_format<W: Write>(writer: &mut W, name: &str) -> Result<()> {
+    let mut payload = Vec::new();
+    put_string(&mut payload, name);
+
+    write_header(writer, MSG_FORMAT, payload.len())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_format<R: Read>(reader: &mut R) -> Result<String> {
+    read_string(reader)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, format: WireFormat, msg: &Message) -> Result<()> {
+    match format {
+        WireFormat::Binary => match msg {
+            Message::Meta(meta) => write_meta(writer, meta),
+            Message::PreflightOk(ok) => write_preflight_ok(writer, ok),
+            Message::PreflightFail(fail) => write_preflight_fail(writer, fail),
+            Message::TransferStart(start) => write_transfer_start(writer, start),
+            Message::Checksum(checksum) => write_checksum(writer, checksum),
+            Message::TransferResult(result) => write_transfer_result(writer, result),
+            Message::MirrorList(list) => write_mirror_list(writer, list),
+            Message::Format(name) => write_format(writer, name),
+            Message::Done => write_empty(writer, MSG_DONE),
+            Message::Ping => write_empty(writer, MSG_PING),
+            Message::Pong => write_empty(writer, MSG_PONG),
+            Message::Error(error) => write_error(writer, error),
+            Message::Range(range) => write_range(writer, range),
+            Message::BatchEnd => write_empty(writer, MSG_BATCH_END),
+        },
+        WireFormat::Json => {
+            let text = message_to_json(msg).to_string();
+            check_length(text.len())?;
+            writer.write_all(&(text.len() as u32).to_be_bytes())?;
+            writer.write_all(text.as_bytes())?;
+            writer.flush()?;
+            Ok(())
+        }
+    }
+}
+
+fn write_empty<W: Write>(writer: &mut W, msg_type: u8) -> Result<()> {
+    write_header(writer, msg_type, 0)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read the next control message, or `None` if the peer closed the
+/// connection cleanly before sending another one.
+pub fn read_next_message<R: Read>(reader: &mut R, format: WireFormat) -> Result<Option<Message>> {
+    let mut first = [0u8; 1];
+    loop {
+        match reader.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    read_message(&mut (&first[..]).chain(reader), format).map(Some)
+}
+
+pub fn read_message<R: Read>(reader: &mut R, format: WireFormat) -> Result<Message> {
+    match format {
+        WireFormat::Binary => {
+            let msg_type = read_message_type(reader)?;
+            let len = read_message_length(reader)?;
+            // Decode from the declared payload only, so no field can claim
+            // more bytes than the peer actually committed to sending.
+            let mut buf = vec![0u8; len as usize];
+            read_exact_bytes(reader, &mut buf)?;
+            let payload = &mut &buf[..];
+            match msg_type {
+                MSG_META => Ok(Message::Meta(read_meta(payload, len)?)),
+                MSG_PREFLIGHT_OK => Ok(Message::PreflightOk(read_preflight_ok(payload)?)),
+                MSG_PREFLIGHT_FAIL => Ok(Message::PreflightFail(read_preflight_fail(payload, len)?)),
+                MSG_TRANSFER_START => Ok(Message::TransferStart(read_transfer_start(payload)?)),
+                MSG_CHECKSUM => Ok(Message::Checksum(read_checksum(payload)?)),
+                MSG_TRANSFER_RESULT => Ok(Message::TransferResult(read_transfer_result(payload)?)),
+                MSG_MIRROR_LIST => Ok(Message::MirrorList(read_mirror_list(payload)?)),
+                MSG_FORMAT => Ok(Message::Format(read_format(payload)?)),
+                MSG_DONE => Ok(Message::Done),
+                MSG_PING => Ok(Message::Ping),
+                MSG_PONG => Ok(Message::Pong),
+                MSG_ERROR => Ok(Message::Error(read_error(payload)?)),
+                MSG_RANGE => Ok(Message::Range(read_range(payload)?)),
+                MSG_BATCH_END => Ok(Message::BatchEnd),
+                other => Err(NcpError::Protocol(format!("Unknown message type: {}", other))),
+            }
+        }
+        WireFormat::Json => {
+            let len = read_message_length(reader)? as usize;
+            let mut buf = vec![0u8; len];
+            read_exact_bytes(reader, &mut buf)?;
+            let text = String::from_utf8(buf).map_err(|_| "Invalid UTF-8 in JSON message")?;
+            message_from_json(&json::parse(&text)?)
+        }
+    }
+}
+
+/// `read_message` for a peer that may ping while we wait: a `Ping` is
+/// answered with a `Pong`, and neither is returned. An `Error` is returned
+/// as the `NcpError::PeerError` it stands for.
+pub fn read_control<S: Read + Write>(stream: &mut S, format: WireFormat) -> Result<Message> {
+    loop {
+        match read_message(stream, format)? {
+            Message::Ping => write_message(stream, format, &Message::Pong)?,
+            Message::Pong => {}
+            Message::Error(error) => return Err(NcpError::from_peer(error.code, error.message)),
+            msg => return Ok(msg),
+        }
+    }
+}
+
+/// `read_next_message`, answering and skipping pings like `read_control`.
+pub fn read_next_control<S: Read + Write>(
+    stream: &mut S,
+    format: WireFormat,
+) -> Result<Option<Message>> {
+    loop {
+        match read_next_message(stream, format)? {
+            Some(Message::Ping) => write_message(stream, format, &Message::Pong)?,
+            Some(Message::Pong) => {}
+            Some(Message::Error(error)) => return Err(NcpError::from_peer(error.code, error.message)),
+            msg => return Ok(msg),
+        }
+    }
+}
+
+fn message_to_json(msg: &Message) -> Json {
+    let field = |k: &str, v: Json| (k.to_string(), v);
+    let fields = match msg {
+        Message::Meta(Meta { session_id, file }) => {
+            let meta = file.clone().unwrap_or_default();
+            let mut fields = vec![
+                field("type", Json::str("meta")),
+                field("session_id", Json::str(session_id)),
+                field("name", Json::str(&meta.name)),
+                field("size", Json::u64(meta.size)),
+                field("is_dir", Json::Bool(meta.is_dir)),
+                field("transfer_mode", Json::u64(meta.transfer_mode as u64)),
+                field("overwrite", Json::u64(meta.overwrite as u64)),
+                field("mode", Json::u64(meta.mode as u64)),
+                field("checksum_alg", Json::str(&meta.checksum_alg)),
+            ];
+            // Only present for `--skip-existing` and `--checksum-on-preflight`.
+            if !meta.checksum.is_empty() {
+                fields.push(field("checksum", Json::str(&to_hex(&meta.checksum))));
+            }
+            if !meta.raw_name.is_empty() {
+                fields.push(field("raw_name", Json::str(&to_hex(&meta.raw_name))));
+            }
+            if meta.contents_only {
+                fields.push(field("contents_only", Json::Bool(true)));
+            }
+            if meta.tree_checksum {
+                fields.push(field("tree_checksum", Json::Bool(true)));
+            }
+            if meta.batched {
+                fields.push(field("batched", Json::Bool(true)));
+            }
+            if !meta.attrs.is_empty() {
+                let mut attrs: Vec<_> = meta.attrs.into_iter().collect();
+                attrs.sort();
+                let attrs = attrs.into_iter().map(|(name, value)| (name, Json::String(value)));
+                fields.push(field("attrs", Json::Object(attrs.collect())));
+            }
+            // Only present when the sender preserves times.
+            if let Some(mtime) = &meta.mtime {
+                fields.push(field("mtime_seconds", Json::Number(mtime.seconds.to_string())));
+                fields.push(field("mtime_nanos", Json::u64(mtime.nanos as u64)));
+            }
+            fields
+        }
+        Message::PreflightOk(ok) => {
+            let mut fields = vec![
+                field("type", Json::str("preflight_ok")),
+                field("destination_exists", Json::Bool(ok.destination_exists)),
+                field("available_space", Json::u64(ok.available_space)),
+                field("resume_offset", Json::u64(ok.resume_offset)),
+            ];
+            if !ok.resume_checksum.is_empty() {
+                fields.push(field("resume_checksum", Json::str(&to_hex(&ok.resume_checksum))));
+            }
+            fields
+        }
+        Message::PreflightFail(fail) => vec![
+            field("type", Json::str("preflight_fail")),
+            field("reason", Json::str(&fail.reason)),
+            field("code", Json::u64(fail.code as u64)),
+        ],
+        Message::TransferStart(start) => {
+            let mut fields = vec![
+                field("type", Json::str("transfer_start")),
+                field("session_id", Json::str(&start.session_id)),
+                field("mode", Json::u64(start.mode as u64)),
+                field("file_size", Json::u64(start.file_size)),
+                field("offset", Json::u64(start.offset)),
+            ];
+            // Only present for a parallel transfer.
+            if start.streams > 1 {
+                fields.push(field("streams", Json::u64(start.streams as u64)));
+            }
+            fields
+        }
+        Message::Checksum(checksum) => vec![
+            field("type", Json::str("checksum")),
+            field("alg", Json::str(&checksum.alg)),
+            field("digest", Json::str(&to_hex(&checksum.digest))),
+        ],
+        Message::TransferResult(result) => {
+            let mut fields = vec![
+                field("type", Json::str("transfer_result")),
+                field("ok", Json::Bool(result.ok)),
+                field("received_bytes", Json::u64(result.received_bytes)),
+                field("reason", Json::str(&result.reason)),
+            ];
+            // Only present on failure.
+            if result.code != 0 {
+                fields.push(field("code", Json::u64(result.code as u64)));
+            }
+            fields
+        }
+        Message::MirrorList(list) => vec![
+            field("type", Json::str("mirror_list")),
+            field("paths", Json::Array(list.paths.iter().map(|p| Json::str(p)).collect())),
+            field("dry_run", Json::Bool(list.dry_run)),
+            field("more", Json::Bool(list.more)),
+        ],
+        Message::Format(name) => vec![
+            field("type", Json::str("format")),
+            field("name", Json::str(name)),
+        ],
+        Message::Done => vec![field("type", Json::str("done"))],
+        Message::Ping => vec![field("type", Json::str("ping"))],
+        Message::Pong => vec![field("type", Json::str("pong"))],
+        Message::Error(error) => vec![
+            field("type", Json::str("error")),
+            field("message", Json::str(&error.message)),
+            field("code", Json::u64(error.code as u64)),
+        ],
+        Message::Range(range) => vec![
+            field("type", Json::str("range")),
+            field("session_id", Json::str(&range.session_id)),
+            field("offset", Json::u64(range.offset)),
+            field("length", Json::u64(range.length)),
+        ],
+        Message::BatchEnd => vec![field("type", Json::str("batch_end"))],
+    };
+    Json::Object(fields)
+}
+
+fn message_from_json(value: &Json) -> Result<Message> {
+    let invalid = |what: &str| NcpError::Protocol(what.to_string());
+    let msg_type =
+        value.get("type").and_then(Json::as_str).ok_or_else(|| invalid("JSON message has no type"))?;
+    let missing = |field: &str| invalid(&format!("JSON {} message is missing '{}'", msg_type, field));
+    let string = |field: &str| -> Result<String> {
+        Ok(value.get(field).and_then(Json::as_str).ok_or_else(|| missing(field))?.to_string())
+    };
+    let number = |field: &str| -> Result<u64> {
+        value.get(field).and_then(Json::as_u64).ok_or_else(|| missing(field))
+    };
+    let boolean = |field: &str| -> Result<bool> {
+        value.get(field).and_then(Json::as_bool).ok_or_else(|| missing(field))
+    };
+
+    match msg_type {
+        "meta" => {
+            let file = FileMeta {
+                name: string("name")?,
+                size: number("size")?,
+                is_dir: boolean("is_dir")?,
+                transfer_mode: i32::try_from(number("transfer_mode")?)
+                    .map_err(|_| invalid("Invalid transfer mode"))?,
+                overwrite: i32::try_from(number("overwrite")?)
+                    .map_err(|_| invalid("Invalid overwrite policy"))?,
+                mode: u32::try_from(number("mode")?).map_err(|_| invalid("Invalid mode"))?,
+                mtime: match value.get("mtime_seconds") {
+                    Some(Json::Number(seconds)) => Some(Timestamp {
+                        seconds: seconds.parse().map_err(|_| invalid("Invalid mtime_seconds"))?,
+                        nanos: i32::try_from(number("mtime_nanos")?)
+                            .map_err(|_| invalid("Invalid mtime_nanos"))?,
+                    }),
+                    Some(_) => return Err(invalid("Invalid mtime_seconds")),
+                    None => None,
+                },
+                checksum_alg: string("checksum_alg")?,
+                checksum: match value.get("checksum") {
+                    Some(_) => from_hex(&string("checksum")?)
+                        .ok_or_else(|| invalid("Meta checksum is not valid hex"))?,
+                    None => Vec::new(),
+                },
+                raw_name: match value.get("raw_name") {
+                    Some(_) => from_hex(&string("raw_name")?)
+                        .ok_or_else(|| invalid("Meta raw_name is not valid hex"))?,
+                    None => Vec::new(),
+                },
+                contents_only: match value.get("contents_only") {
+                    Some(_) => boolean("contents_only")?,
+                    None => false,
+                },
+                tree_checksum: match value.get("tree_checksum") {
+                    Some(_) => boolean("tree_checksum")?,
+                    None => false,
+                },
+                batched: match value.get("batched") {
+                    Some(_) => boolean("batched")?,
+                    None => false,
+                },
+                attrs: match value.get("attrs") {
+                    Some(Json::Object(attrs)) => attrs
+                        .iter()
+                        .map(|(name, value)| match value.as_str() {
+                            Some(value) => Ok((name.clone(), value.to_string())),
+                            None => Err(invalid("Meta attrs must be strings")),
+                        })
+                        .collect::<Result<_>>()?,
+                    Some(_) => return Err(invalid("Invalid Meta attrs")),
+                    None => HashMap::new(),
+                },
+            };
+            Ok(Message::Meta(Meta {
+                session_id: string("session_id")?,
+                file: Some(file),
+            }))
+        }
+        "preflight_ok" => Ok(Message::PreflightOk(PreflightOk {
+            destination_exists: boolean("destination_exists")?,
+            available_space: number("available_space")?,
+            resume_offset: number("resume_offset")?,
+            resume_checksum: match value.get("resume_checksum") {
+                Some(_) => from_hex(&string("resume_checksum")?)
+                    .ok_or_else(|| invalid("resume_checksum is not valid hex"))?,
+                None => Vec::new(),
+            },
+            ..Default::default()
+        })),
+        "preflight_fail" => Ok(Message::PreflightFail(PreflightFail {
+            reason: string("reason")?,
+            code: i32::try_from(number("code")?).map_err(|_| invalid("Invalid preflight code"))?,
+            ..Default::default()
+        })),
+        "transfer_start" => {
+            let mode = i32::try_from(number("mode")?).map_err(|_| invalid("Invalid transfer mode"))?;
+            if TransferMode::try_from(mode).is_err() {
+                return Err(NcpError::Protocol(format!("Unknown transfer mode: {}", mode)));
+            }
+            Ok(Message::TransferStart(TransferStart {
+                session_id: string("session_id")?,
+                mode,
+                file_size: number("file_size")?,
+                offset: number("offset")?,
+                streams: match value.get("streams") {
+                    Some(_) => {
+                        u32::try_from(number("streams")?).map_err(|_| invalid("Invalid streams"))?
+                    }
+                    None => 0,
+                },
+                ..Default::default()
+            }))
+        }
+        "checksum" => Ok(Message::Checksum(FileChecksum {
+            alg: string("alg")?,
+            digest: from_hex(&string("digest")?)
+                .ok_or_else(|| invalid("checksum digest is not valid hex"))?,
+        })),
+        "transfer_result" => Ok(Message::TransferResult(TransferResult {
+            ok: boolean("ok")?,
+            received_bytes: number("received_bytes")?,
+            reason: string("reason")?,
+            code: match value.get("code") {
+                Some(_) => i32::try_from(number("code")?).map_err(|_| invalid("Invalid result code"))?,
+                None => 0,
+            },
+            ..Default::default()
+        })),
+        "mirror_list" => {
+            let paths = value
+                .get("paths")
+                .and_then(Json::as_array)
+                .ok_or_else(|| missing("paths"))?
+                .iter()
+                .map(|p| {
+                    let path = p.as_str().map(str::to_string);
+                    path.ok_or_else(|| invalid("mirror_list paths must be strings"))
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(Message::MirrorList(MirrorList {
+                paths,
+                dry_run: boolean("dry_run")?,
+                more: boolean("more")?,
+            }))
+        }
+        "format" => Ok(Message::Format(string("name")?)),
+        "done" => Ok(Message::Done),
+        "ping" => Ok(Message::Ping),
+        "pong" => Ok(Message::Pong),
+        "error" => Ok(Message::Error(Error {
+            message: string("message")?,
+            code: i32::try_from(number("code")?).map_err(|_| invalid("Invalid error code"))?,
+            ..Default::default()
+        })),
+        "range" => Ok(Message::Range(RangeStart {
+            session_id: string("session_id")?,
+            offset: number("offset")?,
+            length: number("length")?,
+        })),
+        "batch_end" => Ok(Message::BatchEnd),
+        other => Err(NcpError::Protocol(format!("Unknown JSON message type: {}", other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::ErrorCode;
+    use std::io::Cursor;
+
+    fn read_header(cursor: &mut Cursor<Vec<u8>>) -> (u8, u32) {
+        let msg_type = read_message_type(cursor).unwrap();
+        let len = read_message_length(cursor).unwrap();
+        (msg_type, len)
+    }
+
+    fn file_meta(name: &str, size: u64) -> Meta {
+        Meta {
+            session_id: "0123abcd".to_string(),
+            file: Some(FileMeta {
+                name: name.to_string(),
+                size,
+                is_dir: false,
+                mode: 0o640,
+                checksum_alg: "sha256".to_string(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_meta_roundtrip() {
+        let mut sent = file_meta("dir/file.txt", 12345);
+        sent.file.as_mut().unwrap().attrs.insert("user.origin".to_string(), "6869".to_string());
+        let meta = sent.file.clone().unwrap();
+        let mut buf = Vec::new();
+        write_meta(&mut buf, &sent).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        let (msg_type, len) = read_header(&mut cursor);
+        assert_eq!(msg_type, MSG_META);
+        let fixed = 8 + 1 + 1 + 1 + 4 + 13;
+        let strings = 4 + sent.session_id.len() + 4 + meta.name.len() + 4 + meta.checksum_alg.len();
+        let checksum = 4 + meta.checksum.len();
+        let raw_name = 4 + meta.raw_name.len();
+        let attrs = 4 + 4 + "user.origin".len() + 4 + "6869".len();
+        assert_eq!(len as usize, strings + fixed + checksum + raw_name + 3 + attrs);
+
+        let received = read_meta(&mut cursor, len).unwrap();
+        assert_eq!(received.session_id, sent.session_id);
+        let decoded = received.file.unwrap();
+        assert_eq!(decoded.name, meta.name);
+        assert_eq!(decoded.mode, 0o640);
+        assert_eq!(decoded.size, meta.size);
+        assert_eq!(decoded.checksum_alg, "sha256");
+        assert_eq!(decoded.attrs, meta.attrs);
+        assert!(!decoded.is_dir);
+    }
+
+    #[test]
+    fn test_entry_name_checks_raw_name() {
+        let mut meta = file_meta("caf\u{fffd}.txt", 3).file.unwrap();
+        meta.raw_name = b"caf\xe9.txt".to_vec();
+        let name = entry_name(&meta).unwrap();
+        #[cfg(unix)]
+        assert_eq!(raw_name(&name), meta.raw_name);
+        assert_eq!(name.to_string_lossy(), meta.name);
+
+        // Bytes that would be saved under another name than the one checked.
+        meta.raw_name = b"../\xe9.txt".to_vec();
+        assert!(entry_name(&meta).is_err());
+        assert!(raw_name(Path::new("plain.txt")).is_empty());
+    }
+
+    #[test]
+    fn test_transfer_result_roundtrip() {
+        let result = TransferResult {
+            ok: false,
+            received_bytes: 99,
+            reason: "disk on fire".to_string(),
+            code: ErrorCode::ErrNoSpace as i32,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_transfer_result(&mut buf, &result).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_header(&mut cursor).0, MSG_TRANSFER_RESULT);
+        let decoded = read_transfer_result(&mut cursor).unwrap();
+        assert!(!decoded.ok);
+        assert_eq!(decoded.received_bytes, 99);
+        assert_eq!(decoded.reason, "disk on fire");
+        assert_eq!(decoded.code, ErrorCode::ErrNoSpace as i32);
+    }
+
+    #[test]
+    fn test_mirror_list_roundtrip() {
+        let list = MirrorList {
+            paths: vec!["a".to_string(), "a/b.txt".to_string()],
+            dry_run: true,
+            more: true,
+        };
+        let mut buf = Vec::new();
+        write_mirror_list(&mut buf, &list).unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_header(&mut cursor).0, MSG_MIRROR_LIST);
+        assert_eq!(read_mirror_list(&mut cursor).unwrap(), list);
+    }
+
+    #[test]
+    fn test_mirror_list_parts_fit_the_smallest_cap() {
+        // Control characters take six bytes each as JSON.
+        let paths: Vec<String> = (0..20_000).map(|i| format!("dir/\u{1}\u{2}{:0>60}", i)).collect();
+        let parts = mirror_list_parts(paths.clone(), false);
+        assert!(parts.len() > 1);
+        assert!(parts[..parts.len() - 1].iter().all(|part| part.more));
+        let mut rejoined = Vec::new();
+        for part in parts {
+            assert!(!part.paths.is_empty());
+            for format in [WireFormat::Binary, WireFormat::Json] {
+                let mut buf = Vec::new();
+                write_message(&mut buf, format, &Message::MirrorList(part.clone())).unwrap();
+                assert!(buf.len() <= MIN_MESSAGE_SIZE, "{} bytes", buf.len());
+            }
+            rejoined.extend(part.paths);
+        }
+        assert_eq!(rejoined, paths);
+        assert!(!mirror_list_parts(Vec::new(), true)[0].more);
+    }
+
+    #[test]
+    fn test_json_messages_roundtrip() {
+        let messages = vec![
+            Message::Meta(file_meta("dir/a.txt", 42)),
+            Message::Meta({
+                let mut meta = file_meta("old.txt", 1);
+                meta.file.as_mut().unwrap().mtime = Some(Timestamp {
+                    seconds: -86_400,
+                    nanos: 500,
+                });
+                meta
+            }),
+            Message::PreflightFail(PreflightFail {
+                reason: "no".to_string(),
+                ..Default::default()
+            }),
+            Message::Meta({
+                let mut meta = file_meta("same.txt", 3);
+                meta.file.as_mut().unwrap().checksum = vec![0xab, 0x01];
+                meta
+            }),
+            Message::Meta({
+                let mut meta = file_meta("tagged.txt", 3);
+                let attrs = &mut meta.file.as_mut().unwrap().attrs;
+                attrs.insert("user.origin".to_string(), "6869".to_string());
+                attrs.insert("user.empty".to_string(), String::new());
+                meta
+            }),
+            Message::Meta({
+                let mut meta = file_meta("caf\u{fffd}.txt", 3);
+                meta.file.as_mut().unwrap().raw_name = b"caf\xe9.txt".to_vec();
+                meta
+            }),
+            Message::Meta({
+                let mut meta = file_meta("*.txt", 0);
+                let file = meta.file.as_mut().unwrap();
+                (file.is_dir, file.contents_only) = (true, true);
+                meta
+            }),
+            Message::Meta({
+                let mut meta = file_meta("tree", 0);
+                let file = meta.file.as_mut().unwrap();
+                (file.is_dir, file.tree_checksum) = (true, true);
+                meta
+            }),
+            Message::Meta({
+                let mut meta = file_meta("small.txt", 5);
+                meta.file.as_mut().unwrap().batched = true;
+                meta
+            }),
+            Message::PreflightOk(PreflightOk {
+                resume_offset: 3,
+                resume_checksum: vec![0x12, 0xef],
+                ..Default::default()
+            }),
+            Message::PreflightFail(PreflightFail {
+                reason: "Already up to date".to_string(),
+                code: ErrorCode::ErrAlreadyPresent as i32,
+                ..Default::default()
+            }),
+            Message::TransferStart(TransferStart {
+                session_id: "0123abcd".to_string(),
+                mode: TransferMode::TransferRaw as i32,
+                file_size: u64::MAX,
+                offset: 42,
+                ..Default::default()
+            }),
+            Message::TransferStart(TransferStart {
+                session_id: "0123abcd".to_string(),
+                mode: TransferMode::TransferRaw as i32,
+                file_size: 8 << 20,
+                streams: 4,
+                ..Default::default()
+            }),
+            Message::Range(RangeStart {
+                session_id: "0123abcd".to_string(),
+                offset: 2 << 20,
+                length: 2 << 20,
+            }),
+            Message::MirrorList(MirrorList {
+                paths: vec!["a".to_string()],
+                dry_run: false,
+                more: false,
+            }),
+            Message::Checksum(FileChecksum {
+                alg: "sha256".to_string(),
+                digest: vec![0x00, 0xff, 0x10],
+            }),
+            Message::TransferResult(TransferResult {
+                ok: false,
+                received_bytes: 7,
+                reason: "Checksum mismatch".to_string(),
+                code: ErrorCode::ErrChecksum as i32,
+                ..Default::default()
+            }),
+            Message::BatchEnd,
+            Message::Done,
+            Message::Ping,
+            Message::Pong,
+            Message::Error(Error {
+                message: "Permission denied".to_string(),
+                code: ErrorCode::ErrPermission as i32,
+                ..Default::default()
+            }),
+        ];
+
+        for format in [WireFormat::Binary, WireFormat::Json] {
+            let mut buf = Vec::new();
+            for msg in &messages {
+                write_message(&mut buf, format, msg).unwrap();
+            }
+            let mut cursor = Cursor::new(buf);
+            for msg in &messages {
+                assert_eq!(&read_message(&mut cursor, format).unwrap(), msg);
+            }
+            assert!(read_next_message(&mut cursor, format).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_json_wire_layout() {
+        let mut buf = Vec::new();
+        let msg = Message::PreflightOk(PreflightOk {
+            destination_exists: false,
+            available_space: 7,
+            ..Default::default()
+        });
+        write_message(&mut buf, WireFormat::Json, &msg).unwrap();
+
+        let body = concat!(
+            r#"{"type":"preflight_ok","destination_exists":false,"#,
+            r#""available_space":7,"resume_offset":0}"#
+        )
+        .as_bytes();
+        assert_eq!(&buf[..4], &(body.len() as u32).to_be_bytes());
+        assert_eq!(&buf[4..], body);
+    }
+
+    #[test]
+    fn test_json_missing_field() {
+        let body = br#"{"type":"meta","name":"x"}"#;
+        let mut buf = (body.len() as u32).to_be_bytes().to_vec();
+        buf.extend_from_slice(body);
+        let err = read_message(&mut Cursor::new(buf), WireFormat::Json).unwrap_err();
+        assert!(err.to_string().contains("size"));
+    }
+
+    #[test]
+    fn test_oversized_length_rejected() {
+        let mut buf = vec![MSG_META];
+        buf.extend_from_slice(&u32::MAX.to_be_bytes());
+        let err = read_message(&mut Cursor::new(buf), WireFormat::Binary).unwrap_err();
+        assert!(err.to_string().contains("exceeds maximum"));
+
+        let err = read_message(&mut Cursor::new(u32::MAX.to_be_bytes().to_vec()), WireFormat::Json)
+            .unwrap_err();
+        assert!(err.to_string().contains("exceeds maximum"));
+    }
+
+    #[test]
+    fn test_oversized_name_rejected() {
+        // A small frame whose name field claims 4 GiB must not be allocated.
+        let mut payload = u32::MAX.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0u8; 9]);
+        let mut buf = vec![MSG_META];
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        assert!(read_message(&mut Cursor::new(buf), WireFormat::Binary).is_err());
+    }
+
+    #[test]
+    fn test_inconsistent_meta_frames_rejected() {
+        let sent = file_meta("dir/file.txt", 12345);
+        let mut buf = Vec::new();
+        write_meta(&mut buf, &sent).unwrap();
+        let payload = buf[5..].to_vec();
+        let reframe = |payload: &[u8]| {
+            let mut buf = vec![MSG_META];
+            buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            buf.extend_from_slice(payload);
+            read_message(&mut Cursor::new(buf), WireFormat::Binary).unwrap_err().to_string()
+        };
+        let name_len_at = 4 + sent.session_id.len();
+
+        // A name longer than the rest of the frame, though far below the cap.
+        let mut long_name = payload.clone();
+        long_name[name_len_at..name_len_at + 4].copy_from_slice(&1000u32.to_be_bytes());
+        let err = reframe(&long_name);
+        assert!(err.contains("Name of 1000 bytes overruns"), "{}", err);
+
+        // A frame that ends inside its fixed fields, or goes on past them.
+        let err = reframe(&payload[..name_len_at + 4 + 12 + 3]);
+        assert!(err.contains("too short for its fields"), "{}", err);
+        let err = reframe(&[payload.as_slice(), b"extra"].concat());
+        assert!(err.contains("has 5 left after its fields"), "{}", err);
+
+        let mut many_attrs = payload.clone();
+        let count_at = many_attrs.len() - 4;
+        many_attrs[count_at..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(reframe(&many_attrs).contains("attributes overrun"));
+
+        // The reason of a PreflightFail is held to its frame in the same way.
+        let mut buf = vec![MSG_PREFLIGHT_FAIL];
+        buf.extend_from_slice(&5u32.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 200, 0]);
+        let err = read_message(&mut Cursor::new(buf), WireFormat::Binary).unwrap_err().to_string();
+        assert!(err.contains("Reason of 200 bytes overruns the 1 left"), "{}", err);
+    }
+
+    #[test]
+    fn test_truncated_message() {
+        let mut cursor = Cursor::new(vec![0u8, 0, 0, 10, b'a']);
+        assert!(read_meta(&mut cursor, 5).is_err());
+    }
+}
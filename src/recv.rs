@@ -0,0 +1,3249 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use prost_types::Timestamp;
+
+use crate::checksum::{
+    calculate_file_checksum, digests_equal, hash_prefix, to_hex, ChecksumAlg, StreamingChecksum,
+    RETRY_CAPABILITY,
+};
+use crate::compress::{self, BodyReader, SharedDecoder};
+use crate::dedup::{self, Originals};
+use crate::diskspace::{Reservation, SpaceLedger};
+use crate::events::{self, Summary};
+use crate::handshake;
+use crate::interrupt;
+use crate::json::Json;
+use crate::keepalive::{self, Keepalive};
+use crate::net::{self, Stream};
+use crate::parallel;
+use crate::progress::{self, ProgressReporter};
+use crate::proto::{
+    ErrorCode, FileMeta, PreflightFail, PreflightOk, TransferMode, TransferResult, TransferStart,
+};
+use crate::protocol::{
+    entry_name, read_control, read_next_control, write_message, FileChecksum, Message, MirrorList,
+    WireFormat, UNKNOWN_SIZE,
+};
+use crate::retry::{self, Retrying};
+use crate::types::{DedupMode, MirrorMode, NcpError, OverwriteMode, RecvArgs, Result, STDIO_PATH};
+use crate::utils::{format_bytes, ProgressTicker};
+use crate::xattrs;
+
+/// Receive what `args` describe and return what became of each file. With
+/// `--keep-alive` this only returns if accepting connections fails, and
+/// reports nothing.
+pub fn execute(args: RecvArgs) -> Result<Summary> {
+    check_destination(&args)?;
+
+    if let Some(host) = &args.host {
+        let stream = net::connect(host, args.port, args.family, args.timeout, None)?;
+        status!("Connection established with {}:{}", host, args.port);
+        let (ledger, temps) = (SpaceLedger::new(), TempFiles::default());
+        return handle_connection(stream, &args, None, &ledger, &temps).map_err(net::describe);
+    }
+
+    let (listener, port) = net::listen(args.family, args.port)?;
+    if args.keep_alive {
+        events::listening(port);
+        status!("Serving on port {} (Ctrl-C to stop)", port);
+        // Shared by every connection handler so concurrent transfers cannot
+        // each claim the same free space.
+        serve(&listener, &args, &SpaceLedger::new(), &TempFiles::default());
+        return Ok(Summary::new());
+    }
+    receive_one(&listener, &args)
+}
+
+/// Take one transfer from the first sender to connect to `listener`, as
+/// `recv` without `--keep-alive` does.
+pub fn receive_on(listener: &TcpListener, args: &RecvArgs) -> Result<Summary> {
+    check_destination(args)?;
+    receive_one(listener, args)
+}
+
+fn receive_one(listener: &TcpListener, args: &RecvArgs) -> Result<Summary> {
+    let port = listener.local_addr()?.port();
+    events::listening(port);
+    status!("Listening on port {}", port);
+
+    let (stream, peer) = listener.accept()?;
+    status!("Connection established with {}", peer);
+    net::configure(&stream, args.timeout)?;
+    let (ledger, temps) = (SpaceLedger::new(), TempFiles::default());
+    // Nobody else accepts from the listener, so a file split over more
+    // connections (`send --parallel`) can take them from it.
+    handle_connection(stream, args, Some(listener), &ledger, &temps).map_err(net::describe)
+}
+
+/// Fail early, before any connection, if the directory that would hold
+/// `dst` is missing and `--mkdir` was not given, or an `--allow-root` is.
+/// `dst` itself may be missing: receiving creates it.
+fn check_destination(args: &RecvArgs) -> Result<()> {
+    for root in &args.allow_roots {
+        if !root.is_dir() {
+            return Err(format!("--allow-root {} is not a directory", root.display()).into());
+        }
+    }
+    if args.mkdir || args.writes_stdout() {
+        return Ok(());
+    }
+    match args.dst.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => Err(format!(
+            "Destination directory {} does not exist (use --mkdir to create it)",
+            parent.display()
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Printed after each connection in `--keep-alive` mode.
+const SEPARATOR: &str = "----------------------------------------";
+
+/// `--keep-alive`: accept connections until the process is killed, each on
+/// its own thread. A failed transfer is reported and does not stop the
+/// server.
+fn serve(listener: &TcpListener, args: &RecvArgs, ledger: &SpaceLedger, temps: &TempFiles) {
+    thread::scope(|scope| {
+        for incoming in listener.incoming() {
+            let stream = match incoming {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            scope.spawn(move || serve_connection(stream, args, ledger, temps));
+        }
+    });
+}
+
+fn serve_connection(stream: TcpStream, args: &RecvArgs, ledger: &SpaceLedger, temps: &TempFiles) {
+    let peer = net::peer_name(&stream);
+    status!("Connection established with {}", peer);
+
+    let result = match net::configure(&stream, args.timeout) {
+        Ok(()) => handle_connection(stream, args, None, ledger, temps),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        let e = net::describe(e);
+        eprintln!("Transfer from {} failed: {}", peer, e);
+        events::error(&e.to_string());
+    }
+    status!("{}", SEPARATOR);
+}
+
+/// Temp files being written by some connection. Clones share the same set,
+/// like `SpaceLedger`, so two connections receiving the same destination
+/// never write to the same temp file.
+#[derive(Debug, Clone, Default)]
+struct TempFiles {
+    in_use: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+/// Distinguishes fallback temp names within this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// The temp file a transfer into `final_path` normally writes, and the one
+/// `--resume` continues from.
+fn temp_path_for(final_path: &Path) -> PathBuf {
+    let mut name = final_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".ncp_temp");
+    final_path.with_file_name(name)
+}
+
+/// The journal `--in-place` keeps next to a file while rewriting it. A file
+/// with one beside it was cut off mid-write, and holds neither its old
+/// contents nor the new.
+fn journal_path_for(final_path: &Path) -> PathBuf {
+    let mut name = final_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".ncp_incomplete");
+    final_path.with_file_name(name)
+}
+
+/// Whether `path` was left half-written by an interrupted `--in-place`
+/// transfer. Such a file is never taken for a copy of the sender's.
+fn is_incomplete(path: &Path) -> bool {
+    journal_path_for(path).exists()
+}
+
+/// Drop the journal of a file that is whole again, if it had one.
+fn clear_incomplete(path: &Path) -> io::Result<()> {
+    match fs::remove_file(journal_path_for(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl TempFiles {
+    /// Claim and open a temp file for `final_path`. The usual name is used
+    /// unless another connection holds it, in this process or (through a
+    /// file lock) any other; then a new file with a unique name is created,
+    /// which is never resumed.
+    fn claim(&self, final_path: &Path) -> io::Result<TempClaim> {
+        let mut in_use = self.in_use.lock().unwrap();
+        let usual = temp_path_for(final_path);
+
+        if !in_use.contains(&usual) {
+            let existed = usual.exists();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&usual)?;
+            let locked = match file.try_lock() {
+                Ok(()) => true,
+                Err(TryLockError::WouldBlock) => false,
+                // Not every filesystem supports locks; rely on the
+                // in-process check alone there.
+                Err(TryLockError::Error(e)) => {
+                    vvlog!("Cannot lock {}: {}", usual.display(), e);
+                    true
+                }
+            };
+            if locked {
+                in_use.insert(usual.clone());
+                return Ok(TempClaim::new(usual, file, true, existed, self.clone()));
+            }
+            vlog!("{} is in use by another receiver", usual.display());
+        }
+
+        loop {
+            let id = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let mut name = final_path.file_name().unwrap_or_default().to_os_string();
+            name.push(format!(".{}-{}.ncp_temp", std::process::id(), id));
+            let path = final_path.with_file_name(name);
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    in_use.insert(path.clone());
+                    return Ok(TempClaim::new(path, file, false, false, self.clone()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Claim the existing file at `final_path` itself for `--in-place`,
+    /// locked like a temp file. Only a file left incomplete can be resumed.
+    fn claim_in_place(&self, final_path: &Path) -> io::Result<TempClaim> {
+        let mut in_use = self.in_use.lock().unwrap();
+        let busy = || {
+            let message = format!("{} is being received by another connection", final_path.display());
+            io::Error::new(io::ErrorKind::ResourceBusy, message)
+        };
+        if in_use.contains(final_path) {
+            return Err(busy());
+        }
+        let file = OpenOptions::new().read(true).write(true).open(final_path)?;
+        if let Err(TryLockError::WouldBlock) = file.try_lock() {
+            return Err(busy());
+        }
+        in_use.insert(final_path.to_path_buf());
+        Ok(TempClaim {
+            path: final_path.to_path_buf(),
+            file,
+            resumable: is_incomplete(final_path),
+            keep: true,
+            files: self.clone(),
+            journal: Some(journal_path_for(final_path)),
+        })
+    }
+}
+
+/// A claimed temp file, released when dropped and deleted with it unless
+/// `keep` is set.
+struct TempClaim {
+    path: PathBuf,
+    /// Open for the whole transfer; for the usual name it holds the lock.
+    file: File,
+    resumable: bool,
+    /// Leave the file behind: a partial from an earlier transfer that this
+    /// one never touched, or a partial of this one for `--resume`.
+    keep: bool,
+    files: TempFiles,
+    /// Set when the claim is the destination itself (`--in-place`), which
+    /// is always kept.
+    journal: Option<PathBuf>,
+}
+
+impl TempClaim {
+    fn new(path: PathBuf, file: File, resumable: bool, keep: bool, files: TempFiles) -> Self {
+        let mut claim = TempClaim {
+            path,
+            file,
+            resumable,
+            keep: true,
+            files,
+            journal: None,
+        };
+        claim.set_keep(keep);
+        claim
+    }
+
+    /// Whether to leave the file behind when the claim is dropped, or when
+    /// the process is interrupted.
+    fn set_keep(&mut self, keep: bool) {
+        self.keep = keep || self.journal.is_some();
+        if self.keep {
+            interrupt::untrack(&self.path);
+        } else {
+            interrupt::track(&self.path);
+        }
+    }
+
+    /// About to change the file. In place, a journal naming the transfer is
+    /// put on disk first, so that a crash from here on is noticed.
+    fn begin_writing(&self, session_id: &str, size: u64) -> io::Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let record = Json::Object(vec![
+            ("session".to_string(), Json::str(session_id)),
+            ("size".to_string(), Json::u64(size)),
+        ]);
+        let mut file = File::create(journal)?;
+        writeln!(file, "{}", record)?;
+        file.sync_all()
+    }
+
+    /// Move the finished file into place. Unless `clobber`, a file already at
+    /// `final_path` is an `AlreadyExists` error and is left untouched. In
+    /// place, the file only has to reach the disk before its journal goes.
+    fn persist(&mut self, final_path: &Path, clobber: bool, fsync: bool) -> io::Result<()> {
+        if self.journal.is_some() {
+            self.file.sync_all()?;
+        } else {
+            if fsync {
+                self.file.sync_all()?;
+            }
+            if clobber {
+                retry::transient("Rename", || fs::rename(&self.path, final_path))?;
+            } else {
+                rename_no_clobber(&self.path, final_path)?;
+            }
+            if fsync {
+                sync_parent(final_path)?;
+            }
+        }
+        // Whatever appears at `path` from now on belongs to someone else.
+        self.set_keep(true);
+        Ok(())
+    }
+}
+
+/// Move `from` to `to` only if nothing is there yet. `rename` would replace
+/// it, but a hard link fails instead, in the same step that checks. Where
+/// hard links are not supported this falls back to a check and a rename,
+/// which leaves a small window open.
+fn rename_no_clobber(from: &Path, to: &Path) -> io::Result<()> {
+    match retry::transient("Link", || fs::hard_link(from, to)) {
+        Ok(()) => retry::transient("Removal", || fs::remove_file(from)),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(e),
+        Err(e) => {
+            vvlog!("Cannot link {} into place ({}), renaming it", to.display(), e);
+            if fs::symlink_metadata(to).is_ok() {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            retry::transient("Rename", || fs::rename(from, to))
+        }
+    }
+}
+
+/// Flush the directory entry a rename into `path` created (`--fsync`).
+/// Only Unix can open a directory to sync it; elsewhere the rename is left
+/// to the file system.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+    File::open(parent.unwrap_or(Path::new(".")))?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl Drop for TempClaim {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+            interrupt::untrack(&self.path);
+        }
+        self.files.in_use.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// What the two ends of one connection agreed on.
+struct Session<'a> {
+    id: String,
+    format: WireFormat,
+    /// How often to ping the sender while we are busy; `None` if it does
+    /// not take pings.
+    keepalive: Option<Duration>,
+    /// The digest over every file of a directory sent with a tree checksum
+    /// (`send --checksum tree`), so far.
+    tree_digest: Cell<Option<StreamingChecksum>>,
+    /// Where the extra connections of a file split over several come from;
+    /// `None` if they cannot be taken.
+    listener: Option<&'a TcpListener>,
+    /// The zstd stream that runs through the shared bodies of the
+    /// connection (`send --compress-shared`).
+    shared_zstd: SharedDecoder,
+    /// A file that fails its checksum is dropped and the sender offers it
+    /// again, rather than ending the transfer. Off once data has gone
+    /// somewhere it cannot be taken back from.
+    retry_files: Cell<bool>,
+    /// The answers to the files of a batch (`send --batch`), encoded and
+    /// held back until the sender ends it; `None` outside a batch.
+    held: RefCell<Option<Vec<u8>>>,
+    /// The files saved so far that later ones are linked to (`--dedup`).
+    originals: RefCell<Originals>,
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    args: &RecvArgs,
+    listener: Option<&TcpListener>,
+    ledger: &SpaceLedger,
+    temps: &TempFiles,
+) -> Result<Summary> {
+    net::tune(&stream, &args.socket)?;
+    let mut stream = match &args.tls {
+        Some(tls) => tls.accept(stream)?,
+        None => Stream::from(stream),
+    };
+    let psk = args.psk.as_deref().map(str::as_bytes);
+    let pull = args.pull.is_some();
+    let listener = listener.filter(|_| !args.writes_stdout());
+    let probe = handshake::accept(
+        &mut stream,
+        psk,
+        pull,
+        args.verify_only,
+        args.delete,
+        listener.is_some(),
+        args.timeout,
+    )?;
+    if let Some(path) = &args.pull {
+        handshake::request_pull(&mut stream, &probe.session_id, path)?;
+    }
+    events::transfer_start(&probe.session_id, &net::peer_name(&stream));
+    // Binary until the sender negotiates otherwise.
+    let mut session = Session {
+        keepalive: keepalive::agreed(&probe.capabilities, probe.keepalive_seconds),
+        id: probe.session_id,
+        format: WireFormat::Binary,
+        tree_digest: Cell::new(None),
+        listener,
+        shared_zstd: SharedDecoder::new()?,
+        retry_files: Cell::new(probe.capabilities.iter().any(|c| c == RETRY_CAPABILITY)),
+        held: RefCell::new(None),
+        originals: RefCell::new(Originals::default()),
+    };
+    take_entries(&mut stream, &mut session, args, ledger, temps)
+        .inspect_err(|e| tell_sender(&mut stream, &session, e))
+}
+
+/// Answer the sender with `msg`: at once, or, in a batch, once it ends.
+fn answer(stream: &mut Stream, session: &Session, msg: &Message) -> Result<()> {
+    match session.held.borrow_mut().as_mut() {
+        Some(held) => write_message(held, session.format, msg),
+        None => write_message(stream, session.format, msg),
+    }
+}
+
+/// Tell the sender why we are giving up, which it would otherwise only see
+/// as the connection closing. A sender that is gone already is not missed.
+fn tell_sender(stream: &mut Stream, session: &Session, err: &NcpError) {
+    let error = crate::proto::Error {
+        session_id: session.id.clone(),
+        code: err.code() as i32,
+        message: format!("The receiver failed: {}", err),
+    };
+    if let Err(e) = write_message(stream, session.format, &Message::Error(error)) {
+        vvlog!("Could not tell the sender what failed: {}", e);
+    }
+}
+
+/// Take entries from the sender until it is `Done`.
+fn take_entries(
+    stream: &mut Stream,
+    session: &mut Session,
+    args: &RecvArgs,
+    ledger: &SpaceLedger,
+    temps: &TempFiles,
+) -> Result<Summary> {
+    // Set once the sender announces a directory root, to where it was
+    // placed; later entries are relative to it instead of naming a single
+    // file.
+    let mut tree: Option<PathBuf> = None;
+    let mut summary = Summary::new();
+    // Directory times are restored last: creating entries inside a
+    // directory would bump its mtime again.
+    let mut dir_times = Vec::new();
+    let mut verification = Verification::default();
+    let mut mirrored = false;
+    // The parts of a mirror list before its last.
+    let mut listed = Vec::new();
+
+    loop {
+        let format = session.format;
+        let Some(msg) = read_next_control(stream, format)? else {
+            let message = "Connection closed before the sender finished the transfer";
+            return Err(NcpError::Protocol(message.to_string()));
+        };
+
+        match msg {
+            Message::Done => break,
+            Message::Format(name) => {
+                // The reply is binary either way; we only switch if we know the format.
+                let accepted = WireFormat::parse(&name).unwrap_or(WireFormat::Binary);
+                let reply = Message::Format(accepted.name().to_string());
+                write_message(stream, WireFormat::Binary, &reply)?;
+                vlog!("Using {} control format", accepted.name());
+                session.format = accepted;
+            }
+            Message::Meta(meta) => {
+                handshake::check_session(&session.id, &meta.session_id, "Meta")?;
+                let meta = meta.file.unwrap_or_default();
+                if meta.batched {
+                    session.held.borrow_mut().get_or_insert_with(Vec::new);
+                }
+                let strip = args.strip_components;
+                let name = Path::new(&meta.name);
+                let stripped_away = strip > 0 && strip_components(name, strip).is_none();
+                if tree.is_some() && stripped_away {
+                    pass_over(stream, session, args, &meta)?;
+                    if meta.batched {
+                        skip_batched(stream, session, &meta)?;
+                    }
+                    if !meta.is_dir {
+                        summary.skipped(&meta.name, meta.size);
+                    }
+                } else if meta.is_dir {
+                    let dir_path = handle_directory_entry(
+                        stream,
+                        session,
+                        args,
+                        &meta,
+                        tree.as_deref(),
+                        ledger,
+                    )?;
+                    if let Some(mtime) = meta.mtime.filter(|_| !args.verify_only) {
+                        dir_times.push((dir_path.clone(), mtime));
+                    }
+                    if tree.is_none() && meta.tree_checksum {
+                        let alg = ChecksumAlg::parse(&meta.checksum_alg)?;
+                        vlog!("Verifying the tree with one {} checksum at the end", alg.name());
+                        session.tree_digest.set(Some(StreamingChecksum::new(alg)));
+                    }
+                    tree.get_or_insert(dir_path);
+                } else if args.verify_only {
+                    let tree = tree.as_deref();
+                    verification.record(verify_file_entry(stream, session, args, &meta, tree)?);
+                    summary.skipped(&meta.name, meta.size);
+                } else {
+                    let received = handle_file_entry(
+                        stream,
+                        session,
+                        args,
+                        &meta,
+                        tree.as_deref(),
+                        ledger,
+                        temps,
+                    )
+                    .inspect_err(|e| {
+                        let _ = args.manifest.failed(&meta.name, meta.size, &e.to_string());
+                    });
+                    let received = match received {
+                        Err(NcpError::ChecksumMismatch(reason)) if session.retry_files.get() => {
+                            eprintln!("{}; the sender may offer it again", reason);
+                            continue;
+                        }
+                        received => received?,
+                    };
+                    match received {
+                        Some((bytes, checksum)) => summary.transferred(&meta.name, bytes, &checksum),
+                        None if meta.batched => {
+                            skip_batched(stream, session, &meta)?;
+                            summary.skipped(&meta.name, meta.size);
+                        }
+                        None => summary.skipped(&meta.name, meta.size),
+                    }
+                }
+            }
+            Message::BatchEnd => {
+                // All the answers of the batch go out together.
+                let held = session.held.take().unwrap_or_default();
+                vvlog!("Answering a batch ({} bytes)", held.len());
+                stream.write_all(&held)?;
+            }
+            Message::MirrorList(list) if list.more => listed.extend(list.paths),
+            Message::MirrorList(mut list) => {
+                listed.append(&mut list.paths);
+                list.paths = std::mem::take(&mut listed);
+                // Reported, but never carried out, when only verifying.
+                list.dry_run |= args.verify_only || args.delete == MirrorMode::DryRun;
+                mirrored = true;
+                if args.strip_components > 0 {
+                    let strip = |path: &String| {
+                        let rest: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+                        rest.get(args.strip_components..).filter(|r| !r.is_empty()).map(|r| r.join("/"))
+                    };
+                    list.paths = list.paths.iter().filter_map(strip).collect();
+                }
+                handle_mirror_list(stream, session, tree.as_deref(), &list)?;
+            }
+            Message::Checksum(expected) if tree.is_some() => {
+                let Some(digest) = session.tree_digest.take() else {
+                    return Err(NcpError::Protocol("Unexpected tree checksum".to_string()));
+                };
+                verify_tree(stream, session, &expected, digest, summary.total_bytes())?;
+            }
+            other => return Err(NcpError::Protocol(format!("Unexpected {} message", other.name()))),
+        }
+    }
+
+    for (dir_path, mtime) in dir_times.into_iter().rev() {
+        apply_mtime(&dir_path, mtime)?;
+    }
+    // Deleting against anything less than the sender's full list would
+    // take files for extraneous that are not.
+    if args.delete != MirrorMode::Off && tree.is_some() && !mirrored {
+        status!("Nothing was deleted (--delete): the sender did not send its list of entries");
+    }
+
+    if args.verify_only {
+        status!("{}", verification.describe());
+        events::done(&summary);
+        return verification.check().map(|()| summary);
+    }
+
+    status!("Transfer finished");
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Read past the `TransferStart`, body and `Checksum` of a batched file
+/// that was declined, which the sender sent on without waiting to hear it.
+fn skip_batched(stream: &mut Stream, session: &Session, file_meta: &FileMeta) -> Result<()> {
+    let mode = TransferMode::try_from(file_meta.transfer_mode).ok().filter(|&m| compress::supported(m));
+    let Some(mode) = mode else {
+        let why = format!("Cannot read past {}: unsupported transfer mode", file_meta.name);
+        return Err(NcpError::Protocol(why));
+    };
+    let start = read_transfer_start(stream, session, file_meta, mode, 0)?;
+    vvlog!("Reading past {}", file_meta.name);
+    let mut body = open_body(stream, session, mode, &start)?;
+    io::copy(&mut body, &mut io::sink())?;
+    body.finish()?;
+    read_trailer(stream, session.format)?;
+    Ok(())
+}
+
+/// Reject sender-supplied names that could resolve outside `dst_path`:
+/// absolute paths, Windows drive or UNC prefixes, and `..` components.
+/// Both separators are checked so the rule does not depend on the host OS.
+fn validate_entry_name(name: &str) -> Result<()> {
+    let bytes = name.as_bytes();
+    let absolute = name.starts_with('/') || name.starts_with('\\');
+    let drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    if name.is_empty() || absolute || drive {
+        return Err(NcpError::Protocol(format!("Refusing unsafe entry name: {:?}", name)));
+    }
+    if name.split(['/', '\\']).any(|component| component == "..") {
+        let message = format!("Refusing entry name with '..' component: {:?}", name);
+        return Err(NcpError::Protocol(message));
+    }
+    Ok(())
+}
+
+/// `name` without its first `count` components (`--strip-components`), or
+/// `None` if that leaves nothing of it.
+fn strip_components(name: &Path, count: usize) -> Option<PathBuf> {
+    let normal = name.components().filter(|c| matches!(c, Component::Normal(_)));
+    let rest: PathBuf = normal.skip(count).collect();
+    (!rest.as_os_str().is_empty()).then_some(rest)
+}
+
+/// Answer an entry that `--strip-components` leaves no name for: a
+/// directory is acknowledged without being created, a file declined.
+fn pass_over(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    file_meta: &FileMeta,
+) -> Result<()> {
+    let count = args.strip_components;
+    vlog!("Passing over {}: nothing is left after --strip-components {}", file_meta.name, count);
+    if file_meta.is_dir {
+        return answer(stream, session, &Message::PreflightOk(PreflightOk::default()));
+    }
+    let reason = format!("Fewer than {} leading components to strip", count + 1);
+    decline(stream, session, args, file_meta, ErrorCode::ErrInvalidArg, &reason).map(drop)
+}
+
+/// The receiver options that decide where entries land.
+#[derive(Debug, Default)]
+struct Placement<'a> {
+    /// `--as`
+    output_name: Option<&'a str>,
+    /// `--into`
+    into: bool,
+    /// `--strip-components`
+    strip: usize,
+}
+
+impl<'a> Placement<'a> {
+    fn of(args: &'a RecvArgs) -> Self {
+        Placement {
+            output_name: args.output_name.as_deref(),
+            into: args.into,
+            strip: args.strip_components,
+        }
+    }
+}
+
+/// Map an incoming entry to its location on disk.
+///
+/// The root of a directory transfer is created as `dst_path`, or inside it
+/// under its own name if `dst_path` is an existing directory, as `cp -r`
+/// would. With `into` (`--into`), or for a root that only groups wildcard
+/// matches, it maps to `dst_path` itself and the tree is merged into what
+/// is there. Entries inside the tree are joined onto `dst_path`, which is
+/// then the root's location, once their first `strip` components are
+/// dropped. A single file lands inside `dst_path` if it is an existing
+/// directory, otherwise at `dst_path`, replacing a file there as the
+/// overwrite policy allows. With `output_name` (`--as`), a single file is
+/// saved under that name inside `dst_path` instead, and a directory is
+/// refused.
+///
+/// For a root directory, `dst_path` holding a file is an error, and an
+/// existing directory, empty or not, gets the root inside it; whatever is
+/// there under the same name is merged with. `check_kind` then refuses a
+/// location where an entry of the other kind is in the way.
+fn determine_final_path(
+    dst_path: &Path,
+    file_meta: &FileMeta,
+    in_directory: bool,
+    placement: &Placement,
+) -> Result<PathBuf> {
+    let file_name = &file_meta.name;
+    let Placement { output_name, into, strip } = *placement;
+
+    if in_directory {
+        validate_entry_name(file_name)?;
+        let name = entry_name(file_meta)?;
+        if strip == 0 {
+            return Ok(dst_path.join(name));
+        }
+        let stripped = strip_components(&name, strip).ok_or_else(|| {
+            format!("Nothing is left of {} after --strip-components {}", file_name, strip)
+        })?;
+        return Ok(dst_path.join(stripped));
+    }
+
+    if let Some(output_name) = output_name {
+        if file_meta.is_dir {
+            return Err(
+                format!("--as only applies to single files, but {} is a directory", file_name).into(),
+            );
+        }
+        if dst_path.is_file() {
+            return Err(format!("--as needs a directory, but {} is a file", dst_path.display()).into());
+        }
+        validate_entry_name(output_name)?;
+        return Ok(dst_path.join(output_name));
+    }
+
+    if file_meta.is_dir {
+        if dst_path.as_os_str() == STDIO_PATH {
+            return Err(format!("Cannot write directory {} to stdout", file_name).into());
+        }
+        if dst_path.exists() && !dst_path.is_dir() {
+            return Err(NcpError::DeclinedOverwrite(format!(
+                "Cannot receive directory {} into {}: it is a file; remove it or choose another DST",
+                file_name,
+                dst_path.display()
+            )));
+        }
+        // A root without a name of its own, such as `.`, can only merge.
+        if dst_path.is_dir() && !into && !file_meta.contents_only && file_name != "." {
+            if file_name.contains(['/', '\\']) {
+                let message = format!("Refusing directory name with a separator: {:?}", file_name);
+                return Err(NcpError::Protocol(message));
+            }
+            validate_entry_name(file_name)?;
+            return Ok(dst_path.join(entry_name(file_meta)?));
+        }
+        return Ok(dst_path.to_path_buf());
+    }
+
+    if dst_path.is_dir() {
+        validate_entry_name(file_name)?;
+        Ok(dst_path.join(entry_name(file_meta)?))
+    } else {
+        Ok(dst_path.to_path_buf())
+    }
+}
+
+/// What `path` is if it is a FIFO, device or socket, which a received file
+/// must not be renamed over.
+#[cfg(unix)]
+fn special_kind(path: &Path) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = fs::metadata(path).ok()?.file_type();
+    if file_type.is_fifo() {
+        Some("a FIFO")
+    } else if file_type.is_char_device() {
+        Some("a character device")
+    } else if file_type.is_block_device() {
+        Some("a block device")
+    } else if file_type.is_socket() {
+        Some("a socket")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+/// `path` if it lies inside one of the `--allow-root` directories once
+/// symlinks are resolved; refused otherwise. Whatever does not exist yet is
+/// resolved as far as it does, and the rest may not climb out with `..` or
+/// be a dangling symlink, whose target could be anywhere.
+fn check_allowed(args: &RecvArgs, path: PathBuf) -> Result<PathBuf> {
+    if args.allow_roots.is_empty() {
+        return Ok(path);
+    }
+    let outside = || {
+        let reason = format!("{} is outside every --allow-root", path.display());
+        NcpError::Io(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+    };
+    let resolved = resolve(&path).map_err(|_| outside())?;
+    for root in &args.allow_roots {
+        let root = fs::canonicalize(root)
+            .map_err(|e| format!("Cannot use --allow-root {}: {}", root.display(), e))?;
+        if resolved.starts_with(&root) {
+            return Ok(path);
+        }
+    }
+    Err(outside())
+}
+
+/// `path` made absolute with its symlinks resolved, as far as it exists.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let absolute = std::path::absolute(path)?;
+    let mut existing = absolute.as_path();
+    let mut missing = Vec::new();
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(mut resolved) => {
+                resolved.extend(missing.iter().rev());
+                return Ok(resolved);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && fs::symlink_metadata(existing).is_err() => {
+                let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                    return Err(e);
+                };
+                missing.push(name);
+                existing = parent;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Refuse to put an entry where one of the other kind already is: a file
+/// never replaces a directory, nor a directory a file, whatever the
+/// overwrite policy.
+fn check_kind(path: &Path, file_meta: &FileMeta) -> Result<()> {
+    let Ok(existing) = fs::metadata(path) else {
+        return Ok(());
+    };
+    let reason = match (file_meta.is_dir, existing.is_dir()) {
+        (false, true) => format!(
+            "{} is a directory, so file {} cannot be saved there; use --as to give it another name",
+            path.display(),
+            file_meta.name
+        ),
+        (true, false) => format!(
+            "{} is a file, so directory {} cannot be created there",
+            path.display(),
+            file_meta.name
+        ),
+        _ => return Ok(()),
+    };
+    Err(NcpError::DeclinedOverwrite(reason))
+}
+
+/// Create a directory entry. The root of a transfer carries the total size
+/// of its files, which must fit before anything is accepted, so a transfer
+/// that cannot fit fails up front instead of when the disk fills.
+fn handle_directory_entry(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    file_meta: &FileMeta,
+    tree: Option<&Path>,
+    ledger: &SpaceLedger,
+) -> Result<PathBuf> {
+    let in_directory = tree.is_some();
+    let dst = tree.unwrap_or(&args.dst);
+    let dir_path = determine_final_path(dst, file_meta, in_directory, &Placement::of(args));
+    let dir_path = match dir_path.and_then(|path| check_allowed(args, path)) {
+        Ok(path) => path,
+        Err(e) => return refuse(stream, session, &file_meta.name, e),
+    };
+    if args.verify_only {
+        // Nothing is created: the files of a missing directory are simply
+        // reported missing.
+        let ok = PreflightOk {
+            destination_exists: dir_path.is_dir(),
+            ..Default::default()
+        };
+        answer(stream, session, &Message::PreflightOk(ok))?;
+        return Ok(dir_path);
+    }
+    if let Err(e) = check_kind(&dir_path, file_meta) {
+        return refuse(stream, session, &file_meta.name, e);
+    }
+
+    let mut available_space = 0;
+    if !in_directory && file_meta.size > 0 {
+        // Only checked here: each file reserves its own share as it comes.
+        let existing = dir_path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("."));
+        match ledger.reserve(existing, file_meta.size) {
+            Ok(reservation) => available_space = reservation.available(),
+            Err(e) => return refuse(stream, session, &file_meta.name, e),
+        }
+    }
+
+    vlog!("Creating directory {}", dir_path.display());
+    fs::create_dir_all(&dir_path)?;
+
+    let ok = PreflightOk {
+        destination_exists: true,
+        available_space,
+        ..Default::default()
+    };
+    answer(stream, session, &Message::PreflightOk(ok))?;
+    Ok(dir_path)
+}
+
+/// Turn down an entry the transfer cannot go on without, telling the
+/// sender why before giving up on the connection.
+fn refuse<T>(stream: &mut Stream, session: &Session, name: &str, err: NcpError) -> Result<T> {
+    eprintln!("Rejecting {}: {}", name, err);
+    let fail = PreflightFail {
+        code: err.code() as i32,
+        reason: err.to_string(),
+        ..Default::default()
+    };
+    answer(stream, session, &Message::PreflightFail(fail))?;
+    Err(err)
+}
+
+/// Receive one file. Returns the number of bytes written and their checksum
+/// in hex, or `None` if the file was declined during preflight.
+fn handle_file_entry(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    file_meta: &FileMeta,
+    tree: Option<&Path>,
+    ledger: &SpaceLedger,
+    temps: &TempFiles,
+) -> Result<Option<(u64, String)>> {
+    let format = session.format;
+    let mode = match TransferMode::try_from(file_meta.transfer_mode) {
+        Ok(mode) if compress::supported(mode) => mode,
+        _ => {
+            let reason = format!("Unsupported transfer mode {}", file_meta.transfer_mode);
+            eprintln!("Rejecting {}: {}", file_meta.name, reason);
+            return decline(stream, session, args, file_meta, ErrorCode::ErrInvalidArg, &reason);
+        }
+    };
+    let Ok(alg) = ChecksumAlg::parse(&file_meta.checksum_alg) else {
+        let reason = format!("Unsupported checksum algorithm {:?}", file_meta.checksum_alg);
+        eprintln!("Rejecting {}: {}", file_meta.name, reason);
+        return decline(stream, session, args, file_meta, ErrorCode::ErrInvalidArg, &reason);
+    };
+    // Standard input, of unknown size, is held to the limit as it arrives.
+    if let Some(limit) = args.max_file_size
+        && file_meta.size != UNKNOWN_SIZE
+        && file_meta.size > limit
+    {
+        let reason = too_large(file_meta.size, limit);
+        eprintln!("Rejecting {}: {}", file_meta.name, reason);
+        return decline(stream, session, args, file_meta, ErrorCode::ErrTooLarge, &reason);
+    }
+    if args.writes_stdout() {
+        let out = io::stdout().lock();
+        return receive_streaming(stream, session, args, file_meta, mode, alg, out, "stdout");
+    }
+
+    let dst = tree.unwrap_or(&args.dst);
+    let final_path = determine_final_path(dst, file_meta, tree.is_some(), &Placement::of(args))?;
+    let final_path = match check_allowed(args, final_path) {
+        Ok(path) => path,
+        Err(e) => return refuse(stream, session, &file_meta.name, e),
+    };
+    if let Err(e) = check_kind(&final_path, file_meta) {
+        eprintln!("Rejecting {}: {}", file_meta.name, e);
+        return decline(stream, session, args, file_meta, ErrorCode::ErrExists, &e.to_string());
+    }
+    // Never renamed over: a FIFO or device is written into, or refused.
+    if let Some(kind) = special_kind(&final_path) {
+        if !args.write_special {
+            let reason = format!(
+                "{} is {}; use --write-special to write into it, or choose another DST",
+                final_path.display(),
+                kind
+            );
+            eprintln!("Rejecting {}: {}", file_meta.name, reason);
+            return decline(stream, session, args, file_meta, ErrorCode::ErrExists, &reason);
+        }
+        // A FIFO only opens once something reads from it.
+        let opened = {
+            let _keepalive = Keepalive::start(stream, format, session.keepalive);
+            OpenOptions::new().write(true).open(&final_path)
+        };
+        let out = match opened {
+            Ok(out) => out,
+            Err(e) => {
+                let e = NcpError::from(e);
+                eprintln!("Rejecting {}: cannot open {}: {}", file_meta.name, final_path.display(), e);
+                return decline(stream, session, args, file_meta, e.code(), &e.to_string());
+            }
+        };
+        let target = final_path.display().to_string();
+        return receive_streaming(stream, session, args, file_meta, mode, alg, out, &target);
+    }
+    let incomplete = is_incomplete(&final_path);
+    let present = !incomplete && {
+        let _keepalive = Keepalive::start(stream, format, session.keepalive);
+        already_present(&final_path, file_meta, alg)
+    };
+    if present {
+        vlog!("{} is unchanged, skipping it", final_path.display());
+        let fail = PreflightFail {
+            code: ErrorCode::ErrAlreadyPresent as i32,
+            reason: "Already up to date".to_string(),
+            ..Default::default()
+        };
+        answer(stream, session, &Message::PreflightFail(fail))?;
+        events::file_skipped(&file_meta.name, "Already up to date");
+        args.manifest.skipped(&file_meta.name, file_meta.size, "Already up to date")?;
+        return Ok(None);
+    }
+    let destination_exists = final_path.exists();
+    let overwrite = args.overwrite.resolve(file_meta.overwrite);
+    // Continuing it in place finishes the transfer that started it.
+    let continues = args.resume && args.in_place;
+    if destination_exists && incomplete && overwrite == OverwriteMode::No && !continues {
+        // --overwrite no replaces nothing, this included. Its journal stays,
+        // so it is still never taken for a complete copy.
+        let reason = "Left incomplete by an interrupted transfer, and --overwrite no keeps it";
+        status!("Skipping existing file {}: {}", final_path.display(), reason);
+        return decline(stream, session, args, file_meta, ErrorCode::ErrExists, reason);
+    } else if destination_exists && incomplete {
+        // Whatever it held was already given up for the last transfer.
+        status!("Replacing {}, left incomplete by an interrupted transfer", final_path.display());
+    } else if destination_exists {
+        if overwrite != args.overwrite {
+            vlog!("Using sender's overwrite policy ({:?}) for {}", overwrite, file_meta.name);
+        }
+        let exists = "Destination file already exists";
+        let refusal = match overwrite {
+            OverwriteMode::Yes => None,
+            OverwriteMode::No => Some(exists),
+            OverwriteMode::Ask => {
+                let _keepalive = Keepalive::start(stream, format, session.keepalive);
+                (!prompt_overwrite(&final_path)?).then_some(exists)
+            }
+            OverwriteMode::Newer | OverwriteMode::Older => {
+                compare_mtime(&final_path, file_meta, overwrite)?
+            }
+        };
+        if let Some(reason) = refusal {
+            status!("Skipping existing file {}: {}", final_path.display(), reason);
+            return decline(stream, session, args, file_meta, ErrorCode::ErrExists, reason);
+        }
+    }
+    // Only what was agreed to may be replaced. A file that appears after the
+    // check above, under any other policy, is kept instead.
+    let clobber = destination_exists || overwrite == OverwriteMode::Yes;
+
+    let parent = match final_path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    fs::create_dir_all(&parent)?;
+
+    // A partial file from an earlier attempt can be continued rather than
+    // resent, as long as it is not longer than the file being offered. A
+    // batched file is on its way already, from the start.
+    let size_known = file_meta.size != UNKNOWN_SIZE;
+    // A new file takes no more room through a temp file, so only one that
+    // is being replaced is written in place.
+    let in_place = args.in_place && destination_exists;
+    let mut temp = if in_place {
+        temps.claim_in_place(&final_path)?
+    } else {
+        temps.claim(&final_path)?
+    };
+    let temp_path = temp.path.clone();
+    let on_disk = temp.file.metadata().map_or(0, |m| m.len());
+    let resumable = args.resume && temp.resumable && size_known && !file_meta.batched;
+    let partial = match on_disk {
+        len if resumable && len <= file_meta.size => len,
+        _ => 0,
+    };
+    // A sender that sent its checksum up front can check the partial file
+    // against its own first bytes before going on from it.
+    let resume_checksum = if partial > 0 && !file_meta.checksum.is_empty() {
+        let _keepalive = Keepalive::start(stream, format, session.keepalive);
+        let mut prefix = StreamingChecksum::new(alg);
+        hash_prefix(&mut temp.file, partial, &mut prefix)?;
+        temp.file.seek(SeekFrom::Start(0))?;
+        prefix.finalize()
+    } else {
+        Vec::new()
+    };
+
+    // Held until this function returns, success or not. Nothing can be
+    // set aside for a stream of unknown length. Written in place, the file
+    // reuses the space it already takes.
+    let reused = if in_place { on_disk } else { partial };
+    let needed = if size_known { file_meta.size.saturating_sub(reused) } else { 0 };
+    let mut reservation = match ledger.reserve(&parent, needed) {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            eprintln!("Rejecting {}: {}", file_meta.name, e);
+            return decline(stream, session, args, file_meta, e.code(), &e.to_string());
+        }
+    };
+    vvlog!(
+        "Reserved {} for {} ({} reserved in total)",
+        format_bytes(needed),
+        file_meta.name,
+        format_bytes(ledger.reserved())
+    );
+
+    let ok = PreflightOk {
+        destination_exists,
+        available_space: reservation.available(),
+        resume_offset: partial,
+        resume_checksum,
+        ..Default::default()
+    };
+    answer(stream, session, &Message::PreflightOk(ok))?;
+
+    let start = read_transfer_start(stream, session, file_meta, mode, partial)?;
+    let file_size = start.file_size;
+    status!("Receiving {} ({})", file_meta.name, describe_size(file_size));
+    events::file_start(&file_meta.name, file_size);
+
+    // In a tree checksum the bytes go into the digest over the whole tree
+    // instead, and the sender's trailer carries no digest of the file's own.
+    let tree_digest = session.tree_digest.take();
+    let in_tree = tree_digest.is_some();
+    let mut checksum = tree_digest.unwrap_or_else(|| StreamingChecksum::new(alg));
+    if start.offset > 0 {
+        status!("Resuming {} at {}", file_meta.name, format_bytes(start.offset));
+        // The digest must cover the bytes we kept from the earlier attempt.
+        if hash_prefix(&mut temp.file, start.offset, &mut checksum)? != start.offset {
+            return Err(format!("Partial file {} shrank during resume", temp_path.display()).into());
+        }
+    }
+    // Drop anything past the resume point, or everything when starting over.
+    // An empty file has no body to copy but still goes through the checksum
+    // and rename below.
+    temp.begin_writing(&session.id, file_size)?;
+    temp.file.set_len(start.offset)?;
+    temp.file.seek(SeekFrom::Start(start.offset))?;
+    // From here on a failure leaves the partial file behind only if it can
+    // be resumed.
+    temp.set_keep(args.resume && temp.resumable);
+    let total_bytes = if start.streams > 1 {
+        let mut reporter = progress::terminal("Received");
+        let (name, file) = (&file_meta.name, &temp.file);
+        let total_bytes = receive_parallel(stream, session, args, name, &start, file, &mut *reporter)?;
+        reservation.consume(total_bytes);
+        // The ranges arrived in no particular order; the digest is taken
+        // over the file they make up.
+        let _keepalive = Keepalive::start(stream, format, session.keepalive);
+        temp.file.seek(SeekFrom::Start(0))?;
+        hash_prefix(&mut temp.file, total_bytes, &mut checksum)?;
+        total_bytes
+    } else {
+        let mut writer = BufWriter::new(Retrying::new(&temp.file));
+        copy_body(
+            open_body(stream, session, mode, &start)?,
+            args,
+            &file_meta.name,
+            &start,
+            &mut checksum,
+            &mut writer,
+            Some(&mut reservation),
+            &mut *progress::terminal("Received"),
+        )?
+    };
+    // A short file is left for `--resume` like any interrupted one, and
+    // otherwise deleted; it is never renamed into place.
+    check_received(stream, session, &file_meta.name, file_size, total_bytes)?;
+
+    // A complete file that fails verification is not worth resuming.
+    temp.set_keep(false);
+    let digest = if in_tree {
+        read_trailer(stream, format)?;
+        session.tree_digest.set(Some(checksum));
+        Vec::new()
+    } else {
+        verify_checksum(stream, session, &file_meta.name, alg, checksum, total_bytes)?
+    };
+    match temp.persist(&final_path, clobber, args.fsync) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let reason = format!(
+                "{} appeared while it was being received; not overwriting it",
+                final_path.display()
+            );
+            return report_failure(stream, session, total_bytes, NcpError::DeclinedOverwrite(reason));
+        }
+        Err(e) => return Err(e.into()),
+    }
+    clear_incomplete(&final_path)?;
+    vlog!("Saved {}", final_path.display());
+    if args.verify_read && in_tree {
+        vlog!("Not reading {} back: a tree checksum gives it no digest of its own", file_meta.name);
+    } else if args.verify_read {
+        let verified = {
+            let _keepalive = Keepalive::start(stream, format, session.keepalive);
+            verify_on_disk(&final_path, &file_meta.name, alg, &digest)
+        };
+        if let Err(e) = verified {
+            return report_failure(stream, session, total_bytes, e);
+        }
+    }
+    if args.dedup != DedupMode::Off {
+        let _keepalive = Keepalive::start(stream, format, session.keepalive);
+        dedupe(args, session, &final_path, total_bytes, &digest);
+    }
+    // Before the mode, which may take away the write access they need.
+    xattrs::apply(&final_path, &file_meta.attrs)?;
+    if file_meta.mode != 0 {
+        apply_mode(&final_path, file_meta.mode)?;
+    }
+    if let Some(mtime) = file_meta.mtime {
+        apply_mtime(&final_path, mtime)?;
+    }
+
+    let result = TransferResult {
+        ok: true,
+        received_bytes: total_bytes,
+        ..Default::default()
+    };
+    answer(stream, session, &Message::TransferResult(result))?;
+    let digest = to_hex(&digest);
+    events::file_done(&file_meta.name, total_bytes, &digest);
+    args.manifest.done(&file_meta.name, total_bytes, &digest, Some(&final_path))?;
+    Ok(Some((total_bytes, digest)))
+}
+
+/// Link the file just saved at `path` to an earlier one of the connection
+/// with the same contents, if there is one (`--dedup`). The copy stays if
+/// that fails.
+fn dedupe(args: &RecvArgs, session: &Session, path: &Path, size: u64, digest: &[u8]) {
+    let original = match session.originals.borrow_mut().find(path, size, digest) {
+        Ok(Some(original)) => original,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Warning: not comparing {} with the files before it: {}", path.display(), e);
+            return;
+        }
+    };
+    let linked = dedup::link(args.dedup, &original, path);
+    let (path, original) = (path.display(), original.display());
+    match linked {
+        Ok(()) => vlog!("Linked {} to {}, which has the same contents", path, original),
+        Err(e) => eprintln!("Warning: keeping {} as a copy of {}: {}", path, original, e),
+    }
+}
+
+/// How a file already in the destination compares with the sender's
+/// (`--verify-only`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Match,
+    Mismatch,
+    Missing,
+}
+
+impl Verdict {
+    fn label(self) -> &'static str {
+        match self {
+            Verdict::Match => "match",
+            Verdict::Mismatch => "mismatch",
+            Verdict::Missing => "missing",
+        }
+    }
+}
+
+/// Running count of verdicts over a connection.
+#[derive(Debug, Default)]
+struct Verification {
+    matched: usize,
+    mismatched: usize,
+    missing: usize,
+}
+
+impl Verification {
+    fn record(&mut self, verdict: Verdict) {
+        match verdict {
+            Verdict::Match => self.matched += 1,
+            Verdict::Mismatch => self.mismatched += 1,
+            Verdict::Missing => self.missing += 1,
+        }
+    }
+
+    fn total(&self) -> usize {
+        self.matched + self.mismatched + self.missing
+    }
+
+    /// For example `Verified 3 files: 2 matched, 1 mismatched, 0 missing`.
+    fn describe(&self) -> String {
+        let total = self.total();
+        format!(
+            "Verified {} file{}: {} matched, {} mismatched, {} missing",
+            total,
+            if total == 1 { "" } else { "s" },
+            self.matched,
+            self.mismatched,
+            self.missing
+        )
+    }
+
+    /// Fails unless every file matched.
+    fn check(&self) -> Result<()> {
+        let bad = self.mismatched + self.missing;
+        if bad == 0 {
+            return Ok(());
+        }
+        let message = format!("{} of {} files do not match the sender's", bad, self.total());
+        Err(NcpError::ChecksumMismatch(message))
+    }
+}
+
+/// Compare one offered file with what the destination already holds, and
+/// decline it either way so that none of its data is sent.
+fn verify_file_entry(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    file_meta: &FileMeta,
+    tree: Option<&Path>,
+) -> Result<Verdict> {
+    let format = session.format;
+    let Ok(alg) = ChecksumAlg::parse(&file_meta.checksum_alg) else {
+        let reason = format!("Unsupported checksum algorithm {:?}", file_meta.checksum_alg);
+        return refuse(stream, session, &file_meta.name, reason.into());
+    };
+    if file_meta.checksum.is_empty() {
+        let reason = "The sender sent no checksum to verify against (is it using --checksum none?)";
+        return refuse(stream, session, &file_meta.name, reason.into());
+    }
+    let dst = tree.unwrap_or(&args.dst);
+    let path = determine_final_path(dst, file_meta, tree.is_some(), &Placement::of(args));
+    let path = match path.and_then(|path| check_allowed(args, path)) {
+        Ok(path) => path,
+        Err(e) => return refuse(stream, session, &file_meta.name, e),
+    };
+    let compared = {
+        let _keepalive = Keepalive::start(stream, format, session.keepalive);
+        compare_local(&path, file_meta, alg)
+    };
+    let verdict = match compared {
+        Ok(verdict) => verdict,
+        Err(e) => return refuse(stream, session, &file_meta.name, e),
+    };
+
+    status!("{:<8} {}", verdict.label(), file_meta.name);
+    let (code, reason) = match verdict {
+        Verdict::Match => (ErrorCode::ErrAlreadyPresent, "Matches the receiver's copy"),
+        Verdict::Mismatch => (ErrorCode::ErrChecksum, "Differs from the receiver's copy"),
+        Verdict::Missing => (ErrorCode::ErrChecksum, "Missing on the receiver"),
+    };
+    decline(stream, session, args, file_meta, code, reason)?;
+    Ok(verdict)
+}
+
+/// Compare the file at `path` with the sender's, by size and then by its
+/// `alg` digest.
+fn compare_local(path: &Path, file_meta: &FileMeta, alg: ChecksumAlg) -> Result<Verdict> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Verdict::Missing),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_file() || metadata.len() != file_meta.size || is_incomplete(path) {
+        return Ok(Verdict::Mismatch);
+    }
+    let digest = calculate_file_checksum(path, alg)?;
+    if digests_equal(&digest, &file_meta.checksum) {
+        Ok(Verdict::Match)
+    } else {
+        Ok(Verdict::Mismatch)
+    }
+}
+
+/// Receive one file straight into `out`, named `target` in messages: stdout
+/// (`recv -`), or a FIFO or device (`--write-special`). There is no temp
+/// file, so nothing to resume or reserve space for; data that fails
+/// verification has already been written out, and the error is what tells
+/// the pipeline.
+#[allow(clippy::too_many_arguments)]
+fn receive_streaming<W: Write>(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    file_meta: &FileMeta,
+    mode: TransferMode,
+    alg: ChecksumAlg,
+    out: W,
+    target: &str,
+) -> Result<Option<(u64, String)>> {
+    let ok = PreflightOk::default();
+    answer(stream, session, &Message::PreflightOk(ok))?;
+
+    let start = read_transfer_start(stream, session, file_meta, mode, 0)?;
+    status!("Receiving {} ({}) to {}", file_meta.name, describe_size(start.file_size), target);
+    // Sent again, the file would follow the bad copy out.
+    session.retry_files.set(false);
+
+    let mut checksum = StreamingChecksum::new(alg);
+    let mut out = BufWriter::new(out);
+    let mut reporter = progress::terminal("Received");
+    let (name, body) = (&file_meta.name, open_body(stream, session, mode, &start)?);
+    let total_bytes =
+        copy_body(body, args, name, &start, &mut checksum, &mut out, None, &mut *reporter)?;
+    drop(out);
+    check_received(stream, session, &file_meta.name, start.file_size, total_bytes)?;
+    let digest = verify_checksum(stream, session, &file_meta.name, alg, checksum, total_bytes)?;
+
+    let result = TransferResult {
+        ok: true,
+        received_bytes: total_bytes,
+        ..Default::default()
+    };
+    answer(stream, session, &Message::TransferResult(result))?;
+    let digest = to_hex(&digest);
+    args.manifest.done(&file_meta.name, total_bytes, &digest, None)?;
+    Ok(Some((total_bytes, digest)))
+}
+
+/// Read the `TransferStart` for an accepted file and check it against what
+/// was announced and offered.
+fn read_transfer_start(
+    stream: &mut Stream,
+    session: &Session,
+    file_meta: &FileMeta,
+    mode: TransferMode,
+    partial: u64,
+) -> Result<TransferStart> {
+    let start = match read_control(stream, session.format)? {
+        Message::TransferStart(start) => start,
+        other => {
+            let message = format!("Expected TransferStart, got {}", other.name());
+            return Err(NcpError::Protocol(message));
+        }
+    };
+    handshake::check_session(&session.id, &start.session_id, "TransferStart")?;
+    if start.mode != mode as i32 {
+        return Err(NcpError::Protocol(format!(
+            "TransferStart mode {} does not match the announced {}",
+            start.mode,
+            mode.as_str_name()
+        )));
+    }
+    if start.file_size != file_meta.size {
+        return Err(NcpError::Protocol(format!(
+            "TransferStart size {} does not match the {} announced for {}",
+            start.file_size, file_meta.size, file_meta.name
+        )));
+    }
+    if start.offset != 0 && start.offset != partial {
+        return Err(NcpError::Protocol(format!(
+            "Sender resumed {} at {}, but {} bytes were offered",
+            file_meta.name, start.offset, partial
+        )));
+    }
+    Ok(start)
+}
+
+/// Take the body of a file split over `start.streams` connections (`send
+/// --parallel`) into `file`: the first range follows `start` on `stream`,
+/// and each other one comes over a connection of its own from the
+/// session's listener. Returns the number of bytes received.
+fn receive_parallel(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    name: &str,
+    start: &TransferStart,
+    file: &File,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<u64> {
+    let Some(listener) = session.listener else {
+        let message = format!("{} was split over connections this receiver does not take", name);
+        return Err(NcpError::Protocol(message));
+    };
+    if start.streams > parallel::MAX_STREAMS
+        || start.offset != 0
+        || start.file_size == UNKNOWN_SIZE
+        || start.mode != TransferMode::TransferRaw as i32
+    {
+        let message = format!("{} cannot be received over {} connections", name, start.streams);
+        return Err(NcpError::Protocol(message));
+    }
+    let ranges = parallel::split(start.file_size, start.streams);
+    vlog!("Receiving {} over {} connections", name, ranges.len());
+    let received = AtomicU64::new(0);
+    let received = &received;
+
+    thread::scope(|scope| {
+        let first = ranges[0].clone();
+        let mut streams = vec![scope.spawn(move || {
+            parallel::receive_range(stream, file, first, args.buffer_size, received)
+        })];
+        let mut taken = vec![false; ranges.len()];
+        taken[0] = true;
+        for _ in 1..ranges.len() {
+            let (mut conn, range) = accept_range(listener, session, args, &ranges, &mut taken)?;
+            streams.push(scope.spawn(move || {
+                let range_len = range.end - range.start;
+                let result = parallel::receive_range(&mut conn, file, range, args.buffer_size, received);
+                match result {
+                    Ok(()) => {
+                        let result = TransferResult {
+                            ok: true,
+                            received_bytes: range_len,
+                            ..Default::default()
+                        };
+                        write_message(&mut conn, WireFormat::Binary, &Message::TransferResult(result))
+                    }
+                    Err(e) => {
+                        let result = failure(0, &e);
+                        write_message(&mut conn, WireFormat::Binary, &Message::TransferResult(result))?;
+                        Err(e)
+                    }
+                }
+            }));
+        }
+        let finished = || streams.iter().all(|s| s.is_finished());
+        let interval = args.progress_interval;
+        parallel::show_progress(reporter, name, start.file_size, received, interval, finished)?;
+        for handle in streams {
+            handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        }
+        Ok(received.load(Ordering::Relaxed))
+    })
+}
+
+/// Take the next extra connection of a split file from `listener`, and
+/// the one of `ranges` it says it carries, which must not be `taken` yet.
+fn accept_range(
+    listener: &TcpListener,
+    session: &Session,
+    args: &RecvArgs,
+    ranges: &[std::ops::Range<u64>],
+    taken: &mut [bool],
+) -> Result<(Stream, std::ops::Range<u64>)> {
+    let (conn, peer) = net::accept(listener, args.timeout)?;
+    net::configure(&conn, args.timeout)?;
+    net::tune(&conn, &args.socket)?;
+    let mut conn = match &args.tls {
+        Some(tls) => tls.accept(conn)?,
+        None => Stream::from(conn),
+    };
+    let psk = args.psk.as_deref().map(str::as_bytes);
+    let probe = handshake::accept(&mut conn, psk, false, false, MirrorMode::Off, false, args.timeout)?;
+    handshake::check_session(&session.id, &probe.session_id, "Probe")?;
+    let range = match read_control(&mut conn, WireFormat::Binary)? {
+        Message::Range(range) => range,
+        other => return Err(NcpError::Protocol(format!("Expected Range, got {}", other.name()))),
+    };
+    handshake::check_session(&session.id, &range.session_id, "Range")?;
+    let wanted = range.offset..range.offset.saturating_add(range.length);
+    let Some(index) = ranges.iter().position(|r| *r == wanted).filter(|&i| !taken[i]) else {
+        let message = format!("Unexpected range of {} bytes at {}", range.length, range.offset);
+        return Err(NcpError::Protocol(message));
+    };
+    taken[index] = true;
+    vvlog!("Range {}..{} coming from {}", wanted.start, wanted.end, peer);
+    Ok((conn, wanted))
+}
+
+/// The body in `mode` that follows `start` on `stream`.
+fn open_body<'s>(
+    stream: &'s mut Stream,
+    session: &Session,
+    mode: TransferMode,
+    start: &TransferStart,
+) -> Result<BodyReader<&'s mut Stream>> {
+    match mode {
+        TransferMode::TransferZstdShared => Ok(BodyReader::shared(stream, &session.shared_zstd)),
+        _ => BodyReader::new(stream, mode, start.file_size - start.offset, start.offset),
+    }
+}
+
+/// Copy `body`, the one `start` announced, to `out`, feeding `checksum` and
+/// `reporter`, and return the size of the whole file including any resumed
+/// prefix.
+#[allow(clippy::too_many_arguments)]
+fn copy_body<R: Read, W: Write>(
+    mut body: BodyReader<R>,
+    args: &RecvArgs,
+    name: &str,
+    start: &TransferStart,
+    checksum: &mut StreamingChecksum,
+    out: &mut W,
+    mut reservation: Option<&mut Reservation>,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<u64> {
+    let file_size = start.file_size;
+    let mut buffer = vec![0u8; args.buffer_size];
+    let mut total_bytes = start.offset;
+    let mut progress = ProgressTicker::new(args.progress_interval);
+    reporter.on_start(start.offset, file_size);
+
+    loop {
+        let n = body.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        total_bytes += n as u64;
+        if total_bytes > file_size {
+            let declared = format_bytes(file_size);
+            let message = format!("{} is larger than the declared {}", name, declared);
+            return Err(NcpError::Protocol(message));
+        }
+        if let Some(limit) = args.max_file_size.filter(|&limit| total_bytes > limit) {
+            let message = format!("{}: {}", name, too_large(total_bytes, limit));
+            return Err(NcpError::FileTooLarge(message));
+        }
+        out.write_all(&buffer[..n])?;
+        checksum.update(&buffer[..n]);
+        if let Some(reservation) = reservation.as_deref_mut() {
+            reservation.consume(n as u64);
+        }
+
+        if progress.tick() {
+            events::file_progress(name, total_bytes, file_size);
+            reporter.on_progress(total_bytes, file_size)?;
+        }
+    }
+    reporter.on_file_done(name, total_bytes)?;
+
+    body.finish()?;
+    out.flush()?;
+    Ok(total_bytes)
+}
+
+/// Why a file of `size` bytes is refused under `--max-file-size`.
+fn too_large(size: u64, limit: u64) -> String {
+    format!(
+        "{} is over the receiver's limit of {} (--max-file-size)",
+        format_bytes(size),
+        format_bytes(limit)
+    )
+}
+
+/// Fail, and tell the sender, unless exactly the announced `file_size`
+/// bytes arrived. A raw body that ends early simply stops.
+fn check_received(
+    stream: &mut Stream,
+    session: &Session,
+    name: &str,
+    file_size: u64,
+    total_bytes: u64,
+) -> Result<()> {
+    if file_size == UNKNOWN_SIZE || total_bytes == file_size {
+        return Ok(());
+    }
+    let reason = format!(
+        "Connection closed unexpectedly: received {} of {} bytes for {}",
+        total_bytes, file_size, name
+    );
+    let err = io::Error::new(io::ErrorKind::UnexpectedEof, reason);
+    report_failure(stream, session, total_bytes, err.into())
+}
+
+/// Read the sender's checksum trailer and compare it with `checksum`,
+/// telling the sender if they differ. Returns the digest.
+fn verify_checksum(
+    stream: &mut Stream,
+    session: &Session,
+    name: &str,
+    alg: ChecksumAlg,
+    checksum: StreamingChecksum,
+    total_bytes: u64,
+) -> Result<Vec<u8>> {
+    let expected = read_trailer(stream, session.format)?;
+    let digest = checksum.finalize();
+    if expected.alg != alg.name() || expected.digest != digest {
+        let reason = format!(
+            "Checksum mismatch for {}: sender {}:{}, received {}:{}",
+            name,
+            expected.alg,
+            to_hex(&expected.digest),
+            alg.name(),
+            to_hex(&digest)
+        );
+        return report_failure(stream, session, total_bytes, NcpError::ChecksumMismatch(reason));
+    }
+    vvlog!("Checksum verified for {}", name);
+    Ok(digest)
+}
+
+/// `--verify-read`: hash the saved file at `path` again as it reads back
+/// from disk, and remove it if that is not the `expected` digest it was
+/// received with.
+fn verify_on_disk(path: &Path, name: &str, alg: ChecksumAlg, expected: &[u8]) -> Result<()> {
+    let digest = calculate_file_checksum(path, alg)?;
+    if !digests_equal(&digest, expected) {
+        fs::remove_file(path)?;
+        let reason = format!(
+            "{} reads back from disk as {}:{}, not the {} received; removed it",
+            name,
+            alg.name(),
+            to_hex(&digest),
+            to_hex(expected)
+        );
+        return Err(NcpError::ChecksumMismatch(reason));
+    }
+    vvlog!("{} reads back as received", name);
+    Ok(())
+}
+
+/// Read the `Checksum` that follows a file's data.
+fn read_trailer(stream: &mut Stream, format: WireFormat) -> Result<FileChecksum> {
+    match read_control(stream, format)? {
+        Message::Checksum(expected) => Ok(expected),
+        other => Err(NcpError::Protocol(format!("Expected Checksum, got {}", other.name()))),
+    }
+}
+
+/// Compare the sender's digest over every file of the tree with ours
+/// (`send --checksum tree`), and tell the sender how it went.
+fn verify_tree(
+    stream: &mut Stream,
+    session: &Session,
+    expected: &FileChecksum,
+    digest: StreamingChecksum,
+    total_bytes: u64,
+) -> Result<()> {
+    let digest = digest.finalize();
+    if expected.digest != digest {
+        let reason = format!(
+            "Tree checksum mismatch: sender {}:{}, received {}; a file in the tree is corrupt",
+            expected.alg,
+            to_hex(&expected.digest),
+            to_hex(&digest)
+        );
+        return report_failure(stream, session, total_bytes, NcpError::ChecksumMismatch(reason));
+    }
+    vlog!("Tree checksum verified");
+    let result = TransferResult {
+        ok: true,
+        received_bytes: total_bytes,
+        ..Default::default()
+    };
+    answer(stream, session, &Message::TransferResult(result))
+}
+
+/// Answer the sender with a failed `TransferResult` for `err`, then fail
+/// with it.
+fn report_failure<T>(
+    stream: &mut Stream,
+    session: &Session,
+    total_bytes: u64,
+    err: NcpError,
+) -> Result<T> {
+    // A sender that has hung up cannot be told, but `err` is still why.
+    if let Err(e) = answer(stream, session, &Message::TransferResult(failure(total_bytes, &err))) {
+        vlog!("Could not tell the sender the transfer failed: {}", e);
+    }
+    Err(err)
+}
+
+/// The failed `TransferResult` for `err`, after `total_bytes` arrived.
+fn failure(total_bytes: u64, err: &NcpError) -> TransferResult {
+    TransferResult {
+        ok: false,
+        received_bytes: total_bytes,
+        reason: err.to_string(),
+        code: err.code() as i32,
+        ..Default::default()
+    }
+}
+
+/// `size` for a status line.
+fn describe_size(size: u64) -> String {
+    if size == UNKNOWN_SIZE {
+        "size unknown".to_string()
+    } else {
+        format_bytes(size)
+    }
+}
+
+/// Whether `path` already holds the file the sender offered with its
+/// checksum (`send --skip-existing`). Anything unreadable counts as not.
+fn already_present(path: &Path, file_meta: &FileMeta, alg: ChecksumAlg) -> bool {
+    if file_meta.checksum.is_empty() {
+        return false;
+    }
+    match fs::metadata(path) {
+        Ok(m) if m.is_file() && m.len() == file_meta.size => {}
+        _ => return false,
+    }
+    match calculate_file_checksum(path, alg) {
+        Ok(digest) => digests_equal(&digest, &file_meta.checksum),
+        Err(e) => {
+            vlog!("Cannot check {} against the sender's copy: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// Refuse a file during preflight, telling the sender why with `code`.
+fn decline(
+    stream: &mut Stream,
+    session: &Session,
+    args: &RecvArgs,
+    file_meta: &FileMeta,
+    code: ErrorCode,
+    reason: &str,
+) -> Result<Option<(u64, String)>> {
+    let fail = PreflightFail {
+        code: code as i32,
+        reason: reason.to_string(),
+        ..Default::default()
+    };
+    answer(stream, session, &Message::PreflightFail(fail))?;
+    events::file_skipped(&file_meta.name, reason);
+    args.manifest.skipped(&file_meta.name, file_meta.size, reason)?;
+    Ok(None)
+}
+
+/// Apply the sender's permission bits (`send --preserve`). Set-id and sticky
+/// bits are dropped: they are not the sender's to grant on this machine.
+#[cfg(unix)]
+fn apply_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    vvlog!("Setting mode {:o} on {}", mode & 0o777, path.display());
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))
+}
+
+#[cfg(not(unix))]
+fn apply_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Set the sender's modification time on a file or directory.
+fn apply_mtime(path: &Path, mtime: Timestamp) -> Result<()> {
+    let mtime = SystemTime::try_from(mtime).map_err(|e| format!("Invalid mtime: {}", e))?;
+    vvlog!("Setting mtime on {}", path.display());
+    open_for_times(path)?.set_modified(mtime)?;
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Directories can only be opened on Windows with backup semantics, and
+/// changing times needs write access to the attributes.
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// Why `--overwrite newer` or `older` keeps the file at `path` instead of
+/// the incoming one, if it does. Times that are equal keep it either way.
+fn compare_mtime(path: &Path, meta: &FileMeta, mode: OverwriteMode) -> Result<Option<&'static str>> {
+    let Some(incoming) = meta.mtime else {
+        return Ok(Some("The sender gave no modification time to compare"));
+    };
+    let incoming = SystemTime::try_from(incoming).map_err(|e| format!("Invalid mtime: {}", e))?;
+    let existing = fs::metadata(path)?.modified()?;
+    vvlog!("Incoming {:?}, existing {:?} for {}", incoming, existing, path.display());
+    Ok(match mode {
+        OverwriteMode::Newer if incoming <= existing => Some("Destination file is not older"),
+        OverwriteMode::Older if incoming >= existing => Some("Destination file is not newer"),
+        _ => None,
+    })
+}
+
+fn prompt_overwrite(path: &Path) -> Result<bool> {
+    eprint!("File {} already exists. Overwrite? [y/N]: ", path.display());
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Remove everything under `tree`, where the directory transfer was placed,
+/// that the sender did not list. Only entries found by walking `tree` are
+/// considered, and symlinks are removed rather than followed, so nothing
+/// outside the root is touched.
+fn handle_mirror_list(
+    stream: &mut Stream,
+    session: &Session,
+    tree: Option<&Path>,
+    list: &MirrorList,
+) -> Result<()> {
+    let format = session.format;
+    let Some(dst_path) = tree else {
+        let result = TransferResult {
+            ok: false,
+            reason: "Mirror list received outside a directory transfer".to_string(),
+            ..Default::default()
+        };
+        return write_message(stream, format, &Message::TransferResult(result));
+    };
+
+    // Everything the list does not name is a lot to lose at the root.
+    if fs::canonicalize(dst_path)?.parent().is_none() {
+        let result = TransferResult {
+            ok: false,
+            reason: format!("Refusing to delete at the root of the filesystem ({})", dst_path.display()),
+            ..Default::default()
+        };
+        return write_message(stream, format, &Message::TransferResult(result));
+    }
+
+    let expected: HashSet<&Path> = list.paths.iter().map(Path::new).collect();
+    let mut extraneous = Vec::new();
+    let keepalive = Keepalive::start(stream, format, session.keepalive);
+    collect_extraneous(dst_path, Path::new(""), &expected, &mut extraneous)?;
+
+    for (relative, path, is_dir) in &extraneous {
+        if list.dry_run {
+            status!("Would remove: {}", relative.display());
+            continue;
+        }
+        status!("Removing: {}", relative.display());
+        if *is_dir {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+
+    drop(keepalive);
+    let verb = if list.dry_run { "would remove" } else { "removed" };
+    let result = TransferResult {
+        ok: true,
+        reason: format!("{} {} entries", verb, extraneous.len()),
+        ..Default::default()
+    };
+    write_message(stream, format, &Message::TransferResult(result))
+}
+
+/// Collect `(relative path, path, is_dir)` for entries not in `expected`.
+/// An extraneous directory is reported once; its contents are not listed.
+/// The list holds names as text, so one that is not valid UTF-8 cannot be
+/// told apart from another that renders the same, and is left alone with
+/// everything under it.
+fn collect_extraneous(
+    dir: &Path,
+    prefix: &Path,
+    expected: &HashSet<&Path>,
+    out: &mut Vec<(PathBuf, PathBuf, bool)>,
+) -> Result<()> {
+    let mut children: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    children.sort_by_key(|e| e.file_name());
+
+    for entry in children {
+        let relative = prefix.join(entry.file_name());
+        let path = entry.path();
+        if entry.file_name().to_str().is_none() {
+            eprintln!("Warning: keeping {}: the sender cannot name it exactly", relative.display());
+            continue;
+        }
+
+        let is_dir = entry.file_type()?.is_dir();
+        if !expected.contains(relative.as_path()) {
+            out.push((relative, path, is_dir));
+        } else if is_dir {
+            collect_extraneous(&path, &relative, expected, out)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::TransferStart;
+    use crate::manifest::Manifest;
+    use crate::protocol::{read_message, FileChecksum};
+    use std::thread;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ncp-recv-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn recv_args(dst: &Path) -> RecvArgs {
+        RecvArgs {
+            host: None,
+            port: 0,
+            dst: dst.to_path_buf(),
+            overwrite: OverwriteMode::Yes,
+            resume: false,
+            timeout: None,
+            keep_alive: false,
+            buffer_size: crate::utils::DEFAULT_BUFFER_SIZE,
+            progress_interval: crate::utils::DEFAULT_PROGRESS_INTERVAL,
+            family: crate::net::IpFamily::Any,
+            socket: crate::net::SocketOptions::default(),
+            output_name: None,
+            mkdir: false,
+            into: false,
+            verify_only: false,
+            in_place: false,
+            fsync: false,
+            verify_read: false,
+            dedup: DedupMode::Off,
+            write_special: false,
+            allow_roots: Vec::new(),
+            strip_components: 0,
+            max_file_size: None,
+            delete: MirrorMode::Off,
+            pull: None,
+            manifest: Manifest::default(),
+            psk: None,
+            tls: None,
+        }
+    }
+
+    fn meta(name: &str) -> FileMeta {
+        meta_sized(name, 1)
+    }
+
+    fn meta_sized(name: &str, size: u64) -> FileMeta {
+        FileMeta {
+            name: name.to_string(),
+            size,
+            checksum_alg: ChecksumAlg::default().name().to_string(),
+            ..Default::default()
+        }
+    }
+
+    const SESSION: &str = "0123456789abcdef";
+
+    fn meta_message(file: FileMeta) -> Message {
+        Message::Meta(crate::proto::Meta {
+            session_id: SESSION.to_string(),
+            file: Some(file),
+        })
+    }
+
+    #[test]
+    fn test_missing_destination_parent() {
+        let root = temp_dir("mkdir");
+        let mut args = recv_args(&root.join("backups/2024/file.txt"));
+        let err = check_destination(&args).unwrap_err();
+        assert!(err.to_string().contains("--mkdir"), "{}", err);
+
+        // The destination itself may be missing, just not what holds it.
+        args.dst = root.join("new-dir");
+        check_destination(&args).unwrap();
+
+        args.dst = root.join("backups/2024/file.txt");
+        args.mkdir = true;
+        check_destination(&args).unwrap();
+        let (mut stream, receiver) = spawn_receiver(args);
+        offer(&mut stream, "a.txt", 2);
+        let result = send_body(&mut stream, 0, b"ok", b"ok");
+        assert!(result.ok, "{}", result.reason);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(root.join("backups/2024/file.txt")).unwrap(), b"ok");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_determine_final_path_rejects_traversal() {
+        let root = temp_dir("traversal");
+        for name in ["../escape", "/abs/path", "a/../../b", "..\\win", "C:\\evil", "\\\\host\\share"] {
+            assert!(
+                determine_final_path(&root, &meta(name), true, &Placement::default()).is_err(),
+                "accepted {:?}",
+                name
+            );
+            assert!(determine_final_path(&root, &meta(name), false, &Placement::default()).is_err());
+        }
+
+        let placement = Placement::default();
+        let path = determine_final_path(&root, &meta("sub/ok..txt"), true, &placement).unwrap();
+        assert_eq!(path, root.join("sub/ok..txt"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_final_path_for_each_kind_of_destination() {
+        let root = temp_dir("final-path");
+        let (dir, file, absent) = (root.join("dir"), root.join("file"), root.join("absent"));
+        fs::create_dir_all(dir.join("full")).unwrap();
+        fs::write(&file, "old").unwrap();
+        let placement = Placement::default();
+        let mut dir_meta = meta("full");
+        dir_meta.is_dir = true;
+        let place = |dst: &Path, file_meta: &FileMeta| {
+            let path = determine_final_path(dst, file_meta, false, &placement)?;
+            check_kind(&path, file_meta).map(|()| path)
+        };
+
+        // A file goes inside a directory, and at or over anything else.
+        assert_eq!(place(&dir, &meta("a.txt")).unwrap(), dir.join("a.txt"));
+        assert_eq!(place(&file, &meta("a.txt")).unwrap(), file);
+        assert_eq!(place(&absent, &meta("a.txt")).unwrap(), absent);
+        // A directory goes inside a directory, merging with one of its name
+        // there, and becomes a missing destination; it never replaces a file.
+        assert_eq!(place(&dir, &dir_meta).unwrap(), dir.join("full"));
+        assert_eq!(place(&absent, &dir_meta).unwrap(), absent);
+        let err = place(&file, &dir_meta).unwrap_err();
+        assert!(matches!(err, NcpError::DeclinedOverwrite(_)), "{:?}", err);
+        assert!(err.to_string().contains("it is a file"), "{}", err);
+
+        // The name an entry takes inside a directory is held by the other kind.
+        let err = place(&dir, &meta("full")).unwrap_err();
+        assert!(err.to_string().contains("is a directory"), "{}", err);
+        fs::write(dir.join("plain"), "").unwrap();
+        dir_meta.name = "plain".to_string();
+        let err = place(&dir, &dir_meta).unwrap_err();
+        assert!(err.to_string().contains("is a file"), "{}", err);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_file_is_declined_where_a_directory_has_its_name() {
+        let root = temp_dir("kind-clash");
+        fs::create_dir_all(root.join("report/keep")).unwrap();
+        let mut args = recv_args(&root);
+        args.overwrite = OverwriteMode::Yes;
+        let (mut stream, receiver) = spawn_receiver(args);
+
+        write_message(&mut stream, WireFormat::Binary, &meta_message(meta_sized("report", 2))).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => {
+                assert_eq!(fail.code, ErrorCode::ErrExists as i32);
+                assert!(fail.reason.contains("--as"), "{}", fail.reason);
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+
+        finish(stream);
+        receiver.join().unwrap();
+        assert!(root.join("report/keep").is_dir());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_allow_root_accepts_inside_and_refuses_outside() {
+        let root = temp_dir("allow-root");
+        let (allowed, outside) = (root.join("drop"), root.join("outside"));
+        fs::create_dir_all(&allowed).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        let allowing = |dst: &Path| RecvArgs {
+            allow_roots: vec![allowed.clone()],
+            ..recv_args(dst)
+        };
+
+        let (mut stream, receiver) = spawn_receiver(allowing(&allowed));
+        offer(&mut stream, "in.txt", 2);
+        assert!(send_body(&mut stream, 0, b"in", b"in").ok);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(allowed.join("in.txt")).unwrap(), b"in");
+
+        let (mut stream, receiver) = spawn_receiver(allowing(&outside));
+        write_message(&mut stream, WireFormat::Binary, &meta_message(meta_sized("out.txt", 3))).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => {
+                assert_eq!(fail.code, ErrorCode::ErrPermission as i32);
+                assert!(fail.reason.contains("--allow-root"), "{}", fail.reason);
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+        assert!(!receiver.join().unwrap());
+        assert!(!outside.join("out.txt").exists());
+
+        // Nor can a path climb out, or a symlink lead out, of the root.
+        let args = allowing(&allowed);
+        let climbing = allowed.join("new/../../outside/x");
+        assert!(check_allowed(&args, climbing).is_err());
+        assert!(check_allowed(&args, allowed.join("new/deeper/x")).is_ok());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, allowed.join("link")).unwrap();
+            std::os::unix::fs::symlink(outside.join("gone"), allowed.join("dangling")).unwrap();
+            assert!(check_allowed(&args, allowed.join("link/x")).is_err());
+            assert!(check_allowed(&args, allowed.join("dangling")).is_err());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo_is_refused_or_written_into_but_never_replaced() {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::FileTypeExt;
+
+        let root = temp_dir("fifo");
+        let fifo = root.join("pipe");
+        let c_path = CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let is_fifo = || fs::symlink_metadata(&fifo).unwrap().file_type().is_fifo();
+
+        let (mut stream, receiver) = spawn_receiver(recv_args(&fifo));
+        let meta = meta_message(meta_sized("data.bin", 5));
+        write_message(&mut stream, WireFormat::Binary, &meta).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => {
+                assert_eq!(fail.code, ErrorCode::ErrExists as i32);
+                assert!(fail.reason.contains("is a FIFO; use --write-special"), "{}", fail.reason);
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+        finish(stream);
+        receiver.join().unwrap();
+        assert!(is_fifo());
+
+        let reader = {
+            let fifo = fifo.clone();
+            thread::spawn(move || fs::read(fifo).unwrap())
+        };
+        let mut args = recv_args(&fifo);
+        args.write_special = true;
+        let (mut stream, receiver) = spawn_receiver(args);
+        let data = b"through the pipe".to_vec();
+        offer(&mut stream, "data.bin", data.len() as u64);
+        let result = send_body(&mut stream, 0, &data, &data);
+        assert!(result.ok, "{}", result.reason);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(reader.join().unwrap(), data);
+        assert!(is_fifo());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Run `handle_connection` on a loopback socket and return the client end.
+    fn spawn_receiver(args: RecvArgs) -> (TcpStream, thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &args, None, &SpaceLedger::new(), &TempFiles::default()).is_ok()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        handshake::open(&mut stream, SESSION, None, None).unwrap();
+        (stream, receiver)
+    }
+
+    fn offer(stream: &mut TcpStream, name: &str, size: u64) -> PreflightOk {
+        write_message(stream, WireFormat::Binary, &meta_message(meta_sized(name, size))).unwrap();
+        match read_message(stream, WireFormat::Binary).unwrap() {
+            Message::PreflightOk(ok) => ok,
+            other => panic!("unexpected {}", other.name()),
+        }
+    }
+
+    fn offer_dir(stream: &mut TcpStream, name: &str) -> PreflightOk {
+        let mut dir_meta = meta_sized(name, 0);
+        dir_meta.is_dir = true;
+        write_message(stream, WireFormat::Binary, &meta_message(dir_meta)).unwrap();
+        match read_message(stream, WireFormat::Binary).unwrap() {
+            Message::PreflightOk(ok) => ok,
+            other => panic!("unexpected {}", other.name()),
+        }
+    }
+
+    /// End the session the way a sender does once everything is sent.
+    fn finish(mut stream: TcpStream) {
+        write_message(&mut stream, WireFormat::Binary, &Message::Done).unwrap();
+    }
+
+    /// Send `body` as the bytes from `offset` onwards, followed by a
+    /// checksum over `whole`, and return the receiver's verdict.
+    fn send_body(stream: &mut TcpStream, offset: u64, body: &[u8], whole: &[u8]) -> TransferResult {
+        send_body_with(stream, ChecksumAlg::default(), offset, body, whole)
+    }
+
+    fn send_body_with(
+        stream: &mut TcpStream,
+        alg: ChecksumAlg,
+        offset: u64,
+        body: &[u8],
+        whole: &[u8],
+    ) -> TransferResult {
+        let start = TransferStart {
+            session_id: SESSION.to_string(),
+            mode: TransferMode::TransferRaw as i32,
+            file_size: whole.len() as u64,
+            offset,
+            ..Default::default()
+        };
+        write_message(stream, WireFormat::Binary, &Message::TransferStart(start)).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut checksum = StreamingChecksum::new(alg);
+        checksum.update(whole);
+        let trailer = FileChecksum {
+            alg: alg.name().to_string(),
+            digest: checksum.finalize(),
+        };
+        write_message(stream, WireFormat::Binary, &Message::Checksum(trailer)).unwrap();
+
+        match read_message(stream, WireFormat::Binary).unwrap() {
+            Message::TransferResult(result) => result,
+            other => panic!("unexpected {}", other.name()),
+        }
+    }
+
+    #[test]
+    fn test_output_name_renames_single_file() {
+        let root = temp_dir("output-name");
+        let mut args = recv_args(&root);
+        args.output_name = Some("b.txt".to_string());
+        let (mut stream, receiver) = spawn_receiver(args);
+
+        offer(&mut stream, "a.txt", 6);
+        let result = send_body(&mut stream, 0, b"hello\n", b"hello\n");
+        assert!(result.ok, "{}", result.reason);
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(root.join("b.txt")).unwrap(), b"hello\n");
+        assert!(!root.join("a.txt").exists());
+
+        let mut dir_meta = meta("tree");
+        dir_meta.is_dir = true;
+        let as_b = Placement {
+            output_name: Some("b"),
+            ..Placement::default()
+        };
+        assert!(determine_final_path(&root, &dir_meta, false, &as_b).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_body_rejected() {
+        let root = temp_dir("corrupt");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+
+        let data: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        let mut corrupted = data.clone();
+        corrupted[data.len() / 2] ^= 0xff;
+
+        offer(&mut stream, "file.bin", data.len() as u64);
+        let result = send_body(&mut stream, 0, &corrupted, &data);
+        assert!(!result.ok);
+        assert_eq!(result.code, ErrorCode::ErrChecksum as i32);
+        assert!(result.reason.contains("Checksum mismatch"));
+        assert!(!root.join("file.bin").exists());
+        assert!(!temp_path_for(&root.join("file.bin")).exists());
+
+        // The session goes on, and the file can be sent again.
+        let ok = offer(&mut stream, "file.bin", data.len() as u64);
+        assert_eq!(ok.resume_offset, 0);
+        let result = send_body(&mut stream, 0, &data, &data);
+        assert!(result.ok, "{}", result.reason);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(root.join("file.bin")).unwrap(), data);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_mode_declined_in_preflight() {
+        let root = temp_dir("mode");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+
+        let mut file_meta = meta_sized("file.bin", 10);
+        file_meta.transfer_mode = 7;
+        write_message(&mut stream, WireFormat::Binary, &meta_message(file_meta)).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => assert!(fail.reason.contains("Unsupported transfer mode")),
+            other => panic!("unexpected {}", other.name()),
+        }
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_alg_taken_from_meta() {
+        let root = temp_dir("alg");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+        let data = b"checked with crc32";
+
+        let mut file_meta = meta_sized("crc.txt", data.len() as u64);
+        file_meta.checksum_alg = "crc32".to_string();
+        write_message(&mut stream, WireFormat::Binary, &meta_message(file_meta)).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightOk(_) => {}
+            other => panic!("unexpected {}", other.name()),
+        }
+        assert!(send_body_with(&mut stream, ChecksumAlg::Crc32, 0, data, data).ok);
+
+        let mut file_meta = meta_sized("md5.txt", data.len() as u64);
+        file_meta.checksum_alg = "md5".to_string();
+        write_message(&mut stream, WireFormat::Binary, &meta_message(file_meta)).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => assert!(fail.reason.contains("Unsupported checksum")),
+            other => panic!("unexpected {}", other.name()),
+        }
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(root.join("crc.txt")).unwrap(), data);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preserved_mode_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_dir("mode700");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+
+        let data = b"#!/bin/sh\nexit 0\n";
+        let mut file_meta = meta_sized("run.sh", data.len() as u64);
+        file_meta.mode = 0o700;
+        write_message(&mut stream, WireFormat::Binary, &meta_message(file_meta)).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightOk(_) => {}
+            other => panic!("unexpected {}", other.name()),
+        }
+        assert!(send_body(&mut stream, 0, data, data).ok);
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        let mode = fs::metadata(root.join("run.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o700);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_mtimes_restored() {
+        let root = temp_dir("mtime");
+        let dst = root.join("tree");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&dst));
+
+        let dir_time = Timestamp {
+            seconds: 1_500_000_000,
+            nanos: 0,
+        };
+        let file_time = Timestamp {
+            seconds: 1_600_000_000,
+            nanos: 250_000_000,
+        };
+        let mut dir_meta = meta("tree");
+        dir_meta.is_dir = true;
+        dir_meta.mtime = Some(dir_time);
+        write_message(&mut stream, WireFormat::Binary, &meta_message(dir_meta)).unwrap();
+        assert!(matches!(
+            read_message(&mut stream, WireFormat::Binary).unwrap(),
+            Message::PreflightOk(_)
+        ));
+
+        let mut file_meta = meta_sized("file.txt", 5);
+        file_meta.mtime = Some(file_time);
+        write_message(&mut stream, WireFormat::Binary, &meta_message(file_meta)).unwrap();
+        assert!(matches!(
+            read_message(&mut stream, WireFormat::Binary).unwrap(),
+            Message::PreflightOk(_)
+        ));
+        assert!(send_body(&mut stream, 0, b"hello", b"hello").ok);
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+
+        // Allow for filesystems that store times in whole seconds.
+        let close = |path: &Path, expected: Timestamp| {
+            let actual = fs::metadata(path).unwrap().modified().unwrap();
+            let expected = SystemTime::try_from(expected).unwrap();
+            let diff = actual.duration_since(expected).unwrap_or_else(|e| e.duration());
+            assert!(diff < std::time::Duration::from_secs(2), "{}: off by {:?}", path.display(), diff);
+        };
+        close(&dst.join("file.txt"), file_time);
+        close(&dst, dir_time);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Send a root named `tree` holding `a.txt` into the existing `dst`.
+    fn receive_tree(args: RecvArgs, contents_only: bool) {
+        let (mut stream, receiver) = spawn_receiver(args);
+        let mut root = meta_sized("tree", 0);
+        (root.is_dir, root.contents_only) = (true, contents_only);
+        write_message(&mut stream, WireFormat::Binary, &meta_message(root)).unwrap();
+        let reply = read_message(&mut stream, WireFormat::Binary).unwrap();
+        assert!(matches!(reply, Message::PreflightOk(_)), "unexpected {}", reply.name());
+        write_message(&mut stream, WireFormat::Binary, &meta_message(meta_sized("a.txt", 3))).unwrap();
+        // Declined when it exists and is not to be overwritten.
+        if let Message::PreflightOk(_) = read_message(&mut stream, WireFormat::Binary).unwrap() {
+            let result = send_body(&mut stream, 0, b"new", b"new");
+            assert!(result.ok, "{}", result.reason);
+        }
+        finish(stream);
+        assert!(receiver.join().unwrap());
+    }
+
+    #[test]
+    fn test_directory_into_existing_destination() {
+        let root = temp_dir("into");
+        fs::write(root.join("a.txt"), "old").unwrap();
+
+        // Like `cp -r`: the tree is created inside, under its own name.
+        receive_tree(recv_args(&root), false);
+        assert_eq!(fs::read(root.join("tree/a.txt")).unwrap(), b"new");
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"old");
+
+        // `--into` merges it, and the overwrite mode settles the conflict.
+        let mut args = recv_args(&root);
+        args.into = true;
+        args.overwrite = OverwriteMode::No;
+        receive_tree(args, false);
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"old");
+        let mut args = recv_args(&root);
+        args.into = true;
+        receive_tree(args, false);
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"new");
+        assert!(!root.join("tree/tree").exists());
+
+        // Wildcard matches only group their entries, so they always merge.
+        fs::remove_file(root.join("a.txt")).unwrap();
+        receive_tree(recv_args(&root), true);
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"new");
+
+        // A destination that does not exist yet becomes the tree.
+        receive_tree(recv_args(&root.join("fresh")), false);
+        assert_eq!(fs::read(root.join("fresh/a.txt")).unwrap(), b"new");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_empty_files_and_directories() {
+        let root = temp_dir("empty");
+        let dst = root.join("tree");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&dst));
+
+        for name in ["tree", "hollow", "hollow/deeper"] {
+            offer_dir(&mut stream, name);
+        }
+        for name in ["zero.txt", "hollow/deeper/zero.bin"] {
+            offer(&mut stream, name, 0);
+            let result = send_body(&mut stream, 0, b"", b"");
+            assert!(result.ok, "{}", result.reason);
+            assert_eq!(result.received_bytes, 0);
+        }
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert!(dst.join("hollow/deeper").is_dir());
+        for name in ["zero.txt", "hollow/deeper/zero.bin"] {
+            assert_eq!(fs::metadata(dst.join(name)).unwrap().len(), 0);
+            assert!(!temp_path_for(&dst.join(name)).exists());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_strip_components_drops_leading_directories() {
+        let root = temp_dir("strip");
+        let dst = root.join("tree");
+        let mut args = recv_args(&dst);
+        args.strip_components = 1;
+        let (mut stream, receiver) = spawn_receiver(args);
+
+        for name in ["tree", "release", "release/docs"] {
+            offer_dir(&mut stream, name);
+        }
+        for name in ["release/notes.txt", "release/docs/guide.txt"] {
+            offer(&mut stream, name, 3);
+            let result = send_body(&mut stream, 0, b"yes", b"yes");
+            assert!(result.ok, "{}", result.reason);
+        }
+        // Nothing would be left of a file at the top.
+        write_message(&mut stream, WireFormat::Binary, &meta_message(meta_sized("top.txt", 3))).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => assert!(fail.reason.contains("components to strip")),
+            other => panic!("unexpected {}", other.name()),
+        }
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(dst.join("notes.txt")).unwrap(), b"yes");
+        assert_eq!(fs::read(dst.join("docs/guide.txt")).unwrap(), b"yes");
+        assert!(!dst.join("release").exists());
+        assert!(!dst.join("top.txt").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_max_file_size_declines_larger_files_in_preflight() {
+        let root = temp_dir("max-file-size");
+        let dst = root.join("tree");
+        let mut args = recv_args(&dst);
+        args.max_file_size = Some(4);
+        let (mut stream, receiver) = spawn_receiver(args);
+
+        // The directory's total is over the limit, but only files are held to it.
+        let mut dir_meta = meta_sized("tree", 10);
+        dir_meta.is_dir = true;
+        write_message(&mut stream, WireFormat::Binary, &meta_message(dir_meta)).unwrap();
+        let reply = read_message(&mut stream, WireFormat::Binary).unwrap();
+        assert!(matches!(reply, Message::PreflightOk(_)), "unexpected {}", reply.name());
+        write_message(&mut stream, WireFormat::Binary, &meta_message(meta_sized("big.txt", 6))).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => {
+                assert_eq!(fail.code, ErrorCode::ErrTooLarge as i32);
+                assert!(fail.reason.contains("--max-file-size"), "{}", fail.reason);
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+        offer(&mut stream, "small.txt", 4);
+        let result = send_body(&mut stream, 0, b"tiny", b"tiny");
+        assert!(result.ok, "{}", result.reason);
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(dst.join("small.txt")).unwrap(), b"tiny");
+        assert!(!dst.join("big.txt").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_tree_checksum_catches_a_corrupt_file() {
+        let root = temp_dir("tree-checksum");
+        let dst = root.join("tree");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&dst));
+
+        let mut tree_meta = meta_sized("tree", 0);
+        (tree_meta.is_dir, tree_meta.tree_checksum) = (true, true);
+        write_message(&mut stream, WireFormat::Binary, &meta_message(tree_meta)).unwrap();
+        let reply = read_message(&mut stream, WireFormat::Binary).unwrap();
+        assert!(matches!(reply, Message::PreflightOk(_)));
+        // The second file loses a bit on the way; nothing per file notices.
+        let mut sent = StreamingChecksum::default();
+        for (name, body, arrived) in [("a.txt", b"one", b"one"), ("b.txt", b"two", b"twn")] {
+            offer(&mut stream, name, 3);
+            let result = send_body(&mut stream, 0, arrived, arrived);
+            assert!(result.ok, "{}", result.reason);
+            sent.update(body);
+        }
+        let trailer = FileChecksum {
+            alg: ChecksumAlg::default().name().to_string(),
+            digest: sent.finalize(),
+        };
+        write_message(&mut stream, WireFormat::Binary, &Message::Checksum(trailer)).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::TransferResult(result) => {
+                assert!(!result.ok);
+                assert!(result.reason.contains("Tree checksum mismatch"), "{}", result.reason);
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+
+        assert!(!receiver.join().unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_only_empty_subdirectories() {
+        let root = temp_dir("hollow");
+        let dst = root.join("tree");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&dst));
+
+        for name in ["tree", "a", "a/b", "c"] {
+            assert!(offer_dir(&mut stream, name).destination_exists);
+        }
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        let mut found: Vec<_> = fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        found.sort();
+        assert_eq!(found, ["a", "c"]);
+        assert!(dst.join("a/b").is_dir());
+        assert_eq!(fs::read_dir(dst.join("a/b")).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_directory_too_large_rejected_up_front() {
+        let root = temp_dir("no-space");
+        let dst = root.join("dst");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&dst));
+
+        let mut dir_meta = meta_sized("tree", u64::MAX / 2);
+        dir_meta.is_dir = true;
+        write_message(&mut stream, WireFormat::Binary, &meta_message(dir_meta)).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => {
+                assert!(fail.reason.contains("Insufficient disk space"), "{}", fail.reason)
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+
+        assert!(!receiver.join().unwrap());
+        assert!(!dst.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_resume_from_partial_temp_file() {
+        let root = temp_dir("resume");
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 7) as u8).collect();
+        fs::write(temp_path_for(&root.join("file.bin")), &data[..12_345]).unwrap();
+
+        let mut args = recv_args(&root);
+        args.resume = true;
+        let (mut stream, receiver) = spawn_receiver(args);
+
+        let ok = offer(&mut stream, "file.bin", data.len() as u64);
+        assert_eq!(ok.resume_offset, 12_345);
+        let result = send_body(&mut stream, ok.resume_offset, &data[12_345..], &data);
+        assert!(result.ok, "{}", result.reason);
+        assert_eq!(result.received_bytes, data.len() as u64);
+
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(root.join("file.bin")).unwrap(), data);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_read_catches_a_file_corrupted_on_disk() {
+        let root = temp_dir("verify-read");
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 13) as u8).collect();
+
+        let mut args = recv_args(&root);
+        args.verify_read = true;
+        let (mut stream, receiver) = spawn_receiver(args);
+        offer(&mut stream, "file.bin", data.len() as u64);
+        let result = send_body(&mut stream, 0, &data, &data);
+        assert!(result.ok, "{}", result.reason);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+
+        // As if the disk had flipped a bit after the rename.
+        let saved = root.join("file.bin");
+        let digest = calculate_file_checksum(&saved, ChecksumAlg::default()).unwrap();
+        verify_on_disk(&saved, "file.bin", ChecksumAlg::default(), &digest).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[20_000] ^= 0x01;
+        fs::write(&saved, &corrupt).unwrap();
+        let err = verify_on_disk(&saved, "file.bin", ChecksumAlg::default(), &digest).unwrap_err();
+        assert!(matches!(err, NcpError::ChecksumMismatch(_)), "{:?}", err);
+        assert!(err.to_string().contains("reads back"), "{}", err);
+        assert!(!saved.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_links_identical_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = temp_dir("dedup");
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 11) as u8).collect();
+        let other: Vec<u8> = data.iter().map(|b| b ^ 1).collect();
+
+        let mut args = recv_args(&root);
+        args.dedup = DedupMode::HardLink;
+        let (mut stream, receiver) = spawn_receiver(args);
+        for (name, body) in [("a.bin", &data), ("b.bin", &data), ("c.bin", &other)] {
+            offer(&mut stream, name, body.len() as u64);
+            let result = send_body(&mut stream, 0, body, body);
+            assert!(result.ok, "{}", result.reason);
+        }
+        finish(stream);
+        assert!(receiver.join().unwrap());
+
+        let inode = |name: &str| fs::metadata(root.join(name)).unwrap().ino();
+        assert_eq!(inode("a.bin"), inode("b.bin"));
+        assert_ne!(inode("a.bin"), inode("c.bin"));
+        assert_eq!(fs::read(root.join("b.bin")).unwrap(), data);
+        assert_eq!(fs::read(root.join("c.bin")).unwrap(), other);
+        assert!(!root.join("b.bin.ncp_dedup").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_fsync_saves_the_file() {
+        let root = temp_dir("fsync");
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 7) as u8).collect();
+
+        let mut args = recv_args(&root);
+        args.fsync = true;
+        let (mut stream, receiver) = spawn_receiver(args);
+        offer(&mut stream, "file.bin", data.len() as u64);
+        let result = send_body(&mut stream, 0, &data, &data);
+        assert!(result.ok, "{}", result.reason);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+
+        assert_eq!(fs::read(root.join("file.bin")).unwrap(), data);
+        assert!(!temp_path_for(&root.join("file.bin")).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_in_place_overwrites_without_a_temp_file() {
+        let root = temp_dir("in-place");
+        let path = root.join("file.bin");
+        fs::write(&path, vec![1u8; 80_000]).unwrap();
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i * 13) as u8).collect();
+
+        let mut args = recv_args(&root);
+        args.in_place = true;
+        let (mut stream, receiver) = spawn_receiver(args);
+        let ok = offer(&mut stream, "file.bin", data.len() as u64);
+        assert!(ok.destination_exists);
+        assert!(!temp_path_for(&path).exists());
+        let result = send_body(&mut stream, 0, &data, &data);
+        assert!(result.ok, "{}", result.reason);
+        finish(stream);
+        assert!(receiver.join().unwrap());
+
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert!(!temp_path_for(&path).exists());
+        assert!(!journal_path_for(&path).exists());
+
+        // A journal left by a crash marks the file as incomplete, so it is
+        // never taken for the sender's copy.
+        fs::write(journal_path_for(&path), "").unwrap();
+        let meta = FileMeta {
+            checksum: calculate_file_checksum(&path, ChecksumAlg::default()).unwrap(),
+            ..meta_sized("file.bin", data.len() as u64)
+        };
+        assert_eq!(compare_local(&path, &meta, ChecksumAlg::default()).unwrap(), Verdict::Mismatch);
+        fs::remove_file(journal_path_for(&path)).unwrap();
+        assert_eq!(compare_local(&path, &meta, ChecksumAlg::default()).unwrap(), Verdict::Match);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_overwrite_no_keeps_an_incomplete_file() {
+        let root = temp_dir("in-place-no");
+        let path = root.join("file.bin");
+        fs::write(&path, b"half").unwrap();
+        fs::write(journal_path_for(&path), "").unwrap();
+
+        let mut args = recv_args(&root);
+        args.in_place = true;
+        args.overwrite = OverwriteMode::No;
+        let (mut stream, receiver) = spawn_receiver(args);
+        let meta = meta_message(meta_sized("file.bin", 8));
+        write_message(&mut stream, WireFormat::Binary, &meta).unwrap();
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::PreflightFail(fail) => assert_eq!(fail.code, ErrorCode::ErrExists as i32),
+            other => panic!("unexpected {}", other.name()),
+        }
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"half");
+        assert!(journal_path_for(&path).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_overwrite_newer_compares_mtimes() {
+        let root = temp_dir("overwrite-newer");
+        let path = root.join("file.txt");
+        fs::write(&path, "existing").unwrap();
+        let existing = fs::metadata(&path).unwrap().modified().unwrap();
+        let offer_at = |stream: &mut TcpStream, mtime: SystemTime| {
+            let meta = FileMeta {
+                mtime: Some(mtime.into()),
+                ..meta_sized("file.txt", 8)
+            };
+            write_message(stream, WireFormat::Binary, &meta_message(meta)).unwrap();
+            read_message(stream, WireFormat::Binary).unwrap()
+        };
+
+        let mut args = recv_args(&root);
+        args.overwrite = OverwriteMode::Newer;
+        let (mut stream, receiver) = spawn_receiver(args);
+        let later = existing + Duration::from_secs(60);
+        assert!(matches!(offer_at(&mut stream, later), Message::PreflightOk(_)));
+        let result = send_body(&mut stream, 0, b"incoming", b"incoming");
+        assert!(result.ok, "{}", result.reason);
+        assert_eq!(fs::read(&path).unwrap(), b"incoming");
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), later);
+
+        // Anything not later than what is there now is skipped.
+        for mtime in [existing, later] {
+            match offer_at(&mut stream, mtime) {
+                Message::PreflightFail(fail) => assert_eq!(fail.code, ErrorCode::ErrExists as i32),
+                other => panic!("unexpected {}", other.name()),
+            }
+        }
+        finish(stream);
+        assert!(receiver.join().unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"incoming");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_temp_claims_do_not_collide() {
+        let root = temp_dir("claims");
+        let temps = TempFiles::default();
+        let final_path = root.join("file.bin");
+
+        let first = temps.claim(&final_path).unwrap();
+        assert_eq!(first.path, root.join("file.bin.ncp_temp"));
+        assert!(first.resumable);
+
+        let second = temps.claim(&final_path).unwrap();
+        assert_ne!(second.path, first.path);
+        assert!(!second.resumable);
+        assert!(second.path.to_string_lossy().ends_with(".ncp_temp"));
+
+        // Another process has its own claims; the file lock keeps it off
+        // the usual name too.
+        let other = TempFiles::default().claim(&final_path).unwrap();
+        assert!(!other.resumable);
+        assert_ne!(other.path, second.path);
+
+        // Unique temp files go with their claims; once released, the usual
+        // name is handed out again.
+        let (second_path, other_path) = (second.path.clone(), other.path.clone());
+        drop((second, other));
+        assert!(!second_path.exists() && !other_path.exists());
+        drop(first);
+        assert!(temps.claim(&final_path).unwrap().resumable);
+        let txt = temps.claim(&root.join("file.txt")).unwrap();
+        assert_eq!(txt.path, root.join("file.txt.ncp_temp"));
+
+        drop(txt);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_no_clobber_keeps_file_that_appeared() {
+        let root = temp_dir("no-clobber");
+        let temps = TempFiles::default();
+        let final_path = root.join("file.txt");
+
+        let mut temp = temps.claim(&final_path).unwrap();
+        temp.file.write_all(b"received").unwrap();
+        // Created by someone else between the existence check and the rename.
+        fs::write(&final_path, "theirs").unwrap();
+        let err = temp.persist(&final_path, false, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&final_path).unwrap(), b"theirs");
+        let temp_path = temp.path.clone();
+        drop(temp);
+        assert!(!temp_path.exists());
+
+        // Without a rival, and when overwriting was agreed to, it lands.
+        let mut temp = temps.claim(&root.join("new.txt")).unwrap();
+        temp.file.write_all(b"received").unwrap();
+        temp.persist(&root.join("new.txt"), false, false).unwrap();
+        let mut temp = temps.claim(&final_path).unwrap();
+        temp.file.write_all(b"received").unwrap();
+        temp.persist(&final_path, true, false).unwrap();
+        drop(temp);
+        assert_eq!(fs::read(root.join("new.txt")).unwrap(), b"received");
+        assert_eq!(fs::read(&final_path).unwrap(), b"received");
+        let left: Vec<_> = fs::read_dir(&root).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(left.len(), 2, "{:?}", left);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_receivers_same_name() {
+        // Separate receivers stand in for separate ncp processes writing
+        // into the same directory.
+        let root = temp_dir("same-name");
+        let first_data: Vec<u8> = (0..300_000u32).map(|i| (i * 3) as u8).collect();
+        let second_data: Vec<u8> = (0..200_000u32).map(|i| (i * 5 + 1) as u8).collect();
+        let (mut first, first_receiver) = spawn_receiver(recv_args(&root));
+        let (mut second, second_receiver) = spawn_receiver(recv_args(&root));
+
+        offer(&mut first, "file.bin", first_data.len() as u64);
+        offer(&mut second, "file.bin", second_data.len() as u64);
+        let result = send_body(&mut first, 0, &first_data, &first_data);
+        assert!(result.ok, "{}", result.reason);
+        let result = send_body(&mut second, 0, &second_data, &second_data);
+        assert!(result.ok, "{}", result.reason);
+
+        finish(first);
+        finish(second);
+        assert!(first_receiver.join().unwrap());
+        assert!(second_receiver.join().unwrap());
+        // The later transfer wins, intact.
+        assert_eq!(fs::read(root.join("file.bin")).unwrap(), second_data);
+        let names: Vec<_> = fs::read_dir(&root).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["file.bin"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_disconnect_mid_directory_is_failure() {
+        let root = temp_dir("disconnect");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+
+        offer_dir(&mut stream, "tree");
+        offer(&mut stream, "first.txt", 3);
+        let result = send_body(&mut stream, 0, b"one", b"one");
+        assert!(result.ok, "{}", result.reason);
+        // The sender goes away before its second file and without `Done`.
+        drop(stream);
+
+        assert!(!receiver.join().unwrap());
+        assert_eq!(fs::read(root.join("tree/first.txt")).unwrap(), b"one");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_failed_transfer_removes_temp_file() {
+        let root = temp_dir("abort");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+
+        offer(&mut stream, "file.bin", 100_000);
+        let start = TransferStart {
+            session_id: SESSION.to_string(),
+            mode: TransferMode::TransferRaw as i32,
+            file_size: 100_000,
+            ..Default::default()
+        };
+        write_message(&mut stream, WireFormat::Binary, &Message::TransferStart(start)).unwrap();
+        stream.write_all(&[7u8; 40_000]).unwrap();
+        drop(stream);
+
+        assert!(!receiver.join().unwrap());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Keeps everything it is told, for checking what a copy reports.
+    #[derive(Default)]
+    struct Recording {
+        starts: Vec<(u64, u64)>,
+        updates: Vec<u64>,
+        done: Vec<(String, u64)>,
+    }
+
+    impl ProgressReporter for Recording {
+        fn on_start(&mut self, done: u64, total: u64) {
+            self.starts.push((done, total));
+        }
+
+        fn on_progress(&mut self, done: u64, _total: u64) -> io::Result<()> {
+            self.updates.push(done);
+            Ok(())
+        }
+
+        fn on_file_done(&mut self, name: &str, done: u64) -> io::Result<()> {
+            self.done.push((name.to_string(), done));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_body_reports_every_byte() {
+        let root = temp_dir("reporter");
+        let mut args = recv_args(&root);
+        (args.buffer_size, args.progress_interval) = (1000, Duration::ZERO);
+        // Resumed at 500: only the rest of the file is on the wire.
+        let start = TransferStart {
+            mode: TransferMode::TransferRaw as i32,
+            file_size: 3000,
+            offset: 500,
+            ..Default::default()
+        };
+        let body = vec![3u8; 2500];
+        let mut checksum = StreamingChecksum::new(ChecksumAlg::default());
+        let (mut out, mut reporter) = (Vec::new(), Recording::default());
+        let input = BodyReader::new(&body[..], TransferMode::TransferRaw, 2500, 500).unwrap();
+        let sum = &mut checksum;
+        let copied = copy_body(input, &args, "f.bin", &start, sum, &mut out, None, &mut reporter);
+        let total = copied.unwrap();
+
+        assert_eq!((total, out.len()), (3000, 2500));
+        assert_eq!(reporter.starts, [(500, 3000)]);
+        assert_eq!(reporter.updates, [1500, 2500, 3000]);
+        assert_eq!(reporter.done, [("f.bin".to_string(), 3000)]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_short_body_is_reported_and_not_saved() {
+        let root = temp_dir("short");
+        let (mut stream, receiver) = spawn_receiver(recv_args(&root));
+
+        // Announce more than is sent, then stop sending.
+        offer(&mut stream, "file.bin", 1000);
+        let start = TransferStart {
+            session_id: SESSION.to_string(),
+            mode: TransferMode::TransferRaw as i32,
+            file_size: 1000,
+            ..Default::default()
+        };
+        write_message(&mut stream, WireFormat::Binary, &Message::TransferStart(start.clone())).unwrap();
+        stream.write_all(&[7u8; 600]).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        match read_message(&mut stream, WireFormat::Binary).unwrap() {
+            Message::TransferResult(result) => {
+                assert!(!result.ok);
+                assert_eq!(result.received_bytes, 600);
+                assert!(result.reason.contains("600 of 1000"), "{}", result.reason);
+            }
+            other => panic!("unexpected {}", other.name()),
+        }
+        assert!(!receiver.join().unwrap());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        // A sender that hangs up instead cannot be told, but the reason
+        // is still the short body, not the failed answer.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let args = recv_args(&root);
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let ledger = SpaceLedger::new();
+            handle_connection(stream, &args, None, &ledger, &TempFiles::default()).err().unwrap()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        handshake::open(&mut stream, SESSION, None, None).unwrap();
+        offer(&mut stream, "file.bin", 1000);
+        write_message(&mut stream, WireFormat::Binary, &Message::TransferStart(start)).unwrap();
+        stream.write_all(&[7u8; 600]).unwrap();
+        drop(stream);
+        let err = receiver.join().unwrap().to_string();
+        assert!(err.contains("received 600 of 1000 bytes"), "{}", err);
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_psk_writes_nothing() {
+        let root = temp_dir("psk");
+        let mut args = recv_args(&root);
+        args.psk = Some("correct horse".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &args, None, &SpaceLedger::new(), &TempFiles::default()).is_ok()
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let err = handshake::open(&mut stream, SESSION, None, Some(b"battery staple")).unwrap_err();
+        assert!(err.to_string().contains("failed authentication"), "{}", err);
+        drop(stream);
+        assert!(!receiver.join().unwrap());
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_collect_extraneous() {
+        let root = temp_dir("mirror");
+        fs::create_dir_all(root.join("keep/stale_dir")).unwrap();
+        fs::write(root.join("keep/file.txt"), b"a").unwrap();
+        fs::write(root.join("keep/stale_dir/inner.txt"), b"b").unwrap();
+        fs::write(root.join("stale.txt"), b"c").unwrap();
+
+        let expected: HashSet<&Path> = ["keep", "keep/file.txt"].into_iter().map(Path::new).collect();
+        let mut out = Vec::new();
+        collect_extraneous(&root, Path::new(""), &expected, &mut out).unwrap();
+
+        let names: Vec<&Path> = out.iter().map(|(rel, _, _)| rel.as_path()).collect();
+        assert_eq!(names, vec![Path::new("keep/stale_dir"), Path::new("stale.txt")]);
+        assert!(out[0].2);
+        assert!(!out[1].2);
+
+        // A sender lists it as "\u{FFFD}.txt", as it would other names.
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            fs::write(root.join(std::ffi::OsStr::from_bytes(b"\xff.txt")), b"d").unwrap();
+            let mut again = Vec::new();
+            collect_extraneous(&root, Path::new(""), &expected, &mut again).unwrap();
+            assert_eq!(again, out);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+//! Retries of the receiver's writes to disk that fail for a moment: a full
+//! disk that something else is clearing, a busy network file system. Each
+//! write of a file's body, and the rename that puts it in place, is tried
+//! up to `ATTEMPTS` times, `FIRST_DELAY` apart and then twice as long each
+//! time, before its error ends the transfer.
+//!
+//! Only errors that can clear by themselves are retried; one such as
+//! `PermissionDenied` or `NotFound` fails at once. A write that fails has
+//! written nothing, so trying it again cannot leave a gap or a duplicate.
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+/// How many times an operation is tried in all.
+pub const ATTEMPTS: u32 = 5;
+
+/// The wait before the second try.
+pub const FIRST_DELAY: Duration = Duration::from_millis(50);
+
+/// Whether `e` can clear without anyone doing anything about it.
+pub fn is_transient(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY | libc::ENOSPC)) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::StorageFull
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+/// Run `op` until it succeeds, fails for good, or has been tried
+/// `ATTEMPTS` times. `what` names it in the log.
+pub fn transient<T>(what: &str, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = FIRST_DELAY;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if attempt < ATTEMPTS && is_transient(&e) => {
+                vlog!("{} failed ({}); trying again in {:?}", what, e, delay);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A writer whose writes are retried as `transient` says. It goes beneath
+/// any buffering, so that each retry is of one write to the file.
+pub struct Retrying<W> {
+    inner: W,
+}
+
+impl<W: Write> Retrying<W> {
+    pub fn new(inner: W) -> Self {
+        Retrying { inner }
+    }
+}
+
+impl<W: Write> Write for Retrying<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        transient("Write", || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        transient("Flush", || self.inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails each write with one of `errors`, in order, then writes.
+    struct Flaky {
+        errors: Vec<io::ErrorKind>,
+        calls: u32,
+        written: Vec<u8>,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if !self.errors.is_empty() {
+                return Err(self.errors.remove(0).into());
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn flaky(errors: &[io::ErrorKind]) -> Retrying<Flaky> {
+        Retrying::new(Flaky { errors: errors.to_vec(), calls: 0, written: Vec::new() })
+    }
+
+    #[test]
+    fn test_transient_write_failure_succeeds_on_retry() {
+        let mut writer = flaky(&[io::ErrorKind::StorageFull, io::ErrorKind::ResourceBusy]);
+        writer.write_all(b"received").unwrap();
+        assert_eq!(writer.inner.calls, 3);
+        assert_eq!(writer.inner.written, b"received");
+
+        // Denied is not going to change; it is not tried again.
+        let mut writer = flaky(&[io::ErrorKind::PermissionDenied]);
+        let err = writer.write_all(b"received").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(writer.inner.calls, 1);
+
+        // Nor is a transient one past the last attempt.
+        let mut writer = flaky(&[io::ErrorKind::TimedOut; ATTEMPTS as usize]);
+        let err = writer.write_all(b"received").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(writer.inner.calls, ATTEMPTS);
+        assert!(writer.inner.written.is_empty());
+    }
+}
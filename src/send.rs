@@ -0,0 +1,2814 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prost_types::Timestamp;
+
+use crate::batch;
+use crate::checksum::{
+    digests_equal, hash_prefix, to_hex, ChecksumAlg, ChecksumScope, StreamingChecksum, RETRY_CAPABILITY,
+    TREE_CAPABILITY,
+};
+use crate::compress::{self, BodyWriter, SharedEncoder};
+use crate::directory::{
+    self, calculate_total_size, common_base, list_sources, walk_directory, walk_listed, walk_relative,
+    FileEntry, Totals,
+};
+use crate::events;
+use crate::glob;
+use crate::handshake;
+use crate::keepalive::{self, Keepalive};
+use crate::logging;
+use crate::net::{self, Stream};
+use crate::parallel;
+use crate::pause;
+use crate::progress::{self, ProgressReporter};
+use crate::events::Summary;
+use crate::proto::{
+    ErrorCode, FileMeta, Meta, PreflightFail, PreflightOk, RangeStart, TransferMode, TransferResult,
+    TransferStart,
+};
+use crate::protocol::{
+    mirror_list_parts, raw_name, read_control, read_message, write_message, FileChecksum, Message,
+    WireFormat, UNKNOWN_SIZE,
+};
+use crate::types::{LimitScope, MirrorMode, NcpError, Result, SendArgs};
+use crate::utils::{
+    describe_throughput, format_bytes, CountingWriter, FileProgress, ProgressLine, ProgressTicker,
+    RateMeter, Throttle,
+};
+use crate::xattrs;
+use crate::zerocopy::ZeroCopy;
+
+/// The source once any wildcards in it are expanded.
+enum Source {
+    /// `args.src` itself, a file or a directory.
+    Path,
+    /// Everything matching the `args.src` pattern, sent like the contents of
+    /// one directory.
+    Matches(Vec<PathBuf>),
+    /// The paths of `--files-from` that exist, each named by its path below
+    /// `base` as with `--relative-to`.
+    Listed { paths: Vec<PathBuf>, base: PathBuf },
+    /// Standard input (`-`), sent as one file of unknown size. Set once
+    /// reading has begun: what was read cannot be sent again, so a failed
+    /// attempt is not retried after that.
+    Stdin(Cell<bool>),
+}
+
+/// Run the transfer `args` describe and return what became of each file. A
+/// dry run only prints its plan and reports nothing.
+pub fn execute(args: SendArgs) -> Result<Summary> {
+    let summary = send_source(&args);
+    // Kept after a failed send too: what was hashed is still valid.
+    if let Err(e) = args.checksum_cache.save() {
+        eprintln!("Warning: {}", e);
+    }
+    summary
+}
+
+/// `execute`, short of saving the checksum cache.
+fn send_source(args: &SendArgs) -> Result<Summary> {
+    // A path that exists is taken literally even if it contains wildcards.
+    let source = if let Some(paths) = &args.files_from {
+        listed_source(args, paths)?
+    } else if args.reads_stdin() {
+        Source::Stdin(Cell::new(false))
+    } else if !args.src.exists() && glob::has_magic(&args.src) {
+        Source::Matches(glob::expand(&args.src)?)
+    } else {
+        Source::Path
+    };
+    if matches!(source, Source::Path) && !args.src.exists() {
+        return Err(format!("Source path does not exist: {}", args.src.display()).into());
+    }
+    // As with `cp`, a tree is only walked when asked for.
+    if !args.recursive {
+        let directory = match &source {
+            Source::Path => args.src.is_dir().then_some(&args.src),
+            Source::Matches(paths) | Source::Listed { paths, .. } => {
+                paths.iter().find(|path| path.is_dir())
+            }
+            Source::Stdin(_) => None,
+        };
+        if let Some(directory) = directory {
+            let message = format!("{} is a directory; use -r to send a directory", directory.display());
+            return Err(message.into());
+        }
+    }
+    if args.mirror != MirrorMode::Off && !(matches!(source, Source::Path) && args.src.is_dir()) {
+        return Err("--mirror requires a directory source".into());
+    }
+    if args.relative_to.is_some() && matches!(source, Source::Matches(_)) {
+        return Err("--relative-to cannot be combined with a wildcard source".into());
+    }
+    let walks = match source {
+        Source::Matches(_) | Source::Listed { .. } => true,
+        Source::Path => args.src.is_dir(),
+        Source::Stdin(_) => false,
+    };
+    if !args.filter.is_empty() && !walks {
+        return Err("--include and --exclude require a directory or wildcard source".into());
+    }
+
+    if args.dry_run {
+        let entries = planned_entries(args, &source)?;
+        write_plan(&mut std::io::stdout().lock(), &entries)?;
+        return Ok(Summary::new());
+    }
+
+    if args.listen {
+        return execute_listen(args, &source);
+    }
+
+    let host = args.host.as_deref().ok_or("--host is required unless --listen is given")?;
+
+    // Carried from one attempt to the next, so a retried directory transfer
+    // goes on from where the last one stopped.
+    let mut delivered = Summary::new();
+    for attempt in 1..=args.retries {
+        match attempt_transfer(host, args, &source, &mut delivered) {
+            Ok(summary) => return Ok(summary),
+            Err(e) => {
+                let e = net::describe(e);
+                if let Source::Stdin(started) = &source
+                    && started.get()
+                {
+                    return Err(format!("{} (stdin was partly sent, not retrying)", e).into());
+                }
+                // A file that arrived corrupt over and over is not sent once more.
+                if let NcpError::RetriesExhausted(..) = e {
+                    return Err(e);
+                }
+                eprintln!("Attempt {}/{} failed: {}", attempt, args.retries, e);
+                if let NcpError::Auth(_) = e {
+                    vlog!("Not retrying: the receiver would be refused again");
+                    return Err(e);
+                }
+                if let Some(code) = e.peer_code().filter(|&code| !worth_retrying(code)) {
+                    vlog!("Not retrying: the receiver would fail with {} again", code.as_str_name());
+                    return Err(e);
+                }
+                if attempt == args.retries {
+                    return Err(NcpError::RetriesExhausted(attempt, Box::new(e)));
+                }
+                let delay = jittered(retry_delay(args, attempt - 1));
+                vlog!("Retrying in {:.1}s", delay.as_secs_f64());
+                thread::sleep(delay);
+            }
+        }
+    }
+    unreachable!("--retries is at least 1")
+}
+
+/// The `Source` for the paths of `--files-from`, of which those that do not
+/// exist fail the send, or with `--ignore-missing` are recorded as skipped.
+fn listed_source(args: &SendArgs, paths: &[PathBuf]) -> Result<Source> {
+    let mut present = Vec::new();
+    for path in paths {
+        if path.exists() {
+            present.push(path.clone());
+            continue;
+        }
+        if !args.ignore_missing {
+            return Err(format!("Listed path does not exist: {}", path.display()).into());
+        }
+        eprintln!("Warning: skipping {}: it does not exist", path.display());
+        args.manifest.skipped(&path.to_string_lossy(), 0, "does not exist")?;
+    }
+    if present.is_empty() {
+        return Err("No listed path to send (--files-from)".into());
+    }
+    let base = match &args.relative_to {
+        Some(base) => base.clone(),
+        None => common_base(&present)?,
+    };
+    vlog!("Sending {} listed paths below {}", present.len(), base.display());
+    Ok(Source::Listed { paths: present, base })
+}
+
+/// Whether a receiver that failed with `code` may do better next time. The
+/// others are about the destination or our own arguments, which a new
+/// attempt does not change.
+fn worth_retrying(code: ErrorCode) -> bool {
+    !matches!(
+        code,
+        ErrorCode::ErrPermission
+            | ErrorCode::ErrNoSpace
+            | ErrorCode::ErrAuth
+            | ErrorCode::ErrInvalidArg
+            | ErrorCode::ErrExists
+            | ErrorCode::ErrTooLarge
+    )
+}
+
+/// Longest wait between attempts that `--retry-backoff` grows to, unless
+/// `--retry-delay` itself is longer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Wait after the failed attempt number `retry` (from 0), before jitter.
+fn retry_delay(args: &SendArgs, retry: u32) -> Duration {
+    let cap = MAX_RETRY_DELAY.max(args.retry_delay);
+    let scale = args.retry_backoff.powi(retry.min(i32::MAX as u32) as i32);
+    let delay = args.retry_delay.as_secs_f64() * scale;
+    Duration::try_from_secs_f64(delay).map_or(cap, |delay| delay.min(cap))
+}
+
+/// Spread `delay` by up to 10% either way, so that senders started together
+/// do not all come back at once.
+fn jittered(delay: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(delay.as_nanos());
+    let spread = (hasher.finish() % 2001) as f64 / 10_000.0 - 0.1;
+    delay.mul_f64(1.0 + spread)
+}
+
+/// What a transfer of `source` would send, in order. Paths are relative to
+/// the destination as the receiver would place them.
+fn planned_entries(args: &SendArgs, source: &Source) -> Result<Vec<FileEntry>> {
+    if let (Source::Path, Some(base)) = (source, &args.relative_to) {
+        return walk_relative(&args.src, base, &args.filter)?.collect();
+    }
+    match source {
+        Source::Matches(paths) => list_sources(paths, &args.filter)?.collect(),
+        Source::Listed { paths, base } => walk_listed(paths, base, &args.filter).collect(),
+        Source::Path if args.src.is_dir() => walk_directory(&args.src, &args.filter)?.collect(),
+        Source::Stdin(_) => Err("--dry-run cannot be combined with reading stdin".into()),
+        Source::Path => {
+            let name = args.src.file_name().ok_or("Source path has no file name")?;
+            Ok(vec![FileEntry {
+                path: args.src.clone(),
+                relative_path: name.to_string_lossy().to_string(),
+                exact_relative_path: PathBuf::from(name),
+                size: args.src.metadata()?.len(),
+                is_dir: false,
+            }])
+        }
+    }
+}
+
+/// `--dry-run` report: one line per entry, then the totals.
+fn write_plan<W: Write>(out: &mut W, entries: &[FileEntry]) -> std::io::Result<()> {
+    for entry in entries {
+        if entry.is_dir {
+            writeln!(out, "dir  {:>14}  {}/", "-", entry.relative_path)?;
+        } else {
+            writeln!(out, "file {:>14}  {}", entry.size, entry.relative_path)?;
+        }
+    }
+
+    let files = entries.iter().filter(|e| !e.is_dir).count();
+    let dirs = entries.len() - files;
+    let total = calculate_total_size(entries);
+    let count = |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    writeln!(
+        out,
+        "{}, {}, {} ({} bytes) in total",
+        count(files, "file", "files"),
+        count(dirs, "directory", "directories"),
+        format_bytes(total),
+        total
+    )?;
+    writeln!(out, "The destination needs {} of free space", format_bytes(total))
+}
+
+/// Wait for a receiver to connect to us, then run the transfer over that
+/// connection.
+fn execute_listen(args: &SendArgs, source: &Source) -> Result<Summary> {
+    let (listener, port) = net::listen(args.family, args.port)?;
+    events::listening(port);
+    status!("Waiting for receiver on port {}", port);
+
+    // Only ever one receiver: the transfer ends with its connection.
+    let (stream, peer) = net::accept(&listener, args.accept_timeout)?;
+    status!("Connection established with {}", peer);
+    net::configure(&stream, args.timeout)?;
+    net::tune(&stream, &args.socket)?;
+    // The receiver's certificate is checked against the address it came from.
+    let mut stream = match &args.tls {
+        Some(tls) => tls.connect(stream, &peer.ip().to_string())?,
+        None => Stream::from(stream),
+    };
+
+    run_transfer(&mut stream, args, source, &mut Summary::new()).map_err(net::describe)
+}
+
+fn attempt_transfer(
+    host: &str,
+    args: &SendArgs,
+    source: &Source,
+    delivered: &mut Summary,
+) -> Result<Summary> {
+    let mut stream = connect(host, args)?;
+    status!("Connection established with {}:{}", host, args.port);
+
+    run_transfer(&mut stream, args, source, delivered)
+}
+
+/// Open a connection to the receiver at `host`, through `--proxy` if given,
+/// its socket set up as `args.socket` says and inside TLS with `--tls`.
+fn connect(host: &str, args: &SendArgs) -> Result<Stream> {
+    let stream = match &args.proxy {
+        Some(proxy) => proxy.connect(host, args.port, args.family, args.timeout, args.bind)?,
+        None => net::connect(host, args.port, args.family, args.timeout, args.bind)?,
+    };
+    net::tune(&stream, &args.socket)?;
+    match &args.tls {
+        Some(tls) => tls.connect(stream, host),
+        None => Ok(Stream::from(stream)),
+    }
+}
+
+/// Run one attempt at the transfer over `stream`. In a directory or
+/// wildcard transfer, files already in `delivered` are not offered again,
+/// and each one settled is added to it as it goes.
+fn run_transfer(
+    stream: &mut Stream,
+    args: &SendArgs,
+    source: &Source,
+    delivered: &mut Summary,
+) -> Result<Summary> {
+    let session_id = handshake::new_session_id();
+    let psk = args.psk.as_deref().map(str::as_bytes);
+    let established = handshake::open(stream, &session_id, args.timeout, psk)?;
+    events::transfer_start(&session_id, &net::peer_name(stream));
+    let mut tree_checksum = args.checksum_scope == ChecksumScope::Tree && !established.verify_only;
+    if tree_checksum && !established.capabilities.iter().any(|c| c == TREE_CAPABILITY) {
+        status!("The receiver cannot verify a tree checksum; verifying each file instead");
+        tree_checksum = false;
+    }
+    let mut session = Session {
+        keepalive: keepalive::agreed(&established.capabilities, established.keepalive_seconds),
+        verify_only: established.verify_only,
+        tree_checksum,
+        tree_digest: Cell::new(None),
+        mirror: args.mirror,
+        parallel: 1,
+        shared_zstd: None,
+        retry_files: established.capabilities.iter().any(|c| c == RETRY_CAPABILITY),
+        batch: false,
+        id: session_id,
+    };
+    if session.verify_only {
+        status!("The receiver is only verifying: sending checksums, not data");
+    }
+    // A pulling receiver may ask for only part of the source.
+    let pulled = if established.pull {
+        handshake::serve_pull(stream, &session.id, |path| resolve_pull(args, source, path))?
+    } else {
+        None
+    };
+    let src = pulled.as_deref().unwrap_or(&args.src);
+    let requested = match (established.delete, established.delete_dry_run) {
+        (false, _) => MirrorMode::Off,
+        (true, false) => MirrorMode::Delete,
+        (true, true) => MirrorMode::DryRun,
+    };
+    session.mirror = mirror_mode(args, source, src, requested);
+    // Only a single file is split, over connections the receiver can take.
+    let single = matches!(source, Source::Path) && args.relative_to.is_none() && !src.is_dir();
+    if args.parallel > 1 && single {
+        if established.capabilities.iter().any(|c| c == parallel::CAPABILITY) {
+            session.parallel = args.parallel;
+        } else {
+            status!("The receiver takes no extra connections; sending over one (--parallel)");
+        }
+    }
+    // Only the files of a tree have others to be compressed against.
+    if args.compress_shared && !single && !matches!(source, Source::Stdin(_)) {
+        if established.capabilities.iter().any(|c| c == compress::SHARED_CAPABILITY) {
+            session.shared_zstd = Some(SharedEncoder::new(args.compress_level)?);
+        } else {
+            let note = "compressing each file on its own (--compress-shared)";
+            status!("The receiver cannot take one zstd stream across files; {}", note);
+        }
+    }
+    // Only the files of a tree that each get their own answer can wait for it.
+    if args.batch && !single && !session.verify_only && !session.tree_checksum {
+        if established.capabilities.iter().any(|c| c == batch::CAPABILITY) {
+            session.batch = true;
+        } else {
+            status!("The receiver cannot take files in batches; sending them one by one (--batch)");
+        }
+    }
+    negotiate_format(stream, args.format)?;
+
+    let summary = match (source, args.relative_to.as_deref()) {
+        (Source::Path, Some(base)) => {
+            transfer_relative(stream, args, &session, src, base, delivered)?
+        }
+        (Source::Matches(paths), _) => transfer_matches(stream, args, &session, paths, delivered)?,
+        (Source::Listed { paths, base }, _) => {
+            transfer_listed(stream, args, &session, paths, base, delivered)?
+        }
+        (Source::Path, None) if src.is_dir() => {
+            transfer_directory(stream, args, &session, src, delivered)?
+        }
+        (Source::Path, None) => transfer_single_file(stream, args, &session, src)?,
+        (Source::Stdin(started), _) => transfer_stdin(stream, args, &session, started)?,
+    };
+    // Without it the receiver cannot tell the end from a dropped connection.
+    write_message(stream, args.format, &Message::Done)?;
+    Ok(summary)
+}
+
+/// What the two ends of one connection agreed on.
+struct Session {
+    id: String,
+    /// How often to ping the receiver while we are busy; `None` if it does
+    /// not take pings.
+    keepalive: Option<Duration>,
+    /// The receiver wants every file's checksum and none of its data
+    /// (`recv --verify-only`).
+    verify_only: bool,
+    /// A directory is verified with one digest over all its files
+    /// (`--checksum tree`), which the receiver supports.
+    tree_checksum: bool,
+    /// That digest so far, while such a directory is being sent.
+    tree_digest: Cell<Option<StreamingChecksum>>,
+    /// Whether a directory is followed by the mirror list: `--mirror`, or
+    /// the receiver's `--delete`.
+    mirror: MirrorMode,
+    /// Connections a single file may be split over (`--parallel`), once
+    /// the receiver has said it takes them.
+    parallel: u32,
+    /// The zstd stream the files of a tree are compressed into one after
+    /// the other (`--compress-shared`), once the receiver has said it
+    /// takes it.
+    shared_zstd: Option<SharedEncoder>,
+    /// A file that fails its checksum at the receiver is offered again on
+    /// this connection, up to `--retries` times in all.
+    retry_files: bool,
+    /// The small files of a directory go in batches (`--batch`), which the
+    /// receiver takes.
+    batch: bool,
+}
+
+fn meta_message(session_id: &str, file: FileMeta) -> Message {
+    Message::Meta(Meta {
+        session_id: session_id.to_string(),
+        file: Some(file),
+    })
+}
+
+/// Ask the receiver to switch to a non-default control format. The request
+/// and its acknowledgement are always binary; a receiver that does not know
+/// the format answers with the one it will keep using.
+fn negotiate_format(stream: &mut Stream, format: WireFormat) -> Result<()> {
+    if format == WireFormat::Binary {
+        return Ok(());
+    }
+
+    write_message(stream, WireFormat::Binary, &Message::Format(format.name().to_string()))?;
+    match read_message(stream, WireFormat::Binary)? {
+        Message::Format(name) if name == format.name() => {
+            vlog!("Using {} control format", name);
+            Ok(())
+        }
+        Message::Format(name) => Err(format!(
+            "Receiver does not support the {} control format (offered {})",
+            format.name(),
+            name
+        )
+        .into()),
+        other => {
+            let message = format!("Unexpected {} during format negotiation", other.name());
+            Err(NcpError::Protocol(message))
+        }
+    }
+}
+
+/// What a pulling receiver asked for: `None` for the whole source, or a
+/// path inside a directory source. Nothing outside the source is served.
+fn resolve_pull(args: &SendArgs, source: &Source, requested: &str) -> Result<Option<PathBuf>> {
+    let relative = Path::new(requested);
+    if relative.components().all(|c| c == Component::CurDir) {
+        return Ok(None);
+    }
+    if !matches!(source, Source::Path) {
+        return Err("only the whole source is served".into());
+    }
+    if !args.src.is_dir() {
+        // A single file may also be asked for by name.
+        let name = args.src.file_name().unwrap_or_default();
+        if relative.as_os_str() == name {
+            return Ok(None);
+        }
+        return Err(format!("only {} is served", name.to_string_lossy()).into());
+    }
+    if !relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err("not a relative path inside the source".into());
+    }
+    // A symlink inside the source could still lead out of it.
+    let path = args.src.join(relative);
+    match (path.canonicalize(), args.src.canonicalize()) {
+        (Ok(resolved), Ok(root)) if resolved.starts_with(&root) => Ok(Some(path)),
+        (Ok(_), Ok(_)) => Err("not inside the source".into()),
+        _ => Err("no such file or directory".into()),
+    }
+}
+
+fn transfer_single_file(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+) -> Result<Summary> {
+    let exact_name = Path::new(path.file_name().ok_or("Source path has no file name")?);
+    let name = exact_name.to_string_lossy();
+    let size = path.metadata()?.len();
+
+    let mut summary = Summary::new();
+    let mut reporter = progress::terminal("Sent");
+    match send_file_entry(stream, args, session, path, exact_name, size, &mut *reporter)? {
+        Offered::Sent(checksum) => {
+            summary.transferred(&name, size, &checksum);
+            status!("Transfer complete: {} ({})", name, format_bytes(size));
+        }
+        Offered::Unchanged => {
+            summary.skipped(&name, size);
+            status!("{} is already up to date", name);
+        }
+        Offered::Declined(fail) => return Err(declined(&name, fail)),
+    }
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Name offered to the receiver for data read from stdin.
+const STDIN_NAME: &str = "stdin";
+
+fn transfer_stdin(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    started: &Cell<bool>,
+) -> Result<Summary> {
+    let stdin = std::io::stdin().lock();
+    let (size, checksum) = match send_stream(stream, args, session, stdin, started) {
+        Ok(sent) => sent,
+        Err(e) => {
+            let _ = args.manifest.failed(STDIN_NAME, UNKNOWN_SIZE, &e.to_string());
+            return Err(e);
+        }
+    };
+
+    let mut summary = Summary::new();
+    summary.transferred(STDIN_NAME, size, &checksum);
+    status!("Transfer complete: {} ({})", STDIN_NAME, format_bytes(size));
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Send everything `reader` yields as one file of unknown size and wait for
+/// the receiver's verdict. Returns the number of bytes sent and their
+/// checksum in hex.
+fn send_stream<R: Read>(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    mut reader: R,
+    started: &Cell<bool>,
+) -> Result<(u64, String)> {
+    let format = args.format;
+    let mode = transfer_mode(args);
+    let meta = FileMeta {
+        name: STDIN_NAME.to_string(),
+        size: UNKNOWN_SIZE,
+        checksum_alg: args.checksum.name().to_string(),
+        transfer_mode: mode as i32,
+        overwrite: args.overwrite.as_policy() as i32,
+        ..Default::default()
+    };
+    write_message(stream, format, &meta_message(&session.id, meta))?;
+    if let Some(fail) = read_preflight(stream, format)? {
+        return Err(declined(STDIN_NAME, fail));
+    }
+
+    let start = TransferStart {
+        session_id: session.id.clone(),
+        mode: mode as i32,
+        file_size: UNKNOWN_SIZE,
+        ..Default::default()
+    };
+    write_message(stream, format, &Message::TransferStart(start))?;
+    events::file_start(STDIN_NAME, UNKNOWN_SIZE);
+    started.set(true);
+
+    let mut checksum = StreamingChecksum::new(args.checksum);
+    // What went onto the connection, for `--limit-scope wire`.
+    let wire = Cell::new(0);
+    let counted = CountingWriter::new(&mut *stream, &wire);
+    let mut body = BodyWriter::new(counted, mode, UNKNOWN_SIZE, args.compress_level)?;
+    let mut buffer = vec![0u8; args.buffer_size];
+    let mut total_sent = 0u64;
+    let mut progress = ProgressTicker::new(args.progress_interval);
+    let mut reporter = progress::terminal("Sent");
+    reporter.on_start(0, UNKNOWN_SIZE);
+    let mut throttle = args.limit.map(Throttle::new);
+
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        body.write_all(&buffer[..n])?;
+        checksum.update(&buffer[..n]);
+        total_sent += n as u64;
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.sent(limited(args, n, &wire));
+        }
+
+        if progress.tick() {
+            events::file_progress(STDIN_NAME, total_sent, UNKNOWN_SIZE);
+            reporter.on_progress(total_sent, UNKNOWN_SIZE)?;
+        }
+    }
+    body.finish()?;
+    reporter.on_file_done(STDIN_NAME, total_sent)?;
+
+    let digest = checksum.finalize();
+    let trailer = FileChecksum {
+        alg: args.checksum.name().to_string(),
+        digest: digest.clone(),
+    };
+    write_message(stream, format, &Message::Checksum(trailer))?;
+
+    let result = read_transfer_result(stream, format)?;
+    if !result.ok {
+        return Err(failed(result));
+    }
+    vvlog!("Receiver confirmed {} bytes", result.received_bytes);
+    let digest = to_hex(&digest);
+    events::file_done(STDIN_NAME, total_sent, &digest);
+    args.manifest.done(STDIN_NAME, total_sent, &digest, None)?;
+    Ok((total_sent, digest))
+}
+
+fn transfer_directory(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    src: &Path,
+    delivered: &mut Summary,
+) -> Result<Summary> {
+    // Sizes come from a first pass; entries are sent from a second one as
+    // they are found, so a large tree is never held in memory.
+    let totals = {
+        let _keepalive = Keepalive::start(stream, args.format, session.keepalive);
+        walk_directory(src, &args.filter)?.totals()?
+    };
+    let root_name = src
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| ".".to_string());
+
+    status!(
+        "Sending directory {} ({} files, {})",
+        root_name,
+        totals.files,
+        format_bytes(totals.bytes)
+    );
+
+    let root_meta = FileMeta {
+        raw_name: raw_name(Path::new(src.file_name().unwrap_or_default())),
+        name: root_name,
+        size: totals.bytes,
+        is_dir: true,
+        mode: 0o755,
+        mtime: modified(args, src)?,
+        ..Default::default()
+    };
+    let entries = walk_directory(src, &args.filter)?;
+    let summary = transfer_entries(stream, args, session, root_meta, totals, entries, delivered)?;
+
+    status!("Directory transfer complete: {}", describe_summary(&summary));
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Send the paths a wildcard source matched. The receiver sees a directory
+/// transfer, so they all land inside the destination.
+fn transfer_matches(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    paths: &[PathBuf],
+    delivered: &mut Summary,
+) -> Result<Summary> {
+    let totals = {
+        let _keepalive = Keepalive::start(stream, args.format, session.keepalive);
+        list_sources(paths, &args.filter)?.totals()?
+    };
+    let pattern = args.src.display().to_string();
+
+    status!(
+        "Sending {} matches of {} ({})",
+        paths.len(),
+        pattern,
+        format_bytes(totals.bytes)
+    );
+
+    let root_meta = FileMeta {
+        name: pattern,
+        size: totals.bytes,
+        is_dir: true,
+        mode: 0o755,
+        contents_only: true,
+        ..Default::default()
+    };
+    let entries = list_sources(paths, &args.filter)?;
+    let summary = transfer_entries(stream, args, session, root_meta, totals, entries, delivered)?;
+
+    status!("Transfer complete: {}", describe_summary(&summary));
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Send `src` named by its path below `base` (`--relative-to`). Like
+/// wildcard matches, it goes as the contents of one directory, so the
+/// receiver recreates that path inside the destination.
+fn transfer_relative(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    src: &Path,
+    base: &Path,
+    delivered: &mut Summary,
+) -> Result<Summary> {
+    let totals = {
+        let _keepalive = Keepalive::start(stream, args.format, session.keepalive);
+        walk_relative(src, base, &args.filter)?.totals()?
+    };
+    let name = src.display().to_string();
+
+    status!(
+        "Sending {} relative to {} ({} files, {})",
+        name,
+        base.display(),
+        totals.files,
+        format_bytes(totals.bytes)
+    );
+
+    let root_meta = FileMeta {
+        name,
+        size: totals.bytes,
+        is_dir: true,
+        mode: 0o755,
+        contents_only: true,
+        ..Default::default()
+    };
+    let entries = walk_relative(src, base, &args.filter)?;
+    let summary = transfer_entries(stream, args, session, root_meta, totals, entries, delivered)?;
+
+    status!("Transfer complete: {}", describe_summary(&summary));
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Send the paths of `--files-from` by their paths below `base`, each as
+/// `transfer_relative` sends one, in the order listed.
+fn transfer_listed(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    paths: &[PathBuf],
+    base: &Path,
+    delivered: &mut Summary,
+) -> Result<Summary> {
+    let totals = {
+        let _keepalive = Keepalive::start(stream, args.format, session.keepalive);
+        directory::totals(walk_listed(paths, base, &args.filter))?
+    };
+    let name = base.display().to_string();
+
+    status!(
+        "Sending {} listed paths relative to {} ({} files, {})",
+        paths.len(),
+        name,
+        totals.files,
+        format_bytes(totals.bytes)
+    );
+
+    let root_meta = FileMeta {
+        name,
+        size: totals.bytes,
+        is_dir: true,
+        mode: 0o755,
+        contents_only: true,
+        ..Default::default()
+    };
+    let entries = walk_listed(paths, base, &args.filter);
+    let summary = transfer_entries(stream, args, session, root_meta, totals, entries, delivered)?;
+
+    status!("Transfer complete: {}", describe_summary(&summary));
+    events::done(&summary);
+    Ok(summary)
+}
+
+/// Files sent, their bytes, how long it all took and the average rate, e.g.
+/// `12 files, 3.00 MiB in 1.52s (1.97 MiB/s)`.
+fn describe_summary(summary: &Summary) -> String {
+    let files = summary.files_transferred();
+    let throughput = describe_throughput(summary.total_bytes(), summary.elapsed());
+    format!("{} file{}, {}", files, if files == 1 { "" } else { "s" }, throughput)
+}
+
+/// Announce `root` and then send `entries` relative to it as they come,
+/// followed by the mirror list if requested. `totals` only drives progress.
+/// Files in `summary`, settled by an earlier attempt, are passed over, and
+/// each file settled now is added to it, so that if this attempt fails the
+/// next can go on from there; on success, the whole of it is returned.
+fn transfer_entries(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    mut root: FileMeta,
+    totals: Totals,
+    entries: impl Iterator<Item = Result<FileEntry>>,
+    summary: &mut Summary,
+) -> Result<Summary> {
+    let format = args.format;
+
+    if session.tree_checksum {
+        root.tree_checksum = true;
+        root.checksum_alg = args.checksum.name().to_string();
+        session.tree_digest.set(Some(StreamingChecksum::new(args.checksum)));
+    }
+    // The root entry tells the receiver that everything that follows is
+    // relative to the destination directory.
+    write_message(stream, format, &meta_message(&session.id, root))?;
+    if let Some(fail) = read_preflight(stream, format)? {
+        let message = format!("Receiver rejected directory: {}", fail.reason);
+        return Err(NcpError::from_peer(fail.code, message));
+    }
+
+    let settled: HashSet<String> = summary.files().iter().map(|f| f.path.clone()).collect();
+    if !settled.is_empty() {
+        status!("Skipping {} files sent before the connection was lost", settled.len());
+    }
+    let mut overall = OverallProgress::new(totals.files, totals.bytes);
+    // The one list that has to be complete before it is sent.
+    let mut mirror_paths = Vec::new();
+    // Small files sent in the batch that is open, and their bytes.
+    let mut batched = Vec::new();
+    let mut batched_bytes = 0;
+
+    for entry in entries {
+        let entry = entry?;
+        let goes_in_batch = session.batch && !entry.is_dir && batch::fits(entry.size);
+        if !batched.is_empty() && !goes_in_batch && !settled.contains(&entry.relative_path) {
+            end_batch(stream, args, session, &mut batched, summary, &mut overall)?;
+            batched_bytes = 0;
+        }
+        if pause::requested() {
+            overall.finish_line();
+            let _keepalive = Keepalive::start(stream, format, session.keepalive);
+            pause::hold();
+        }
+        if session.mirror != MirrorMode::Off {
+            mirror_paths.push(entry.relative_path.clone());
+        }
+        if entry.is_dir {
+            vlog!("Creating directory {}", entry.relative_path);
+            let meta = FileMeta {
+                name: entry.relative_path.clone(),
+                raw_name: raw_name(&entry.exact_relative_path),
+                is_dir: true,
+                mode: 0o755,
+                mtime: modified(args, &entry.path)?,
+                ..Default::default()
+            };
+            write_message(stream, format, &meta_message(&session.id, meta))?;
+            if let Some(fail) = read_preflight(stream, format)? {
+                let message = format!("Receiver rejected {}: {}", entry.relative_path, fail.reason);
+                return Err(NcpError::from_peer(fail.code, message));
+            }
+        } else if settled.contains(&entry.relative_path) {
+            vvlog!("{} was settled by an earlier attempt", entry.relative_path);
+            overall.next_file();
+            overall.skip(entry.size);
+        } else if goes_in_batch {
+            overall.next_file();
+            vlog!("Sending {} in a batch", entry.relative_path);
+            let file = send_batched(stream, args, session, &entry, &mut overall)?;
+            batched_bytes += file.size;
+            batched.push(file);
+            if batch::full(batched.len(), batched_bytes) {
+                end_batch(stream, args, session, &mut batched, summary, &mut overall)?;
+                batched_bytes = 0;
+            }
+        } else {
+            let name = &entry.relative_path;
+            overall.next_file();
+            if logging::verbosity() >= 1 {
+                status!("Sending {}", name);
+            }
+            let (path, exact_name) = (&entry.path, &entry.exact_relative_path);
+            let offered =
+                send_file_entry(stream, args, session, path, exact_name, entry.size, &mut overall)?;
+            if let Offered::Sent(checksum) = offered {
+                summary.transferred(name, entry.size, &checksum);
+            } else {
+                // Counted as done so the total still reaches 100%.
+                overall.skip(entry.size);
+                summary.skipped(name, entry.size);
+            }
+        }
+    }
+    end_batch(stream, args, session, &mut batched, summary, &mut overall)?;
+    overall.on_complete();
+    // Before the mirror list, so nothing is deleted to match a corrupt tree.
+    if let Some(digest) = session.tree_digest.take() {
+        verify_tree(stream, args, digest)?;
+    }
+
+    if session.mirror != MirrorMode::Off {
+        send_mirror_list(stream, format, mirror_paths, session.mirror == MirrorMode::DryRun)?;
+    }
+
+    Ok(std::mem::take(summary))
+}
+
+/// Our `--mirror` mode, or failing that the one a receiver asked for with
+/// `recv --delete`, if this transfer can give it a complete list.
+fn mirror_mode(args: &SendArgs, source: &Source, src: &Path, requested: MirrorMode) -> MirrorMode {
+    if args.mirror != MirrorMode::Off || requested == MirrorMode::Off {
+        return args.mirror;
+    }
+    let why_not = if !matches!(source, Source::Path) || args.relative_to.is_some() {
+        "the source is not a single directory"
+    } else if !src.is_dir() {
+        // A single file: there is nothing to delete beside it.
+        return MirrorMode::Off;
+    } else if !args.filter.is_empty() {
+        "it would delete whatever --include and --exclude leave out"
+    } else {
+        vlog!("Sending the list of entries the receiver asked for (--delete)");
+        return requested;
+    };
+    status!("Not sending the list of entries the receiver asked for (--delete): {}", why_not);
+    MirrorMode::Off
+}
+
+/// Send the complete set of relative paths so the receiver can drop
+/// anything it holds that is not part of the source tree. A large tree's
+/// list goes in several parts, answered once after the last.
+fn send_mirror_list(
+    stream: &mut Stream,
+    format: WireFormat,
+    paths: Vec<String>,
+    dry_run: bool,
+) -> Result<()> {
+    let parts = mirror_list_parts(paths, dry_run);
+    vvlog!("Sending the mirror list in {} part(s)", parts.len());
+    for list in parts {
+        write_message(stream, format, &Message::MirrorList(list))?;
+    }
+
+    let result = read_transfer_result(stream, format)?;
+    if !result.ok {
+        return Err(NcpError::from_peer(result.code, format!("Mirror failed: {}", result.reason)));
+    }
+    status!("Mirror: {}", result.reason);
+    Ok(())
+}
+
+/// Returns `None` on `PreflightOk`, or the receiver's `PreflightFail`.
+fn read_preflight(stream: &mut Stream, format: WireFormat) -> Result<Option<PreflightFail>> {
+    Ok(read_preflight_ok(stream, format)?.err())
+}
+
+/// Like `read_preflight`, but keeps the `PreflightOk` for callers that need
+/// its fields.
+fn read_preflight_ok(
+    stream: &mut Stream,
+    format: WireFormat,
+) -> Result<std::result::Result<PreflightOk, PreflightFail>> {
+    match read_control(stream, format)? {
+        Message::PreflightOk(ok) => {
+            vvlog!(
+                "Preflight ok (exists: {}, available: {}, resume offset: {})",
+                ok.destination_exists,
+                format_bytes(ok.available_space),
+                ok.resume_offset
+            );
+            Ok(Ok(ok))
+        }
+        Message::PreflightFail(fail) => Ok(Err(fail)),
+        other => {
+            let message = format!("Expected preflight response, got {}", other.name());
+            Err(NcpError::Protocol(message))
+        }
+    }
+}
+
+fn read_transfer_result(stream: &mut Stream, format: WireFormat) -> Result<TransferResult> {
+    match read_control(stream, format)? {
+        Message::TransferResult(result) => Ok(result),
+        other => Err(NcpError::Protocol(format!("Expected TransferResult, got {}", other.name()))),
+    }
+}
+
+/// The error for `name`, which the receiver turned down with `fail`.
+fn declined(name: &str, fail: PreflightFail) -> NcpError {
+    let message = format!("Receiver declined {}: {}", name, fail.reason);
+    if fail.code == ErrorCode::ErrExists as i32 {
+        return NcpError::DeclinedOverwrite(message);
+    }
+    NcpError::from_peer(fail.code, message)
+}
+
+/// Send the digest over every file of the tree and wait for the receiver
+/// to compare it with its own (`--checksum tree`).
+fn verify_tree(stream: &mut Stream, args: &SendArgs, digest: StreamingChecksum) -> Result<()> {
+    let trailer = FileChecksum {
+        alg: args.checksum.name().to_string(),
+        digest: digest.finalize(),
+    };
+    write_message(stream, args.format, &Message::Checksum(trailer))?;
+    let result = read_transfer_result(stream, args.format)?;
+    if !result.ok {
+        return Err(failed(result));
+    }
+    vlog!("Receiver verified the tree checksum");
+    Ok(())
+}
+
+/// The error for a file the receiver did not accept once it had arrived.
+fn failed(result: TransferResult) -> NcpError {
+    NcpError::from_peer(result.code, format!("Transfer failed: {}", result.reason))
+}
+
+/// Running totals across a directory transfer, shown as one line such as
+/// `[347/10000]  45.2%    1.20 GiB/2.60 GiB  8.50 MiB/s, ETA 2:45` that is
+/// redrawn in place. As the `ProgressReporter` of the transfer it adds
+/// each file's bytes as they go, and at `-v` appends the file's own
+/// progress to the line.
+struct OverallProgress {
+    files: usize,
+    total_files: usize,
+    bytes: u64,
+    total_bytes: u64,
+    /// Part of `bytes` that was never sent (declined files, resumed
+    /// prefixes), left out of the rate.
+    skipped: u64,
+    meter: RateMeter,
+    line: ProgressLine,
+    /// The file whose data is going out, and how much of it is counted.
+    file: Option<(FileProgress, u64)>,
+}
+
+impl OverallProgress {
+    fn new(total_files: usize, total_bytes: u64) -> Self {
+        OverallProgress {
+            files: 0,
+            total_files,
+            bytes: 0,
+            total_bytes,
+            skipped: 0,
+            meter: RateMeter::new(0),
+            line: ProgressLine::default(),
+            file: None,
+        }
+    }
+
+    fn next_file(&mut self) {
+        self.files += 1;
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.meter.update(self.bytes - self.skipped);
+    }
+
+    /// Count `bytes` as done without sending them.
+    fn skip(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.skipped += bytes;
+    }
+
+    /// The counts, then the aggregate rate and time left.
+    fn text(&self) -> String {
+        let sent = self.bytes - self.skipped;
+        match self.meter.describe(sent, self.total_bytes - self.skipped) {
+            rate if rate.is_empty() => self.counts(),
+            rate => format!("{}  {}", self.counts(), rate),
+        }
+    }
+
+    fn counts(&self) -> String {
+        // A tree of empty files still makes progress, one file at a time.
+        let percent = match (self.total_bytes, self.total_files) {
+            (0, 0) => 100.0,
+            (0, files) => self.files as f64 * 100.0 / files as f64,
+            (total, _) => self.bytes as f64 * 100.0 / total as f64,
+        };
+        let width = self.total_files.to_string().len();
+        format!(
+            "[{:>width$}/{}] {:>5.1}% {:>11}/{}",
+            self.files,
+            self.total_files,
+            percent,
+            format_bytes(self.bytes),
+            format_bytes(self.total_bytes),
+            width = width
+        )
+    }
+
+    /// End the line so that other output starts on a fresh one.
+    fn finish_line(&mut self) {
+        self.line.end();
+    }
+}
+
+impl ProgressReporter for OverallProgress {
+    fn on_start(&mut self, done: u64, total: u64) {
+        self.skip(done);
+        self.file = Some((FileProgress::new("Sent", total, done), done));
+    }
+
+    fn on_progress(&mut self, done: u64, _total: u64) -> std::io::Result<()> {
+        let Some((file, counted)) = self.file.as_mut() else {
+            return Ok(());
+        };
+        let sent = done - std::mem::replace(counted, done);
+        // At -v the file's part of the line; its meter needs every update.
+        let text = (logging::verbosity() >= 1).then(|| file.text(done));
+        self.add(sent);
+        if events::json_enabled() {
+            return Ok(());
+        }
+        match text {
+            Some(text) => {
+                let text = format!("{}  {}", self.text(), text);
+                self.line.draw(&text)
+            }
+            None => self.line.draw(&self.text()),
+        }
+    }
+
+    fn on_file_done(&mut self, _name: &str, done: u64) -> std::io::Result<()> {
+        self.on_progress(done, done)?;
+        if self.file.take().is_some() && logging::verbosity() >= 1 {
+            self.finish_line();
+        }
+        Ok(())
+    }
+
+    fn on_interrupt(&mut self) {
+        self.finish_line();
+    }
+
+    fn on_retry(&mut self, total: u64) {
+        self.total_bytes += total;
+    }
+
+    fn on_complete(&mut self) {
+        self.finish_line();
+    }
+}
+
+/// What became of a file offered to the receiver.
+#[derive(Debug, Clone, PartialEq)]
+enum Offered {
+    /// Sent and verified against this checksum, in hex.
+    Sent(String),
+    /// Turned down in preflight, for the reason already reported.
+    Declined(PreflightFail),
+    /// The receiver already had it (`--skip-existing`).
+    Unchanged,
+}
+
+/// `offer_file`, and again while the receiver finds the file arrived
+/// corrupt and takes it once more, at most `--retries` times in all.
+fn send_file_entry(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+    exact_name: &Path,
+    size: u64,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<Offered> {
+    match offer_file(stream, args, session, path, exact_name, size, reporter) {
+        Err(e) if retrying(session, &e) => {
+            send_again(stream, args, session, path, exact_name, size, reporter, e)
+        }
+        offered => offered,
+    }
+}
+
+/// Whether a file that failed with `e` is offered again.
+fn retrying(session: &Session, e: &NcpError) -> bool {
+    session.retry_files && e.peer_code() == Some(ErrorCode::ErrChecksum)
+}
+
+/// Go on with `send_file_entry` once its first attempt failed with `e`.
+#[allow(clippy::too_many_arguments)]
+fn send_again(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+    exact_name: &Path,
+    size: u64,
+    reporter: &mut dyn ProgressReporter,
+    mut e: NcpError,
+) -> Result<Offered> {
+    let mut attempt = 1;
+    loop {
+        reporter.on_interrupt();
+        let name = exact_name.display();
+        eprintln!("Attempt {}/{} at {} failed: {}", attempt, args.retries, name, e);
+        if attempt == args.retries {
+            return Err(NcpError::RetriesExhausted(attempt, Box::new(e)));
+        }
+        attempt += 1;
+        status!("Sending {} again", name);
+        reporter.on_retry(size);
+        e = match offer_file(stream, args, session, path, exact_name, size, reporter) {
+            Err(e) if retrying(session, &e) => e,
+            offered => return offered,
+        };
+    }
+}
+
+/// A file sent in the open batch, whose answers are still to be read.
+struct Batched {
+    path: PathBuf,
+    exact_name: PathBuf,
+    size: u64,
+    /// Of what was sent, in hex.
+    checksum: String,
+}
+
+/// Send the file of `entry` in the open batch: its `Meta`, and its data
+/// right after, without waiting for the receiver to take it.
+fn send_batched(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    entry: &FileEntry,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<Batched> {
+    let (path, exact_name, size) = (&entry.path, &entry.exact_relative_path, entry.size);
+    let name = &*exact_name.to_string_lossy();
+    let (mut meta, mode) = offer_meta(stream, args, session, path, exact_name, size)?;
+    meta.batched = true;
+    write_message(stream, args.format, &meta_message(&session.id, meta))?;
+
+    events::file_start(name, size);
+    let start = TransferStart {
+        session_id: session.id.clone(),
+        mode: mode as i32,
+        file_size: size,
+        ..Default::default()
+    };
+    write_message(stream, args.format, &Message::TransferStart(start))?;
+    let checksum = send_body(stream, args, session, path, name, mode, size, 0, reporter)?;
+    Ok(Batched {
+        path: path.clone(),
+        exact_name: exact_name.clone(),
+        size,
+        checksum: to_hex(&checksum),
+    })
+}
+
+/// End the open batch, if any: ask the receiver for its answers to the
+/// files in `batched`, and settle each one in `summary` by them as
+/// `offer_file` would have. A file that arrived corrupt is offered again
+/// on its own once all the answers are in.
+fn end_batch(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    batched: &mut Vec<Batched>,
+    summary: &mut Summary,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<()> {
+    if batched.is_empty() {
+        return Ok(());
+    }
+    let format = args.format;
+    vvlog!("Ending a batch of {} files", batched.len());
+    write_message(stream, format, &Message::BatchEnd)?;
+    let mut corrupt = Vec::new();
+    for file in batched.drain(..) {
+        let name = &*file.exact_name.to_string_lossy();
+        match read_preflight(stream, format)? {
+            None => {}
+            Some(fail) if fail.code == ErrorCode::ErrAlreadyPresent as i32 => {
+                vlog!("{} is unchanged", name);
+                events::file_skipped(name, &fail.reason);
+                args.manifest.skipped(name, file.size, &fail.reason)?;
+                summary.skipped(name, file.size);
+                continue;
+            }
+            Some(fail) => {
+                reporter.on_interrupt();
+                status!("Skipped {}: {}", name, fail.reason);
+                events::file_skipped(name, &fail.reason);
+                args.manifest.skipped(name, file.size, &fail.reason)?;
+                summary.skipped(name, file.size);
+                continue;
+            }
+        }
+        let result = read_transfer_result(stream, format)?;
+        if result.ok {
+            events::file_done(name, file.size, &file.checksum);
+            args.manifest.done(name, file.size, &file.checksum, None)?;
+            summary.transferred(name, file.size, &file.checksum);
+            continue;
+        }
+        let e = failed(result);
+        if !retrying(session, &e) {
+            let _ = args.manifest.failed(name, file.size, &e.to_string());
+            return Err(e);
+        }
+        corrupt.push((file, e));
+    }
+    for (file, e) in corrupt {
+        let (path, exact_name, size) = (&file.path, &file.exact_name, file.size);
+        let offered = send_again(stream, args, session, path, exact_name, size, reporter, e)?;
+        let name = &*exact_name.to_string_lossy();
+        match offered {
+            Offered::Sent(checksum) => summary.transferred(name, size, &checksum),
+            _ => summary.skipped(name, size),
+        }
+    }
+    Ok(())
+}
+
+/// Offer one file to the receiver and stream it if accepted. `exact_name`
+/// is the name it is sent under, as the file system spells it.
+fn offer_file(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+    exact_name: &Path,
+    size: u64,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<Offered> {
+    let name = &*exact_name.to_string_lossy();
+    let (meta, mode) = offer_meta(stream, args, session, path, exact_name, size)?;
+    write_message(stream, args.format, &meta_message(&session.id, meta))?;
+
+    let ok = match read_preflight_ok(stream, args.format)? {
+        Ok(ok) => ok,
+        Err(fail) if fail.code == ErrorCode::ErrAlreadyPresent as i32 => {
+            vlog!("{} is unchanged, not sending it", name);
+            events::file_skipped(name, &fail.reason);
+            args.manifest.skipped(name, size, &fail.reason)?;
+            return Ok(Offered::Unchanged);
+        }
+        Err(fail) => {
+            reporter.on_interrupt();
+            status!("Skipped {}: {}", name, fail.reason);
+            events::file_skipped(name, &fail.reason);
+            args.manifest.skipped(name, size, &fail.reason)?;
+            return Ok(Offered::Declined(fail));
+        }
+    };
+
+    // Only take up the receiver's offer if we were asked to; sending offset
+    // 0 tells it to discard the partial file.
+    let mut offset = if args.resume && ok.resume_offset <= size {
+        ok.resume_offset
+    } else {
+        0
+    };
+    if offset > 0 && !ok.resume_checksum.is_empty() {
+        let _keepalive = Keepalive::start(stream, args.format, session.keepalive);
+        let mut prefix = StreamingChecksum::new(args.checksum);
+        hash_prefix(&mut File::open(path)?, offset, &mut prefix)?;
+        if !digests_equal(&prefix.finalize(), &ok.resume_checksum) {
+            reporter.on_interrupt();
+            status!("The receiver's partial copy of {} differs from it; sending all of it", name);
+            offset = 0;
+        }
+    }
+    if offset > 0 {
+        reporter.on_interrupt();
+        status!("Resuming {} at {}", name, format_bytes(offset));
+    }
+
+    events::file_start(name, size);
+    let started = Instant::now();
+    let sent = transfer_file_data(stream, args, session, path, name, mode, size, offset, reporter);
+    let checksum = match sent {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            let _ = args.manifest.failed(name, size, &e.to_string());
+            return Err(e);
+        }
+    };
+    vlog!("Sent {}: {}", name, describe_throughput(size - offset, started.elapsed()));
+    let checksum = to_hex(&checksum);
+    events::file_done(name, size, &checksum);
+    args.manifest.done(name, size, &checksum, None)?;
+    Ok(Offered::Sent(checksum))
+}
+
+/// The `FileMeta` to offer the file at `path` with, under `exact_name`, and
+/// the mode its body will be sent in.
+fn offer_meta(
+    stream: &Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+    exact_name: &Path,
+    size: u64,
+) -> Result<(FileMeta, TransferMode)> {
+    let mode = match file_mode(args, path)? {
+        TransferMode::TransferZstd if session.shared_zstd.is_some() => TransferMode::TransferZstdShared,
+        mode => mode,
+    };
+    // Costs a read of the whole file, but no bytes on the wire when the
+    // receiver turns out to have it.
+    let checksum = if args.skip_existing || args.checksum_on_preflight || session.verify_only {
+        let _keepalive = Keepalive::start(stream, args.format, session.keepalive);
+        args.checksum_cache.checksum(path, args.checksum)?
+    } else {
+        Vec::new()
+    };
+    let meta = FileMeta {
+        name: exact_name.to_string_lossy().to_string(),
+        raw_name: raw_name(exact_name),
+        size,
+        is_dir: false,
+        mode: if args.preserve { permissions(path)? } else { 0 },
+        mtime: modified(args, path)?,
+        checksum_alg: args.checksum.name().to_string(),
+        checksum,
+        attrs: if args.xattrs { xattrs::read(path)? } else { HashMap::new() },
+        transfer_mode: mode as i32,
+        overwrite: args.overwrite.as_policy() as i32,
+        ..Default::default()
+    };
+    Ok((meta, mode))
+}
+
+/// Permission bits for `--preserve`. 0 tells the receiver to leave its
+/// default, which is all that makes sense off Unix.
+#[cfg(unix)]
+fn permissions(path: &Path) -> Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(path.metadata()?.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn permissions(_path: &Path) -> Result<u32> {
+    Ok(0)
+}
+
+/// Modification time to send for `path`, only with `--preserve`.
+fn modified(args: &SendArgs, path: &Path) -> Result<Option<Timestamp>> {
+    if !args.preserve && !args.overwrite.by_mtime() {
+        return Ok(None);
+    }
+    Ok(Some(path.metadata()?.modified()?.into()))
+}
+
+fn transfer_mode(args: &SendArgs) -> TransferMode {
+    if args.compress {
+        TransferMode::TransferZstd
+    } else if args.verify_chunks {
+        TransferMode::TransferChunked
+    } else if args.framed {
+        TransferMode::TransferFramed
+    } else {
+        TransferMode::TransferRaw
+    }
+}
+
+/// `transfer_mode` for the file at `path`: `--compress` sends it raw where
+/// compression would not pay.
+fn file_mode(args: &SendArgs, path: &Path) -> Result<TransferMode> {
+    let mode = transfer_mode(args);
+    if mode == TransferMode::TransferZstd && !compress::worth_compressing(path, args.compress_level)? {
+        vlog!("Sending {} uncompressed: it is compressed already", path.display());
+        return Ok(TransferMode::TransferRaw);
+    }
+    Ok(mode)
+}
+
+/// Stream one accepted file and wait for the receiver's verdict, reporting
+/// the data to `reporter` as it goes out. Returns the checksum of the bytes
+/// that were sent.
+#[allow(clippy::too_many_arguments)]
+fn transfer_file_data(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+    name: &str,
+    mode: TransferMode,
+    file_size: u64,
+    offset: u64,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<Vec<u8>> {
+    let format = args.format;
+    // A resumed file goes on over one connection.
+    let streams = match offset {
+        0 if mode == TransferMode::TransferRaw => parallel::streams_for(session.parallel, file_size),
+        _ => 1,
+    };
+    let start = TransferStart {
+        session_id: session.id.clone(),
+        mode: mode as i32,
+        file_size,
+        offset,
+        streams,
+        ..Default::default()
+    };
+    write_message(stream, format, &Message::TransferStart(start))?;
+    if streams > 1 {
+        return send_parallel(stream, args, &session.id, path, name, file_size, streams, reporter);
+    }
+    let digest = send_body(stream, args, session, path, name, mode, file_size, offset, reporter)?;
+
+    let result = read_transfer_result(stream, format)?;
+    if !result.ok {
+        return Err(failed(result));
+    }
+    vvlog!("Receiver confirmed {} bytes", result.received_bytes);
+    Ok(digest)
+}
+
+/// Send the body that follows a `TransferStart` for the file at `path`,
+/// from `offset` on, and the `Checksum` after it. Returns the digest in
+/// that trailer.
+#[allow(clippy::too_many_arguments)]
+fn send_body(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session: &Session,
+    path: &Path,
+    name: &str,
+    mode: TransferMode,
+    file_size: u64,
+    offset: u64,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<Vec<u8>> {
+    let mut reader = File::open(path)?;
+    // In a tree checksum the bytes go into the digest over the whole tree
+    // instead, and the trailer carries no digest of the file's own.
+    let tree_digest = session.tree_digest.take();
+    let in_tree = tree_digest.is_some();
+    let mut checksum = tree_digest.unwrap_or_else(|| StreamingChecksum::new(args.checksum));
+    // The receiver already has the first `offset` bytes; hash them locally
+    // so the trailing checksum still covers the whole file.
+    if hash_prefix(&mut reader, offset, &mut checksum)? != offset {
+        return Err(format!("{} is shorter than the resume offset", path.display()).into());
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+
+    // With nothing to hash or encode, the kernel can move the data itself,
+    // unless it has to be encrypted.
+    let plain = mode == TransferMode::TransferRaw && args.checksum == ChecksumAlg::None;
+    let mut zero_copy = ZeroCopy::new(stream, plain);
+    let wire = Cell::new(0);
+    let counted = CountingWriter::new(&mut *stream, &wire);
+    let mut body = match &session.shared_zstd {
+        Some(encoder) if mode == TransferMode::TransferZstdShared => {
+            BodyWriter::shared(counted, encoder)
+        }
+        _ => BodyWriter::new(counted, mode, file_size, args.compress_level)?,
+    };
+    let mut buffer = vec![0u8; args.buffer_size];
+    let mut total_sent = offset;
+    let mut progress = ProgressTicker::new(args.progress_interval);
+    reporter.on_start(offset, file_size);
+    let mut throttle = args.limit.map(Throttle::new);
+
+    // Never past the size the receiver was told, even if the file grows.
+    while total_sent < file_size {
+        let count = (file_size - total_sent).min(buffer.len() as u64) as usize;
+        let n = match zero_copy.send(&reader, count) {
+            Some(n) => {
+                wire.set(wire.get() + n as u64);
+                n
+            }
+            None => {
+                let n = reader.read(&mut buffer[..count])?;
+                body.write_all(&buffer[..n])?;
+                checksum.update(&buffer[..n]);
+                n
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        total_sent += n as u64;
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.sent(limited(args, n, &wire));
+        }
+        if pause::requested() {
+            reporter.on_interrupt();
+            // No pings in the middle of file data: the receiver's
+            // --timeout is all the pause has.
+            pause::hold();
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.restart();
+            }
+        }
+
+        if progress.tick() {
+            events::file_progress(name, total_sent, file_size);
+            reporter.on_progress(total_sent, file_size)?;
+        }
+    }
+    body.finish()?;
+    reporter.on_file_done(name, total_sent)?;
+
+    if total_sent != file_size {
+        return Err(format!(
+            "File size changed during transfer: expected {} bytes, sent {}",
+            file_size, total_sent
+        )
+        .into());
+    }
+    if reader.read(&mut [0u8])? > 0 {
+        return Err(format!(
+            "File size changed during transfer: {} grew past the {} bytes sent",
+            path.display(),
+            file_size
+        )
+        .into());
+    }
+
+    let digest = if in_tree {
+        session.tree_digest.set(Some(checksum));
+        Vec::new()
+    } else {
+        checksum.finalize()
+    };
+    let trailer = FileChecksum {
+        alg: args.checksum.name().to_string(),
+        digest: digest.clone(),
+    };
+    write_message(stream, args.format, &Message::Checksum(trailer))?;
+    Ok(digest)
+}
+
+/// The bytes `--limit` counts for `n` bytes of file data just sent, which
+/// took what `wire` has added up since the last call.
+fn limited(args: &SendArgs, n: usize, wire: &Cell<u64>) -> u64 {
+    match args.limit_scope {
+        LimitScope::File => n as u64,
+        LimitScope::Wire => wire.take(),
+    }
+}
+
+/// Send the body of a file split over `streams` connections (`--parallel`)
+/// and wait for the receiver's verdict: the first range over `stream`, the
+/// others over connections opened for them. Returns the checksum of the
+/// whole file.
+#[allow(clippy::too_many_arguments)]
+fn send_parallel(
+    stream: &mut Stream,
+    args: &SendArgs,
+    session_id: &str,
+    path: &Path,
+    name: &str,
+    file_size: u64,
+    streams: u32,
+    reporter: &mut dyn ProgressReporter,
+) -> Result<Vec<u8>> {
+    let host = args.host.as_deref().ok_or("--parallel needs --host")?;
+    let ranges = parallel::split(file_size, streams);
+    vlog!("Sending {} over {} connections", name, ranges.len());
+    // Each connection takes an equal share of --limit.
+    let limit = args.limit.map(|rate| (rate / u64::from(streams)).max(1));
+    let sent = AtomicU64::new(0);
+    let sent = &sent;
+    let main = &mut *stream;
+
+    let digest = thread::scope(|scope| -> Result<Vec<u8>> {
+        // Hashed meanwhile from a reader of its own, since no one stream
+        // sees every byte.
+        let hashing = scope.spawn(|| -> Result<Vec<u8>> {
+            let mut checksum = StreamingChecksum::new(args.checksum);
+            if hash_prefix(&mut File::open(path)?, file_size, &mut checksum)? != file_size {
+                return Err(format!("{} shrank during transfer", path.display()).into());
+            }
+            Ok(checksum.finalize())
+        });
+        let first = ranges[0].clone();
+        let mut senders = vec![scope.spawn(move || {
+            parallel::send_range(main, path, first, args.buffer_size, limit, sent)
+        })];
+        for range in ranges[1..].iter().cloned() {
+            senders.push(scope.spawn(move || {
+                send_range_over(host, args, session_id, path, range, limit, sent)
+            }));
+        }
+        let finished = || senders.iter().all(|s| s.is_finished());
+        parallel::show_progress(reporter, name, file_size, sent, args.progress_interval, finished)?;
+        for handle in senders {
+            handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        }
+        hashing.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })?;
+
+    let trailer = FileChecksum {
+        alg: args.checksum.name().to_string(),
+        digest: digest.clone(),
+    };
+    write_message(stream, args.format, &Message::Checksum(trailer))?;
+    let result = read_transfer_result(stream, args.format)?;
+    if !result.ok {
+        return Err(failed(result));
+    }
+    vvlog!("Receiver confirmed {} bytes", result.received_bytes);
+    Ok(digest)
+}
+
+/// Send `range` of the file at `path` over a connection of its own, and
+/// wait for the receiver to confirm it.
+fn send_range_over(
+    host: &str,
+    args: &SendArgs,
+    session_id: &str,
+    path: &Path,
+    range: std::ops::Range<u64>,
+    limit: Option<u64>,
+    sent: &AtomicU64,
+) -> Result<()> {
+    let mut conn = connect(host, args)?;
+    let psk = args.psk.as_deref().map(str::as_bytes);
+    handshake::open(&mut conn, session_id, args.timeout, psk)?;
+    let start = RangeStart {
+        session_id: session_id.to_string(),
+        offset: range.start,
+        length: range.end - range.start,
+    };
+    write_message(&mut conn, WireFormat::Binary, &Message::Range(start))?;
+    parallel::send_range(&mut conn, path, range, args.buffer_size, limit, sent)?;
+    let result = read_transfer_result(&mut conn, WireFormat::Binary)?;
+    if !result.ok {
+        return Err(failed(result));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum_cache::ChecksumCache;
+    use crate::directory::Filter;
+    use crate::framing;
+    use crate::json::{self, Json};
+    use crate::manifest::Manifest;
+    use crate::net::{IpFamily, SocketOptions};
+    use crate::proto::Established;
+    use crate::types::{DedupMode, OverwriteMode, RecvArgs};
+    use crate::utils::{DEFAULT_BUFFER_SIZE, DEFAULT_PROGRESS_INTERVAL};
+    use std::fs;
+    use std::io;
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::thread::JoinHandle;
+
+    fn send_args(src: &Path) -> SendArgs {
+        SendArgs {
+            host: None,
+            port: 0,
+            src: src.to_path_buf(),
+            recursive: true,
+            retries: 1,
+            retry_delay: Duration::from_secs(1),
+            retry_backoff: 1.0,
+            overwrite: OverwriteMode::Yes,
+            listen: true,
+            accept_timeout: None,
+            mirror: MirrorMode::Off,
+            format: WireFormat::Binary,
+            resume: false,
+            compress: false,
+            compress_level: compress::ZSTD_LEVEL,
+            compress_shared: false,
+            verify_chunks: false,
+            framed: false,
+            parallel: 1,
+            batch: false,
+            checksum: ChecksumAlg::default(),
+            checksum_scope: ChecksumScope::default(),
+            checksum_cache: ChecksumCache::default(),
+            timeout: None,
+            limit: None,
+            limit_scope: LimitScope::File,
+            preserve: false,
+            xattrs: false,
+            skip_existing: false,
+            checksum_on_preflight: false,
+            dry_run: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            family: IpFamily::Any,
+            bind: None,
+            socket: SocketOptions::default(),
+            proxy: None,
+            manifest: Manifest::default(),
+            filter: Filter::default(),
+            relative_to: None,
+            files_from: None,
+            ignore_missing: false,
+            psk: None,
+            tls: None,
+        }
+    }
+
+    /// Send `src` with `args` to a receiver in this process, which copies file
+    /// data through a buffer of the same size as the sender's.
+    fn loopback(args: SendArgs, dst: &Path) {
+        loopback_recording(args, dst, Manifest::default());
+    }
+
+    /// `loopback` with the receiver writing `manifest`.
+    fn loopback_recording(args: SendArgs, dst: &Path, manifest: Manifest) {
+        let (sent, received) = loopback_pulling(args, dst, manifest, None);
+        received.unwrap();
+        sent.unwrap();
+    }
+
+    /// `loopback` with the receiver asking for `pull`; returns how each
+    /// side ended.
+    fn loopback_pulling(
+        args: SendArgs,
+        dst: &Path,
+        manifest: Manifest,
+        pull: Option<&str>,
+    ) -> (std::result::Result<(), String>, Result<()>) {
+        let receiver = RecvArgs {
+            buffer_size: args.buffer_size,
+            pull: pull.map(str::to_string),
+            manifest,
+            ..recv_args(dst)
+        };
+        loopback_to(args, receiver)
+    }
+
+    /// Run the transfer into a receiver set up with `receiver`, which
+    /// connects to us.
+    fn loopback_to(args: SendArgs, receiver: RecvArgs) -> (std::result::Result<(), String>, Result<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sender = thread::spawn(move || {
+            let mut stream = Stream::from(listener.accept().unwrap().0);
+            let sent = run_transfer(&mut stream, &args, &Source::Path, &mut Summary::new());
+            sent.map(drop).map_err(|e| e.to_string())
+        });
+
+        let received = crate::recv::execute(RecvArgs {
+            host: Some("127.0.0.1".to_string()),
+            port,
+            ..receiver
+        })
+        .map(drop);
+        (sender.join().unwrap(), received)
+    }
+
+    /// A receiver that writes into `dst`.
+    fn recv_args(dst: &Path) -> RecvArgs {
+        RecvArgs {
+            host: None,
+            port: 0,
+            dst: dst.to_path_buf(),
+            overwrite: OverwriteMode::Yes,
+            resume: false,
+            timeout: None,
+            keep_alive: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            family: IpFamily::Any,
+            socket: SocketOptions::default(),
+            output_name: None,
+            mkdir: false,
+            // Merged, so a test can send into the same destination twice.
+            into: true,
+            verify_only: false,
+            in_place: false,
+            fsync: false,
+            verify_read: false,
+            dedup: DedupMode::Off,
+            write_special: false,
+            allow_roots: Vec::new(),
+            strip_components: 0,
+            max_file_size: None,
+            delete: MirrorMode::Off,
+            pull: None,
+            manifest: Manifest::default(),
+            psk: None,
+            tls: None,
+        }
+    }
+
+    /// Relay the first two connections to `listener` on to `target`,
+    /// cutting the first once the receiver has acknowledged `files` files.
+    fn flaky_relay(listener: TcpListener, target: SocketAddr, files: usize) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for (i, sender) in listener.incoming().take(2).enumerate() {
+                let mut sender = sender.unwrap();
+                let mut receiver = TcpStream::connect(target).unwrap();
+                let (mut from, mut to) = (sender.try_clone().unwrap(), receiver.try_clone().unwrap());
+                let upstream = thread::spawn(move || {
+                    let _ = io::copy(&mut from, &mut to);
+                    let _ = to.shutdown(Shutdown::Write);
+                });
+                if i == 0 {
+                    let established: Established = framing::read_message(&mut receiver).unwrap();
+                    framing::write_message(&mut sender, &established).unwrap();
+                    let mut acknowledged = 0;
+                    while acknowledged < files {
+                        let msg = read_message(&mut receiver, WireFormat::Binary).unwrap();
+                        acknowledged += matches!(msg, Message::TransferResult(_)) as usize;
+                        write_message(&mut sender, WireFormat::Binary, &msg).unwrap();
+                    }
+                    let _ = sender.shutdown(Shutdown::Both);
+                    let _ = receiver.shutdown(Shutdown::Both);
+                } else {
+                    let _ = io::copy(&mut receiver, &mut sender);
+                    let _ = sender.shutdown(Shutdown::Write);
+                }
+                upstream.join().unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let root = std::env::temp_dir().join(format!("ncp-buffer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/sub")).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        fs::write(root.join("src/sub/data.bin"), &data).unwrap();
+        fs::write(root.join("src/small.txt"), "tiny").unwrap();
+
+        for size in [1, 1024 * 1024] {
+            let dst = root.join(format!("dst-{}", size));
+            let mut args = send_args(&root.join("src"));
+            args.buffer_size = size;
+            loopback(args, &dst);
+            assert_eq!(fs::read(dst.join("sub/data.bin")).unwrap(), data, "buffer {}", size);
+            assert_eq!(fs::read(dst.join("small.txt")).unwrap(), b"tiny");
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_pull_single_file() {
+        let root = std::env::temp_dir().join(format!("ncp-pull-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/sub")).unwrap();
+        fs::create_dir_all(root.join("dst")).unwrap();
+        fs::write(root.join("src/sub/wanted.txt"), "pulled").unwrap();
+        fs::write(root.join("src/other.txt"), "not pulled").unwrap();
+        fs::write(root.join("secret.txt"), "outside").unwrap();
+
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        let pull = |path| loopback_pulling(send_args(&src), &dst, Manifest::default(), Some(path));
+        let (sent, received) = pull("sub/wanted.txt");
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(fs::read(dst.join("wanted.txt")).unwrap(), b"pulled");
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), 1);
+
+        for path in ["../secret.txt", "/etc/passwd", "sub/missing.txt"] {
+            let (sent, received) = pull(path);
+            assert!(sent.unwrap_err().contains("Refused to send"), "{}", path);
+            let err = received.unwrap_err().to_string();
+            assert!(err.contains("Sender refused to send"), "{}: {}", path, err);
+        }
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_verified_chunks() {
+        let root = std::env::temp_dir().join(format!("ncp-chunks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dst")).unwrap();
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs::write(root.join("data.bin"), &data).unwrap();
+
+        let mut args = send_args(&root.join("data.bin"));
+        args.verify_chunks = true;
+        assert_eq!(transfer_mode(&args), TransferMode::TransferChunked);
+        loopback(args, &root.join("dst"));
+        assert!(fs::read(root.join("dst/data.bin")).unwrap() == data);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_manifests_list_every_file() {
+        let root = std::env::temp_dir().join(format!("ncp-manifest-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/sub")).unwrap();
+        fs::write(root.join("src/a.txt"), "hello").unwrap();
+        fs::write(root.join("src/sub/b.bin"), vec![9u8; 70_000]).unwrap();
+        fs::write(root.join("src/sub/empty"), "").unwrap();
+
+        let mut args = send_args(&root.join("src"));
+        args.manifest = Manifest::create(&root.join("sent.jsonl")).unwrap();
+        let received = Manifest::create(&root.join("received.jsonl")).unwrap();
+        loopback_recording(args, &root.join("dst"), received);
+
+        let read = |name: &str| -> Vec<Json> {
+            let text = fs::read_to_string(root.join(name)).unwrap();
+            text.lines().map(|line| json::parse(line).unwrap()).collect()
+        };
+        let (sent, received) = (read("sent.jsonl"), read("received.jsonl"));
+        assert_eq!(sent.len(), 3);
+        assert_eq!(received.len(), 3);
+        for (sent, received) in sent.iter().zip(&received) {
+            let path = sent.get("path").and_then(Json::as_str).unwrap();
+            let size = sent.get("size").and_then(Json::as_u64).unwrap();
+            assert_eq!(fs::metadata(root.join("src").join(path)).unwrap().len(), size);
+            assert_eq!(sent.get("status").and_then(Json::as_str), Some("ok"));
+            for key in ["path", "size", "status", "checksum"] {
+                assert_eq!(sent.get(key), received.get(key), "{} of {}", key, path);
+            }
+            let saved_as = received.get("saved_as").and_then(Json::as_str).unwrap();
+            assert_eq!(Path::new(saved_as), root.join("dst").join(path));
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_file_name_round_trips() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = std::env::temp_dir().join(format!("ncp-non-utf8-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        // Latin-1 names, as an older system would have written them.
+        let dir = OsStr::from_bytes(b"r\xe9sum\xe9s");
+        let file = OsStr::from_bytes(b"caf\xe9.txt");
+        fs::create_dir_all(root.join("src").join(dir)).unwrap();
+        fs::write(root.join("src").join(dir).join(file), "latin-1").unwrap();
+        loopback(send_args(&root.join("src")), &root.join("dst"));
+
+        let saved = root.join("dst").join(dir).join(file);
+        assert_eq!(fs::read(&saved).unwrap(), b"latin-1");
+
+        // A single file keeps its exact name too.
+        fs::create_dir_all(root.join("single")).unwrap();
+        loopback(send_args(&root.join("src").join(dir).join(file)), &root.join("single"));
+        assert_eq!(fs::read(root.join("single").join(file)).unwrap(), b"latin-1");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattrs_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join(format!("ncp-xattrs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("dst")).unwrap();
+        let file = root.join("src/tagged.txt");
+        fs::write(&file, "tagged").unwrap();
+        let attrs = HashMap::from([("user.origin".to_string(), "00ff6869".to_string())]);
+        xattrs::apply(&file, &attrs).unwrap();
+        if xattrs::read(&file).unwrap().is_empty() {
+            eprintln!("{} takes no user attributes; skipping", root.display());
+            fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+        // Read-only, so the receiver has to set them before the mode.
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let mut args = send_args(&file);
+        args.preserve = true;
+        args.xattrs = true;
+        loopback(args, &root.join("dst"));
+        let saved = root.join("dst/tagged.txt");
+        assert_eq!(xattrs::read(&saved).unwrap(), attrs);
+        assert_eq!(saved.metadata().unwrap().permissions().mode() & 0o777, 0o444);
+
+        // Without --xattrs they stay behind.
+        fs::remove_file(&saved).unwrap();
+        loopback(send_args(&file), &root.join("dst"));
+        assert!(xattrs::read(&saved).unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_relative_to_keeps_the_path_from_base() {
+        let root = std::env::temp_dir().join(format!("ncp-relative-to-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let src = root.join("src/var/log/app");
+        fs::create_dir_all(src.join("old")).unwrap();
+        fs::write(src.join("a.log"), "a").unwrap();
+        fs::write(src.join("old/b.log"), "b").unwrap();
+
+        // The receiver merges, so a plain send lands the contents of `app`.
+        loopback(send_args(&src), &root.join("plain"));
+        assert_eq!(fs::read(root.join("plain/a.log")).unwrap(), b"a");
+        assert_eq!(fs::read(root.join("plain/old/b.log")).unwrap(), b"b");
+
+        let mut args = send_args(&src);
+        args.relative_to = Some(root.join("src"));
+        loopback(args, &root.join("relative"));
+        assert_eq!(fs::read(root.join("relative/var/log/app/a.log")).unwrap(), b"a");
+        assert_eq!(fs::read(root.join("relative/var/log/app/old/b.log")).unwrap(), b"b");
+        assert!(!root.join("relative/a.log").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_files_from_sends_only_the_listed_files() {
+        let root = std::env::temp_dir().join(format!("ncp-files-from-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let src = root.join("src");
+        for dir in ["a/deep", "b", "c"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+        }
+        let names = ["top.txt", "a/one.txt", "a/deep/two.txt", "a/deep/left.txt", "b/three.txt"];
+        for name in names.into_iter().chain(["c/out.txt"]) {
+            fs::write(src.join(name), name).unwrap();
+        }
+        let listed = ["a/one.txt", "a/deep/two.txt", "b/three.txt"];
+        let listed_args = |paths: Vec<PathBuf>| {
+            let mut args = send_args(Path::new(""));
+            args.listen = false;
+            args.host = Some("127.0.0.1".to_string());
+            args.files_from = Some(paths);
+            args
+        };
+        let send_to = |args: SendArgs, dst: &Path| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let receiver_args = recv_args(dst);
+            let receiver = thread::spawn(move || {
+                crate::recv::receive_on(&listener, &receiver_args).map_err(|e| e.to_string())
+            });
+            execute(SendArgs { port, ..args }).unwrap();
+            receiver.join().unwrap().unwrap();
+        };
+
+        send_to(listed_args(listed.iter().map(|name| src.join(name)).collect()), &root.join("dst"));
+        for name in listed {
+            assert_eq!(fs::read_to_string(root.join("dst").join(name)).unwrap(), name);
+        }
+        for name in ["top.txt", "a/deep/left.txt", "c"] {
+            assert!(!root.join("dst").join(name).exists(), "{}", name);
+        }
+
+        // A missing one fails the send, unless it is to be left out.
+        let mut listed: Vec<PathBuf> = listed.iter().map(|name| src.join(name)).collect();
+        listed.push(src.join("gone.txt"));
+        let mut args = listed_args(listed.clone());
+        args.relative_to = Some(root.clone());
+        let err = execute(args).err().unwrap();
+        assert!(err.to_string().contains("gone.txt"), "{}", err);
+        let mut args = listed_args(listed);
+        args.relative_to = Some(root.clone());
+        args.ignore_missing = true;
+        send_to(args, &root.join("relative"));
+        let two = fs::read_to_string(root.join("relative/src/a/deep/two.txt")).unwrap();
+        assert_eq!(two, "a/deep/two.txt");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_tree_checksum_verifies_the_whole_directory() {
+        let root = std::env::temp_dir().join(format!("ncp-tree-checksum-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::write(root.join("src/top.txt"), "top").unwrap();
+        fs::write(root.join("src/nested/deep.txt"), "deep").unwrap();
+        fs::write(root.join("src/nested/empty.txt"), "").unwrap();
+        let mut args = send_args(&root.join("src"));
+        args.checksum_scope = ChecksumScope::Tree;
+        args.manifest = Manifest::create(&root.join("sent.jsonl")).unwrap();
+        loopback(args, &root.join("dst"));
+
+        for name in ["top.txt", "nested/deep.txt", "nested/empty.txt"] {
+            let (sent, received) = (root.join("src").join(name), root.join("dst").join(name));
+            assert_eq!(fs::read(received).unwrap(), fs::read(sent).unwrap());
+        }
+        // Verified together, so no file has a checksum of its own.
+        let text = fs::read_to_string(root.join("sent.jsonl")).unwrap();
+        for line in text.lines().map(|l| json::parse(l).unwrap()) {
+            assert_eq!(line.get("status").and_then(Json::as_str), Some("ok"));
+            assert_eq!(line.get("checksum").and_then(Json::as_str), Some(""));
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_receiver_delete_removes_what_the_source_lacks() {
+        let root = std::env::temp_dir().join(format!("ncp-recv-delete-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/sub")).unwrap();
+        fs::write(root.join("src/a.txt"), "a").unwrap();
+        fs::write(root.join("src/sub/b.txt"), "b").unwrap();
+        let (src, dst) = (root.join("src"), root.join("dst"));
+        fs::create_dir_all(dst.join("stale")).unwrap();
+        fs::write(dst.join("old.txt"), "old").unwrap();
+        fs::write(dst.join("stale/x.txt"), "x").unwrap();
+        let run = |args: SendArgs, delete: MirrorMode| {
+            let (sent, received) = loopback_to(args, RecvArgs { delete, ..recv_args(&dst) });
+            received.unwrap();
+            sent.unwrap();
+        };
+        let left = || {
+            let walk = crate::directory::walk_directory(&dst, &Filter::default()).unwrap();
+            let mut names: Vec<String> = walk.map(|entry| entry.unwrap().relative_path).collect();
+            names.sort();
+            names
+        };
+
+        // A sender filtering what it sends cannot say what to keep.
+        let mut filtered = send_args(&src);
+        filtered.filter.exclude.push("*.md".to_string());
+        run(filtered, MirrorMode::Delete);
+        assert!(dst.join("old.txt").exists());
+
+        run(send_args(&src), MirrorMode::DryRun);
+        assert!(dst.join("old.txt").exists() && dst.join("stale/x.txt").exists());
+
+        run(send_args(&src), MirrorMode::Delete);
+        assert_eq!(left(), ["a.txt", "sub", "sub/b.txt"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_mirror_list_larger_than_a_message() {
+        let root = std::env::temp_dir().join(format!("ncp-mirror-large-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        // Long names, so that few files make a list past the cap.
+        let count = crate::protocol::MAX_MESSAGE_SIZE / 240 + 100;
+        for i in 0..count {
+            fs::write(root.join("src").join(format!("{:0>240}", i)), "").unwrap();
+        }
+        let dst = root.join("dst");
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(dst.join("stale.txt"), "old").unwrap();
+
+        let mut args = send_args(&root.join("src"));
+        args.mirror = MirrorMode::Delete;
+        args.batch = true;
+        let (sent, received) = loopback_to(args, RecvArgs { into: true, ..recv_args(&dst) });
+        received.unwrap();
+        sent.unwrap();
+        assert!(!dst.join("stale.txt").exists());
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), count);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_skip_existing_sends_only_changed_files() {
+        let root = std::env::temp_dir().join(format!("ncp-skip-existing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/same.txt"), "unchanged").unwrap();
+        fs::write(root.join("src/edited.txt"), "version 1").unwrap();
+        let mut args = send_args(&root.join("src"));
+        args.skip_existing = true;
+        loopback(args, &root.join("dst"));
+
+        // Same size, so only the checksum can tell the edit apart.
+        fs::write(root.join("src/edited.txt"), "version 2").unwrap();
+        let mut args = send_args(&root.join("src"));
+        args.skip_existing = true;
+        args.manifest = Manifest::create(&root.join("sent.jsonl")).unwrap();
+        loopback(args, &root.join("dst"));
+
+        let text = fs::read_to_string(root.join("sent.jsonl")).unwrap();
+        let status = |path: &str| {
+            let line = text.lines().map(|l| json::parse(l).unwrap()).find(|l| {
+                l.get("path").and_then(Json::as_str) == Some(path)
+            });
+            line.unwrap().get("status").and_then(Json::as_str).unwrap().to_string()
+        };
+        assert_eq!(status("same.txt"), "skipped");
+        assert_eq!(status("edited.txt"), "ok");
+        assert_eq!(fs::read(root.join("dst/edited.txt")).unwrap(), b"version 2");
+        assert_eq!(fs::read(root.join("dst/same.txt")).unwrap(), b"unchanged");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_on_preflight_skips_matches_and_restarts_stale_partials() {
+        let root = std::env::temp_dir().join(format!("ncp-preflight-sum-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join("dst")).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+        fs::write(root.join("src/same.bin"), &data).unwrap();
+        fs::write(root.join("dst/same.bin"), &data).unwrap();
+        fs::write(root.join("src/big.bin"), &data).unwrap();
+        // Left by an earlier transfer of some other version of the file.
+        fs::write(root.join("dst/big.bin.ncp_temp"), vec![7u8; 40_000]).unwrap();
+
+        let mut args = send_args(&root.join("src"));
+        (args.checksum_on_preflight, args.resume) = (true, true);
+        args.manifest = Manifest::create(&root.join("sent.jsonl")).unwrap();
+        let receiver = RecvArgs {
+            resume: true,
+            into: true,
+            ..recv_args(&root.join("dst"))
+        };
+        let (sent, received) = loopback_to(args, receiver);
+        received.unwrap();
+        sent.unwrap();
+
+        let text = fs::read_to_string(root.join("sent.jsonl")).unwrap();
+        let status = |path: &str| {
+            let line = text.lines().map(|l| json::parse(l).unwrap()).find(|l| {
+                l.get("path").and_then(Json::as_str) == Some(path)
+            });
+            line.unwrap().get("status").and_then(Json::as_str).unwrap().to_string()
+        };
+        assert_eq!(status("same.bin"), "skipped");
+        assert_eq!(status("big.bin"), "ok");
+        assert!(fs::read(root.join("dst/big.bin")).unwrap() == data);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_compression_skipped_where_it_does_not_pay() {
+        let root = std::env::temp_dir().join(format!("ncp-adaptive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        let text = "All work and no play makes Jack a dull boy.\n".repeat(5000);
+        fs::write(root.join("src/notes.txt"), &text).unwrap();
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        fs::write(root.join("src/noise.bin"), &noise).unwrap();
+        fs::write(root.join("src/photo.JPG"), &text).unwrap();
+
+        let mut args = send_args(&root.join("src"));
+        args.compress = true;
+        let mode = |name: &str| file_mode(&args, &root.join("src").join(name)).unwrap();
+        assert_eq!(mode("notes.txt"), TransferMode::TransferZstd);
+        assert_eq!(mode("noise.bin"), TransferMode::TransferRaw);
+        assert_eq!(mode("photo.JPG"), TransferMode::TransferRaw);
+
+        loopback(args, &root.join("dst"));
+        assert_eq!(fs::read(root.join("dst/notes.txt")).unwrap(), text.as_bytes());
+        assert!(fs::read(root.join("dst/noise.bin")).unwrap() == noise);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_retry_goes_on_from_the_first_unsent_file() {
+        let root = std::env::temp_dir().join(format!("ncp-retry-resume-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        let names = ["f0", "f1", "f2", "f3", "f4"];
+        for (i, name) in names.iter().enumerate() {
+            fs::write(root.join("src").join(name), vec![i as u8; 50_000]).unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        let dst = root.join("dst");
+        let receiver = thread::spawn(move || {
+            let args = recv_args(&dst);
+            let first = crate::recv::receive_on(&listener, &args).map(drop);
+            assert!(first.is_err(), "the cut connection should fail");
+            crate::recv::receive_on(&listener, &args).map_err(|e| e.to_string())
+        });
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut args = send_args(&root.join("src"));
+        args.listen = false;
+        args.host = Some("127.0.0.1".to_string());
+        args.port = relay.local_addr().unwrap().port();
+        args.retries = 2;
+        args.retry_delay = Duration::from_millis(10);
+        let relay = flaky_relay(relay, target, 2);
+
+        let sent = execute(args).unwrap();
+        let received = receiver.join().unwrap().unwrap();
+        relay.join().unwrap();
+
+        let paths = |summary: &Summary| -> Vec<String> {
+            summary.files().iter().map(|f| f.path.clone()).collect()
+        };
+        assert_eq!(paths(&sent), names);
+        assert!(sent.files().iter().all(|f| f.status == "ok"));
+        assert_eq!(paths(&received), names[2..]);
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(fs::read(root.join("dst").join(name)).unwrap(), vec![i as u8; 50_000]);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_file_is_sent_again_on_the_same_connection() {
+        let root = std::env::temp_dir().join(format!("ncp-retry-file-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/a.txt"), "hello").unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(root.join("src/big.bin"), &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap();
+        let dst = root.join("dst");
+        let receiver = thread::spawn(move || {
+            crate::recv::receive_on(&listener, &recv_args(&dst)).map_err(|e| e.to_string())
+        });
+        // Flips one byte well inside big.bin's data, on the one connection.
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = relay.local_addr().unwrap().port();
+        let relay = thread::spawn(move || {
+            let (mut sender, _) = relay.accept().unwrap();
+            let mut receiver = TcpStream::connect(target).unwrap();
+            let (mut from, mut to) = (sender.try_clone().unwrap(), receiver.try_clone().unwrap());
+            let upstream = thread::spawn(move || {
+                let mut buffer = vec![0u8; 8192];
+                let mut relayed = 0;
+                loop {
+                    let n = from.read(&mut buffer).unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    if (relayed..relayed + n).contains(&150_000) {
+                        buffer[150_000 - relayed] ^= 0xff;
+                    }
+                    relayed += n;
+                    if to.write_all(&buffer[..n]).is_err() {
+                        break;
+                    }
+                }
+                let _ = to.shutdown(Shutdown::Write);
+            });
+            let _ = io::copy(&mut receiver, &mut sender);
+            let _ = sender.shutdown(Shutdown::Write);
+            upstream.join().unwrap();
+        });
+        let mut args = send_args(&root.join("src"));
+        args.listen = false;
+        args.host = Some("127.0.0.1".to_string());
+        args.port = port;
+        args.retries = 2;
+
+        let sent = execute(args).unwrap();
+        let received = receiver.join().unwrap().unwrap();
+        relay.join().unwrap();
+
+        assert!(sent.files().iter().all(|f| f.status == "ok"));
+        assert_eq!(received.files().len(), 2);
+        assert!(fs::read(root.join("dst/big.bin")).unwrap() == data);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_psk_is_not_retried() {
+        let root = std::env::temp_dir().join(format!("ncp-retry-psk-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepting = listener.try_clone().unwrap();
+        let dst = root.join("dst");
+        let receiver = thread::spawn(move || {
+            let args = RecvArgs { psk: Some("correct horse".to_string()), ..recv_args(&dst) };
+            crate::recv::receive_on(&accepting, &args).map(drop).is_err()
+        });
+        let mut args = send_args(&root.join("a.txt"));
+        args.listen = false;
+        args.host = Some("127.0.0.1".to_string());
+        args.port = port;
+        args.psk = Some("battery staple".to_string());
+        args.retries = 3;
+        args.retry_delay = Duration::from_millis(10);
+
+        let err = execute(args).err().unwrap();
+        assert!(matches!(err, NcpError::Auth(_)), "{}", err);
+        assert!(receiver.join().unwrap());
+        // No second attempt is waiting to be accepted.
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert!(!root.join("dst").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_transfer_with_socket_options() {
+        let root = std::env::temp_dir().join(format!("ncp-socket-options-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let file = root.join("data.bin");
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 241) as u8).collect();
+        fs::write(&file, &data).unwrap();
+
+        let tuned = SocketOptions { nodelay: false, sndbuf: Some(32 * 1024), rcvbuf: Some(32 * 1024) };
+        for (n, socket) in [SocketOptions::default(), tuned].into_iter().enumerate() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let dst = root.join(format!("dst{}", n));
+            let receiver_args = RecvArgs { socket, ..recv_args(&dst) };
+            let receiver = thread::spawn(move || {
+                crate::recv::receive_on(&listener, &receiver_args).map_err(|e| e.to_string())
+            });
+            let mut args = send_args(&file);
+            args.listen = false;
+            args.host = Some("127.0.0.1".to_string());
+            args.port = port;
+            args.socket = socket;
+
+            execute(args).unwrap();
+            receiver.join().unwrap().unwrap();
+            assert!(fs::read(&dst).unwrap() == data);
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_wire_scope_limits_compressed_bytes() {
+        let root = std::env::temp_dir().join(format!("ncp-limit-scope-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let file = root.join("zeros.bin");
+        fs::write(&file, vec![0u8; 1024 * 1024]).unwrap();
+
+        let mut took = Vec::new();
+        for scope in [LimitScope::File, LimitScope::Wire] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let receiver_args = recv_args(&root.join(format!("{:?}", scope)));
+            let receiver = thread::spawn(move || {
+                crate::recv::receive_on(&listener, &receiver_args).map_err(|e| e.to_string())
+            });
+            let mut args = send_args(&file);
+            args.listen = false;
+            args.host = Some("127.0.0.1".to_string());
+            args.port = port;
+            args.compress = true;
+            args.limit = Some(1024 * 1024);
+            args.limit_scope = scope;
+
+            let started = Instant::now();
+            execute(args).unwrap();
+            took.push(started.elapsed());
+            receiver.join().unwrap().unwrap();
+        }
+        // A second for the file's mebibyte, next to nothing for the few
+        // bytes it compresses to.
+        assert!(took[0] >= Duration::from_millis(900), "{:?}", took);
+        assert!(took[1] * 2 < took[0], "{:?}", took);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_batch_answers_many_small_files_together() {
+        let root = std::env::temp_dir().join(format!("ncp-batch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src")).unwrap();
+        for i in 0..1000 {
+            fs::write(root.join(format!("src/f{:04}.txt", i)), format!("file {}", i)).unwrap();
+        }
+
+        let mut answers = Vec::new();
+        for batch in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let target = listener.local_addr().unwrap();
+            let dst = root.join(format!("dst-{}", batch));
+            // Already there and not to be replaced, so one file is declined.
+            fs::create_dir_all(&dst).unwrap();
+            fs::write(dst.join("f0000.txt"), "old").unwrap();
+            let receiver_args = RecvArgs { overwrite: OverwriteMode::No, ..recv_args(&dst) };
+            let receiver = thread::spawn(move || {
+                crate::recv::receive_on(&listener, &receiver_args).map_err(|e| e.to_string())
+            });
+            // Counts the reads of what the receiver writes, one for each
+            // time the sender waited on it.
+            let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = relay.local_addr().unwrap().port();
+            let relay = thread::spawn(move || {
+                let (mut sender, _) = relay.accept().unwrap();
+                let mut receiver = TcpStream::connect(target).unwrap();
+                sender.set_nodelay(true).unwrap();
+                receiver.set_nodelay(true).unwrap();
+                let (mut from, mut to) = (sender.try_clone().unwrap(), receiver.try_clone().unwrap());
+                let upstream = thread::spawn(move || {
+                    let _ = io::copy(&mut from, &mut to);
+                    let _ = to.shutdown(Shutdown::Write);
+                });
+                let mut buffer = vec![0u8; 64 * 1024];
+                let mut reads = 0;
+                loop {
+                    let n = receiver.read(&mut buffer).unwrap_or(0);
+                    if n == 0 || sender.write_all(&buffer[..n]).is_err() {
+                        break;
+                    }
+                    reads += 1;
+                }
+                let _ = sender.shutdown(Shutdown::Write);
+                upstream.join().unwrap();
+                reads
+            });
+            let mut args = send_args(&root.join("src"));
+            args.listen = false;
+            args.host = Some("127.0.0.1".to_string());
+            args.port = port;
+            args.batch = batch;
+
+            let sent = execute(args).unwrap();
+            receiver.join().unwrap().unwrap();
+            answers.push(relay.join().unwrap());
+
+            assert_eq!(sent.files_transferred(), 999);
+            assert_eq!(fs::read_to_string(dst.join("f0000.txt")).unwrap(), "old");
+            for i in 1..1000 {
+                let name = format!("f{:04}.txt", i);
+                assert_eq!(fs::read_to_string(dst.join(name)).unwrap(), format!("file {}", i));
+            }
+        }
+        assert!(answers[1] * 10 < answers[0], "{:?}", answers);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let mut args = send_args(Path::new("."));
+        args.retry_delay = Duration::from_millis(100);
+        assert_eq!(retry_delay(&args, 0), Duration::from_millis(100));
+        assert_eq!(retry_delay(&args, 5), Duration::from_millis(100));
+
+        args.retry_backoff = 2.0;
+        let delays: Vec<Duration> = (0..6).map(|retry| jittered(retry_delay(&args, retry))).collect();
+        for pair in delays.windows(2) {
+            let ratio = pair[1].as_secs_f64() / pair[0].as_secs_f64();
+            assert!((1.6..=2.5).contains(&ratio), "{:?}", delays);
+        }
+        assert_eq!(retry_delay(&args, 100), MAX_RETRY_DELAY);
+
+        args.retry_delay = Duration::from_secs(90);
+        assert_eq!(retry_delay(&args, 3), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn test_directory_needs_recursive() {
+        let root = std::env::temp_dir().join(format!("ncp-recursive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/logs")).unwrap();
+        fs::write(root.join("src/a.txt"), "hello").unwrap();
+        fs::write(root.join("src/logs/b.txt"), "world").unwrap();
+        let refused = |src: PathBuf| {
+            let mut args = send_args(&src);
+            args.recursive = false;
+            execute(args).map(drop).unwrap_err().to_string()
+        };
+
+        let err = refused(root.join("src"));
+        assert!(err.ends_with("src is a directory; use -r to send a directory"), "{}", err);
+        let err = refused(root.join("src/*"));
+        assert!(err.contains("logs is a directory; use -r"), "{}", err);
+        assert!(!root.join("dst").exists());
+
+        // A file goes as before.
+        let mut args = send_args(&root.join("src/a.txt"));
+        args.recursive = false;
+        loopback(args, &root.join("one.txt"));
+        assert_eq!(fs::read(root.join("one.txt")).unwrap(), b"hello");
+
+        loopback(send_args(&root.join("src")), &root.join("dst"));
+        assert_eq!(fs::read(root.join("dst/logs/b.txt")).unwrap(), b"world");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_dry_run_plan() {
+        let root = std::env::temp_dir().join(format!("ncp-plan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::write(root.join("b.txt"), "hello").unwrap();
+        fs::write(root.join("a.txt"), "").unwrap();
+        fs::write(root.join("sub/deeper/c.bin"), vec![0u8; 2048]).unwrap();
+
+        let mut out = Vec::new();
+        let walk = walk_directory(&root, &Filter::default()).unwrap();
+        let entries: Vec<_> = walk.map(|e| e.unwrap()).collect();
+        write_plan(&mut out, &entries).unwrap();
+        let expected = "\
+dir               -  sub/
+dir               -  sub/deeper/
+file           2048  sub/deeper/c.bin
+file              0  a.txt
+file              5  b.txt
+3 files, 2 directories, 2.00 KiB (2053 bytes) in total
+The destination needs 2.00 KiB of free space
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_large_file_without_checksum() {
+        // On Linux this takes the sendfile path; elsewhere the buffered one.
+        let root = std::env::temp_dir().join(format!("ncp-zerocopy-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dst")).unwrap();
+        let data: Vec<u8> = (0..12_000_000u32).map(|i| (i % 241) as u8).collect();
+        fs::write(root.join("large.bin"), &data).unwrap();
+
+        let mut args = send_args(&root.join("large.bin"));
+        args.checksum = ChecksumAlg::None;
+        loopback(args, &root.join("dst"));
+        assert!(fs::read(root.join("dst/large.bin")).unwrap() == data);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_body_stops_at_the_declared_size() {
+        let root = std::env::temp_dir().join(format!("ncp-grown-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("grown.txt");
+        fs::write(&path, "abcdefghij").unwrap();
+
+        // Both with sendfile and through the buffer.
+        for checksum in [ChecksumAlg::None, ChecksumAlg::default()] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut stream = Stream::from(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+            let (mut peer, _) = listener.accept().unwrap();
+            let mut args = send_args(&path);
+            args.checksum = checksum;
+            let session = Session {
+                id: String::new(),
+                keepalive: None,
+                verify_only: false,
+                tree_checksum: false,
+                tree_digest: Cell::new(None),
+                mirror: MirrorMode::Off,
+                parallel: 1,
+                shared_zstd: None,
+                retry_files: false,
+                batch: false,
+            };
+            let (mode, mut silent) = (TransferMode::TransferRaw, progress::Silent);
+            let sent = send_body(&mut stream, &args, &session, &path, "", mode, 4, 0, &mut silent);
+            let err = sent.unwrap_err();
+            assert!(err.to_string().contains("grew past the 4 bytes sent"), "{}", err);
+
+            drop(stream);
+            let mut sent = Vec::new();
+            peer.read_to_end(&mut sent).unwrap();
+            assert_eq!(sent, b"abcd");
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_overall_progress_line() {
+        let mut overall = OverallProgress::new(10, 4096);
+        overall.next_file();
+        overall.add(1024);
+        assert_eq!(overall.counts(), "[ 1/10]  25.0%    1.00 KiB/4.00 KiB");
+
+        // Only empty files: count them instead of bytes.
+        let mut empty = OverallProgress::new(4, 0);
+        empty.next_file();
+        assert_eq!(empty.counts(), "[1/4]  25.0%         0 B/0 B");
+    }
+}
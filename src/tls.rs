@@ -0,0 +1,380 @@
+//! `--tls`: each connection wrapped in TLS (rustls, with the `ring`
+//! provider) as soon as it is made, so that the handshake of `handshake`
+//! and everything after it is encrypted.
+//!
+//! The receiver is always the TLS server, with the certificate chain and
+//! key of `recv --tls --cert --key`, and the sender the client, whichever
+//! of them listens: a `send --listen` checks the certificate of the
+//! receiver that connects to it against that receiver's IP address. The
+//! sender trusts the public CAs of `webpki-roots`, or with `--ca` only the
+//! ones in that file; `--insecure` takes any certificate, for a self-signed
+//! one that was never handed out, and still encrypts, but can no longer
+//! tell who is at the other end. A certificate that does not verify, like
+//! a `--psk` that does not match, is not retried.
+//!
+//! Extra connections of a split file (`--parallel`) are wrapped like the
+//! first. `sendfile` is never used under TLS, as the data has to be
+//! encrypted on its way (see `zerocopy`).
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, ConnectionCommon, DigitallySignedStruct, RootCertStore,
+    ServerConfig, ServerConnection, SideData, SignatureScheme, StreamOwned,
+};
+
+use crate::net::{self, Stream};
+use crate::types::{NcpError, Result};
+
+/// The `Client` for `send --tls --ca --insecure`, or `None` without
+/// `--tls`.
+pub fn client(tls: bool, ca: Option<&Path>, insecure: bool) -> Result<Option<Client>> {
+    match (tls, ca, insecure) {
+        (false, None, false) => Ok(None),
+        (false, _, _) => Err("--ca and --insecure only apply with --tls".into()),
+        (true, Some(_), true) => Err("--insecure trusts any certificate; it cannot go with --ca".into()),
+        (true, ca, insecure) => Client::new(ca, insecure).map(Some),
+    }
+}
+
+/// The `Server` for `recv --tls --cert --key`, or `None` without `--tls`.
+pub fn server(tls: bool, cert: Option<&Path>, key: Option<&Path>) -> Result<Option<Server>> {
+    match (tls, cert, key) {
+        (false, None, None) => Ok(None),
+        (false, _, _) => Err("--cert and --key only apply with --tls".into()),
+        (true, Some(cert), Some(key)) => Server::new(cert, key).map(Some),
+        (true, _, _) => Err("--tls needs --cert and --key to receive with".into()),
+    }
+}
+
+/// How the sender checks the receiver it connects to.
+#[derive(Debug, Clone)]
+pub struct Client {
+    config: Arc<ClientConfig>,
+}
+
+impl Client {
+    /// Trust the CAs in the PEM file `ca`, or without it the public ones;
+    /// with `insecure`, any certificate at all.
+    pub fn new(ca: Option<&Path>, insecure: bool) -> Result<Self> {
+        let provider = provider();
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let config = if insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+                .with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            match ca {
+                Some(path) => {
+                    for cert in load_certs(path, "--ca")? {
+                        roots
+                            .add(cert)
+                            .map_err(|e| format!("Cannot use --ca {}: {}", path.display(), e))?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+        Ok(Client { config: Arc::new(config) })
+    }
+
+    /// Start TLS over `socket` with the receiver at `host`, the name or IP
+    /// address its certificate has to be for.
+    pub fn connect(&self, socket: TcpStream, host: &str) -> Result<Stream> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|_| format!("{} is not a name a TLS certificate can be checked against", host))?;
+        let conn = ClientConnection::new(Arc::clone(&self.config), name).map_err(tls_error)?;
+        establish(Session::Client(StreamOwned::new(conn, socket)))
+    }
+}
+
+/// The certificate the receiver proves itself with.
+#[derive(Debug, Clone)]
+pub struct Server {
+    config: Arc<ServerConfig>,
+}
+
+impl Server {
+    /// Serve the certificate chain in the PEM file `cert`, the receiver's
+    /// own first, with the private key in `key`.
+    pub fn new(cert: &Path, key: &Path) -> Result<Self> {
+        let certs = load_certs(cert, "--cert")?;
+        let private_key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| format!("Cannot read a private key from --key {}: {}", key.display(), e))?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_single_cert(certs, private_key)
+            .map_err(|e| {
+                format!("Cannot serve --cert {} with --key {}: {}", cert.display(), key.display(), e)
+            })?;
+        Ok(Server { config: Arc::new(config) })
+    }
+
+    /// Start TLS over `socket` with the sender at the other end.
+    pub fn accept(&self, socket: TcpStream) -> Result<Stream> {
+        let conn = ServerConnection::new(Arc::clone(&self.config)).map_err(tls_error)?;
+        establish(Session::Server(StreamOwned::new(conn, socket)))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn tls_error(e: rustls::Error) -> NcpError {
+    format!("TLS: {}", e).into()
+}
+
+/// Every certificate in the PEM file at `path`, given with `flag`.
+fn load_certs(path: &Path, flag: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let unreadable = |e: rustls::pki_types::pem::Error| {
+        format!("Cannot read certificates from {} {}: {}", flag, path.display(), e)
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(unreadable)?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(unreadable)?;
+    if certs.is_empty() {
+        return Err(format!("{} {} holds no certificate", flag, path.display()).into());
+    }
+    Ok(certs)
+}
+
+/// Finish the TLS handshake of `session` before anything else is sent, so
+/// that a failure says what it is instead of turning up as a garbled
+/// `Probe`.
+fn establish(mut session: Session) -> Result<Stream> {
+    let socket = session.socket().try_clone()?;
+    let peer = net::peer_name(&socket);
+    session.handshake().map_err(|e| {
+        let message = format!("TLS handshake with {} failed: {}", peer, e);
+        // What rustls rejects, such as a certificate it does not trust or
+        // a peer that does not speak TLS, will be rejected again.
+        match e.kind() {
+            io::ErrorKind::InvalidData => NcpError::Auth(message),
+            kind => NcpError::Io(io::Error::new(kind, message)),
+        }
+    })?;
+    vlog!("TLS session with {} established", peer);
+    let session = Arc::new(Mutex::new(session));
+    Ok(Stream::Tls(TlsStream { session, socket }))
+}
+
+/// A connection inside TLS. Its clones share the one TLS session, which a
+/// `Keepalive` pings the peer through while the owner does not use it.
+pub struct TlsStream {
+    session: Arc<Mutex<Session>>,
+    /// The socket beneath, for its options and address.
+    socket: TcpStream,
+}
+
+impl TlsStream {
+    /// The socket beneath; anything read or written on it bypasses TLS.
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Another handle on the same session, as `Stream::try_clone`.
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            session: Arc::clone(&self.session),
+            socket: self.socket.try_clone()?,
+        })
+    }
+
+    fn session(&self) -> MutexGuard<'_, Session> {
+        self.session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut *self.session() {
+            Session::Client(stream) => stream.read(buf),
+            Session::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.session() {
+            Session::Client(stream) => stream.write(buf),
+            Session::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.session() {
+            Session::Client(stream) => stream.flush(),
+            Session::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+/// One side of a TLS session over its socket.
+enum Session {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl Session {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Session::Client(stream) => &stream.sock,
+            Session::Server(stream) => &stream.sock,
+        }
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        match self {
+            Session::Client(stream) => handshake(&mut stream.conn, &mut stream.sock),
+            Session::Server(stream) => handshake(&mut stream.conn, &mut stream.sock),
+        }
+    }
+}
+
+impl Drop for Session {
+    /// Say the connection ends here, so the peer does not take the end for
+    /// a cut. Nothing is waited for: a peer that is gone is not missed.
+    fn drop(&mut self) {
+        match self {
+            Session::Client(stream) => close(&mut stream.conn, &mut stream.sock),
+            Session::Server(stream) => close(&mut stream.conn, &mut stream.sock),
+        }
+    }
+}
+
+fn handshake<D: SideData>(conn: &mut ConnectionCommon<D>, socket: &mut TcpStream) -> io::Result<()> {
+    while conn.is_handshaking() {
+        conn.complete_io(socket)?;
+    }
+    Ok(())
+}
+
+fn close<D: SideData>(conn: &mut ConnectionCommon<D>, socket: &mut TcpStream) {
+    conn.send_close_notify();
+    while conn.wants_write() {
+        if conn.write_tls(socket).is_err() {
+            break;
+        }
+    }
+}
+
+/// `--insecure`: any certificate is taken, though the handshake must still
+/// be signed with its key.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::zerocopy::ZeroCopy;
+
+    #[test]
+    fn test_self_signed_loopback_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ncp-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert, issued.cert.pem()).unwrap();
+        fs::write(&key, issued.signing_key.serialize_pem()).unwrap();
+
+        let server = Server::new(&cert, &key).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Echoes what it reads in capitals, for each of the three clients.
+        let receiver = thread::spawn(move || {
+            (0..3)
+                .map(|_| {
+                    let mut stream = server.accept(listener.accept().unwrap().0)?;
+                    let mut buf = [0; 5];
+                    stream.read_exact(&mut buf)?;
+                    stream.write_all(&buf.to_ascii_uppercase())?;
+                    Ok(())
+                })
+                .collect::<Vec<Result<()>>>()
+        });
+        let connect = |client: &Client| {
+            client.connect(TcpStream::connect(("127.0.0.1", port)).unwrap(), "localhost")
+        };
+        let echo = |mut stream: Stream| {
+            stream.write_all(b"hello").unwrap();
+            let mut reply = [0; 5];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"HELLO");
+        };
+
+        let trusted = connect(&Client::new(Some(&cert), false).unwrap()).unwrap();
+        // The kernel cannot encrypt what it copies from the file.
+        let file = File::open(&cert).unwrap();
+        assert_eq!(ZeroCopy::new(&trusted, true).send(&file, 1024), None);
+        echo(trusted);
+
+        // The public CAs never signed it, and that is not worth a retry.
+        match connect(&Client::new(None, false).unwrap()) {
+            Err(NcpError::Auth(message)) => assert!(message.contains("UnknownIssuer"), "{}", message),
+            Err(e) => panic!("not an authentication failure: {}", e),
+            Ok(_) => panic!("an untrusted certificate was taken"),
+        }
+
+        echo(connect(&Client::new(None, true).unwrap()).unwrap());
+
+        let served = receiver.join().unwrap();
+        assert!(served[0].is_ok() && served[1].is_err() && served[2].is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::net::{IpFamily, SocketOptions};
+use crate::parallel;
+use crate::proxy::Proxy;
+use crate::tls;
+use crate::proto::{ErrorCode, OverwritePolicy};
+use crate::directory::Filter;
+use crate::manifest::Manifest;
+use crate::checksum::{ChecksumAlg, ChecksumScope};
+use crate::checksum_cache::ChecksumCache;
+use crate::protocol::WireFormat;
+use crate::utils::format_bytes;
+
+pub type Result<T> = std::result::Result<T, NcpError>;
+
+/// Why a transfer, or anything leading up to it, failed.
+#[derive(Debug)]
+pub enum NcpError {
+    /// Reading or writing a file or the connection failed.
+    Io(io::Error),
+    /// The peer sent something that does not follow the protocol.
+    Protocol(String),
+    /// A file arrived but does not match the sender's checksum.
+    ChecksumMismatch(String),
+    /// The destination cannot hold a file; `free` is what was left after
+    /// the other transfers' reservations.
+    InsufficientSpace { needed: u64, free: u64, reserved: u64 },
+    /// A file is already at the destination and may not be replaced.
+    DeclinedOverwrite(String),
+    /// A file is larger than the receiver takes (`--max-file-size`).
+    FileTooLarge(String),
+    /// The peer gave up and told us why.
+    PeerError(ErrorCode, String),
+    /// A side could not prove it holds the `--psk` key, or has none where
+    /// the other requires one, or the TLS handshake was refused, as for a
+    /// certificate the sender does not trust. Trying again will not change
+    /// that.
+    Auth(String),
+    /// Every attempt allowed by `--retries` failed, the last one with this.
+    RetriesExhausted(u32, Box<NcpError>),
+    /// Anything else: a bad argument, a missing source, a refused key.
+    Other(String),
+}
+
+impl NcpError {
+    /// What to tell the peer this failure was.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            NcpError::Io(e) => ErrorCode::from(e),
+            NcpError::Protocol(_) => ErrorCode::ErrProtocol,
+            NcpError::ChecksumMismatch(_) => ErrorCode::ErrChecksum,
+            NcpError::InsufficientSpace { .. } => ErrorCode::ErrNoSpace,
+            NcpError::DeclinedOverwrite(_) => ErrorCode::ErrExists,
+            NcpError::FileTooLarge(_) => ErrorCode::ErrTooLarge,
+            NcpError::Auth(_) => ErrorCode::ErrAuth,
+            NcpError::PeerError(code, _) => *code,
+            NcpError::RetriesExhausted(_, last) => last.code(),
+            NcpError::Other(_) => ErrorCode::ErrorUnknown,
+        }
+    }
+
+    /// The code the peer failed with, if this is its failure.
+    pub fn peer_code(&self) -> Option<ErrorCode> {
+        match self {
+            NcpError::PeerError(code, _) => Some(*code),
+            NcpError::RetriesExhausted(_, last) => last.peer_code(),
+            _ => None,
+        }
+    }
+
+    /// The status `ncp` exits with, as listed under Exit Codes in the
+    /// README; whichever side the failure happened on.
+    pub fn exit_status(&self) -> u8 {
+        if let NcpError::RetriesExhausted(..) = self {
+            return 11;
+        }
+        match self.code() {
+            ErrorCode::ErrProtocol => 2,
+            ErrorCode::ErrTimeout | ErrorCode::ErrUnexpectedEof => 3,
+            ErrorCode::ErrPermission => 4,
+            ErrorCode::ErrChecksum => 5,
+            ErrorCode::ErrNoSpace => 6,
+            _ if matches!(self, NcpError::Io(_)) => 3,
+            _ => 1,
+        }
+    }
+
+    /// The error a peer's failure report stands for. A code this side does
+    /// not know is kept as unknown.
+    pub fn from_peer(code: i32, message: String) -> Self {
+        NcpError::PeerError(ErrorCode::try_from(code).unwrap_or(ErrorCode::ErrorUnknown), message)
+    }
+}
+
+impl fmt::Display for NcpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NcpError::Io(e) => write!(f, "{}", e),
+            NcpError::InsufficientSpace { needed, free, reserved } => write!(
+                f,
+                "Insufficient disk space: need {}, available {} ({} reserved by other transfers)",
+                format_bytes(*needed),
+                format_bytes(*free),
+                format_bytes(*reserved)
+            ),
+            NcpError::RetriesExhausted(attempts, _) => {
+                write!(f, "Transfer failed after {} attempts", attempts)
+            }
+            NcpError::Protocol(message)
+            | NcpError::ChecksumMismatch(message)
+            | NcpError::DeclinedOverwrite(message)
+            | NcpError::FileTooLarge(message)
+            | NcpError::Auth(message)
+            | NcpError::PeerError(_, message)
+            | NcpError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for NcpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NcpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NcpError {
+    fn from(err: io::Error) -> Self {
+        NcpError::Io(err)
+    }
+}
+
+impl From<String> for NcpError {
+    fn from(message: String) -> Self {
+        NcpError::Other(message)
+    }
+}
+
+impl From<&str> for NcpError {
+    fn from(message: &str) -> Self {
+        NcpError::Other(message.to_string())
+    }
+}
+
+/// How the receiver treats a destination file that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverwriteMode {
+    Ask,
+    Yes,
+    No,
+    /// Replace it only if the incoming file's mtime is later.
+    Newer,
+    /// Replace it only if the incoming file's mtime is earlier.
+    Older,
+}
+
+impl OverwriteMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "ask" => Ok(OverwriteMode::Ask),
+            "yes" => Ok(OverwriteMode::Yes),
+            "no" => Ok(OverwriteMode::No),
+            "newer" => Ok(OverwriteMode::Newer),
+            "older" => Ok(OverwriteMode::Older),
+            _ => Err(format!(
+                "Invalid overwrite mode: {} (expected ask, yes, no, newer or older)",
+                value
+            )
+            .into()),
+        }
+    }
+
+    /// `newer` and `older` compare modification times, so the sender
+    /// sends them even without `--preserve`.
+    pub fn by_mtime(self) -> bool {
+        matches!(self, OverwriteMode::Newer | OverwriteMode::Older)
+    }
+
+    /// The preference a sender transmits; `Ask` leaves the decision to the
+    /// receiver.
+    pub fn as_policy(self) -> OverwritePolicy {
+        match self {
+            OverwriteMode::Ask => OverwritePolicy::OverwriteUnspecified,
+            OverwriteMode::Yes => OverwritePolicy::OverwriteYes,
+            OverwriteMode::No => OverwritePolicy::OverwriteNo,
+            OverwriteMode::Newer => OverwritePolicy::OverwriteNewer,
+            OverwriteMode::Older => OverwritePolicy::OverwriteOlder,
+        }
+    }
+
+    /// Combine the receiver's own mode with the sender's preference. An
+    /// explicit receiver setting always wins; the sender only decides what
+    /// the receiver would otherwise have prompted for.
+    pub fn resolve(self, sender: i32) -> OverwriteMode {
+        match (self, OverwritePolicy::try_from(sender)) {
+            (OverwriteMode::Ask, Ok(OverwritePolicy::OverwriteYes)) => OverwriteMode::Yes,
+            (OverwriteMode::Ask, Ok(OverwritePolicy::OverwriteNo)) => OverwriteMode::No,
+            (OverwriteMode::Ask, Ok(OverwritePolicy::OverwriteNewer)) => OverwriteMode::Newer,
+            (OverwriteMode::Ask, Ok(OverwritePolicy::OverwriteOlder)) => OverwriteMode::Older,
+            (mode, _) => mode,
+        }
+    }
+}
+
+/// What `--limit` counts (`--limit-scope`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitScope {
+    /// File data as read, before any compression.
+    #[default]
+    File,
+    /// Bytes of file bodies as they go onto the connection, once
+    /// compressed or framed.
+    Wire,
+}
+
+impl LimitScope {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "file" => Ok(LimitScope::File),
+            "wire" => Ok(LimitScope::Wire),
+            _ => Err(format!("Invalid limit scope: {} (expected file or wire)", value).into()),
+        }
+    }
+}
+
+/// Whether a directory send asks the receiver to prune entries that are
+/// not part of the source tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorMode {
+    Off,
+    Delete,
+    DryRun,
+}
+
+/// Whether the receiver links a file with the same contents as one it
+/// received before to that one (`--dedup`, `--dedup-reflink`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupMode {
+    Off,
+    HardLink,
+    /// Share the blocks, and keep the files apart otherwise.
+    Reflink,
+}
+
+/// A `src` or `dst` of `-` stands for standard input or output.
+pub const STDIO_PATH: &str = "-";
+
+/// Connection attempts when `--retries` is not given.
+pub const DEFAULT_RETRIES: u32 = 3;
+/// Wait before the first retry when `--retry-delay` is not given.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct SendArgs {
+    pub host: Option<String>,
+    pub port: u16,
+    pub src: PathBuf,
+    /// Send a directory source, or a wildcard that matches one (`-r`);
+    /// without it only files are sent.
+    pub recursive: bool,
+    pub retries: u32,
+    /// Wait before the first retry (`--retry-delay`).
+    pub retry_delay: Duration,
+    /// Multiplier applied to the wait after each failed attempt; 1.0 keeps
+    /// it constant.
+    pub retry_backoff: f64,
+    pub overwrite: OverwriteMode,
+    pub listen: bool,
+    /// How long `--listen` waits for the receiver (`--accept-timeout`).
+    pub accept_timeout: Option<Duration>,
+    pub mirror: MirrorMode,
+    pub format: WireFormat,
+    /// Accept the receiver's offer to continue a partial file.
+    pub resume: bool,
+    /// Send file bodies zstd-compressed, where that pays off.
+    pub compress: bool,
+    /// zstd level for `compress`.
+    pub compress_level: i32,
+    /// Compress the files of a directory as one zstd stream, where the
+    /// receiver takes it (`TRANSFER_ZSTD_SHARED`).
+    pub compress_shared: bool,
+    /// Send raw bodies in CRC32-checked chunks (`TRANSFER_CHUNKED`).
+    pub verify_chunks: bool,
+    /// Send raw bodies in length-prefixed blocks (`TRANSFER_FRAMED`).
+    pub framed: bool,
+    /// Split a single file over this many connections (`--parallel`); 1
+    /// sends it over one.
+    pub parallel: u32,
+    /// Send the small files of a directory back to back and read their
+    /// answers together (`--batch`).
+    pub batch: bool,
+    /// Digest the receiver verifies each file against.
+    pub checksum: ChecksumAlg,
+    /// Whether a directory is verified file by file or all at once
+    /// (`--checksum tree`).
+    pub checksum_scope: ChecksumScope,
+    /// Digests of unchanged files from earlier sends (`--checksum-cache`).
+    pub checksum_cache: ChecksumCache,
+    /// Limit on connecting and on each socket read or write; `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// Cap on file data sent per second.
+    pub limit: Option<u64>,
+    /// Whether `limit` counts file data or what goes on the wire
+    /// (`--limit-scope`).
+    pub limit_scope: LimitScope,
+    /// Send file permissions and modification times for the receiver to apply.
+    pub preserve: bool,
+    /// Send each file's extended attributes for the receiver to set.
+    pub xattrs: bool,
+    /// Offer each file's checksum up front so the receiver can skip files it
+    /// already has (`--skip-existing`).
+    pub skip_existing: bool,
+    /// Put every file's checksum in its `Meta`, as `skip_existing` does,
+    /// and only resume a partial file whose bytes match ours.
+    pub checksum_on_preflight: bool,
+    /// Print the entries that would be sent and exit without connecting.
+    pub dry_run: bool,
+    /// Bytes read from the file and written to the socket at a time.
+    pub buffer_size: usize,
+    /// Least time between progress updates (`--progress-interval`).
+    pub progress_interval: Duration,
+    pub family: IpFamily,
+    /// Local address to connect from (`--bind`).
+    pub bind: Option<SocketAddr>,
+    /// `TCP_NODELAY` and buffer sizes of each connection.
+    pub socket: SocketOptions,
+    /// Reach the receiver through this proxy (`--proxy`).
+    pub proxy: Option<Proxy>,
+    /// Where each finished file is recorded (`--manifest`).
+    pub manifest: Manifest,
+    /// `--include` and `--exclude` patterns for directory sources.
+    pub filter: Filter,
+    /// Name what is sent by its path below this directory, not from the
+    /// source itself (`--relative-to`).
+    pub relative_to: Option<PathBuf>,
+    /// The paths to send instead of `src`, which is empty then, read from
+    /// a list (`--files-from`).
+    pub files_from: Option<Vec<PathBuf>>,
+    /// Leave out listed paths that do not exist, with a warning, rather
+    /// than fail (`--ignore-missing`).
+    pub ignore_missing: bool,
+    /// Key both sides must prove they hold before anything is sent (`--psk`).
+    pub psk: Option<String>,
+    /// Connect inside TLS, checking the receiver's certificate as this
+    /// says (`--tls`, `--ca`, `--insecure`).
+    pub tls: Option<tls::Client>,
+}
+
+#[derive(Debug)]
+pub struct RecvArgs {
+    pub host: Option<String>,
+    pub port: u16,
+    pub dst: PathBuf,
+    pub overwrite: OverwriteMode,
+    /// Offer to continue from an existing `.ncp_temp` file.
+    pub resume: bool,
+    pub timeout: Option<Duration>,
+    /// Keep accepting connections after the first transfer.
+    pub keep_alive: bool,
+    /// Bytes read from the socket and written to the file at a time.
+    pub buffer_size: usize,
+    /// Least time between progress updates (`--progress-interval`).
+    pub progress_interval: Duration,
+    pub family: IpFamily,
+    /// `TCP_NODELAY` and buffer sizes of each connection.
+    pub socket: SocketOptions,
+    /// Save a single received file under this name inside `dst` (`--as`).
+    pub output_name: Option<String>,
+    /// Create missing parent directories of `dst` instead of failing.
+    pub mkdir: bool,
+    /// Merge a directory transfer into an existing `dst` rather than
+    /// creating the tree inside it (`--into`).
+    pub into: bool,
+    /// Only compare what is already in `dst` with the sender's checksums,
+    /// accepting no file data (`--verify-only`).
+    pub verify_only: bool,
+    /// Rewrite an existing file where it is instead of through a temp file
+    /// renamed over it (`--in-place`).
+    pub in_place: bool,
+    /// Flush each file, and the directory it is renamed into, to disk
+    /// before reporting it received (`--fsync`).
+    pub fsync: bool,
+    /// Hash each saved file again as it reads back from disk, and fail it
+    /// if that differs from what was received (`--verify-read`).
+    pub verify_read: bool,
+    /// Link each file with the same contents as one received before on the
+    /// connection to that one, rather than keep both (`--dedup`).
+    pub dedup: DedupMode,
+    /// Write a file whose destination is an existing FIFO or device into
+    /// it, instead of refusing it (`--write-special`).
+    pub write_special: bool,
+    /// Directories every received entry must land inside once symlinks are
+    /// resolved (`--allow-root`); empty allows anywhere.
+    pub allow_roots: Vec<PathBuf>,
+    /// Leading components dropped from the name of each entry inside a
+    /// directory transfer (`--strip-components`).
+    pub strip_components: usize,
+    /// Refuse files larger than this (`--max-file-size`).
+    pub max_file_size: Option<u64>,
+    /// Remove what a directory transfer did not bring from where it landed,
+    /// or only report it (`--delete`, `--delete-dry-run`).
+    pub delete: MirrorMode,
+    /// Ask a listening sender for this path instead of taking what it offers
+    /// (`--pull`).
+    pub pull: Option<String>,
+    /// Where each file received or declined is recorded (`--manifest`).
+    pub manifest: Manifest,
+    /// Key a sender must prove it holds before it may send (`--psk`).
+    pub psk: Option<String>,
+    /// Take each connection inside TLS with this certificate (`--tls`,
+    /// `--cert`, `--key`).
+    pub tls: Option<tls::Server>,
+}
+
+impl SendArgs {
+    /// Whether the source is standard input.
+    pub fn reads_stdin(&self) -> bool {
+        self.src.as_os_str() == STDIO_PATH
+    }
+
+    /// Reject settings that cannot be used together, however they were
+    /// given.
+    pub fn check(&self) -> Result<()> {
+        if self.retries == 0 {
+            return Err("--retries must be at least 1".into());
+        }
+        // The receiver would delete whatever was filtered out.
+        if self.mirror != MirrorMode::Off && !self.filter.is_empty() {
+            return Err("--mirror cannot be combined with --include or --exclude".into());
+        }
+        if self.skip_existing && self.checksum == ChecksumAlg::None {
+            return Err("--skip-existing cannot be combined with --checksum none".into());
+        }
+        if self.checksum_on_preflight && self.checksum == ChecksumAlg::None {
+            return Err("--checksum-on-preflight cannot be combined with --checksum none".into());
+        }
+        if self.checksum_scope == ChecksumScope::Tree && self.checksum == ChecksumAlg::None {
+            return Err("--checksum tree cannot be combined with --checksum none".into());
+        }
+        // The receiver would merge into, and so delete from, the destination itself.
+        if self.relative_to.is_some() && self.mirror != MirrorMode::Off {
+            return Err("--relative-to cannot be combined with --mirror".into());
+        }
+        if self.relative_to.is_some() && self.reads_stdin() {
+            return Err("--relative-to needs a file or directory source, not stdin".into());
+        }
+        if self.compress && self.verify_chunks {
+            return Err("--verify-chunks cannot be combined with --compress".into());
+        }
+        // Both frame the data already.
+        if self.framed && (self.compress || self.verify_chunks) {
+            return Err("--framed cannot be combined with --compress or --verify-chunks".into());
+        }
+        if self.buffer_size == 0 {
+            return Err("--buffer-size must be greater than 0".into());
+        }
+        if !(1..=parallel::MAX_STREAMS).contains(&self.parallel) {
+            return Err(format!("--parallel must be between 1 and {}", parallel::MAX_STREAMS).into());
+        }
+        if self.parallel > 1 && self.listen {
+            return Err("--parallel needs --host: the extra connections go to the receiver".into());
+        }
+        // The ranges are written where they belong as they arrive.
+        if self.parallel > 1 && (self.compress || self.verify_chunks || self.framed) {
+            let message = "--parallel cannot be combined with --compress, --verify-chunks or --framed";
+            return Err(message.into());
+        }
+        if self.parallel > 1 && self.reads_stdin() {
+            return Err("--parallel needs a file source, not stdin".into());
+        }
+        Ok(())
+    }
+}
+
+impl RecvArgs {
+    /// Whether the received file goes to standard output.
+    pub fn writes_stdout(&self) -> bool {
+        self.dst.as_os_str() == STDIO_PATH
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overwrite_resolve() {
+        let yes = OverwritePolicy::OverwriteYes as i32;
+        let no = OverwritePolicy::OverwriteNo as i32;
+        let unset = OverwritePolicy::OverwriteUnspecified as i32;
+        let newer = OverwritePolicy::OverwriteNewer as i32;
+
+        assert_eq!(OverwriteMode::Ask.resolve(yes), OverwriteMode::Yes);
+        assert_eq!(OverwriteMode::Ask.resolve(no), OverwriteMode::No);
+        assert_eq!(OverwriteMode::Ask.resolve(unset), OverwriteMode::Ask);
+        assert_eq!(OverwriteMode::Ask.resolve(99), OverwriteMode::Ask);
+        assert_eq!(OverwriteMode::Ask.resolve(newer), OverwriteMode::Newer);
+        // The receiver's explicit choice is never overridden.
+        assert_eq!(OverwriteMode::No.resolve(yes), OverwriteMode::No);
+        assert_eq!(OverwriteMode::Yes.resolve(no), OverwriteMode::Yes);
+        assert_eq!(OverwriteMode::Older.resolve(newer), OverwriteMode::Older);
+    }
+}
@@ -0,0 +1,534 @@
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::types::Result;
+
+/// How `format_bytes_in` scales a byte count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteUnits {
+    /// Powers of 1024: `KiB`, `MiB`, ...
+    Iec,
+    /// Powers of 1000: `KB`, `MB`, ... (`--si`).
+    Si,
+}
+
+/// Set by `--si`: `format_bytes` prints powers of 1000 instead of 1024.
+static SI_UNITS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_si_units(si: bool) {
+    SI_UNITS.store(si, Ordering::Relaxed);
+}
+
+impl ByteUnits {
+    fn base(self) -> f64 {
+        match self {
+            ByteUnits::Iec => 1024.0,
+            ByteUnits::Si => 1000.0,
+        }
+    }
+
+    /// Suffixes from bytes up; `u64::MAX` is 16 EiB (18.4 EB).
+    fn names(self) -> [&'static str; 7] {
+        match self {
+            ByteUnits::Iec => ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"],
+            ByteUnits::Si => ["B", "KB", "MB", "GB", "TB", "PB", "EB"],
+        }
+    }
+}
+
+/// Format a byte count for humans in IEC units, e.g. `1536` -> `1.50 KiB`,
+/// or in SI units after `set_si_units`. A rate computed as `f64` and cast
+/// down saturates at 0, never below.
+pub fn format_bytes(bytes: u64) -> String {
+    let units = if SI_UNITS.load(Ordering::Relaxed) { ByteUnits::Si } else { ByteUnits::Iec };
+    format_bytes_in(bytes, units)
+}
+
+/// Format a byte count for humans in the given units.
+pub fn format_bytes_in(bytes: u64, units: ByteUnits) -> String {
+    let base = units.base();
+    let names = units.names();
+    if (bytes as f64) < base {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= base && unit < names.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+
+    format!("{:.2} {}", value, names[unit])
+}
+
+/// Inverse of `format_bytes`: parse `500`, `500K`, `5M`, `1.5GB`, `2 MiB`
+/// and the like into bytes. Units are binary, with or without the `i`, and
+/// case-insensitive.
+pub fn parse_bytes(value: &str) -> Result<u64> {
+    let invalid = || format!("Invalid size: {}", value);
+    let upper = value.trim().to_ascii_uppercase();
+    let digits_end = upper
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(upper.len());
+    let (number, unit) = upper.split_at(digits_end);
+
+    let multiplier: u64 = match unit.trim_start() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024u64.pow(4),
+        "P" | "PB" | "PIB" => 1024u64.pow(5),
+        "E" | "EB" | "EIB" => 1024u64.pow(6),
+        _ => return Err(invalid().into()),
+    };
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let bytes = number * multiplier as f64;
+    if !bytes.is_finite() || bytes > u64::MAX as f64 {
+        return Err(invalid().into());
+    }
+    Ok(bytes as u64)
+}
+
+/// Paces a stream to at most `rate` bytes per second on average since it
+/// was created: each `sent` sleeps until the running total is back under
+/// the limit.
+pub struct Throttle {
+    rate: u64,
+    started: Instant,
+    total: u64,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            started: Instant::now(),
+            total: 0,
+        }
+    }
+
+    pub fn sent(&mut self, bytes: u64) {
+        self.total += bytes;
+        let due = Duration::from_secs_f64(self.total as f64 / self.rate as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+
+    /// Count from now, so that time spent paused is not made up for in a
+    /// burst above the rate.
+    pub fn restart(&mut self) {
+        self.started = Instant::now();
+        self.total = 0;
+    }
+}
+
+/// Passes writes through to `inner`, adding up the bytes it takes in
+/// `written`, for `--limit-scope wire`.
+pub struct CountingWriter<'a, W: Write> {
+    inner: W,
+    written: &'a Cell<u64>,
+}
+
+impl<'a, W: Write> CountingWriter<'a, W> {
+    pub fn new(inner: W, written: &'a Cell<u64>) -> Self {
+        CountingWriter { inner, written }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.set(self.written.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Size of the buffer file data is copied through, unless `--buffer-size`
+/// says otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Time between progress updates, unless `--progress-interval` says
+/// otherwise.
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Decides when enough time has passed to report progress again: at most
+/// once per `interval`, however fast or slowly the bytes move. The final
+/// update is up to the caller.
+pub struct ProgressTicker {
+    interval: Duration,
+    last_print: Instant,
+}
+
+impl ProgressTicker {
+    pub fn new(interval: Duration) -> Self {
+        ProgressTicker {
+            interval,
+            last_print: Instant::now(),
+        }
+    }
+
+    pub fn tick(&mut self) -> bool {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_print) < self.interval {
+            return false;
+        }
+        self.last_print = now;
+        true
+    }
+}
+
+/// `bytes` moved in `elapsed` and the rate that works out to, e.g.
+/// `3.00 MiB in 1.52s (1.97 MiB/s)`. A minute or more is shown as on a
+/// progress line.
+pub fn describe_throughput(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    let time = if elapsed >= Duration::from_secs(60) {
+        format_duration(elapsed)
+    } else {
+        format!("{:.2}s", secs)
+    };
+    if secs == 0.0 {
+        return format!("{} in {}", format_bytes(bytes), time);
+    }
+    let rate = format_bytes((bytes as f64 / secs) as u64);
+    format!("{} in {} ({}/s)", format_bytes(bytes), time, rate)
+}
+
+/// Format a duration for a progress line, e.g. `0:07`, `12:30` or `1:02:03`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Weight of the newest sample in the smoothed rate.
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Samples closer together than this are merged into the next one, so a
+/// burst of fast updates does not swing the rate.
+const MIN_SAMPLE: Duration = Duration::from_millis(200);
+
+/// Transfer rate for progress lines: an exponentially smoothed average of
+/// recent samples, or the average since the start until there are any.
+pub struct RateMeter {
+    started: Instant,
+    start_bytes: u64,
+    last: Instant,
+    last_bytes: u64,
+    smoothed: Option<f64>,
+}
+
+impl RateMeter {
+    /// Start measuring with `bytes` already done, e.g. after a resume.
+    pub fn new(bytes: u64) -> Self {
+        let now = Instant::now();
+        RateMeter {
+            started: now,
+            start_bytes: bytes,
+            last: now,
+            last_bytes: bytes,
+            smoothed: None,
+        }
+    }
+
+    /// Record that `bytes` are done in total.
+    pub fn update(&mut self, bytes: u64) {
+        self.update_at(bytes, Instant::now());
+    }
+
+    fn update_at(&mut self, bytes: u64, now: Instant) {
+        let elapsed = now.duration_since(self.last);
+        if elapsed < MIN_SAMPLE {
+            return;
+        }
+        let sample = bytes.saturating_sub(self.last_bytes) as f64 / elapsed.as_secs_f64();
+        self.smoothed = Some(match self.smoothed {
+            Some(rate) => RATE_SMOOTHING * sample + (1.0 - RATE_SMOOTHING) * rate,
+            None => sample,
+        });
+        self.last = now;
+        self.last_bytes = bytes;
+    }
+
+    /// Bytes per second, if any time has passed.
+    pub fn rate(&self) -> Option<f64> {
+        self.smoothed.or_else(|| self.average(self.last_bytes))
+    }
+
+    /// Average bytes per second since the start, with `bytes` done in total.
+    pub fn average(&self, bytes: u64) -> Option<f64> {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        (elapsed > 0.0).then(|| bytes.saturating_sub(self.start_bytes) as f64 / elapsed)
+    }
+
+    /// Time left for `remaining` bytes at the current rate.
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        let rate = self.rate().filter(|&r| r > 0.0)?;
+        Duration::try_from_secs_f64(remaining as f64 / rate).ok()
+    }
+
+    /// `"1.20 MiB/s, ETA 0:42"` for a transfer at `done` of `total` bytes, or
+    /// just the average rate once it is complete.
+    pub fn describe(&self, done: u64, total: u64) -> String {
+        if done >= total {
+            return match self.average(done) {
+                Some(rate) => format!("{}/s", format_bytes(rate as u64)),
+                None => String::new(),
+            };
+        }
+        match (self.rate(), self.eta(total - done)) {
+            (Some(rate), Some(eta)) => {
+                format!("{}/s, ETA {}", format_bytes(rate as u64), format_duration(eta))
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+/// A status line redrawn in place with `\r`. A redraw shorter than the
+/// previous one is padded so none of the old text is left behind.
+#[derive(Default)]
+pub struct ProgressLine {
+    shown: usize,
+}
+
+impl ProgressLine {
+    pub fn draw(&mut self, text: &str) -> std::io::Result<()> {
+        use std::io::Write;
+        if crate::logging::quiet() {
+            return Ok(());
+        }
+        let len = text.chars().count();
+        let line = format!("\r{}{}", text, " ".repeat(self.shown.saturating_sub(len)));
+        self.shown = len;
+        if crate::logging::stdout_is_data() {
+            eprint!("{}", line);
+            return Ok(());
+        }
+        print!("{}", line);
+        std::io::stdout().flush()
+    }
+
+    /// Draw `text` a last time and move to the next line.
+    pub fn finish(&mut self, text: &str) -> std::io::Result<()> {
+        self.draw(text)?;
+        self.end();
+        Ok(())
+    }
+
+    /// Move to the next line if anything is showing, so that other output
+    /// starts on a fresh one.
+    pub fn end(&mut self) {
+        if self.shown > 0 {
+            if crate::logging::stdout_is_data() {
+                eprintln!();
+            } else {
+                println!();
+            }
+            self.shown = 0;
+        }
+    }
+}
+
+/// Progress of one file, e.g. `Sent: 1.00 MiB/2.86 MiB  1.20 MiB/s, ETA 0:02`,
+/// or `Sent: 1.00 MiB  1.20 MiB/s` while the size is unknown.
+pub struct FileProgress {
+    label: &'static str,
+    size: Option<u64>,
+    meter: RateMeter,
+    line: ProgressLine,
+}
+
+impl FileProgress {
+    /// `start` bytes were already there, e.g. after a resume.
+    pub fn new(label: &'static str, size: u64, start: u64) -> Self {
+        FileProgress {
+            label,
+            size: Some(size),
+            meter: RateMeter::new(start),
+            line: ProgressLine::default(),
+        }
+    }
+
+    /// For data of unknown size, such as a pipe.
+    pub fn open_ended(label: &'static str) -> Self {
+        FileProgress {
+            size: None,
+            ..FileProgress::new(label, 0, 0)
+        }
+    }
+
+    /// Text for `done` bytes, updating the rate.
+    pub fn text(&mut self, done: u64) -> String {
+        self.meter.update(done);
+        let (counts, rate) = match self.size {
+            Some(size) => (
+                format!("{}: {}/{}", self.label, format_bytes(done), format_bytes(size)),
+                self.meter.describe(done, size),
+            ),
+            None => (
+                format!("{}: {}", self.label, format_bytes(done)),
+                match self.meter.rate().filter(|&r| r > 0.0) {
+                    Some(rate) => format!("{}/s", format_bytes(rate as u64)),
+                    None => String::new(),
+                },
+            ),
+        };
+        match rate {
+            rate if rate.is_empty() => counts,
+            rate => format!("{}  {}", counts, rate),
+        }
+    }
+
+    pub fn draw(&mut self, done: u64) -> std::io::Result<()> {
+        let text = self.text(done);
+        self.line.draw(&text)
+    }
+
+    /// Move to the next line; the next `draw` starts a fresh one.
+    pub fn end(&mut self) {
+        self.line.end();
+    }
+
+    pub fn finish(&mut self, done: u64) -> std::io::Result<()> {
+        // By now the size is known, and the average rate is shown.
+        self.size.get_or_insert(done);
+        let text = self.text(done);
+        self.line.finish(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes_small() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+    }
+
+    #[test]
+    fn test_format_bytes_units() {
+        assert_eq!(format_bytes(1024), "1.00 KiB");
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+        assert_eq!(format_bytes(1024 * 1024), "1.00 MiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.00 GiB");
+        assert_eq!(format_bytes(1024u64.pow(4)), "1.00 TiB");
+    }
+
+    #[test]
+    fn test_format_bytes_petabyte_scale() {
+        assert_eq!(format_bytes(1024u64.pow(5)), "1.00 PiB");
+        assert_eq!(format_bytes(3 * 1024u64.pow(5) / 2), "1.50 PiB");
+        assert_eq!(format_bytes(1024u64.pow(6)), "1.00 EiB");
+        assert_eq!(format_bytes(u64::MAX), "16.00 EiB");
+        assert_eq!(format_bytes(-5.0f64 as u64), "0 B");
+    }
+
+    #[test]
+    fn test_format_bytes_si() {
+        assert_eq!(format_bytes_in(999, ByteUnits::Si), "999 B");
+        assert_eq!(format_bytes_in(1000, ByteUnits::Si), "1.00 KB");
+        assert_eq!(format_bytes_in(1024, ByteUnits::Si), "1.02 KB");
+        assert_eq!(format_bytes_in(2_500_000_000, ByteUnits::Si), "2.50 GB");
+        assert_eq!(format_bytes_in(10u64.pow(15), ByteUnits::Si), "1.00 PB");
+        assert_eq!(format_bytes_in(u64::MAX, ByteUnits::Si), "18.45 EB");
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("500").unwrap(), 500);
+        assert_eq!(parse_bytes("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_bytes("5m").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_bytes("1.5GB").unwrap(), 1536 * 1024 * 1024);
+        assert_eq!(parse_bytes("2 MB").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_bytes("1.50 KiB").unwrap(), 1536);
+        assert_eq!(parse_bytes("2P").unwrap(), 2 * 1024u64.pow(5));
+        assert!(parse_bytes("fast").is_err());
+        assert!(parse_bytes("5X").is_err());
+        assert!(parse_bytes("").is_err());
+    }
+
+    #[test]
+    fn test_throttle_minimum_duration() {
+        // 64 KiB at 256 KiB/s cannot finish in under a quarter second.
+        let started = Instant::now();
+        let mut throttle = Throttle::new(256 * 1024);
+        for _ in 0..8 {
+            throttle.sent(8192);
+        }
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_progress_ticker_fires_once_per_interval() {
+        let mut ticker = ProgressTicker::new(Duration::from_millis(100));
+        let start = ticker.last_print;
+        let fired: Vec<u64> = [30, 99, 101, 150, 199, 350, 351, 400]
+            .into_iter()
+            .filter(|&ms| ticker.tick_at(start + Duration::from_millis(ms)))
+            .collect();
+        // Each update restarts the wait, so 199 is too soon after 101.
+        assert_eq!(fired, vec![101, 350]);
+
+        // Zero reports on every call.
+        let mut ticker = ProgressTicker::new(Duration::ZERO);
+        assert!(ticker.tick());
+        assert!(ticker.tick());
+    }
+
+    #[test]
+    fn test_describe_throughput() {
+        let describe = describe_throughput;
+        let three_mib = 3 * 1024 * 1024;
+        assert_eq!(describe(three_mib, Duration::from_millis(1500)), "3.00 MiB in 1.50s (2.00 MiB/s)");
+        assert_eq!(describe(1024, Duration::from_secs(64)), "1.00 KiB in 1:04 (16 B/s)");
+        assert_eq!(describe(0, Duration::ZERO), "0 B in 0.00s");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(7)), "0:07");
+        assert_eq!(format_duration(Duration::from_secs(750)), "12:30");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1:02:03");
+    }
+
+    #[test]
+    fn test_rate_meter_smoothing() {
+        let mut meter = RateMeter::new(0);
+        let t0 = meter.started;
+        let second = |n: u64| t0 + Duration::from_secs(n);
+
+        meter.update_at(1000, second(1));
+        assert_eq!(meter.rate(), Some(1000.0));
+        assert_eq!(meter.eta(5000), Some(Duration::from_secs(5)));
+
+        // Too soon after the last sample: ignored.
+        meter.update_at(1_000_000, second(1) + Duration::from_millis(10));
+        assert_eq!(meter.rate(), Some(1000.0));
+
+        // A burst moves the rate only part of the way.
+        meter.update_at(3000, second(2));
+        let rate = meter.rate().unwrap();
+        assert!(rate > 1000.0 && rate < 2000.0, "{}", rate);
+    }
+}
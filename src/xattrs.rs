@@ -0,0 +1,156 @@
+//! Extended attributes for `--xattrs`.
+//!
+//! The sender reads a file's attributes into `FileMeta.attrs`, each name
+//! mapped to its value in hex, and the receiver sets them on the file it
+//! saved. Only Linux and macOS have them here. A file system without them
+//! gives an empty map on one side and a warning on the other; an attribute
+//! the receiver may not set (`trusted.*` without root, say) is skipped with
+//! a warning of its own, and the file is still saved.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::{from_hex, to_hex};
+use crate::types::{NcpError, Result};
+
+/// The attributes of the file at `path`, values in hex. Names that are not
+/// valid UTF-8 are left out, as are values that cannot be read.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn read(path: &Path) -> io::Result<HashMap<String, String>> {
+    let c_path = sys::c_path(path)?;
+    let names = match sys::sized(|buf, size| unsafe { sys::list(c_path.as_ptr(), buf, size) }) {
+        Ok(names) => names,
+        Err(e) if unsupported(&e) => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut attrs = HashMap::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let Ok(text) = std::str::from_utf8(name) else {
+            vlog!("Leaving out an attribute of {} whose name is not UTF-8", path.display());
+            continue;
+        };
+        let c_name = std::ffi::CString::new(name)?;
+        match sys::sized(|buf, size| unsafe { sys::get(c_path.as_ptr(), c_name.as_ptr(), buf, size) }) {
+            Ok(value) => {
+                attrs.insert(text.to_string(), to_hex(&value));
+            }
+            Err(e) => eprintln!("Warning: not sending {} of {}: {}", text, path.display(), e),
+        }
+    }
+    Ok(attrs)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn read(_path: &Path) -> io::Result<HashMap<String, String>> {
+    Ok(HashMap::new())
+}
+
+/// Set `attrs`, as `read` gives them, on the file at `path`.
+pub fn apply(path: &Path, attrs: &HashMap<String, String>) -> Result<()> {
+    let mut names: Vec<&String> = attrs.keys().collect();
+    names.sort();
+    for name in names {
+        let value = from_hex(&attrs[name])
+            .ok_or_else(|| NcpError::Protocol(format!("Attribute {} is not valid hex", name)))?;
+        vvlog!("Setting {} on {}", name, path.display());
+        match set(path, name, &value) {
+            Ok(()) => {}
+            Err(e) if unsupported(&e) => {
+                let path = path.display();
+                eprintln!("Warning: {} takes no extended attributes ({}); leaving them off", path, e);
+                return Ok(());
+            }
+            Err(e) => eprintln!("Warning: could not set {} on {}: {}", name, path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let c_path = sys::c_path(path)?;
+    let c_name = std::ffi::CString::new(name)?;
+    let ret = unsafe { sys::set(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr(), value.len()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+fn unsupported(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if e.raw_os_error() == Some(libc::ENOTSUP) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::c_char;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    pub fn c_path(path: &Path) -> io::Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    /// The bytes `call` gives once asked for their size with a null buffer,
+    /// asking again if they grew in between.
+    pub fn sized(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = call(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; size as usize];
+            let n = call(buf.as_mut_ptr(), buf.len());
+            if n >= 0 {
+                buf.truncate(n as usize);
+                return Ok(buf);
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ERANGE) {
+                return Err(e);
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn list(path: *const c_char, buf: *mut u8, size: usize) -> isize {
+        unsafe { libc::listxattr(path, buf.cast(), size) }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn list(path: *const c_char, buf: *mut u8, size: usize) -> isize {
+        unsafe { libc::listxattr(path, buf.cast(), size, 0) }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn get(path: *const c_char, name: *const c_char, buf: *mut u8, size: usize) -> isize {
+        unsafe { libc::getxattr(path, name, buf.cast(), size) }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn get(path: *const c_char, name: *const c_char, buf: *mut u8, size: usize) -> isize {
+        unsafe { libc::getxattr(path, name, buf.cast(), size, 0, 0) }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub unsafe fn set(path: *const c_char, name: *const c_char, value: *const u8, size: usize) -> i32 {
+        unsafe { libc::setxattr(path, name, value.cast(), size, 0) }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub unsafe fn set(path: *const c_char, name: *const c_char, value: *const u8, size: usize) -> i32 {
+        unsafe { libc::setxattr(path, name, value.cast(), size, 0, 0) }
+    }
+}
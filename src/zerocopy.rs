@@ -0,0 +1,117 @@
+//! Kernel-side copying of file data to the socket with `sendfile(2)`.
+//!
+//! Only used on Linux, and only for raw bodies without a checksum over a
+//! connection without TLS: the bytes never pass through userspace, so
+//! nothing could be hashed, compressed or encrypted. Anywhere else, or
+//! after `sendfile` fails once, callers fall back to their buffered loop.
+
+use std::fs::File;
+
+use crate::net::Stream;
+
+pub struct ZeroCopy {
+    #[cfg(target_os = "linux")]
+    socket: std::os::unix::io::RawFd,
+    enabled: bool,
+}
+
+impl ZeroCopy {
+    /// `wanted` is whether the transfer allows it at all; a `stream` inside
+    /// TLS never does.
+    #[cfg(target_os = "linux")]
+    pub fn new(stream: &Stream, wanted: bool) -> Self {
+        use crate::net::Socket;
+        use std::os::unix::io::AsRawFd;
+        ZeroCopy {
+            socket: stream.socket().as_raw_fd(),
+            enabled: wanted && matches!(stream, Stream::Plain(_)),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_stream: &Stream, _wanted: bool) -> Self {
+        ZeroCopy { enabled: false }
+    }
+
+    /// Send up to `count` bytes from the current position of `file`, which
+    /// advances past them. Returns `None` if the caller should copy this
+    /// chunk itself; 0 means end of file.
+    pub fn send(&mut self, file: &File, count: usize) -> Option<usize> {
+        if !self.enabled {
+            return None;
+        }
+        match self.sendfile(file, count) {
+            Ok(n) => Some(n),
+            Err(e) => {
+                vlog!("sendfile failed ({}), copying through userspace instead", e);
+                self.enabled = false;
+                None
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sendfile(&self, file: &File, count: usize) -> std::io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        loop {
+            // A null offset makes the kernel use and advance the file's own
+            // position, so a buffered read can carry on from where it stops.
+            let ret = unsafe {
+                libc::sendfile(self.socket, file.as_raw_fd(), std::ptr::null_mut(), count)
+            };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sendfile(&self, _file: &File, _count: usize) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_sendfile_streams_whole_file() {
+        let path = std::env::temp_dir().join(format!("ncp-sendfile-{}", std::process::id()));
+        let data: Vec<u8> = (0..4_000_000u32).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Stream::from(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut server, _) = listener.accept().unwrap();
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        let mut zero_copy = ZeroCopy::new(&client, true);
+        let mut total = 0;
+        loop {
+            match zero_copy.send(&file, 256 * 1024) {
+                Some(0) => break,
+                Some(n) => total += n,
+                None => panic!("sendfile was not used"),
+            }
+        }
+        drop(client);
+
+        assert_eq!(total, data.len() - 1000);
+        assert_eq!(reader.join().unwrap(), data[1000..]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Transfers driven through the library rather than the binary.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use ncp::{OverwriteMode, Options};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ncp-api-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[test]
+fn test_loopback_directory_transfer() {
+    let root = temp_dir("loopback");
+    fs::create_dir_all(root.join("src/sub")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+    fs::write(root.join("src/sub/b.bin"), vec![7u8; 300 * 1024]).unwrap();
+    ncp::set_quiet(true);
+
+    let addr = free_addr();
+    let opts = Options {
+        recursive: true,
+        overwrite: OverwriteMode::Yes,
+        // Covers the receiver not listening yet.
+        retries: 20,
+        retry_delay: std::time::Duration::from_millis(50),
+        ..Options::default()
+    };
+    let receiver = {
+        let (addr, dst, opts) = (addr.clone(), root.join("dst"), opts.clone());
+        thread::spawn(move || ncp::receive(&addr, &dst, &opts).map_err(|e| e.to_string()))
+    };
+    let sent = ncp::send_file(&addr, &root.join("src"), &opts).unwrap();
+    let received = receiver.join().unwrap().unwrap();
+
+    assert_eq!(sent, received);
+    assert_eq!(sent.bytes, 5 + 300 * 1024);
+    let mut paths: Vec<&str> = sent.files.iter().map(|f| f.path.as_str()).collect();
+    paths.sort();
+    assert_eq!(paths, ["a.txt", "sub/b.bin"]);
+    assert!(sent.files.iter().all(|f| !f.skipped && f.checksum.len() == 64));
+    assert_eq!(fs::read(root.join("dst/a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::metadata(root.join("dst/sub/b.bin")).unwrap().len(), 300 * 1024);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_send_needs_a_port() {
+    let err = ncp::send_file("localhost", &std::env::temp_dir(), &Options::default()).unwrap_err();
+    assert_eq!(err.to_string(), "No port in address: localhost");
+    let err = ncp::send_file("localhost:0", &std::env::temp_dir(), &Options::default()).unwrap_err();
+    assert_eq!(err.to_string(), "Cannot connect to port 0: localhost:0");
+}
+
+/// A SOCKS5 proxy that wants `user:pass`, on a thread of its own for the
+/// rest of the test run. Counts the tunnels it opened.
+fn socks5_proxy() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let tunnels = Arc::new(AtomicUsize::new(0));
+    let counted = tunnels.clone();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let counted = counted.clone();
+            thread::spawn(move || socks5_tunnel(client?, &counted));
+        }
+        io::Result::Ok(())
+    });
+    (addr, tunnels)
+}
+
+fn socks5_tunnel(mut client: TcpStream, tunnels: &AtomicUsize) -> io::Result<()> {
+    let mut head = [0u8; 2];
+    client.read_exact(&mut head)?;
+    let mut methods = vec![0u8; head[1] as usize];
+    client.read_exact(&mut methods)?;
+    assert!(methods.contains(&2), "no username/password method offered");
+    client.write_all(&[5, 2])?;
+
+    let field = |client: &mut TcpStream| -> io::Result<Vec<u8>> {
+        let mut len = [0u8; 1];
+        client.read_exact(&mut len)?;
+        let mut value = vec![0u8; len[0] as usize];
+        client.read_exact(&mut value)?;
+        Ok(value)
+    };
+    client.read_exact(&mut [0u8; 1])?;
+    let (user, pass) = (field(&mut client)?, field(&mut client)?);
+    let accepted = user == b"user" && pass == b"pass";
+    client.write_all(&[1, if accepted { 0 } else { 1 }])?;
+    if !accepted {
+        return Ok(());
+    }
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    assert_eq!(request[..2], [5, 1]);
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => String::from_utf8(field(&mut client)?).unwrap(),
+        other => panic!("unexpected address type {}", other),
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    let Ok(target) = TcpStream::connect((host.as_str(), u16::from_be_bytes(port))) else {
+        // Connection refused, for a receiver that is not listening yet.
+        return client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+    };
+    client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+    tunnels.fetch_add(1, Ordering::SeqCst);
+
+    let (mut upstream, mut downstream) = (target.try_clone()?, client.try_clone()?);
+    let copy = thread::spawn(move || {
+        let _ = io::copy(&mut downstream, &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    let (mut target, mut client) = (target, client);
+    let _ = io::copy(&mut target, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = copy.join();
+    Ok(())
+}
+
+#[test]
+fn test_send_through_socks5_proxy() {
+    let root = temp_dir("proxy");
+    fs::write(root.join("a.txt"), "through the proxy").unwrap();
+    ncp::set_quiet(true);
+    let (proxy, tunnels) = socks5_proxy();
+
+    let addr = free_addr();
+    let opts = Options {
+        overwrite: OverwriteMode::Yes,
+        retries: 20,
+        retry_delay: std::time::Duration::from_millis(50),
+        proxy: Some(format!("socks5://user:pass@{}", proxy)),
+        ..Options::default()
+    };
+    let receiver = {
+        let (addr, dst, opts) = (addr.clone(), root.join("dst.txt"), opts.clone());
+        thread::spawn(move || ncp::receive(&addr, &dst, &opts).map_err(|e| e.to_string()))
+    };
+    let sent = ncp::send_file(&addr, &root.join("a.txt"), &opts).unwrap();
+    assert_eq!(receiver.join().unwrap().unwrap(), sent);
+    assert_eq!(fs::read(root.join("dst.txt")).unwrap(), b"through the proxy");
+    assert_eq!(tunnels.load(Ordering::SeqCst), 1);
+
+    // Wrong credentials fail the send instead of going around the proxy.
+    let opts = Options {
+        retries: 1,
+        proxy: Some(format!("socks5://user:wrong@{}", proxy)),
+        ..opts
+    };
+    match ncp::send_file(&addr, &root.join("a.txt"), &opts) {
+        Err(ncp::NcpError::RetriesExhausted(1, last)) => {
+            assert!(last.to_string().contains("rejected the user name"), "{}", last)
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_defaults_keep_an_existing_file_without_asking() {
+    let root = temp_dir("keep");
+    fs::write(root.join("a.txt"), "new").unwrap();
+    fs::write(root.join("dst.txt"), "old").unwrap();
+    ncp::set_quiet(true);
+
+    let addr = free_addr();
+    let opts = Options {
+        retries: 20,
+        retry_delay: std::time::Duration::from_millis(50),
+        ..Options::default()
+    };
+    let receiver = {
+        let (addr, dst) = (addr.clone(), root.join("dst.txt"));
+        thread::spawn(move || ncp::receive(&addr, &dst, &Options::default()).map_err(|e| e.to_string()))
+    };
+    // The receiver declines the file rather than prompting, and returns.
+    assert!(ncp::send_file(&addr, &root.join("a.txt"), &opts).is_err());
+    receiver.join().unwrap().unwrap_err();
+    assert_eq!(fs::read(root.join("dst.txt")).unwrap(), b"old");
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,688 @@
+//! End-to-end runs of the `ncp` binary.
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn ncp() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_ncp"))
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ncp-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn free_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port().to_string()
+}
+
+#[test]
+fn test_quiet_transfer_prints_nothing() {
+    let root = temp_dir("quiet");
+    fs::create_dir_all(root.join("src/sub")).unwrap();
+    fs::write(root.join("src/a.txt"), "hello").unwrap();
+    fs::write(root.join("src/sub/b.bin"), vec![7u8; 3 * 1024 * 1024]).unwrap();
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "-q", "--port", &port])
+        .arg(root.join("dst"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The retries cover the receiver not listening yet.
+    let sender = ncp()
+        .args(["send", "-q", "-r", "--retries", "10", "--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("src"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+
+    for (side, output) in [("send", &sender), ("recv", &receiver)] {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{} failed: {}", side, stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.is_empty(), "{} printed {:?}", side, stdout);
+    }
+    assert_eq!(fs::read(root.join("dst/a.txt")).unwrap(), b"hello");
+    assert_eq!(fs::metadata(root.join("dst/sub/b.bin")).unwrap().len(), 3 * 1024 * 1024);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_pipe_through_stdin_and_stdout() {
+    use std::io::Write;
+
+    let data: Vec<u8> = (0..5_000_000u32).map(|i| (i * 13 % 251) as u8).collect();
+    for extra in [None, Some("--compress")] {
+        let port = free_port();
+        let receiver = ncp()
+            .args(["recv", "--port", &port, "-"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut sender = ncp()
+            .args(["send", "--retries", "10", "--host", "127.0.0.1", "--port", &port])
+            .args(extra)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Drained on its own thread while stdin is fed, or the pipes fill up.
+        let receiver = std::thread::spawn(move || receiver.wait_with_output().unwrap());
+        // Fed in pieces, as a producer in a pipeline would.
+        let mut stdin = sender.stdin.take().unwrap();
+        for chunk in data.chunks(700_000) {
+            stdin.write_all(chunk).unwrap();
+        }
+        drop(stdin);
+
+        let sender = sender.wait_with_output().unwrap();
+        let receiver = receiver.join().unwrap();
+        assert!(sender.status.success(), "send failed: {}", String::from_utf8_lossy(&sender.stderr));
+        let stderr = String::from_utf8_lossy(&receiver.stderr);
+        assert!(receiver.status.success(), "recv failed: {}", stderr);
+        // Status lines move to stderr, leaving stdout to the data alone.
+        assert!(stderr.contains("Transfer finished"), "{}", stderr);
+        assert!(receiver.stdout == data, "stdout differs ({} bytes)", receiver.stdout.len());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupt_removes_temp_file() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let root = temp_dir("interrupt");
+    fs::write(root.join("big.bin"), vec![3u8; 4 * 1024 * 1024]).unwrap();
+    fs::create_dir_all(root.join("dst")).unwrap();
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "-q", "--port", &port])
+        .arg(root.join("dst"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut sender = ncp()
+        .args(["send", "-q", "--retries", "10", "--limit", "256K", "--host", "127.0.0.1"])
+        .args(["--port", &port])
+        .arg(root.join("big.bin"))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Wait until the receiver is part-way through writing its temp file.
+    let temp = root.join("dst/big.bin.ncp_temp");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while fs::metadata(&temp).map_or(0, |m| m.len()) == 0 {
+        assert!(Instant::now() < deadline, "transfer never started");
+        thread::sleep(Duration::from_millis(20));
+    }
+    unsafe { libc::kill(receiver.id() as libc::pid_t, libc::SIGINT) };
+    let output = receiver.wait_with_output().unwrap();
+    let _ = sender.kill();
+    let _ = sender.wait();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(130), "{}", stderr);
+    assert!(stderr.contains("Transfer aborted"), "{}", stderr);
+    assert!(!temp.exists());
+    assert!(!root.join("dst/big.bin").exists());
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_progress_interval_bounds_updates() {
+    let root = temp_dir("progress");
+    fs::write(root.join("big.bin"), vec![5u8; 16 * 1024 * 1024]).unwrap();
+    fs::create_dir_all(root.join("dst")).unwrap();
+    let port = free_port();
+
+    // Far longer than the transfer takes, so only the final update is left.
+    let receiver = ncp()
+        .args(["recv", "--json", "--progress-interval", "60000", "--port", &port])
+        .arg(root.join("dst"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "--json", "--progress-interval", "60000", "--retries", "10"])
+        .args(["--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("big.bin"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+
+    for (side, output) in [("send", &sender), ("recv", &receiver)] {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{} failed: {}", side, stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let updates = stdout.lines().filter(|l| l.contains("\"file_progress\"")).count();
+        assert_eq!(updates, 0, "{} printed {}", side, stdout);
+        assert!(stdout.contains("\"file_done\""), "{} printed {}", side, stdout);
+    }
+    assert_eq!(fs::metadata(root.join("dst/big.bin")).unwrap().len(), 16 * 1024 * 1024);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_directory_summary_reports_timing() {
+    let root = temp_dir("summary");
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::write(root.join("src/big.bin"), vec![1u8; 2 * 1024 * 1024]).unwrap();
+    fs::write(root.join("src/small.txt"), "hello").unwrap();
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "-q", "--port", &port])
+        .arg(root.join("dst"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Held to about two seconds, so the time taken is well clear of zero.
+    let sender = ncp()
+        .args(["send", "-r", "--retries", "10", "--limit", "1M", "--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("src"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+    assert!(receiver.status.success(), "{}", String::from_utf8_lossy(&receiver.stderr));
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+
+    let stdout = String::from_utf8_lossy(&sender.stdout);
+    let summary = stdout
+        .lines()
+        .find_map(|l| l.trim_start_matches('\r').strip_prefix("Directory transfer complete: "))
+        .unwrap_or_else(|| panic!("no summary in {:?}", stdout));
+    assert!(summary.starts_with("2 files, 2.00 MiB in "), "{}", summary);
+    let seconds: f64 = summary
+        .split(" in ")
+        .nth(1)
+        .and_then(|rest| rest.split('s').next())
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or_else(|| panic!("no duration in {}", summary));
+    assert!((1.0..30.0).contains(&seconds), "{}", summary);
+    assert!(summary.ends_with("/s)"), "{}", summary);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_listen_gives_up_when_nobody_connects() {
+    use std::time::{Duration, Instant};
+
+    let root = temp_dir("accept-timeout");
+    fs::write(root.join("a.txt"), "hello").unwrap();
+    let started = Instant::now();
+    let output = ncp()
+        .args(["send", "--listen", "--once", "--accept-timeout", "1", "--port", "0"])
+        .arg(root.join("a.txt"))
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stderr.contains("Nobody connected within 1s"), "{}", stderr);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(20), "{:?}", elapsed);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_verify_only_compares_without_receiving() {
+    let root = temp_dir("verify-only");
+    for dir in ["src", "dst"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+        fs::write(root.join(dir).join("same.txt"), "hello").unwrap();
+    }
+    fs::write(root.join("src/changed.txt"), "new contents").unwrap();
+    fs::write(root.join("dst/changed.txt"), "old contents").unwrap();
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "--verify-only", "--into", "--port", &port])
+        .arg(root.join("dst"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "-r", "--retries", "10", "--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("src"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+
+    // A mismatch fails the receiver, like any failed verification.
+    let stdout = String::from_utf8_lossy(&receiver.stdout);
+    let stderr = String::from_utf8_lossy(&receiver.stderr);
+    assert!(!receiver.status.success(), "{}", stderr);
+    assert!(stdout.lines().any(|l| l == "match    same.txt"), "{}", stdout);
+    assert!(stdout.lines().any(|l| l == "mismatch changed.txt"), "{}", stdout);
+    assert!(stdout.contains("Verified 2 files: 1 matched, 1 mismatched, 0 missing"), "{}", stdout);
+    assert!(stderr.contains("1 of 2 files do not match the sender's"), "{}", stderr);
+    assert_eq!(fs::read(root.join("dst/changed.txt")).unwrap(), b"old contents");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_port_errors_say_what_is_wrong() {
+    let fails_with = |args: &[&str], expected: &str| {
+        let output = ncp().args(args).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{:?} succeeded", args);
+        assert!(stderr.contains(expected), "{:?}: {}", args, stderr);
+    };
+    fails_with(&["recv", "--port", "65536", "."], "Invalid port 65536: out of range 0-65535");
+    fails_with(&["recv", "--port", "nine", "."], "Invalid port nine: not a number");
+    fails_with(&["send", "--host", "localhost:-5", "."], "Invalid port in host localhost:-5: out of");
+    let no_port = ["send", "--host", "localhost", "--port", "0", "."];
+    fails_with(&no_port, "--port 0 only works with --listen");
+}
+
+#[test]
+fn test_permission_denied_destination_fails_the_sender_with_its_code() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = temp_dir("permission");
+    fs::write(root.join("a.txt"), "hello").unwrap();
+    let locked = root.join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+    // Root writes through the mode bits, but not into sysfs.
+    let dst = match fs::write(locked.join("probe"), "") {
+        Err(_) => locked.join("a.txt"),
+        Ok(()) => PathBuf::from("/sys/ncp-permission-test.txt"),
+    };
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "--port", &port])
+        .arg(&dst)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "--retries", "10", "--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("a.txt"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+
+    let stderr = String::from_utf8_lossy(&sender.stderr);
+    assert_eq!(sender.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("(ERR_PERMISSION)"), "{}", stderr);
+    // Retrying could not have helped, so the sender did not (the attempts
+    // before the receiver was listening aside).
+    assert_eq!(stderr.matches("failed: The receiver failed").count(), 1, "{}", stderr);
+    assert!(!receiver.status.success());
+
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_parallel_splits_a_file_over_connections() {
+    let root = temp_dir("parallel");
+    let data = (0..6 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8);
+    let data: Vec<u8> = data.collect();
+    fs::write(root.join("big.bin"), &data).unwrap();
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "-v", "--port", &port])
+        .arg(root.join("copy.bin"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "-v", "--parallel", "4", "--retries", "10"])
+        .args(["--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("big.bin"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+
+    let stderr = String::from_utf8_lossy(&sender.stderr);
+    assert!(sender.status.success(), "{}", stderr);
+    assert!(stderr.contains("Sending big.bin over 4 connections"), "{}", stderr);
+    let stderr = String::from_utf8_lossy(&receiver.stderr);
+    assert!(receiver.status.success(), "{}", stderr);
+    assert!(stderr.contains("Receiving big.bin over 4 connections"), "{}", stderr);
+    assert!(fs::read(root.join("copy.bin")).unwrap() == data);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_compress_shared_sends_a_directory_as_one_stream() {
+    let root = temp_dir("shared");
+    fs::create_dir_all(root.join("src/conf")).unwrap();
+    let mut files = Vec::new();
+    for i in 0..20 {
+        let text = format!("[worker.{i}]\nqueue = \"jobs-{i}\"\nthreads = 8\n").repeat(20);
+        fs::write(root.join(format!("src/conf/w{i}.toml")), &text).unwrap();
+        files.push((format!("conf/w{i}.toml"), text));
+    }
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "-q", "--port", &port])
+        .arg(root.join("dst"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "-q", "-r", "--compress-shared", "--retries", "10"])
+        .args(["--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("src"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+    assert!(receiver.status.success(), "{}", String::from_utf8_lossy(&receiver.stderr));
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+
+    for (name, text) in files {
+        assert_eq!(fs::read_to_string(root.join("dst").join(name)).unwrap(), text);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_progress_socket_gets_the_event_stream() {
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+
+    let root = temp_dir("progress-socket");
+    fs::write(root.join("big.bin"), vec![9u8; 4 * 1024 * 1024]).unwrap();
+    let socket = root.join("progress.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let reader = std::thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut events = String::new();
+        conn.read_to_string(&mut events).unwrap();
+        events
+    });
+    let port = free_port();
+
+    let receiver = ncp()
+        .args(["recv", "-q", "--port", &port])
+        .arg(root.join("copy.bin"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "--progress-interval", "0", "--retries", "10"])
+        .args(["--host", "127.0.0.1", "--port", &port, "--progress-socket"])
+        .arg(&socket)
+        .arg(root.join("big.bin"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+    assert!(receiver.status.success(), "{}", String::from_utf8_lossy(&receiver.stderr));
+
+    // The terminal keeps its own output; the events go to the socket.
+    let stdout = String::from_utf8_lossy(&sender.stdout);
+    assert!(!stdout.contains("\"event\""), "{}", stdout);
+    let events = reader.join().unwrap();
+    let kinds: Vec<&str> = events
+        .lines()
+        .map(|line| {
+            assert!(line.starts_with("{\"event\":\"") && line.ends_with('}'), "{}", line);
+            line.split('"').nth(3).unwrap()
+        })
+        .collect();
+    assert_eq!(kinds.first(), Some(&"transfer_start"), "{}", events);
+    assert_eq!(kinds[1], "file_start", "{}", events);
+    assert!(kinds[2..kinds.len() - 2].iter().all(|&k| k == "file_progress"), "{}", events);
+    assert!(kinds.len() > 4, "no progress in {}", events);
+    assert_eq!(&kinds[kinds.len() - 2..], ["file_done", "done"], "{}", events);
+    assert!(events.contains("\"rate\":"), "{}", events);
+    assert!(events.contains("\"bytes\":4194304"), "{}", events);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_config_file_supplies_the_port() {
+    let root = temp_dir("config");
+    fs::write(root.join("a.txt"), "configured").unwrap();
+    let port = free_port();
+    let config = root.join("config.toml");
+    // compress, at the top, is a flag of send only; recv leaves it alone.
+    let text = format!(
+        "port = {}\ncompress = true\n\n[send]\nhost = \"127.0.0.1\"\nretries = 10\n",
+        port
+    );
+    fs::write(&config, text).unwrap();
+
+    let receiver = ncp()
+        .args(["recv", "-q", "--config"])
+        .arg(&config)
+        .arg(root.join("copy.txt"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Neither --host nor --port: both come from the file.
+    let sender = ncp()
+        .args(["send", "-q", "--config"])
+        .arg(&config)
+        .arg(root.join("a.txt"))
+        .output()
+        .unwrap();
+    let receiver = receiver.wait_with_output().unwrap();
+    assert!(receiver.status.success(), "{}", String::from_utf8_lossy(&receiver.stderr));
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+    assert_eq!(fs::read_to_string(root.join("copy.txt")).unwrap(), "configured");
+
+    // A flag on the command line wins over the file's.
+    let sender = ncp()
+        .args(["send", "--port", "0", "--config"])
+        .arg(&config)
+        .arg(root.join("a.txt"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&sender.stderr);
+    assert!(stderr.contains("--port 0 only works with --listen"), "{}", stderr);
+
+    // Even over one it could not be combined with, and a switch the file
+    // turns on can be turned off.
+    for flags in [&["--verify-chunks"][..], &["--no-compress", "--framed"]] {
+        let sender = ncp()
+            .args(["send", "-q", "--dry-run", "--config"])
+            .arg(&config)
+            .args(flags)
+            .arg(root.join("a.txt"))
+            .output()
+            .unwrap();
+        assert!(sender.status.success(), "{:?}: {}", flags, String::from_utf8_lossy(&sender.stderr));
+    }
+
+    let bad = root.join("bad.toml");
+    for (text, why) in [
+        ("[both]\n", "bad.toml:1: unknown table [both]"),
+        ("port = 1\n[recv]\ncompress = true\n", "bad.toml:3: compress is a flag of send only"),
+        ("\ncolour = true\n", "bad.toml:2: unknown key colour"),
+    ] {
+        fs::write(&bad, text).unwrap();
+        let output = ncp().args(["send", "--config"]).arg(&bad).arg(root.join("a.txt")).output();
+        let sender = output.unwrap();
+        let stderr = String::from_utf8_lossy(&sender.stderr);
+        assert!(stderr.contains(why), "{}", stderr);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_si_prints_sizes_in_powers_of_1000() {
+    let root = temp_dir("si");
+    fs::write(root.join("a.bin"), vec![0u8; 5000]).unwrap();
+    for (flags, size) in [(&[][..], "4.88 KiB"), (&["--si"], "5.00 KB")] {
+        let output = ncp().args(["send", "--dry-run"]).args(flags).arg(root.join("a.bin")).output();
+        let output = output.unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains(&format!("{} (5000 bytes) in total", size)), "{}", stdout);
+    }
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_hash_prints_digests_like_sha256sum() {
+    let root = temp_dir("hash");
+    let file = root.join("abc.txt");
+    fs::write(&file, "abc").unwrap();
+
+    let output = ncp().arg("hash").arg(&file).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let expected = format!(
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  {}\n",
+        file.display()
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    // Standard input, another algorithm, and a file that is not there.
+    let mut hasher = ncp()
+        .args(["hash", "--checksum", "crc32", "-"])
+        .arg(root.join("missing"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::io::Write::write_all(&mut hasher.stdin.take().unwrap(), b"abc").unwrap();
+    let output = hasher.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "352441c2  -\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("1 of 2 files could not be hashed"), "{}", stderr);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_verify_audits_a_tree_against_its_manifest() {
+    let root = temp_dir("verify-manifest");
+    fs::create_dir_all(root.join("src/sub")).unwrap();
+    fs::create_dir_all(root.join("dst")).unwrap();
+    let files = [("kept.txt", "hello"), ("sub/tampered.txt", "original"), ("gone.txt", "bye")];
+    for (path, contents) in files {
+        fs::write(root.join("src").join(path), contents).unwrap();
+    }
+    let port = free_port();
+    let manifest = root.join("received.jsonl");
+
+    let receiver = ncp()
+        .args(["recv", "--into", "--port", &port, "--manifest"])
+        .arg(&manifest)
+        .arg(root.join("dst"))
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let sender = ncp()
+        .args(["send", "-r", "-q", "--retries", "10", "--host", "127.0.0.1", "--port", &port])
+        .arg(root.join("src"))
+        .output()
+        .unwrap();
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+    assert!(receiver.wait_with_output().unwrap().status.success());
+
+    let verify = || ncp().args(["verify", "--manifest"]).arg(&manifest).arg(root.join("dst")).output();
+    assert!(verify().unwrap().status.success());
+
+    fs::write(root.join("dst/sub/tampered.txt"), "changed!").unwrap();
+    fs::remove_file(root.join("dst/gone.txt")).unwrap();
+    fs::write(root.join("dst/new.txt"), "extra").unwrap();
+    let output = verify().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.lines().any(|l| l == "match    kept.txt"), "{}", stdout);
+    assert!(stdout.lines().any(|l| l == "mismatch sub/tampered.txt"), "{}", stdout);
+    assert!(stdout.lines().any(|l| l == "missing  gone.txt"), "{}", stdout);
+    assert!(stdout.lines().any(|l| l == "extra    new.txt"), "{}", stdout);
+    let summary = "Audited 4 files: 1 matched, 1 mismatched, 1 missing, 1 extra";
+    assert!(stdout.contains(summary), "{}", stdout);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_tls_transfer_with_a_self_signed_certificate() {
+    let root = temp_dir("tls");
+    let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    fs::write(root.join("cert.pem"), issued.cert.pem()).unwrap();
+    fs::write(root.join("key.pem"), issued.signing_key.serialize_pem()).unwrap();
+    let data: Vec<u8> = (0..6 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    fs::write(root.join("big.bin"), &data).unwrap();
+
+    let transfer = |trust: &[&str], copy: &str| {
+        let port = free_port();
+        let receiver = ncp()
+            .args(["recv", "-q", "--tls", "--port", &port, "--cert"])
+            .arg(root.join("cert.pem"))
+            .arg("--key")
+            .arg(root.join("key.pem"))
+            .arg(root.join(copy))
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let sender = ncp()
+            .args(["send", "-v", "--tls", "--parallel", "4", "--retries", "10"])
+            .args(["--host", "localhost", "--port", &port])
+            .args(trust)
+            .arg(root.join("big.bin"))
+            .output()
+            .unwrap();
+        (sender, receiver.wait_with_output().unwrap())
+    };
+
+    let ca = root.join("cert.pem").to_string_lossy().into_owned();
+    let (sender, receiver) = transfer(&["--ca", &ca], "trusted.bin");
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+    assert!(receiver.status.success(), "{}", String::from_utf8_lossy(&receiver.stderr));
+    assert!(String::from_utf8_lossy(&sender.stderr).contains("over 4 connections"));
+    assert!(fs::read(root.join("trusted.bin")).unwrap() == data);
+
+    // The public CAs never signed it, and one refusal is enough.
+    let (sender, receiver) = transfer(&[], "untrusted.bin");
+    let stderr = String::from_utf8_lossy(&sender.stderr);
+    assert!(!sender.status.success() && !receiver.status.success(), "{}", stderr);
+    assert!(stderr.contains("TLS handshake"), "{}", stderr);
+    assert!(stderr.contains("Not retrying"), "{}", stderr);
+    assert!(!root.join("untrusted.bin").exists());
+
+    let (sender, receiver) = transfer(&["--insecure"], "insecure.bin");
+    assert!(sender.status.success(), "{}", String::from_utf8_lossy(&sender.stderr));
+    assert!(receiver.status.success(), "{}", String::from_utf8_lossy(&receiver.stderr));
+    assert!(fs::read(root.join("insecure.bin")).unwrap() == data);
+
+    fs::remove_dir_all(&root).unwrap();
+}